@@ -0,0 +1,72 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use yarve::bus::{Bus, SharedBus};
+use yarve::cpu::core::Core;
+use yarve::device::Device;
+use yarve::dram::DRAM;
+
+// Mix of arithmetic, calls and memory accesses, 14 instructions per iteration.
+const PROGRAM: [u32; 19] = [
+    0x0C80_0113,  // addi x2, x0, 200
+    0x0000_1537,  // lui  x10, 0x1
+    0x0070_0293,  // addi x5, x0, 7
+    0x0280_00EF,  // jal  x1, 40
+    0x0065_3023,  // sd   x6, 0(x10)
+    0x0045_2383,  // lw   x7, 4(x10)
+    0x4053_D433,  // sra  x8, x7, x5
+    0x4033_5493,  // srai x9, x6, 3
+    0x0085_0513,  // addi x10, x10, 8
+    0xFFF1_0113,  // addi x2, x2, -1
+    0xFE01_12E3,  // bne  x2, x0, -28
+    0x0084_B5B3,  // sltu x11, x9, x8
+    0x0000_006F,  // jal  x0, 0
+    0x0053_4333,  // xor  x6, x6, x5
+    0x0053_1613,  // slli x12, x6, 5
+    0x00C3_0333,  // add  x6, x6, x12
+    0x0053_5633,  // srl  x12, x6, x5
+    0x40C3_0333,  // sub  x6, x6, x12
+    0x0000_8067,  // jalr x0, 0(x1)
+];
+const PROGRAM_STEPS: u64 = 3 + 200 * 14;
+
+fn new_core() -> Core {
+    let mut dram = DRAM::new(0x10000);
+    for (i, instruction) in PROGRAM.iter().enumerate() {
+        dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+    }
+    let bus = Bus::new(vec![(0, Box::new(dram))]).unwrap();
+    Core::new(SharedBus::new(bus))
+}
+
+fn bench_interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+
+    let mut core = new_core();
+    group.bench_function("single_step", |b| b.iter(|| {
+        core.pc = 0;
+        for _ in 0..PROGRAM_STEPS {
+            core.execute().unwrap();
+        }
+    }));
+
+    let mut core = new_core();
+    #[cfg(feature = "jit")]
+    core.block_cache.set_jit_mode(yarve::cpu::jit::JitMode::Disabled);
+    group.bench_function("blocks", |b| b.iter(|| {
+        core.pc = 0;
+        core.execute_blocks(PROGRAM_STEPS).unwrap();
+    }));
+
+    #[cfg(feature = "jit")]
+    {
+        let mut core = new_core();
+        group.bench_function("jit", |b| b.iter(|| {
+            core.pc = 0;
+            core.execute_blocks(PROGRAM_STEPS).unwrap();
+        }));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_interpreter);
+criterion_main!(benches);
@@ -0,0 +1,115 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::any::Any;
+use std::borrow::Cow;
+use std::hint::black_box;
+use yarve::bus::{Bus, SharedBus};
+use yarve::cpu::core::Core;
+use yarve::device::{Device, DeviceError};
+use yarve::dram::DRAM;
+use yarve::endianness::Endianness;
+
+const DATA_ADDRESS: usize = 0x2000;
+const DATA_SIZE: usize = 2048;
+
+// Increments every doubleword in DATA_ADDRESS..DATA_ADDRESS + DATA_SIZE.
+const PROGRAM: [u32; 8] = [
+    0x000020B7, // lui  x1, 0x2
+    0x10000113, // addi x2, x0, 256
+    0x0000B183, // ld   x3, 0(x1)
+    0x00118193, // addi x3, x3, 1
+    0x0030B023, // sd   x3, 0(x1)
+    0x00808093, // addi x1, x1, 8
+    0xFFF10113, // addi x2, x2, -1
+    0xFE0116E3, // bne  x2, x0, -20
+];
+const PROGRAM_STEPS: usize = 2 + (DATA_SIZE / 8) * 6;
+
+/// DRAM that doesn't expose its memory, so every access takes the `Device` path.
+#[derive(Debug)]
+struct SlowDRAM(DRAM);
+
+impl Device for SlowDRAM {
+    fn get_address_space_size(&self) -> usize { self.0.get_address_space_size() }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        self.0.read_bytes(address, size)
+    }
+
+    fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
+        self.0.write_bytes(address, binary)
+    }
+
+    fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        self.0.read_int(address, size, endianness, sign_extend)
+    }
+
+    fn write_int(&mut self, address: usize, value: u64, size: usize, endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        self.0.write_int(address, value, size, endianness)
+    }
+
+    fn get_name(&self) -> &str { "slow_dram" }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+fn new_dram() -> DRAM {
+    let mut dram = DRAM::new(0x10000);
+    for (i, instruction) in PROGRAM.iter().enumerate() {
+        dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+    }
+    dram
+}
+
+fn new_core(device: Box<dyn Device>) -> Core {
+    let bus = Bus::new(vec![(0, device)]).unwrap();
+    Core::new(SharedBus::new(bus))
+}
+
+fn run_program(core: &mut Core) {
+    core.pc = 0;
+    for _ in 0..PROGRAM_STEPS {
+        core.execute().unwrap();
+    }
+}
+
+fn bench_memory_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("memory_loop");
+
+    let mut core = new_core(Box::new(new_dram()));
+    run_program(&mut core);
+    assert_eq!(core.pc, PROGRAM.len() * 4);
+    assert_eq!(core.bus.lock().read_int(DATA_ADDRESS + DATA_SIZE - 8, 8,
+                                          Endianness::LittleEndian, false).unwrap(), 1);
+    group.bench_function("fast_path", |b| b.iter(|| run_program(&mut core)));
+
+    let mut core = new_core(Box::new(SlowDRAM(new_dram())));
+    group.bench_function("device_path", |b| b.iter(|| run_program(&mut core)));
+
+    group.finish();
+}
+
+fn bench_bus_access(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bus_access");
+
+    for (name, device) in [("fast_path", Box::new(new_dram()) as Box<dyn Device>),
+                           ("device_path", Box::new(SlowDRAM(new_dram())))] {
+        let mut bus = Bus::new(vec![(0, device)]).unwrap();
+        group.bench_function(name, |b| b.iter(|| {
+            for address in (DATA_ADDRESS..(DATA_ADDRESS + DATA_SIZE)).step_by(8) {
+                let value = bus.read_int(black_box(address), 8, Endianness::LittleEndian, false)
+                    .unwrap();
+                bus.write_int(address, value.wrapping_add(1), 8, Endianness::LittleEndian)
+                    .unwrap();
+            }
+        }));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_memory_loop, bench_bus_access);
+criterion_main!(benches);
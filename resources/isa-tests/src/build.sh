@@ -0,0 +1,16 @@
+#!/bin/sh
+# Builds the ISA tests into the parent directory with LLVM's assembler and linker:
+#   ./build.sh [llvm-mc] [ld.lld]
+set -e
+cd "$(dirname "$0")"
+MC=${1:-llvm-mc}
+LD=${2:-ld.lld}
+BUILD=$(mktemp -d)
+trap 'rm -rf "$BUILD"' EXIT
+
+python3 generate.py "$BUILD"
+for source in "$BUILD"/*.S; do
+    name=$(basename "$source" .S)
+    "$MC" -triple=riscv64 -mattr=+m,-relax -filetype=obj "$source" -o "$BUILD/$name.o"
+    "$LD" -static -nostdlib -T link.ld "$BUILD/$name.o" -o "../$name"
+done
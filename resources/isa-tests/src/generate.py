@@ -0,0 +1,463 @@
+#!/usr/bin/env python3
+"""Generates the assembly of the ISA tests in this directory.
+
+The tests follow the layout and macros of riscv-tests (env/p and isa/macros/scalar):
+each one starts in M-mode, drops to U-mode with mret, runs numbered test cases with
+the case number in gp, and reports through HTIF by writing 1 to `tohost` on success
+or (case << 1) | 1 on failure. The expected results are computed here, independently
+of the emulator.
+
+    python3 generate.py out-directory
+"""
+
+import os
+import re
+import sys
+
+XLEN = 64
+MASK = (1 << XLEN) - 1
+
+
+def u(value):
+    return value & MASK
+
+
+def s(value):
+    value = u(value)
+    return value - (1 << XLEN) if value >> (XLEN - 1) else value
+
+
+def sext32(value):
+    value &= 0xFFFF_FFFF
+    return u(value - (1 << 32) if value >> 31 else value)
+
+
+def s32(value):
+    value &= 0xFFFF_FFFF
+    return value - (1 << 32) if value >> 31 else value
+
+
+def trunc_div(a, b):
+    quotient = abs(a) // abs(b)
+    return -quotient if (a < 0) != (b < 0) else quotient
+
+
+def div(a, b):
+    if b == 0:
+        return MASK
+    if s(a) == -(1 << 63) and s(b) == -1:
+        return u(a)
+    return u(trunc_div(s(a), s(b)))
+
+
+def rem(a, b):
+    if b == 0:
+        return u(a)
+    if s(a) == -(1 << 63) and s(b) == -1:
+        return 0
+    return u(s(a) - trunc_div(s(a), s(b)) * s(b))
+
+
+def divw(a, b):
+    a, b = s32(a), s32(b)
+    if b == 0:
+        return MASK
+    if a == -(1 << 31) and b == -1:
+        return sext32(a)
+    return sext32(trunc_div(a, b))
+
+
+def remw(a, b):
+    a, b = s32(a), s32(b)
+    if b == 0:
+        return sext32(a)
+    if a == -(1 << 31) and b == -1:
+        return 0
+    return sext32(a - trunc_div(a, b) * b)
+
+
+def divuw(a, b):
+    a, b = a & 0xFFFF_FFFF, b & 0xFFFF_FFFF
+    return MASK if b == 0 else sext32(a // b)
+
+
+def remuw(a, b):
+    a, b = a & 0xFFFF_FFFF, b & 0xFFFF_FFFF
+    return sext32(a) if b == 0 else sext32(a % b)
+
+
+RR_OPS = {
+    'add': lambda a, b: u(a + b),
+    'sub': lambda a, b: u(a - b),
+    'xor': lambda a, b: a ^ b,
+    'or': lambda a, b: a | b,
+    'and': lambda a, b: a & b,
+    'sll': lambda a, b: u(a << (b & 63)),
+    'srl': lambda a, b: a >> (b & 63),
+    'sra': lambda a, b: u(s(a) >> (b & 63)),
+    'slt': lambda a, b: int(s(a) < s(b)),
+    'sltu': lambda a, b: int(a < b),
+    'addw': lambda a, b: sext32(a + b),
+    'subw': lambda a, b: sext32(a - b),
+    'sllw': lambda a, b: sext32(a << (b & 31)),
+    'srlw': lambda a, b: sext32((a & 0xFFFF_FFFF) >> (b & 31)),
+    'sraw': lambda a, b: sext32(s32(a) >> (b & 31)),
+    'mul': lambda a, b: u(a * b),
+    'mulh': lambda a, b: u((s(a) * s(b)) >> 64),
+    'mulhsu': lambda a, b: u((s(a) * b) >> 64),
+    'mulhu': lambda a, b: (a * b) >> 64,
+    'div': div,
+    'divu': lambda a, b: MASK if b == 0 else a // b,
+    'rem': rem,
+    'remu': lambda a, b: a if b == 0 else a % b,
+    'mulw': lambda a, b: sext32(a * b),
+    'divw': divw,
+    'divuw': divuw,
+    'remw': remw,
+    'remuw': remuw,
+}
+
+IMM_OPS = {
+    'addi': lambda a, i: u(a + i),
+    'xori': lambda a, i: a ^ u(i),
+    'ori': lambda a, i: a | u(i),
+    'andi': lambda a, i: a & u(i),
+    'slti': lambda a, i: int(s(a) < i),
+    'sltiu': lambda a, i: int(a < u(i)),
+    'slli': lambda a, i: u(a << i),
+    'srli': lambda a, i: a >> i,
+    'srai': lambda a, i: u(s(a) >> i),
+    'addiw': lambda a, i: sext32(a + i),
+    'slliw': lambda a, i: sext32(a << i),
+    'srliw': lambda a, i: sext32((a & 0xFFFF_FFFF) >> i),
+    'sraiw': lambda a, i: sext32(s32(a) >> i),
+}
+
+# Operands shared by the register-register tests.
+VALUES = [
+    0, 1, 3, 7, 0xFFFF_FFFF_FFFF_8000, 0x8000_0000, 0xFFFF_FFFF_8000_0000,
+    0x8000_0000_0000_0000, 0x7FFF_FFFF_FFFF_FFFF, 0x0000_0000_7FFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF, 0x0000_0000_0000_7FFF, 0x1234_5678_9ABC_DEF0,
+    0xFFFF_FFFF_0000_0001, 0x0000_0001_0000_0000, 0xFEDC_BA98_7654_3210,
+]
+SHIFTS = [0, 1, 7, 14, 20, 31, 32, 33, 39, 63, 0xFFFF_FFFF_FFFF_FFC0, 0xFFFF_FFFF_FFFF_FFE1]
+IMMEDIATES = [0, 1, 7, -1, -2048, 2047, 0x555, -0x556, 0x7FF, 0x800 - 0x1000 + 3]
+
+
+INVERSE = {'beq': 'bne', 'bne': 'beq', 'blt': 'bge', 'bge': 'blt', 'bltu': 'bgeu', 'bgeu': 'bltu'}
+
+
+class Test:
+    def __init__(self):
+        self.lines = []
+        self.data = []
+        self.number = 1
+
+    def case(self, code):
+        self.number += 1
+        self.lines.append(f'test_{self.number}:')
+        self.lines.append(f'    li gp, {self.number}')
+        for line in code:
+            # `fail` may be out of the range of a branch, so it's reached with a jump.
+            match = re.fullmatch(r'(\d+: )?(b\w+) (\S+), (\S+), fail', line)
+            if match:
+                label, branch, first, second = match.groups()
+                self.lines.append(f'    {label or ""}{INVERSE[branch]} {first}, {second}, 9f')
+                self.lines.append('    j fail')
+                self.lines.append('9:')
+            else:
+                self.lines.append('    ' + line)
+
+    def check(self, register, value):
+        return [f'li t2, {u(value):#x}', f'bne {register}, t2, fail']
+
+    def rr(self, op, a, b):
+        result = RR_OPS[op](a, b)
+        self.case([f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x14, x1, x2'] + self.check('x14', result))
+
+    def rr_variants(self, op, a, b):
+        function = RR_OPS[op]
+        self.case([f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x1, x1, x2'] + self.check('x1', function(a, b)))
+        self.case([f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x2, x1, x2'] + self.check('x2', function(a, b)))
+        self.case([f'li x1, {a:#x}', f'{op} x1, x1, x1'] + self.check('x1', function(a, a)))
+        for nops in range(3):
+            self.case(['li x4, 0', '1:', f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x14, x1, x2']
+                      + ['nop'] * nops + ['addi x6, x14, 0', 'addi x4, x4, 1', 'li x5, 2', 'bne x4, x5, 1b']
+                      + self.check('x6', function(a, b)))
+        self.case([f'li x1, {a:#x}', f'{op} x2, x0, x1'] + self.check('x2', function(0, a)))
+        self.case([f'li x1, {a:#x}', f'{op} x2, x1, x0'] + self.check('x2', function(a, 0)))
+        self.case([f'{op} x1, x0, x0'] + self.check('x1', function(0, 0)))
+        self.case([f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x0, x1, x2'] + self.check('x0', 0))
+
+    def imm(self, op, a, i):
+        result = IMM_OPS[op](a, i)
+        self.case([f'li x1, {a:#x}', f'{op} x14, x1, {i}'] + self.check('x14', result))
+
+    def imm_variants(self, op, a, i):
+        function = IMM_OPS[op]
+        self.case([f'li x1, {a:#x}', f'{op} x1, x1, {i}'] + self.check('x1', function(a, i)))
+        for nops in range(3):
+            self.case(['li x4, 0', '1:', f'li x1, {a:#x}', f'{op} x14, x1, {i}']
+                      + ['nop'] * nops + ['addi x6, x14, 0', 'addi x4, x4, 1', 'li x5, 2', 'bne x4, x5, 1b']
+                      + self.check('x6', function(a, i)))
+        self.case([f'{op} x1, x0, {i}'] + self.check('x1', function(0, i)))
+        self.case([f'li x1, {a:#x}', f'{op} x0, x1, {i}'] + self.check('x0', 0))
+
+    def source(self):
+        lines = [
+            '    .section .text.init, "ax"',
+            '    .globl _start',
+            '_start:',
+            '    j reset_vector',
+            '    .align 2',
+            'trap_vector:',
+            '    csrr t5, mcause',
+            '    li t6, 8',
+            '    beq t5, t6, write_tohost',
+            '    li t6, 9',
+            '    beq t5, t6, write_tohost',
+            '    li t6, 11',
+            '    beq t5, t6, write_tohost',
+            '    ori gp, gp, 1337',
+            'write_tohost:',
+            '    la t5, tohost',
+            '    sw gp, 0(t5)',
+            '    sw zero, 4(t5)',
+            '    j write_tohost',
+            'reset_vector:',
+        ]
+        lines += [f'    li x{i}, 0' for i in range(1, 32)]
+        lines += [
+            '    la t0, trap_vector',
+            '    csrw mtvec, t0',
+            '    csrwi mstatus, 0',
+            '    la t0, 1f',
+            '    csrw mepc, t0',
+            '    csrr a0, mhartid',
+            '    mret',
+            '1:',
+        ]
+        lines += self.lines
+        lines += [
+            '    bne x0, gp, pass' if self.lines else '    j pass',
+            'fail:',
+            '    fence',
+            '1:  beqz gp, 1b',
+            '    sll gp, gp, 1',
+            '    ori gp, gp, 1',
+            '    li a7, 93',
+            '    addi a0, gp, 0',
+            '    ecall',
+            'pass:',
+            '    fence',
+            '    li gp, 1',
+            '    li a7, 93',
+            '    li a0, 0',
+            '    ecall',
+            '    unimp',
+            '',
+            '    .section .tohost, "aw", @progbits',
+            '    .align 6',
+            '    .globl tohost',
+            'tohost: .dword 0',
+            '    .align 6',
+            '    .globl fromhost',
+            'fromhost: .dword 0',
+            '',
+            '    .data',
+            '    .align 4',
+        ]
+        lines += self.data
+        return '\n'.join(lines) + '\n'
+
+
+def rr_test(op):
+    test = Test()
+    values = VALUES
+    seconds = SHIFTS if op[:3] in ('sll', 'srl', 'sra') else VALUES
+    for a in values:
+        for b in seconds:
+            test.rr(op, a, b)
+    test.rr_variants(op, 0x1234_5678_9ABC_DEF0, 7)
+    return test
+
+
+def imm_test(op):
+    test = Test()
+    if op in ('slli', 'srli', 'srai'):
+        immediates = [0, 1, 7, 14, 31, 32, 33, 63]
+    elif op in ('slliw', 'srliw', 'sraiw'):
+        immediates = [0, 1, 7, 14, 20, 31]
+    else:
+        immediates = IMMEDIATES
+    for a in VALUES:
+        for i in immediates:
+            test.imm(op, a, i)
+    test.imm_variants(op, 0xFEDC_BA98_7654_3210, immediates[2])
+    return test
+
+
+LOADS = {'lb': (1, True), 'lh': (2, True), 'lw': (4, True), 'ld': (8, False),
+         'lbu': (1, False), 'lhu': (2, False), 'lwu': (4, False)}
+DATA = bytes([0x00, 0xFF, 0x0F, 0xF0, 0x80, 0x7F, 0x01, 0xFE,
+              0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0,
+              0xAA, 0x55, 0x00, 0x80, 0xFF, 0xFF, 0xFF, 0x7F])
+
+
+def load_value(op, offset):
+    size, signed = LOADS[op]
+    value = int.from_bytes(DATA[offset:offset + size], 'little')
+    if signed and value >> (size * 8 - 1):
+        value -= 1 << (size * 8)
+    return u(value)
+
+
+def load_test(op):
+    test = Test()
+    size = LOADS[op][0]
+    for offset in range(0, len(DATA) - size + 1, size):
+        test.case(['la x1, tdat', f'{op} x14, {offset}(x1)'] + test.check('x14', load_value(op, offset)))
+        # The same address with a negative offset.
+        test.case([f'la x1, tdat + {offset + 8 * size}', f'{op} x14, {-8 * size}(x1)']
+                  + test.check('x14', load_value(op, offset)))
+    for nops in range(3):
+        test.case(['li x4, 0', '1:', 'la x1, tdat', f'{op} x14, {size}(x1)'] + ['nop'] * nops
+                  + ['addi x6, x14, 0', 'addi x4, x4, 1', 'li x5, 2', 'bne x4, x5, 1b']
+                  + test.check('x6', load_value(op, size)))
+    # The base register is overwritten by the load.
+    test.case(['la x5, tdat', f'{op} x5, 0(x5)'] + test.check('x5', load_value(op, 0)))
+    test.data = ['tdat:'] + [f'    .byte {byte:#x}' for byte in DATA]
+    return test
+
+
+STORES = {'sb': ('lb', 1), 'sh': ('lh', 2), 'sw': ('lw', 4), 'sd': ('ld', 8)}
+
+
+def store_test(op):
+    test = Test()
+    load, size = STORES[op]
+    values = [0xAA, 0xFFFF_FFFF_FFFF_FF00, 0x0AA0, 0xA00A, 0xAABB_CCDD, 0xDDCC_BBAA_0011_2233,
+              0x1234_5678_9ABC_DEF0, 0xF000_0000_0000_000F]
+    for i, value in enumerate(values):
+        expected = value & ((1 << (size * 8)) - 1)
+        if expected >> (size * 8 - 1):
+            expected -= 1 << (size * 8)
+        offset = i * size
+        test.case(['la x1, tdat', f'li x2, {value:#x}', f'{op} x2, {offset}(x1)', f'{load} x14, {offset}(x1)']
+                  + test.check('x14', u(expected)))
+        test.case([f'la x1, tdat + {offset + 16 * size}', f'li x2, {value:#x}', f'{op} x2, {-16 * size}(x1)',
+                   f'{load} x14, {-16 * size}(x1)'] + test.check('x14', u(expected)))
+    # Stores only touch their own bytes.
+    mask = (1 << (size * 8)) - 1
+    test.case(['la x1, tdat', 'li x2, -1', 'sd x2, 0(x1)', 'sd x2, 8(x1)', 'sd x2, 16(x1)',
+               f'{op} x0, 8(x1)', 'ld x14, 0(x1)', 'ld x15, 8(x1)', 'ld x16, 16(x1)']
+              + test.check('x14', u(-1)) + test.check('x15', u(-1) & ~mask) + test.check('x16', u(-1)))
+    test.data = ['tdat:', f'    .zero {32 * size}']
+    return test
+
+
+BRANCHES = {
+    'beq': lambda a, b: a == b,
+    'bne': lambda a, b: a != b,
+    'blt': lambda a, b: s(a) < s(b),
+    'bge': lambda a, b: s(a) >= s(b),
+    'bltu': lambda a, b: a < b,
+    'bgeu': lambda a, b: a >= b,
+}
+
+
+def branch_test(op):
+    test = Test()
+    values = [0, 1, u(-1), u(-2), 0x7FFF_FFFF_FFFF_FFFF, 0x8000_0000_0000_0000, 0xFFFF_FFFF]
+    for a in values:
+        for b in values:
+            if BRANCHES[op](a, b):
+                test.case([f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x1, x2, 2f', 'bne x0, gp, fail',
+                           '1: bne x0, gp, 3f', f'2: {op} x1, x2, 1b', 'bne x0, gp, fail', '3:'])
+            else:
+                test.case([f'li x1, {a:#x}', f'li x2, {b:#x}', f'{op} x1, x2, 1f', 'bne x0, gp, 2f',
+                           '1: bne x0, gp, fail', f'2: {op} x1, x2, 1b', '3:'])
+    return test
+
+
+def jump_test(op):
+    test = Test()
+    if op == 'jal':
+        test.case(['li ra, 0', 'jal x4, 2f', '1: nop', 'nop', 'j fail', '2: la x2, 1b', 'bne x2, x4, fail'])
+        test.case(['li ra, 1', 'jal x0, 2f', 'addi ra, ra, 1', 'addi ra, ra, 1', '2: addi ra, ra, 1']
+                  + test.check('ra', 2))
+        test.case(['jal x0, 2f', '.rept 300', 'j fail', '.endr', '2:'])
+        test.case(['jal x0, 3f', '2: jal x0, 4f', '.rept 300', 'j fail', '.endr', '3: jal x0, 2b', '4:'])
+    else:
+        test.case(['li t0, 0', 'la t1, 2f', 'jalr t0, t1, 0', '1: j fail', '2: la t1, 1b', 'bne t0, t1, fail'])
+        test.case(['li t0, 0', 'la t1, 2f', 'jalr t1, t1, 0', '1: j fail', '2: la t0, 1b', 'bne t0, t1, fail'])
+        # The low bit of the target is cleared, and the offset is added to it.
+        test.case(['la t1, 2f + 1', 'jalr x0, t1, 0', 'j fail', '.align 2', '2:'])
+        test.case(['la t1, 2f + 40', 'jalr x0, t1, -40', 'j fail', '2:'])
+        test.case(['la t1, 2f - 2047', 'jalr x0, t1, 2047', 'j fail', '2:'])
+    return test
+
+
+def other_test(op):
+    test = Test()
+    if op == 'lui':
+        for value in [0, 0xFFFFF, 0x7FFFF, 0x80000, 0x12345]:
+            test.case([f'lui x1, {value:#x}'] + test.check('x1', sext32(value << 12)))
+        test.case(['lui x1, 0x80000', 'srai x1, x1, 12'] + test.check('x1', u(-0x80000)))
+        test.case(['lui x0, 0x80000'] + test.check('x0', 0))
+    elif op == 'auipc':
+        test.case(['.align 3', '1: auipc a0, 0x12', 'jal a1, 2f', '2: sub a0, a0, a1']
+                  + test.check('a0', 0x12000 - 8))
+        test.case(['.align 3', '1: auipc a0, 0xFFFFF', 'jal a1, 2f', '2: sub a0, a0, a1']
+                  + test.check('a0', u(-0x1000 - 8)))
+    elif op == 'fence_i':
+        # Patches an instruction, which has to be seen once the fetch is synchronized.
+        test.case(['li a3, 111', 'la t0, 2f', 'la t1, 3f', 'lw t1, 0(t1)', 'sw t1, 0(t0)', 'fence.i',
+                   '2: addi a3, a3, 1', 'j 4f', '3: addi a3, a3, 222', '4:'] + test.check('a3', 333))
+        # Code that already ran once is patched as well.
+        test.case(['li a3, 0', 'li a4, 0', 'la t0, 2f', 'la t1, 3f', 'lw t1, 0(t1)', '1:', '2: addi a3, a3, 1',
+                   'bnez a4, 4f', 'sw t1, 0(t0)', 'fence.i', 'li a4, 1', 'j 1b', '3: addi a3, a3, 100', '4:']
+                  + test.check('a3', 101))
+    elif op == 'simple':
+        pass
+    return test
+
+
+SUITES = {
+    'rv64ui': ['add', 'addi', 'addiw', 'addw', 'and', 'andi', 'auipc', 'beq', 'bge', 'bgeu', 'blt',
+               'bltu', 'bne', 'fence_i', 'jal', 'jalr', 'lb', 'lbu', 'ld', 'lh', 'lhu', 'lui', 'lw',
+               'lwu', 'or', 'ori', 'sb', 'sd', 'sh', 'simple', 'sll', 'slli', 'slliw', 'sllw', 'slt',
+               'slti', 'sltiu', 'sltu', 'sra', 'srai', 'sraiw', 'sraw', 'srl', 'srli', 'srliw', 'srlw',
+               'sub', 'subw', 'sw', 'xor', 'xori'],
+    'rv64um': ['div', 'divu', 'divuw', 'divw', 'mul', 'mulh', 'mulhsu', 'mulhu', 'mulw', 'rem',
+               'remu', 'remuw', 'remw'],
+}
+
+
+def generate(op):
+    if op in RR_OPS:
+        return rr_test(op)
+    if op in IMM_OPS:
+        return imm_test(op)
+    if op in LOADS:
+        return load_test(op)
+    if op in STORES:
+        return store_test(op)
+    if op in BRANCHES:
+        return branch_test(op)
+    if op in ('jal', 'jalr'):
+        return jump_test(op)
+    return other_test(op)
+
+
+def main():
+    directory = sys.argv[1]
+    os.makedirs(directory, exist_ok=True)
+    for suite, ops in SUITES.items():
+        for op in ops:
+            with open(os.path.join(directory, f'{suite}-p-{op}.S'), 'w') as file:
+                file.write(generate(op).source())
+
+
+if __name__ == '__main__':
+    main()
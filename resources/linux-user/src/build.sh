@@ -0,0 +1,14 @@
+#!/bin/sh
+# Builds the user-mode test programs into the parent directory with a nightly rustc,
+# without compressed instructions:
+#   ./build.sh [rust-lld]
+set -e
+cd "$(dirname "$0")"
+LD=${1:-$(rustc +nightly --print sysroot)/lib/rustlib/$(rustc +nightly -vV | sed -n 's/host: //p')/bin/rust-lld}
+
+for source in *.rs; do
+    rustc +nightly --target riscv64gc-unknown-linux-musl -C target-feature=-c,-zca \
+        -C opt-level=2 -C panic=abort -C overflow-checks=off -C relocation-model=static \
+        -C linker="$LD" -C linker-flavor=ld.lld -C link-self-contained=no -C link-arg=-static \
+        "$source" -o "../$(basename "$source" .rs)"
+done
@@ -0,0 +1,258 @@
+//! Statically linked RV64 Linux program used by the user-mode emulation tests. It's
+//! built without the standard library, so it only needs a nightly compiler, see
+//! `build.sh`.
+//!
+//! For each number given as an argument it prints the number of primes up to it and
+//! their sum, the length of its Collatz sequence, the alternating sum of its quotients
+//! and the sum of its remainders by 1 to 1000, its greatest common divisor with
+//! 360, and its quotient and remainder by -7. It exits with the number of arguments.
+#![feature(no_core, lang_items, rustc_attrs, decl_macro)]
+#![allow(internal_features)]
+#![no_core]
+#![no_std]
+#![no_main]
+
+#[lang = "pointee_sized"] pub trait PointeeSized {}
+#[lang = "meta_sized"] pub trait MetaSized: PointeeSized {}
+#[lang = "sized"] pub trait Sized: MetaSized {}
+#[lang = "copy"] pub trait Copy {}
+#[lang = "legacy_receiver"] pub trait LegacyReceiver {}
+impl<T: ?Sized> LegacyReceiver for &T {}
+#[lang = "drop_glue"] unsafe fn drop_glue<T: ?Sized>(_: *mut T) {}
+
+macro_rules! primitives {
+    ($($t:ty)*) => {$(
+        impl Copy for $t {}
+        impl Add for $t { type Output = $t; fn add(self, rhs: $t) -> $t { self + rhs } }
+        impl Sub for $t { type Output = $t; fn sub(self, rhs: $t) -> $t { self - rhs } }
+        impl Mul for $t { type Output = $t; fn mul(self, rhs: $t) -> $t { self * rhs } }
+        impl Div for $t { type Output = $t; fn div(self, rhs: $t) -> $t { self / rhs } }
+        impl Rem for $t { type Output = $t; fn rem(self, rhs: $t) -> $t { self % rhs } }
+        impl BitAnd for $t { type Output = $t; fn bitand(self, rhs: $t) -> $t { self & rhs } }
+        impl Shr<u32> for $t { type Output = $t; fn shr(self, rhs: u32) -> $t { self >> rhs } }
+        impl PartialEq for $t {
+            fn eq(&self, other: &$t) -> bool { *self == *other }
+            fn ne(&self, other: &$t) -> bool { *self != *other }
+        }
+        impl PartialOrd for $t {
+            fn lt(&self, other: &$t) -> bool { *self < *other }
+            fn le(&self, other: &$t) -> bool { *self <= *other }
+            fn gt(&self, other: &$t) -> bool { *self > *other }
+            fn ge(&self, other: &$t) -> bool { *self >= *other }
+        }
+    )*}
+}
+
+#[lang = "add"] pub trait Add<Rhs = Self> { type Output; fn add(self, rhs: Rhs) -> Self::Output; }
+#[lang = "sub"] pub trait Sub<Rhs = Self> { type Output; fn sub(self, rhs: Rhs) -> Self::Output; }
+#[lang = "mul"] pub trait Mul<Rhs = Self> { type Output; fn mul(self, rhs: Rhs) -> Self::Output; }
+#[lang = "div"] pub trait Div<Rhs = Self> { type Output; fn div(self, rhs: Rhs) -> Self::Output; }
+#[lang = "rem"] pub trait Rem<Rhs = Self> { type Output; fn rem(self, rhs: Rhs) -> Self::Output; }
+#[lang = "bitand"] pub trait BitAnd<Rhs = Self> { type Output; fn bitand(self, rhs: Rhs) -> Self::Output; }
+#[lang = "shr"] pub trait Shr<Rhs = Self> { type Output; fn shr(self, rhs: Rhs) -> Self::Output; }
+#[lang = "eq"] pub trait PartialEq<Rhs: ?Sized = Self> {
+    fn eq(&self, other: &Rhs) -> bool;
+    fn ne(&self, other: &Rhs) -> bool;
+}
+#[lang = "partial_ord"] pub trait PartialOrd<Rhs: ?Sized = Self>: PartialEq<Rhs> {
+    fn lt(&self, other: &Rhs) -> bool;
+    fn le(&self, other: &Rhs) -> bool;
+    fn gt(&self, other: &Rhs) -> bool;
+    fn ge(&self, other: &Rhs) -> bool;
+}
+
+primitives!(u8 u32 i32 u64 i64 usize);
+impl Copy for bool {}
+impl<T: ?Sized> Copy for *const T {}
+impl<T: ?Sized> Copy for *mut T {}
+
+#[rustc_builtin_macro] pub macro asm("assembly template", $(operands,)* $(options($(option),*))?) {}
+#[rustc_builtin_macro] pub macro global_asm("assembly template", $(operands,)* $(options($(option),*))?) {}
+
+#[lang = "panic_const_div_by_zero"] fn panic_div_by_zero() -> ! { exit(101) }
+#[lang = "panic_const_rem_by_zero"] fn panic_rem_by_zero() -> ! { exit(101) }
+#[lang = "panic_const_div_overflow"] fn panic_div_overflow() -> ! { exit(101) }
+#[lang = "panic_const_rem_overflow"] fn panic_rem_overflow() -> ! { exit(101) }
+#[lang = "panic_location"] struct Location<'a> { _file: &'a str, _line: u32, _column: u32 }
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT_GROUP: u64 = 94;
+const LIMIT: usize = 100_000;
+
+static mut COMPOSITE: [u8; LIMIT + 1] = [0; LIMIT + 1];
+static mut OUTPUT: [u8; 256] = [0; 256];
+static mut LENGTH: usize = 0;
+
+fn exit(code: u64) -> ! {
+    unsafe { asm!("ecall", in("a7") SYS_EXIT_GROUP, in("a0") code, options(noreturn)) }
+}
+
+fn write(fd: u64, data: *const u8, length: usize) {
+    unsafe { asm!("ecall", in("a7") SYS_WRITE, inlateout("a0") fd => _, in("a1") data, in("a2") length) }
+}
+
+fn byte(address: usize) -> u8 {
+    unsafe { *(address as *const u8) }
+}
+
+fn put(value: u8) {
+    unsafe {
+        *((&raw mut OUTPUT as usize + LENGTH) as *mut u8) = value;
+        LENGTH = LENGTH + 1;
+    }
+}
+
+fn put_str(text: &str) {
+    let start = text as *const str as *const u8 as usize;
+    let mut i = 0;
+    while i < text_len(text) {
+        put(byte(start + i));
+        i = i + 1;
+    }
+}
+
+fn text_len(text: &str) -> usize {
+    // A `&str` is a pointer and a length.
+    unsafe { *((&raw const text as usize + 8) as *const usize) }
+}
+
+fn put_unsigned(value: u64) {
+    let mut digits = [0u8; 20];
+    let mut count = 0;
+    let mut value = value;
+    loop {
+        digits_set(&raw mut digits as usize, count, b'0' + (value % 10) as u8);
+        count = count + 1;
+        value = value / 10;
+        if value == 0 {
+            break
+        }
+    }
+    while count > 0 {
+        count = count - 1;
+        put(byte(&raw const digits as usize + count));
+    }
+}
+
+fn digits_set(base: usize, index: usize, value: u8) {
+    unsafe { *((base + index) as *mut u8) = value }
+}
+
+fn put_signed(value: i64) {
+    if value < 0 {
+        put(b'-');
+        put_unsigned((0 - value) as u64);
+    } else {
+        put_unsigned(value as u64);
+    }
+}
+
+fn flush() {
+    unsafe {
+        write(1, &raw const OUTPUT as *const u8, LENGTH);
+        LENGTH = 0;
+    }
+}
+
+fn parse(address: usize) -> u64 {
+    let mut value = 0u64;
+    let mut i = 0;
+    while byte(address + i) != 0 {
+        value = value * 10 + (byte(address + i) - b'0') as u64;
+        i = i + 1;
+    }
+    value
+}
+
+/// Number and sum of the primes up to `limit`.
+fn primes(limit: usize) -> (u32, u64) {
+    let sieve = &raw mut COMPOSITE as usize;
+    let mut i = 0;
+    while i <= limit {
+        digits_set(sieve, i, 0);
+        i = i + 1;
+    }
+    let (mut count, mut sum) = (0u32, 0u64);
+    let mut n = 2;
+    while n <= limit {
+        if byte(sieve + n) == 0 {
+            count = count + 1;
+            sum = sum + n as u64;
+            let mut multiple = n * n;
+            while multiple <= limit {
+                digits_set(sieve, multiple, 1);
+                multiple = multiple + n;
+            }
+        }
+        n = n + 1;
+    }
+    (count, sum)
+}
+
+/// Steps for `n` to reach 1, in 32-bit arithmetic.
+fn collatz(n: u32) -> i32 {
+    let mut n = n;
+    let mut steps = 0i32;
+    while n > 1 {
+        n = if n & 1 == 0 { n / 2 } else { 3 * n + 1 };
+        steps = steps + 1;
+    }
+    steps
+}
+
+/// Sum of n / k and of n % k for k up to 1000, in 32-bit arithmetic.
+fn divisor_sums(n: i32) -> (i32, u32) {
+    let (mut quotients, mut remainders) = (0i32, 0u32);
+    let mut k = 1;
+    while k <= 1000 {
+        quotients = quotients + n / k * (k & 1) - n / k * (1 - (k & 1));
+        remainders = remainders + n as u32 % k as u32;
+        k = k + 1;
+    }
+    (quotients, remainders)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn start_rust(stack: *const u64) -> ! {
+    let argc = unsafe { *stack };
+    let mut i = 1;
+    while i < argc {
+        let argument = unsafe { *((stack as usize + 8 * (i as usize + 1)) as *const u64) } as usize;
+        let n = parse(argument);
+        let limit = if n > LIMIT as u64 { LIMIT } else { n as usize };
+        let (count, sum) = primes(limit);
+        put_unsigned(n);
+        put_str(": primes=");
+        put_unsigned(count as u64);
+        put_str(" sum=");
+        put_unsigned(sum);
+        put_str(" collatz=");
+        put_signed(collatz(n as u32) as i64);
+        let (quotients, remainders) = divisor_sums(n as i32);
+        put_str(" quotients=");
+        put_signed(quotients as i64);
+        put_str(" remainders=");
+        put_unsigned(remainders as u64);
+        put_str(" gcd=");
+        put_unsigned(gcd(n, 360));
+        put_str(" div=");
+        put_signed(n as i64 / (0 - 7));
+        put_str(" rem=");
+        put_signed(n as i64 % (0 - 7));
+        put(b'\n');
+        flush();
+        i = i + 1;
+    }
+    exit(argc - 1)
+}
+
+global_asm!(
+    ".globl _start",
+    "_start:",
+    "mv a0, sp",
+    "call start_rust",
+);
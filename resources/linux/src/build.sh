@@ -0,0 +1,19 @@
+#!/bin/sh
+# Builds the boot test kernel and initramfs into the parent directory with LLVM's
+# assembler, linker and objcopy:
+#   ./build.sh [llvm-mc] [ld.lld] [llvm-objcopy]
+set -e
+cd "$(dirname "$0")"
+MC=${1:-llvm-mc}
+LD=${2:-ld.lld}
+OBJCOPY=${3:-llvm-objcopy}
+BUILD=$(mktemp -d)
+trap 'rm -rf "$BUILD"' EXIT
+
+for name in kernel init; do
+    "$MC" -triple=riscv64 -mattr=+m,-relax -filetype=obj "$name.S" -o "$BUILD/$name.o"
+done
+"$LD" -static -nostdlib -T kernel.ld "$BUILD/kernel.o" -o "$BUILD/kernel"
+"$OBJCOPY" -O binary "$BUILD/kernel" ../Image
+"$LD" -static -nostdlib -T init.ld "$BUILD/init.o" -o "$BUILD/init"
+python3 mkcpio.py "$BUILD/init" ../rootfs.cpio
@@ -0,0 +1,29 @@
+#!/usr/bin/env python3
+"""Packs /init into a newc cpio archive, the initramfs format Linux unpacks:
+    python3 mkcpio.py init rootfs.cpio
+"""
+
+import sys
+
+
+def entry(name, mode, data=b""):
+    # Fixed inode numbers, owners and times keep the archive reproducible.
+    fields = [0, mode, 0, 0, 1, 0, len(data), 0, 0, 0, 0, len(name) + 1, 0]
+    header = b"070701" + b"".join(b"%08X" % field for field in fields)
+    encoded = header + name.encode() + b"\0"
+    encoded += b"\0" * (-len(encoded) % 4)
+    return encoded + data + b"\0" * (-len(data) % 4)
+
+
+def main():
+    init, output = sys.argv[1:]
+    with open(init, "rb") as file:
+        data = file.read()
+    archive = entry(".", 0o40755) + entry("init", 0o100755, data) + entry("TRAILER!!!", 0)
+    archive += b"\0" * (-len(archive) % 512)
+    with open(output, "wb") as file:
+        file.write(archive)
+
+
+if __name__ == "__main__":
+    main()
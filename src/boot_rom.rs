@@ -0,0 +1,132 @@
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use std::fmt::{Debug, Formatter};
+use std::any::Any;
+use std::borrow::Cow;
+
+const BOOT_ROM_SIZE: usize = 0x1000;
+
+// Offsets of the data words following the trampoline.
+const ENTRY_ADDRESS_OFFSET: usize = 24;
+const FDT_ADDRESS_OFFSET: usize = 32;
+
+// Registers used by the trampoline.
+const T0: u32 = 5;
+const A0: u32 = 10;
+const A1: u32 = 11;
+
+const MHARTID: u32 = 0xF14;
+
+
+/// Read-only memory holding the code executed at reset.
+///
+/// The trampoline follows the standard firmware handoff: it jumps to the
+/// entry address with the hart id in `a0` and the device tree address in `a1`.
+///
+/// ```text
+/// auipc t0, 0
+/// csrr  a0, mhartid
+/// ld    a1, 32(t0)
+/// ld    t0, 24(t0)
+/// jr    t0
+/// .word 0
+/// .dword entry_address
+/// .dword fdt_address
+/// ```
+pub struct BootROM {
+    memory: Vec<u8>,
+}
+
+impl BootROM {
+    pub fn new(entry_address: u64) -> Self {
+        let mut memory = vec![0; BOOT_ROM_SIZE];
+
+        let trampoline = [
+            encode_u(0b0010111, T0, 0),
+            encode_i(0b1110011, A0, 0b010, 0, MHARTID),
+            encode_i(0b0000011, A1, 0b011, T0, FDT_ADDRESS_OFFSET as u32),
+            encode_i(0b0000011, T0, 0b011, T0, ENTRY_ADDRESS_OFFSET as u32),
+            encode_i(0b1100111, 0, 0b000, T0, 0),
+        ];
+        for (i, instruction) in trampoline.iter().enumerate() {
+            memory[(i * 4)..(i * 4 + 4)].copy_from_slice(&instruction.to_le_bytes());
+        }
+
+        let mut boot_rom = Self { memory };
+        boot_rom.set_entry_address(entry_address);
+        boot_rom
+    }
+
+    pub fn set_entry_address(&mut self, address: u64) {
+        self.memory[ENTRY_ADDRESS_OFFSET..(ENTRY_ADDRESS_OFFSET + 8)]
+            .copy_from_slice(&address.to_le_bytes());
+    }
+
+    pub fn set_fdt_address(&mut self, address: u64) {
+        self.memory[FDT_ADDRESS_OFFSET..(FDT_ADDRESS_OFFSET + 8)]
+            .copy_from_slice(&address.to_le_bytes());
+    }
+}
+
+fn encode_i(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: u32) -> u32 {
+    (imm << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn encode_u(opcode: u32, rd: u32, imm: u32) -> u32 {
+    (imm << 12) | (rd << 7) | opcode
+}
+
+impl Debug for BootROM {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BootROM {{ size: {:?} }}", self.memory.len())
+    }
+}
+
+impl Device for BootROM {
+    fn get_address_space_size(&self) -> usize { self.memory.len() }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        if address + size <= self.memory.len() {
+            Ok(Cow::Borrowed(&self.memory[address..(address + size)]))
+        } else {
+            Err(DeviceError::InvalidAddressReadFault)
+        }
+    }
+
+    fn write_bytes(&mut self, address: usize, _binary: &[u8]) -> Result<(), DeviceError> {
+        Err(DeviceError::ReadOnlyWriteFault { address })
+    }
+
+    fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        match size {
+            1..=8 => Ok(int_from_bytes(&self.read_bytes(address, size)?, endianness, sign_extend)),
+            _ => Err(DeviceError::InvalidSizeReadFault)
+        }
+    }
+
+    fn write_int(&mut self, address: usize, _value: u64, _size: usize, _endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        Err(DeviceError::ReadOnlyWriteFault { address })
+    }
+
+    fn get_name(&self) -> &str { "boot_rom" }
+
+    fn get_memory_region(&self, _address: usize) -> Option<(usize, &[u8])> {
+        Some((0, &self.memory))
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_bytes(&self.memory)
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        reader.read_bytes_into(&mut self.memory, "boot_rom")
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
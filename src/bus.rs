@@ -1,7 +1,16 @@
 use crate::device::{Device, DeviceError};
 use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::{extend_sign, int_from_bytes};
 use rangemap::RangeMap;
 use std::any::Any;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use core::ops::Range;
 
 
@@ -9,61 +18,451 @@ use core::ops::Range;
 pub struct Bus {
     address_space_size: usize,
     address_space_map: RangeMap<usize, usize>,
-    devices: Vec<Box<dyn Device>>
+    devices: Vec<Box<dyn Device>>,
+    misaligned_access_policy: MisalignedAccessPolicy,
+    read_region_cache: Cell<Option<CachedRegion>>,
+    write_region_cache: Cell<Option<CachedRegion>>,
+    code_pages: HashMap<usize, u64>,
+    code_generation: u64,
+    code_flush_generation: u64,
+    exit_request: Option<u64>,
+    interrupt_raised: bool,
+    reservations: Vec<(u64, usize)>
 }
 
+/// Handle to a bus shared between harts and host threads. Cloning it gives another
+/// handle to the same bus.
+#[derive(Debug, Clone)]
+pub struct SharedBus(Arc<Mutex<Bus>>);
+
+impl SharedBus {
+    pub fn new(bus: Bus) -> Self {
+        Self(Arc::new(Mutex::new(bus)))
+    }
+
+    /// Locks the bus, blocking until no other thread holds it. A panic on another
+    /// thread while it held the lock doesn't poison the bus, since every access leaves
+    /// it consistent.
+    pub fn lock(&self) -> MutexGuard<'_, Bus> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Granularity at which the bus tracks stores to memory holding code.
+pub const CODE_PAGE_SIZE: usize = 4096;
+
+/// Size of the naturally aligned block a load-reserved reserves. Any store that
+/// overlaps it breaks the reservation.
+pub const RESERVATION_GRANULE: usize = 8;
+
+/// Host memory backing a range of bus addresses, as returned by
+/// `Device::get_memory_region`. Accesses that hit the last used region skip the
+/// device lookup and the `Device` calls.
+#[derive(Debug, Copy, Clone)]
+struct CachedRegion {
+    start: usize,
+    end: usize,
+    host_address: *mut u8
+}
+
+// SAFETY: the pointer is into memory owned by a device on the same bus, so it moves
+// with the bus, and it's only dereferenced through `&self` or `&mut self` of the bus.
+unsafe impl Send for CachedRegion {}
+
+impl CachedRegion {
+    fn contains(&self, address: usize, size: usize) -> bool {
+        address >= self.start && address.checked_add(size)
+            .is_some_and(|end_address| end_address <= self.end)
+    }
+}
+
+/// How the bus handles integer accesses whose address is not a multiple of their size.
+///
+/// Byte accesses (`read_bytes`/`write_bytes`) are never subject to this policy, but
+/// any access, aligned or not, that does not fit in the device at its start address
+/// results in a `DeviceError::StraddlingAccessFault` unless it is emulated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MisalignedAccessPolicy {
+    /// Perform the access. If it crosses into another device it's split into byte
+    /// accesses, each of which is forwarded to the device it falls in.
+    Emulate,
+    /// Return a `MisalignedAddress*Trap` so the core can raise a misaligned-address
+    /// exception in the guest.
+    Trap,
+    /// Return a `MisalignedAddress*Fault`, stopping the emulator.
+    Fault,
+}
+
+#[derive(Debug)]
+pub enum BusError {
+    OverlappingDevices { first: MemoryMapEntry, second: MemoryMapEntry },
+    ZeroSizedDevice { name: String, base_address: usize },
+    AddressSpaceOverflow { name: String, base_address: usize, size: usize },
+}
+
+/// A single device mapping on the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMapEntry {
+    pub name: String,
+    pub address_range: Range<usize>,
+}
+
+/// Listing of all devices on the bus, sorted by base address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap(pub Vec<MemoryMapEntry>);
+
 impl Bus {
-    pub fn new(devices: Vec<(usize, Box<dyn Device>)>) -> Self {
+    pub fn new(devices: Vec<(usize, Box<dyn Device>)>) -> Result<Self, BusError> {
         let mut bus = Self {
             address_space_size: 0,
             address_space_map: RangeMap::new(),
-            devices: Vec::new()
+            devices: Vec::new(),
+            misaligned_access_policy: MisalignedAccessPolicy::Emulate,
+            read_region_cache: Cell::new(None),
+            write_region_cache: Cell::new(None),
+            code_pages: HashMap::new(),
+            code_generation: 0,
+            code_flush_generation: 0,
+            exit_request: None,
+            interrupt_raised: false,
+            reservations: Vec::new()
         };
 
         for (base_address, device) in devices {
             let address_space_size = device.get_address_space_size();
-            let end_address = base_address + address_space_size;
+
+            if address_space_size == 0 {
+                return Err(BusError::ZeroSizedDevice {
+                    name: device.get_name().to_string(),
+                    base_address
+                })
+            }
+
+            let end_address = match base_address.checked_add(address_space_size) {
+                Some(x) => x,
+                None => {
+                    return Err(BusError::AddressSpaceOverflow {
+                        name: device.get_name().to_string(),
+                        base_address,
+                        size: address_space_size
+                    })
+                }
+            };
+            let address_range = base_address..end_address;
+
+            if let Some(first) = bus.memory_map().0.into_iter().find(|entry|
+                    entry.address_range.start < address_range.end
+                    && address_range.start < entry.address_range.end) {
+                return Err(BusError::OverlappingDevices {
+                    first,
+                    second: MemoryMapEntry {
+                        name: device.get_name().to_string(),
+                        address_range
+                    }
+                })
+            }
 
             if bus.address_space_size < end_address {
                 bus.address_space_size = end_address;
             }
 
-            bus.address_space_map.insert(base_address..end_address, bus.devices.len());
+            bus.address_space_map.insert(address_range, bus.devices.len());
             bus.devices.push(device)
         }
 
-        bus
+        Ok(bus)
     }
 
-    pub fn get_device(&self, address: usize) -> Option<(&Range<usize>, &Box<dyn Device>)> {
-        let (address_range, device_idx) =
-            match self.address_space_map.get_key_value(&address) {
-                Some(x) => x,
-                None => { return None }
-        };
-        Some((address_range, &self.devices[*device_idx]))
+    pub fn get_misaligned_access_policy(&self) -> MisalignedAccessPolicy {
+        self.misaligned_access_policy
+    }
+
+    pub fn set_misaligned_access_policy(&mut self, policy: MisalignedAccessPolicy) {
+        self.misaligned_access_policy = policy;
+    }
+
+    pub fn memory_map(&self) -> MemoryMap {
+        MemoryMap(self.address_space_map.iter()
+            .map(|(address_range, device_idx)| MemoryMapEntry {
+                name: self.devices[*device_idx].get_name().to_string(),
+                address_range: address_range.clone()
+            })
+            .collect())
+    }
+
+    /// Address ranges of all devices of type `T`, sorted by base address.
+    pub fn find_devices<T: Any>(&self) -> Vec<Range<usize>> {
+        self.address_space_map.iter()
+            .filter(|(_, device_idx)| self.devices[**device_idx].as_any().is::<T>())
+            .map(|(address_range, _)| address_range.clone())
+            .collect()
     }
 
+    pub fn get_device(&self, address: usize) -> Option<(&Range<usize>, &dyn Device)> {
+        let (address_range, device_idx) = self.address_space_map.get_key_value(&address)?;
+        Some((address_range, self.devices[*device_idx].as_ref()))
+    }
+
+    /// Mutable access to the device at `address`. The device may be modified in ways
+    /// the bus can't track, so all code pages are treated as written.
     pub fn get_device_mut(&mut self, address: usize) -> Option<(&Range<usize>, &mut Box<dyn Device>)> {
-        let (address_range, device_idx) =
-            match self.address_space_map.get_key_value(&address) {
-                Some(x) => x,
-                None => { return None }
-            };
+        if !self.code_pages.is_empty() {
+            self.code_generation += 1;
+            self.code_flush_generation = self.code_generation;
+        }
+        self.lookup_device_mut(address)
+    }
+
+    /// Registers the page holding `address` as containing code, so stores to it are
+    /// recorded for `get_code_page_generation`. Returns false if the address isn't
+    /// backed by memory, in which case the bus can't tell when its contents change.
+    pub fn mark_code_page(&mut self, address: usize) -> bool {
+        let is_memory = match self.get_device(address) {
+            Some((address_range, device)) =>
+                device.get_memory_region(address - address_range.start).is_some(),
+            None => false
+        };
+        if !is_memory {
+            return false
+        }
+
+        let page = address / CODE_PAGE_SIZE;
+        if !self.code_pages.contains_key(&page) {
+            self.code_pages.insert(page, self.code_generation);
+            // Stores to the page must not bypass the tracking any more.
+            self.write_region_cache.set(None);
+        }
+        true
+    }
 
-        Some((address_range, match self.devices.get_mut(*device_idx) {
-            Some(x) => x,
-            None => { return None }
-        }))
+    /// Incremented on every store to a code page. Decoded instructions cached at
+    /// an older generation may be stale.
+    pub fn get_code_generation(&self) -> u64 {
+        self.code_generation
+    }
+
+    /// Generation of the last store to the code page holding `address`.
+    pub fn get_code_page_generation(&self, address: usize) -> u64 {
+        self.code_pages.get(&(address / CODE_PAGE_SIZE)).copied().unwrap_or(0)
+            .max(self.code_flush_generation)
+    }
+
+    /// Generation at which all code pages were last treated as written.
+    pub fn get_code_flush_generation(&self) -> u64 {
+        self.code_flush_generation
+    }
+
+    /// Asks the cores to stop with `code`, as a `TestFinisher` write does.
+    pub fn request_exit(&mut self, code: u64) {
+        self.exit_request = Some(code);
+    }
+
+    /// Returns true if the guest asked to exit and the request hasn't been taken yet.
+    pub fn has_exit_request(&self) -> bool {
+        self.exit_request.is_some()
+    }
+
+    /// Returns true if a device raised an interrupt on a write, and it hasn't been
+    /// taken yet with `Device::take_interrupt_raised`.
+    pub fn has_interrupt_raised(&self) -> bool {
+        self.interrupt_raised
+    }
+
+    /// Reserves the granule holding `address` for the hart, replacing its previous
+    /// reservation, as a load-reserved does.
+    pub fn reserve(&mut self, hart_id: u64, address: usize) {
+        let granule = address - address % RESERVATION_GRANULE;
+        self.reservations.retain(|(hart, _)| *hart != hart_id);
+        self.reservations.push((hart_id, granule));
+    }
+
+    /// Releases the reservation of the hart. Returns true if it was for the granule
+    /// holding `address` and no store broke it since.
+    pub fn take_reservation(&mut self, hart_id: u64, address: usize) -> bool {
+        let granule = address - address % RESERVATION_GRANULE;
+        let position = self.reservations.iter().position(|(hart, _)| *hart == hart_id);
+        match position {
+            Some(i) => self.reservations.swap_remove(i).1 == granule,
+            None => false
+        }
+    }
+}
+
+impl Bus {
+    fn lookup_device_mut(&mut self, address: usize) -> Option<(&Range<usize>, &mut Box<dyn Device>)> {
+        // The device may change its memory regions, e.g. DRAM allocating a page
+        // that used to be backed by the shared zero page.
+        self.invalidate_region_caches();
+
+        let (address_range, device_idx) = self.address_space_map.get_key_value(&address)?;
+        Some((address_range, self.devices.get_mut(*device_idx)?))
+    }
+
+    /// Calls `Device::access_bus` on the device at `address`, which is replaced by a
+    /// `DetachedDevice` in the meantime.
+    fn serve_bus_request(&mut self, address: usize) -> Result<(), DeviceError> {
+        let device_idx = match self.address_space_map.get(&address) {
+            Some(x) => *x,
+            None => { return Ok(()) }
+        };
+        let detached = Box::new(DetachedDevice {
+            name: self.devices[device_idx].get_name().to_string(),
+            size: self.devices[device_idx].get_address_space_size()
+        });
+        let mut device = std::mem::replace(&mut self.devices[device_idx], detached);
+        let result = device.access_bus(self);
+        self.interrupt_raised |= device.take_interrupt_raised();
+        self.devices[device_idx] = device;
+        self.invalidate_region_caches();
+        result
+    }
+
+    /// Records a store to `address..address + size` for the code pages it touches.
+    fn record_code_write(&mut self, address: usize, size: usize) {
+        if self.code_pages.is_empty() || size == 0 {
+            return
+        }
+        let first_page = address / CODE_PAGE_SIZE;
+        let last_page = address.saturating_add(size - 1) / CODE_PAGE_SIZE;
+        for page in first_page..=last_page {
+            if let Some(generation) = self.code_pages.get_mut(&page) {
+                self.code_generation += 1;
+                *generation = self.code_generation;
+            }
+        }
+    }
+
+    /// Breaks the reservations of all harts on granules overlapping the store to
+    /// `address..address + size`.
+    fn break_reservations(&mut self, address: usize, size: usize) {
+        if self.reservations.is_empty() || size == 0 {
+            return
+        }
+        let end = address.saturating_add(size);
+        self.reservations.retain(|(_, granule)| end <= *granule || granule + RESERVATION_GRANULE <= address);
+    }
+
+    fn overlaps_code_page(&self, start: usize, end: usize) -> bool {
+        self.code_pages.keys()
+            .any(|page| page * CODE_PAGE_SIZE < end && start < (page + 1) * CODE_PAGE_SIZE)
+    }
+
+    fn invalidate_region_caches(&self) {
+        self.read_region_cache.set(None);
+        self.write_region_cache.set(None);
+    }
+
+    /// Host memory for `address..address + size`, if it lies in a single memory region.
+    fn read_region(&self, address: usize, size: usize) -> Option<&[u8]> {
+        let region = match self.read_region_cache.get() {
+            Some(region) if region.contains(address, size) => region,
+            _ => {
+                let (address_range, device) = self.get_device(address)?;
+                let (offset, memory) = device.get_memory_region(address - address_range.start)?;
+                let start = address_range.start + offset;
+                let region = CachedRegion {
+                    start,
+                    end: (start + memory.len()).min(address_range.end),
+                    host_address: memory.as_ptr() as *mut u8
+                };
+                self.read_region_cache.set(Some(region));
+                if !region.contains(address, size) {
+                    return None
+                }
+                region
+            }
+        };
+
+        // SAFETY: the region was borrowed from a device owned by the bus. Devices
+        // can only be modified through `&mut self`, and every such path either goes
+        // through `lookup_device_mut`, which drops the cached regions, or writes through
+        // the write region, which is obtained through `lookup_device_mut` as well.
+        Some(unsafe {
+            std::slice::from_raw_parts(region.host_address.add(address - region.start), size)
+        })
+    }
+
+    /// Writable host memory for `address..address + size`, if it lies in a single memory region.
+    fn write_region(&mut self, address: usize, size: usize) -> Option<&mut [u8]> {
+        let region = match self.write_region_cache.get() {
+            Some(region) if region.contains(address, size) => region,
+            _ => {
+                let (address_range, device) = self.lookup_device_mut(address)?;
+                let device_start = address_range.start;
+                let device_end = address_range.end;
+                let (offset, memory) = device.get_memory_region_mut(address - device_start)?;
+                let start = device_start + offset;
+                let region = CachedRegion {
+                    start,
+                    end: (start + memory.len()).min(device_end),
+                    host_address: memory.as_mut_ptr()
+                };
+                // Stores to code take the slow path, which records them.
+                if self.overlaps_code_page(region.start, region.end) {
+                    return None
+                }
+                self.write_region_cache.set(Some(region));
+                if !region.contains(address, size) {
+                    return None
+                }
+                region
+            }
+        };
+
+        // SAFETY: see `read_region`. `&mut self` guarantees no slice returned by
+        // `read_region` is still alive.
+        Some(unsafe {
+            std::slice::from_raw_parts_mut(region.host_address.add(address - region.start), size)
+        })
+    }
+
+    /// Applies the misaligned access policy. Returns whether the access is misaligned
+    /// and allowed to be emulated.
+    fn check_alignment(&self, address: usize, size: usize, write: bool) -> Result<bool, DeviceError> {
+        if size == 0 || address.is_multiple_of(size) {
+            return Ok(false)
+        }
+
+        match (self.misaligned_access_policy, write) {
+            (MisalignedAccessPolicy::Emulate, _) => Ok(true),
+            (MisalignedAccessPolicy::Trap, false) =>
+                Err(DeviceError::MisalignedAddressReadTrap { address }),
+            (MisalignedAccessPolicy::Trap, true) =>
+                Err(DeviceError::MisalignedAddressWriteTrap { address }),
+            (MisalignedAccessPolicy::Fault, false) =>
+                Err(DeviceError::MisalignedAddressReadFault { address }),
+            (MisalignedAccessPolicy::Fault, true) =>
+                Err(DeviceError::MisalignedAddressWriteFault { address }),
+        }
+    }
+
+    /// Returns true if all bytes in the access map to some device.
+    fn is_mapped(&self, address: usize, size: usize) -> bool {
+        (0..size).all(|i| address.checked_add(i)
+            .is_some_and(|address| self.address_space_map.contains_key(&address)))
+    }
+
+    fn fits_in(address_range: &Range<usize>, address: usize, size: usize) -> bool {
+        match address.checked_add(size) {
+            Some(end_address) => end_address <= address_range.end,
+            None => false
+        }
     }
 }
 
 impl Device for Bus {
     fn get_address_space_size(&self) -> usize{ self.address_space_size }
 
-    fn read_bytes(&self, address: usize, size: usize) -> Result<&[u8], DeviceError> {
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        if let Some(memory) = self.read_region(address, size) {
+            return Ok(Cow::Borrowed(memory))
+        }
+
         match self.get_device(address) {
             Some((address_range, device)) => {
+                if !Self::fits_in(address_range, address, size) {
+                    return Err(DeviceError::StraddlingAccessFault { address, size })
+                }
                 let address = address - address_range.start;
                 device.read_bytes(address, size)
             },
@@ -72,35 +471,271 @@ impl Device for Bus {
     }
 
     fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
-        match self.get_device_mut(address) {
+        self.break_reservations(address, binary.len());
+        if let Some(memory) = self.write_region(address, binary.len()) {
+            memory.copy_from_slice(binary);
+            return Ok(())
+        }
+
+        self.record_code_write(address, binary.len());
+        let (result, exit_request, interrupt_raised, bus_request) = match self.lookup_device_mut(address) {
             Some((address_range, device)) => {
+                if !Self::fits_in(address_range, address, binary.len()) {
+                    return Err(DeviceError::StraddlingAccessFault { address, size: binary.len() })
+                }
                 let address = address - address_range.start;
-                device.write_bytes(address, binary)
+                (device.write_bytes(address, binary), device.take_exit_request(),
+                 device.take_interrupt_raised(), device.take_bus_request())
             },
-            None => { Err(DeviceError::InvalidAddressWriteFault) }
+            None => { return Err(DeviceError::InvalidAddressWriteFault) }
+        };
+        if exit_request.is_some() {
+            self.exit_request = exit_request;
         }
+        self.interrupt_raised |= interrupt_raised;
+        if bus_request {
+            self.serve_bus_request(address)?;
+        }
+        result
     }
 
     fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
             -> Result<u64, DeviceError> {
+        let emulate = self.check_alignment(address, size, false)?;
+
+        if (1..=8).contains(&size) {
+            if let Some(memory) = self.read_region(address, size) {
+                return Ok(int_from_bytes(memory, endianness, sign_extend))
+            }
+        }
+
         match self.get_device(address) {
             Some((address_range, device)) => {
-                let address = address - address_range.start;
-                device.read_int(address, size, endianness, sign_extend)
+                if Self::fits_in(address_range, address, size) {
+                    let address = address - address_range.start;
+                    return device.read_int(address, size, endianness, sign_extend)
+                }
             },
-            None => { Err(DeviceError::InvalidAddressReadFault) }
+            None => { return Err(DeviceError::InvalidAddressReadFault) }
+        }
+
+        if !emulate || size > 8 {
+            return Err(DeviceError::StraddlingAccessFault { address, size })
+        }
+        if !self.is_mapped(address, size) {
+            return Err(DeviceError::InvalidAddressReadFault)
+        }
+
+        let mut value = 0u64;
+        for i in 0..size {
+            let byte = self.read_int(address + i, 1, Endianness::LittleEndian, false)?;
+            value |= match endianness {
+                Endianness::LittleEndian => byte << (i * 8),
+                Endianness::BigEndian => byte << ((size - 1 - i) * 8)
+            };
+        }
+
+        if sign_extend && size < 8 {
+            value = extend_sign(value, size * 8);
         }
+
+        Ok(value)
     }
 
     fn write_int(&mut self, address: usize, value: u64, size: usize, endianness: Endianness) -> Result<(), DeviceError> {
-        match self.get_device_mut(address) {
+        let emulate = self.check_alignment(address, size, true)?;
+        self.break_reservations(address, size);
+
+        if (1..=8).contains(&size) {
+            if let Some(memory) = self.write_region(address, size) {
+                match endianness {
+                    Endianness::LittleEndian => memory.copy_from_slice(&value.to_le_bytes()[0..size]),
+                    Endianness::BigEndian => memory.copy_from_slice(&value.to_be_bytes()[(8-size)..])
+                }
+                return Ok(())
+            }
+        }
+
+        self.record_code_write(address, size);
+        let written = match self.lookup_device_mut(address) {
             Some((address_range, device)) => {
-                let address = address - address_range.start;
-                device.write_int(address, value, size, endianness)
+                if Self::fits_in(address_range, address, size) {
+                    let address = address - address_range.start;
+                    Some((device.write_int(address, value, size, endianness),
+                          device.take_exit_request(), device.take_interrupt_raised(),
+                          device.take_bus_request()))
+                } else {
+                    None
+                }
             },
-            None => { Err(DeviceError::InvalidAddressWriteFault) }
+            None => { return Err(DeviceError::InvalidAddressWriteFault) }
+        };
+        if let Some((result, exit_request, interrupt_raised, bus_request)) = written {
+            if exit_request.is_some() {
+                self.exit_request = exit_request;
+            }
+            self.interrupt_raised |= interrupt_raised;
+            if bus_request {
+                self.serve_bus_request(address)?;
+            }
+            return result
+        }
+
+        if !emulate || size > 8 {
+            return Err(DeviceError::StraddlingAccessFault { address, size })
+        }
+        // Check the whole access before writing anything, so a fault
+        // doesn't leave a partial write behind.
+        if !self.is_mapped(address, size) {
+            return Err(DeviceError::InvalidAddressWriteFault)
+        }
+
+        for i in 0..size {
+            let byte = match endianness {
+                Endianness::LittleEndian => value >> (i * 8),
+                Endianness::BigEndian => value >> ((size - 1 - i) * 8)
+            };
+            self.write_int(address + i, byte & 0xFF, 1, Endianness::LittleEndian)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_name(&self) -> &str { "bus" }
+
+    fn take_exit_request(&mut self) -> Option<u64> {
+        self.exit_request.take()
+    }
+
+    fn take_interrupt_raised(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_raised)
+    }
+
+    /// Saves the memory map and the state of every device in it.
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_u64(self.address_space_map.iter().count() as u64)?;
+        for (address_range, device_idx) in self.address_space_map.iter() {
+            let device = &self.devices[*device_idx];
+            writer.write_string(device.get_name())?;
+            writer.write_u64(address_range.start as u64)?;
+            writer.write_u64(address_range.end as u64)?;
+            device.save_state(writer)?;
+        }
+        writer.write_bool(self.exit_request.is_some())?;
+        writer.write_u64(self.exit_request.unwrap_or(0))?;
+        writer.write_u64(self.reservations.len() as u64)?;
+        for (hart_id, granule) in &self.reservations {
+            writer.write_u64(*hart_id)?;
+            writer.write_u64(*granule as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Restores the devices, which must have the same names and address ranges as
+    /// when the snapshot was taken.
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let memory_map = self.memory_map();
+        let device_count = reader.read_u64()? as usize;
+        if device_count != memory_map.0.len() {
+            return Err(SnapshotError::DeviceCountMismatch {
+                expected: memory_map.0.len(),
+                found: device_count
+            })
+        }
+
+        for expected in memory_map.0 {
+            let found = MemoryMapEntry {
+                name: reader.read_string()?,
+                address_range: (reader.read_u64()? as usize)..(reader.read_u64()? as usize)
+            };
+            if found != expected {
+                return Err(SnapshotError::DeviceMismatch { expected, found })
+            }
+            if let Some((_, device)) = self.get_device_mut(expected.address_range.start) {
+                device.restore_state(reader)?;
+            }
         }
+
+        let pending = reader.read_bool()?;
+        let code = reader.read_u64()?;
+        self.exit_request = if pending { Some(code) } else { None };
+        let reservation_count = reader.read_u64()? as usize;
+        self.reservations.clear();
+        for _ in 0..reservation_count {
+            let hart_id = reader.read_u64()?;
+            let granule = reader.read_u64()? as usize;
+            self.reservations.push((hart_id, granule));
+        }
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        for (address_range, device_idx) in self.address_space_map.iter() {
+            self.devices[*device_idx].fdt_node(base_address + address_range.start, fdt)?;
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+/// Stands in for a device while it accesses the bus, faulting on every access.
+#[derive(Debug)]
+struct DetachedDevice {
+    name: String,
+    size: usize
+}
+
+impl Device for DetachedDevice {
+    fn get_address_space_size(&self) -> usize { self.size }
+
+    fn read_bytes(&self, _address: usize, _size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        Err(DeviceError::InvalidAddressReadFault)
+    }
+
+    fn write_bytes(&mut self, _address: usize, _binary: &[u8]) -> Result<(), DeviceError> {
+        Err(DeviceError::InvalidAddressWriteFault)
+    }
+
+    fn read_int(&self, _address: usize, _size: usize, _endianness: Endianness, _sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        Err(DeviceError::InvalidAddressReadFault)
+    }
+
+    fn write_int(&mut self, _address: usize, _value: u64, _size: usize, _endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        Err(DeviceError::InvalidAddressWriteFault)
     }
 
+    fn get_name(&self) -> &str { &self.name }
+
     fn as_any(&self) -> &dyn Any { self }
-}
\ No newline at end of file
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for BusError {}
+
+impl Display for MemoryMapEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#018x}-{:#018x} {}",
+               self.address_range.start, self.address_range.end - 1, self.name)
+    }
+}
+
+impl Display for MemoryMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for entry in &self.0 {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
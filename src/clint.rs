@@ -0,0 +1,206 @@
+use crate::cpu::cpu::interrupt_controller_label;
+use crate::cpu::csr::{MIP_MSIP, MIP_MTIP};
+use crate::cpu::replay::Clock;
+use crate::cpu::trap::Interrupt;
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use std::any::Any;
+use std::borrow::Cow;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const CLINT_SIZE: usize = 0x10000;
+
+const CLINT_MSIP: usize = 0x0;
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xBFF8;
+
+
+/// Core-local interruptor, with the software interrupt and timer registers of every
+/// hart in the layout of the SiFive CLINT.
+///
+/// Every hart has an interrupt line, with the `mip` bits the CLINT drives. Writes to
+/// `msip` and `mtimecmp` update it right away, but the timer interrupt only becomes
+/// pending when `CPU::run` polls `get_pending`, so that its arrival can be recorded.
+/// Writes to `mtime` are ignored, the time always follows the host clock.
+pub struct CLINT {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    lines: Vec<Arc<AtomicU64>>,
+    clock: Clock,
+    interrupt_raised: bool,
+}
+
+impl CLINT {
+    pub fn new(hart_count: usize) -> Self {
+        Self {
+            msip: vec![false; hart_count],
+            mtimecmp: vec![u64::MAX; hart_count],
+            lines: (0..hart_count).map(|_| Arc::new(AtomicU64::new(0))).collect(),
+            clock: Clock::new(),
+            interrupt_raised: false,
+        }
+    }
+
+    /// Interrupt line of `hart`, for `Core::set_interrupt_line`.
+    pub fn get_interrupt_line(&self, hart: usize) -> Option<Arc<AtomicU64>> {
+        self.lines.get(hart).cloned()
+    }
+
+    /// Shares the time with the CPU, which records the values read by the guest.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// `mip` bits of the interrupts pending for `hart`.
+    pub fn get_pending(&self, hart: usize) -> u64 {
+        let mut pending = 0;
+        if self.msip.get(hart).is_some_and(|msip| *msip) {
+            pending |= MIP_MSIP;
+        }
+        if self.mtimecmp.get(hart).is_some_and(|mtimecmp| self.clock.now() >= *mtimecmp) {
+            pending |= MIP_MTIP;
+        }
+        pending
+    }
+
+    /// Register at `address` as a 64-bit value, with the offset of `address` in it.
+    fn get_register(&self, address: usize) -> Result<(u64, usize), DeviceError> {
+        let hart_count = self.msip.len();
+        if (CLINT_MSIP..CLINT_MSIP + 4 * hart_count).contains(&address) {
+            let hart = (address - CLINT_MSIP) / 4;
+            Ok((self.msip[hart] as u64, (address - CLINT_MSIP) % 4))
+        } else if (CLINT_MTIMECMP..CLINT_MTIMECMP + 8 * hart_count).contains(&address) {
+            let hart = (address - CLINT_MTIMECMP) / 8;
+            Ok((self.mtimecmp[hart], (address - CLINT_MTIMECMP) % 8))
+        } else if (CLINT_MTIME..CLINT_MTIME + 8).contains(&address) {
+            let time = self.clock.read()
+                .map_err(|error| DeviceError::InternalDeviceError(Box::new(error)))?;
+            Ok((time, address - CLINT_MTIME))
+        } else {
+            Ok((0, address % 8))
+        }
+    }
+}
+
+impl Debug for CLINT {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CLINT")
+    }
+}
+
+impl Device for CLINT {
+    fn get_address_space_size(&self) -> usize { CLINT_SIZE }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        let value = self.read_int(address, size, Endianness::LittleEndian, false)?;
+        Ok(Cow::Owned(value.to_le_bytes()[..size].to_vec()))
+    }
+
+    fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
+        match binary.len() {
+            4 | 8 => {
+                let value = int_from_bytes(binary, Endianness::LittleEndian, false);
+                self.write_int(address, value, binary.len(), Endianness::LittleEndian)
+            },
+            _ => Err(DeviceError::InvalidSizeWriteFault)
+        }
+    }
+
+    fn read_int(&self, address: usize, size: usize, _endianness: Endianness, _sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        if size != 4 && size != 8 {
+            return Err(DeviceError::InvalidSizeReadFault)
+        }
+        if !address.is_multiple_of(size) {
+            return Err(DeviceError::MisalignedAddressReadTrap { address })
+        }
+        let (value, offset) = self.get_register(address)?;
+        let value = value >> (offset * 8);
+        Ok(if size == 4 { value & 0xFFFF_FFFF } else { value })
+    }
+
+    fn write_int(&mut self, address: usize, value: u64, size: usize, _endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        if size != 4 && size != 8 {
+            return Err(DeviceError::InvalidSizeWriteFault)
+        }
+        if !address.is_multiple_of(size) {
+            return Err(DeviceError::MisalignedAddressWriteTrap { address })
+        }
+        let hart_count = self.msip.len();
+        if (CLINT_MSIP..CLINT_MSIP + 4 * hart_count).contains(&address) && size == 4 {
+            let hart = (address - CLINT_MSIP) / 4;
+            self.msip[hart] = value & 1 != 0;
+            if self.msip[hart] {
+                self.lines[hart].fetch_or(MIP_MSIP, Ordering::Relaxed);
+                self.interrupt_raised = true;
+            } else {
+                self.lines[hart].fetch_and(!MIP_MSIP, Ordering::Relaxed);
+            }
+        } else if (CLINT_MTIMECMP..CLINT_MTIMECMP + 8 * hart_count).contains(&address) {
+            let hart = (address - CLINT_MTIMECMP) / 8;
+            let mtimecmp = &mut self.mtimecmp[hart];
+            *mtimecmp = match (size, address % 8) {
+                (8, _) => value,
+                (_, 0) => (*mtimecmp & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF),
+                _ => (*mtimecmp & 0xFFFF_FFFF) | (value << 32)
+            };
+            // Comparing with the time here would make the write nondeterministic. If
+            // the new deadline has already passed, the next poll raises it again.
+            self.lines[hart].fetch_and(!MIP_MTIP, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn get_name(&self) -> &str { "clint" }
+
+    fn take_interrupt_raised(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_raised)
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_u64(self.msip.len() as u64)?;
+        for hart in 0..self.msip.len() {
+            writer.write_bool(self.msip[hart])?;
+            writer.write_u64(self.mtimecmp[hart])?;
+            writer.write_u64(self.lines[hart].load(Ordering::Relaxed))?;
+        }
+        Ok(())
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        if reader.read_u64()? != self.msip.len() as u64 {
+            return Err(SnapshotError::InvalidState { name: "clint".to_string() })
+        }
+        for hart in 0..self.msip.len() {
+            self.msip[hart] = reader.read_bool()?;
+            self.mtimecmp[hart] = reader.read_u64()?;
+            self.lines[hart].store(reader.read_u64()?, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        let mut interrupts = Vec::new();
+        for hart_id in 0..self.msip.len() as u64 {
+            let phandle = fdt.get_phandle(&interrupt_controller_label(hart_id));
+            interrupts.extend([phandle, Interrupt::MachineSoftware as u32,
+                               phandle, Interrupt::MachineTimer as u32]);
+        }
+
+        fdt.begin_node(&format!("clint@{:x}", base_address))?;
+        fdt.property_string_list("compatible", &["sifive,clint0", "riscv,clint0"])?;
+        fdt.property_reg("reg", &[(base_address as u64, CLINT_SIZE as u64)])?;
+        fdt.property_cells("interrupts-extended", &interrupts)?;
+        fdt.end_node()
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
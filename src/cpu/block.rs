@@ -0,0 +1,564 @@
+use crate::bus::{Bus, SharedBus, CODE_PAGE_SIZE};
+use crate::cpu::core::{Core, CoreError};
+use crate::cpu::execute::{self, sign_extend_word, InstructionExecuteError};
+use crate::cpu::instruction::Instruction;
+#[cfg(feature = "jit")]
+use crate::cpu::jit::{self, Context, JitMode, MemoryAccess, TracedAccess};
+use crate::cpu::register::XRegister;
+use crate::device::Device;
+use crate::endianness::Endianness;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, Weak};
+
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// A translated instruction. Returns true if the block must be left after it,
+/// which is the case when a store modified code, requested an exit or raised an
+/// interrupt.
+type Operation = Box<dyn Fn(&mut Core) -> Result<bool, InstructionExecuteError> + Send + Sync>;
+
+/// Start address of a block executed after this one, and the block.
+type Successor = Option<(usize, Weak<Block>)>;
+
+/// How the operations of a block ended.
+enum Exit {
+    /// All of them were executed.
+    End,
+    /// The operation at the index asked to leave the block after it.
+    Leave(usize),
+    /// The operation at the index failed.
+    Error(usize, InstructionExecuteError),
+}
+
+
+/// Straight-line run of instructions translated to closures with their operands
+/// already resolved.
+///
+/// Operations never touch `pc`, it's set once the block is left. The last instruction
+/// of the block, if it's a control transfer or anything else without a translation,
+/// is kept as the terminator and executed by `Instruction::execute`.
+pub struct Block {
+    start: usize,
+    epoch: u64,
+    operations: Vec<Operation>,
+    terminator: Option<Instruction>,
+    successors: Mutex<[Successor; 2]>,
+    #[cfg(feature = "jit")]
+    jit: jit::BlockState,
+}
+
+impl Block {
+    pub fn get_start(&self) -> usize {
+        self.start
+    }
+
+    /// Number of instructions in the block.
+    pub fn len(&self) -> usize {
+        self.operations.len() + self.terminator.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The block previously executed after this one, if it started at `pc`.
+    fn get_successor(&self, pc: usize, epoch: u64) -> Option<Arc<Block>> {
+        self.successors.lock().unwrap().iter().flatten()
+            .find(|(start, _)| *start == pc)
+            .and_then(|(_, block)| block.upgrade())
+            .filter(|block| block.epoch == epoch)
+    }
+
+    /// Chains `block` to this one. Blocks end in at most two direct targets, so
+    /// the older of the two links is replaced.
+    fn add_successor(&self, block: &Arc<Block>) {
+        let mut successors = self.successors.lock().unwrap();
+        successors[1] = successors[0].take();
+        successors[0] = Some((block.start, Arc::downgrade(block)));
+    }
+}
+
+impl Debug for Block {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Block {{ start: {:#x}, length: {:?}, terminator: {:?} }}",
+               self.start, self.len(), self.terminator)
+    }
+}
+
+/// Translated blocks, keyed by start address.
+///
+/// Like the decode cache, the block cache follows stores to code pages, but it drops
+/// every block instead of only those on the written pages, since blocks are chained
+/// across pages.
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: HashMap<usize, Arc<Block>>,
+    generation: u64,
+    epoch: u64,
+    translations: u64,
+    executions: u64,
+    #[cfg(feature = "jit")]
+    jit_mode: JitMode,
+    #[cfg(feature = "jit")]
+    compilations: u64,
+    /// Memory accesses of the block being interpreted in differential mode.
+    #[cfg(feature = "jit")]
+    trace: Option<Vec<TracedAccess>>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of blocks translated.
+    pub fn get_translations(&self) -> u64 {
+        self.translations
+    }
+
+    /// Number of blocks executed.
+    pub fn get_executions(&self) -> u64 {
+        self.executions
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn get_jit_mode(&self) -> JitMode {
+        self.jit_mode
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn set_jit_mode(&mut self, mode: JitMode) {
+        self.jit_mode = mode;
+    }
+
+    /// Number of blocks compiled to native code.
+    #[cfg(feature = "jit")]
+    pub fn get_compilations(&self) -> u64 {
+        self.compilations
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.epoch += 1;
+    }
+
+    #[cfg(feature = "jit")]
+    fn record(&mut self, access: MemoryAccess, value: u64, stop: bool) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(TracedAccess { access, value, stop });
+        }
+    }
+
+    fn synchronize(&mut self, bus: &Bus) {
+        let generation = bus.get_code_generation();
+        if generation != self.generation {
+            self.flush();
+            self.generation = generation;
+        }
+    }
+}
+
+impl Core {
+    /// Executes up to `max_instructions` instructions a block at a time, and returns
+    /// the number of instructions executed. Code that isn't backed by memory, blocks
+    /// that don't fit in the remaining budget, and code running with address
+    /// translation are single-stepped. Pending
+    /// interrupts are taken between blocks. Stops early after `wfi` or when the guest
+    /// requests an exit.
+    ///
+    /// Stores by other harts to code are picked up on the next call, like on hardware,
+    /// where they're only guaranteed to be visible after a `fence.i`.
+    pub fn execute_blocks(&mut self, max_instructions: u64) -> Result<u64, CoreError> {
+        let mut executed = 0;
+        let mut previous: Option<Arc<Block>> = None;
+        // Whether the bus has to be checked for code changes and exit requests before
+        // the next block. Blocks ending in a jump or branch can't have caused either.
+        let mut synchronize = true;
+
+        while executed < max_instructions {
+            if synchronize {
+                let mut bus = self.bus.lock();
+                if self.waiting || bus.has_exit_request() {
+                    break
+                }
+                self.block_cache.synchronize(&bus);
+                bus.take_interrupt_raised();
+                drop(bus);
+                // Interrupts only become pending and enabled between calls or through
+                // instructions that end a block, so they're taken as soon as possible.
+                if self.take_interrupt() {
+                    previous = None;
+                }
+            }
+
+            // Blocks access memory by physical address, so translated code is single-stepped.
+            if self.is_translating() {
+                self.execute()?;
+                executed += 1;
+                previous = None;
+                synchronize = true;
+                continue
+            }
+
+            let epoch = self.block_cache.epoch;
+            let chained = previous.as_ref().and_then(|block| block.get_successor(self.pc, epoch));
+            let block = match chained {
+                Some(block) => block,
+                None => match self.get_block(self.pc)? {
+                    Some(block) => {
+                        if let Some(previous) = &previous {
+                            previous.add_successor(&block);
+                        }
+                        block
+                    },
+                    None => {
+                        self.execute()?;
+                        executed += 1;
+                        previous = None;
+                        synchronize = true;
+                        continue
+                    }
+                }
+            };
+
+            if block.len() as u64 > max_instructions - executed {
+                self.execute()?;
+                executed += 1;
+                previous = None;
+                synchronize = true;
+                continue
+            }
+
+            let block_executed = self.execute_block(&block)?;
+            executed += block_executed;
+            synchronize = block_executed < block.len() as u64 ||
+                !block.terminator.is_some_and(is_control_transfer);
+            previous = Some(block);
+        }
+
+        Ok(executed)
+    }
+
+    fn execute_block(&mut self, block: &Block) -> Result<u64, CoreError> {
+        self.block_cache.executions += 1;
+
+        #[cfg(feature = "jit")]
+        let exit = match self.execute_native(block)? {
+            Some(exit) => exit,
+            None => self.interpret(block)
+        };
+        #[cfg(not(feature = "jit"))]
+        let exit = self.interpret(block);
+
+        match exit {
+            Exit::End => {},
+            Exit::Leave(i) => {
+                self.pc = block.start + (i + 1) * 4;
+                self.instructions_retired += i as u64 + 1;
+                return Ok(i as u64 + 1)
+            },
+            Exit::Error(i, error) => {
+                self.pc = block.start + i * 4;
+                self.instructions_retired += i as u64;
+                return Err(error.into())
+            }
+        }
+
+        self.pc = block.start + block.operations.len() * 4;
+        self.instructions_retired += block.operations.len() as u64;
+        if let Some(terminator) = block.terminator {
+            terminator.execute(self)?;
+            self.instructions_retired += 1;
+        }
+        Ok(block.len() as u64)
+    }
+
+    fn interpret(&mut self, block: &Block) -> Exit {
+        for (i, operation) in block.operations.iter().enumerate() {
+            match operation(self) {
+                Ok(false) => {},
+                Ok(true) => return Exit::Leave(i),
+                Err(error) => return Exit::Error(i, error)
+            }
+        }
+        Exit::End
+    }
+
+    /// Runs the operations of `block` as native code if it has been compiled.
+    /// Returns `None` if they still have to be interpreted.
+    #[cfg(feature = "jit")]
+    fn execute_native(&mut self, block: &Block) -> Result<Option<Exit>, CoreError> {
+        if self.block_cache.jit_mode == JitMode::Disabled {
+            return Ok(None)
+        }
+
+        let native = match block.jit.get_native() {
+            (Some(native), compiled) => {
+                self.block_cache.compilations += compiled as u64;
+                native
+            },
+            (None, _) => return Ok(None)
+        };
+
+        if self.block_cache.jit_mode == JitMode::Differential {
+            let mut registers = self.x_registers.clone();
+            self.block_cache.trace = Some(Vec::new());
+            let exit = self.interpret(block);
+            let trace = self.block_cache.trace.take().unwrap_or_default();
+
+            let mut context = Context::replay(&trace);
+            native.execute(&mut registers, &mut context);
+            if let Some((expected, actual)) = context.take_mismatch() {
+                return Err(CoreError::JitAccessMismatch { pc: block.start, expected, actual })
+            }
+            for i in 1..32 {
+                let register = XRegister::from(i);
+                if registers[register] != self.x_registers[register] {
+                    return Err(CoreError::JitMismatch {
+                        pc: block.start,
+                        register,
+                        expected: self.x_registers[register],
+                        actual: registers[register]
+                    })
+                }
+            }
+            Ok(Some(exit))
+        } else {
+            let mut context = Context::new(&self.bus);
+            let exit = match native.execute(&mut self.x_registers, &mut context) {
+                None => Exit::End,
+                Some(i) => match context.take_error() {
+                    Some(error) => Exit::Error(i, error),
+                    None => Exit::Leave(i)
+                }
+            };
+            Ok(Some(exit))
+        }
+    }
+
+    /// Looks up or translates the block at `address`. Returns `None` if the code
+    /// isn't in memory, or `pc` isn't aligned.
+    fn get_block(&mut self, address: usize) -> Result<Option<Arc<Block>>, CoreError> {
+        if let Some(block) = self.block_cache.blocks.get(&address) {
+            return Ok(Some(block.clone()))
+        }
+        if !address.is_multiple_of(4) {
+            return Ok(None)
+        }
+
+        let mut operations = Vec::new();
+        #[cfg(feature = "jit")]
+        let mut instructions = Vec::new();
+        let mut terminator = None;
+        let mut pc = address;
+        loop {
+            let instruction = match self.fetch(pc) {
+                Ok((instruction, true)) => instruction,
+                // Leave faults to single-stepping, so they're raised at the right pc.
+                Ok((_, false)) | Err(_) => break
+            };
+
+            match translate(instruction, pc) {
+                Some(operation) => {
+                    operations.push(operation);
+                    #[cfg(feature = "jit")]
+                    instructions.push(instruction);
+                },
+                None => {
+                    terminator = Some(instruction);
+                    break
+                }
+            }
+
+            pc += 4;
+            if operations.len() == MAX_BLOCK_LENGTH || pc.is_multiple_of(CODE_PAGE_SIZE) {
+                break
+            }
+        }
+
+        if operations.is_empty() && terminator.is_none() {
+            return Ok(None)
+        }
+
+        let block = Arc::new(Block {
+            start: address,
+            epoch: self.block_cache.epoch,
+            operations,
+            terminator,
+            successors: Mutex::new([None, None]),
+            #[cfg(feature = "jit")]
+            jit: jit::BlockState::new(address, instructions),
+        });
+        self.block_cache.translations += 1;
+        self.block_cache.blocks.insert(address, block.clone());
+        Ok(Some(block))
+    }
+}
+
+fn alu(operation: impl Fn(&mut Core) + Send + Sync + 'static) -> Option<Operation> {
+    Some(Box::new(move |core| {
+        operation(core);
+        Ok(false)
+    }))
+}
+
+/// Rd = `function`(rs1, rs2), for the M extension helpers of the interpreter.
+fn binary(rd: XRegister, rs1: XRegister, rs2: XRegister, function: fn(u64, u64) -> u64) -> Option<Operation> {
+    alu(move |core| core.x_registers[rd] = function(core.x_registers[rs1], core.x_registers[rs2]))
+}
+
+/// Loads through the bus like the interpreter does.
+pub(crate) fn read(bus: &SharedBus, address: usize, size: usize, sign_extend: bool)
+        -> Result<u64, InstructionExecuteError> {
+    bus.lock().read_int(address, size, Endianness::LittleEndian, sign_extend)
+        .map_err(|error| InstructionExecuteError::from_access(error, address))
+}
+
+/// Stores through the bus like the interpreter does. Returns true if the block must be
+/// left because the store modified code, requested an exit or raised an interrupt.
+pub(crate) fn write(bus: &SharedBus, address: usize, value: u64, size: usize)
+        -> Result<bool, InstructionExecuteError> {
+    let mut bus = bus.lock();
+    let generation = bus.get_code_generation();
+    bus.write_int(address, value, size, Endianness::LittleEndian)
+        .map_err(|error| InstructionExecuteError::from_access(error, address))?;
+    Ok(bus.get_code_generation() != generation || bus.has_exit_request() ||
+       bus.has_interrupt_raised())
+}
+
+fn load(rd: XRegister, rs1: XRegister, imm: i64, size: usize, sign_extend: bool) -> Option<Operation> {
+    Some(Box::new(move |core| {
+        let address = (core.x_registers[rs1] as i64).wrapping_add(imm) as usize;
+        let result = read(&core.bus, address, size, sign_extend);
+        #[cfg(feature = "jit")]
+        core.block_cache.record(MemoryAccess::Load { address, size, sign_extend },
+                                *result.as_ref().unwrap_or(&0), result.is_err());
+        core.x_registers[rd] = result?;
+        Ok(false)
+    }))
+}
+
+fn store(rs1: XRegister, rs2: XRegister, imm: i64, size: usize) -> Option<Operation> {
+    Some(Box::new(move |core| {
+        let address = (core.x_registers[rs1] as i64).wrapping_add(imm) as usize;
+        let value = core.x_registers[rs2];
+        let result = write(&core.bus, address, value, size);
+        #[cfg(feature = "jit")]
+        core.block_cache.record(MemoryAccess::Store { address, size, value },
+                                0, !matches!(result, Ok(false)));
+        result
+    }))
+}
+
+/// Jumps and branches, which only change `pc` and registers.
+fn is_control_transfer(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::jal { .. } | Instruction::jalr { .. } |
+        Instruction::beq { .. } | Instruction::bne { .. } |
+        Instruction::blt { .. } | Instruction::bge { .. } |
+        Instruction::bltu { .. } | Instruction::bgeu { .. })
+}
+
+/// Translates an instruction at `pc`. Returns `None` for instructions that end a block.
+fn translate(instruction: Instruction, pc: usize) -> Option<Operation> {
+    match instruction {
+        Instruction::add { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1].wrapping_add(core.x_registers[rs2])),
+        Instruction::sub { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1].wrapping_sub(core.x_registers[rs2])),
+        Instruction::xor { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] ^ core.x_registers[rs2]),
+        Instruction::or { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] | core.x_registers[rs2]),
+        Instruction::and { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] & core.x_registers[rs2]),
+        Instruction::sll { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] << (core.x_registers[rs2] & 0x3F)),
+        Instruction::srl { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] >> (core.x_registers[rs2] & 0x3F)),
+        Instruction::sra { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] =
+                ((core.x_registers[rs1] as i64) >> (core.x_registers[rs2] & 0x3F)) as u64),
+        Instruction::slt { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] =
+                ((core.x_registers[rs1] as i64) < (core.x_registers[rs2] as i64)) as u64),
+        Instruction::sltu { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = (core.x_registers[rs1] < core.x_registers[rs2]) as u64),
+
+        Instruction::addi { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1].wrapping_add(imm as u64)),
+        Instruction::xori { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] ^ imm),
+        Instruction::ori { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] | imm),
+        Instruction::andi { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] & imm),
+        Instruction::slli { rd, rs1, shamt } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] << shamt),
+        Instruction::srli { rd, rs1, shamt } => alu(move |core|
+            core.x_registers[rd] = core.x_registers[rs1] >> shamt),
+        Instruction::srai { rd, rs1, shamt } => alu(move |core|
+            core.x_registers[rd] = ((core.x_registers[rs1] as i64) >> shamt) as u64),
+        Instruction::slti { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = ((core.x_registers[rs1] as i64) < imm) as u64),
+        Instruction::sltiu { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = (core.x_registers[rs1] < imm) as u64),
+
+        Instruction::addw { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word(core.x_registers[rs1].wrapping_add(core.x_registers[rs2]))),
+        Instruction::subw { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word(core.x_registers[rs1].wrapping_sub(core.x_registers[rs2]))),
+        Instruction::sllw { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word(core.x_registers[rs1] << (core.x_registers[rs2] & 0x1F))),
+        Instruction::srlw { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] =
+                sign_extend_word((core.x_registers[rs1] as u32 >> (core.x_registers[rs2] & 0x1F)) as u64)),
+        Instruction::sraw { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = ((core.x_registers[rs1] as i32) >> (core.x_registers[rs2] & 0x1F)) as u64),
+        Instruction::addiw { rd, rs1, imm } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word(core.x_registers[rs1].wrapping_add(imm as u64))),
+        Instruction::slliw { rd, rs1, shamt } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word(core.x_registers[rs1] << shamt)),
+        Instruction::srliw { rd, rs1, shamt } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word((core.x_registers[rs1] as u32 >> shamt) as u64)),
+        Instruction::sraiw { rd, rs1, shamt } => alu(move |core|
+            core.x_registers[rd] = ((core.x_registers[rs1] as i32) >> shamt) as u64),
+
+        Instruction::mul { rd, rs1, rs2 } => binary(rd, rs1, rs2, u64::wrapping_mul),
+        Instruction::mulh { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::mulh),
+        Instruction::mulhsu { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::mulhsu),
+        Instruction::mulhu { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::mulhu),
+        Instruction::div { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::div),
+        Instruction::divu { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::divu),
+        Instruction::rem { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::rem),
+        Instruction::remu { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::remu),
+        Instruction::mulw { rd, rs1, rs2 } => alu(move |core|
+            core.x_registers[rd] = sign_extend_word(core.x_registers[rs1].wrapping_mul(core.x_registers[rs2]))),
+        Instruction::divw { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::divw),
+        Instruction::divuw { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::divuw),
+        Instruction::remw { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::remw),
+        Instruction::remuw { rd, rs1, rs2 } => binary(rd, rs1, rs2, execute::remuw),
+
+        Instruction::lui { rd, uimm } => alu(move |core| core.x_registers[rd] = uimm),
+        Instruction::auipc { rd, imm } => {
+            let value = pc.wrapping_add(imm as usize) as u64;
+            alu(move |core| core.x_registers[rd] = value)
+        },
+
+        Instruction::lb { rd, rs1, imm } => load(rd, rs1, imm, 1, true),
+        Instruction::lh { rd, rs1, imm } => load(rd, rs1, imm, 2, true),
+        Instruction::lw { rd, rs1, imm } => load(rd, rs1, imm, 4, true),
+        Instruction::ld { rd, rs1, imm } => load(rd, rs1, imm, 8, false),
+        Instruction::lbu { rd, rs1, imm } => load(rd, rs1, imm, 1, false),
+        Instruction::lhu { rd, rs1, imm } => load(rd, rs1, imm, 2, false),
+        Instruction::lwu { rd, rs1, imm } => load(rd, rs1, imm, 4, false),
+
+        Instruction::sb { rs1, rs2, imm } => store(rs1, rs2, imm, 1),
+        Instruction::sh { rs1, rs2, imm } => store(rs1, rs2, imm, 2),
+        Instruction::sw { rs1, rs2, imm } => store(rs1, rs2, imm, 4),
+        Instruction::sd { rs1, rs2, imm } => store(rs1, rs2, imm, 8),
+
+        _ => None
+    }
+}
@@ -1,54 +1,456 @@
+use std::collections::HashSet;
 use std::error::Error;
-use std::rc::Rc;
-use std::cell::RefCell;
 use std::fmt::{Display, Formatter, Debug};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
 
-use crate::bus::Bus;
+use crate::bus::SharedBus;
 use crate::endianness::Endianness;
 use crate::device::{DeviceError, Device};
 use crate::cpu::instruction::Instruction;
 use crate::cpu::decode::InstructionDecodeError;
 use crate::cpu::execute::InstructionExecuteError;
-use crate::cpu::register::{XRegisterMap, FRegisterMap};
+use crate::cpu::register::{XRegister, XRegisterMap, FRegister, FRegisterMap};
+use crate::cpu::csr::{CsrMap, MHARTID, MIE, MIP};
+use crate::cpu::decode_cache::DecodeCache;
+use crate::cpu::block::BlockCache;
+use crate::cpu::mmu::{AccessType, Tlb};
+#[cfg(feature = "jit")]
+use crate::cpu::jit::MemoryAccess;
+use crate::cpu::trap::{StopReason, WatchKind};
+use crate::cpu::trace::CommitLog;
+use crate::cpu::replay::Clock;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// Privilege level of a hart. The discriminant is its encoding in `mstatus.MPP`.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decodes a privilege level. Returns `None` for the reserved encoding.
+    pub fn from_bits(bits: u64) -> Option<Privilege> {
+        match bits {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None
+        }
+    }
+}
 
 
 pub struct Core {
     pub pc: usize,
+    pub reset_vector: usize,
     pub x_registers: XRegisterMap,
     pub f_registers: FRegisterMap,
-    pub bus: Rc<RefCell<Bus>>
+    pub csr_registers: CsrMap,
+    pub decode_cache: DecodeCache,
+    pub block_cache: BlockCache,
+    pub tlb: Tlb,
+    pub bus: SharedBus,
+    pub privilege: Privilege,
+    /// Set by `wfi`, cleared when the run loop reports it.
+    pub(crate) waiting: bool,
+    breakpoints: HashSet<usize>,
+    /// Breakpoint the last call stopped at, which the next call resumes past.
+    stopped_at: Option<usize>,
+    watchpoints: Vec<(usize, usize, WatchKind)>,
+    pub(crate) instructions_retired: u64,
+    /// Exceptions for which the trap handler was entered.
+    pub(crate) exceptions_taken: u64,
+    /// Address of the last `lr`, cleared by `sc` and traps. The bus tracks whether a
+    /// store broke the reservation since.
+    pub(crate) reservation: Option<usize>,
+    /// Device interrupts pending for the hart, as `mip` bits.
+    pub(crate) interrupt_line: Option<Arc<AtomicU64>>,
+    /// Log of the retired instructions, written while single-stepping.
+    pub(crate) commit_log: Option<CommitLog>,
+    /// Whether semihosting calls stop the hart instead of entering the trap handler.
+    pub(crate) semihosting: bool,
+    /// Stopped by the SBI, so `run` doesn't execute the hart until it's started.
+    pub(crate) stopped: bool,
+    /// Whether the timer interrupt of the CLINT is raised as the supervisor timer
+    /// interrupt, as firmware forwards it to S-mode. Set by the SBI.
+    pub(crate) supervisor_timer: bool,
+    /// Time of the CLINT, read through the `time` CSR.
+    pub(crate) clock: Option<Clock>,
 }
 
 #[derive(Debug)]
 pub enum CoreError {
     DeviceError(DeviceError),
     InstructionDecodeError(InstructionDecodeError),
-    InstructionExecuteError(InstructionExecuteError)
+    InstructionExecuteError(InstructionExecuteError),
+    /// A compiled block left `register` with a different value than the interpreter.
+    #[cfg(feature = "jit")]
+    JitMismatch { pc: usize, register: XRegister, expected: u64, actual: u64 },
+    /// A compiled block made a different memory access than the interpreter, or one
+    /// more or less, in which case `actual` or `expected` is `None`.
+    #[cfg(feature = "jit")]
+    JitAccessMismatch { pc: usize, expected: Option<MemoryAccess>, actual: Option<MemoryAccess> },
+    /// Writing the commit log failed.
+    IoError(io::Error),
 }
 
 impl Core {
-    pub fn new(bus: Rc<RefCell<Bus>>) -> Core {
+    pub fn new(bus: SharedBus) -> Core {
         Core {
             pc: 0,
+            reset_vector: 0,
             x_registers: XRegisterMap::new(),
             f_registers: FRegisterMap::new(),
-            bus
+            csr_registers: CsrMap::new(),
+            decode_cache: DecodeCache::new(),
+            block_cache: BlockCache::new(),
+            tlb: Tlb::new(),
+            bus,
+            privilege: Privilege::Machine,
+            waiting: false,
+            breakpoints: HashSet::new(),
+            stopped_at: None,
+            watchpoints: Vec::new(),
+            instructions_retired: 0,
+            exceptions_taken: 0,
+            reservation: None,
+            interrupt_line: None,
+            commit_log: None,
+            semihosting: false,
+            stopped: false,
+            supervisor_timer: false,
+            clock: None,
         }
     }
 
+    pub fn get_hart_id(&self) -> u64 {
+        self.csr_registers[MHARTID]
+    }
+
+    /// Sets the value of `mhartid`, which is kept across resets.
+    pub fn set_hart_id(&mut self, hart_id: u64) {
+        self.csr_registers[MHARTID] = hart_id;
+    }
+
+    /// Connects the hart to the interrupt line a device like the CLINT drives.
+    pub fn set_interrupt_line(&mut self, line: Arc<AtomicU64>) {
+        self.interrupt_line = Some(line);
+    }
+
+    pub fn reset(&mut self) {
+        let hart_id = self.get_hart_id();
+        self.pc = self.reset_vector;
+        self.x_registers = XRegisterMap::new();
+        self.f_registers = FRegisterMap::new();
+        self.csr_registers = CsrMap::new();
+        self.csr_registers[MHARTID] = hart_id;
+        self.privilege = Privilege::Machine;
+        self.tlb.flush();
+        self.waiting = false;
+        self.reservation = None;
+        self.stopped_at = None;
+        self.stopped = false;
+    }
+
     pub fn execute(&mut self) -> Result<(), CoreError>{
+        let address = self.translate(self.pc, AccessType::Fetch)?;
+        let (instruction, _) = self.fetch(address)?;
+        instruction.execute(self)?;
+        self.instructions_retired += 1;
+        Ok(())
+    }
+
+    /// Takes a pending interrupt, then executes one instruction, entering the trap
+    /// handler if it raises an exception. Returns the reason to stop, if any.
+    pub fn step(&mut self) -> Result<Option<StopReason>, CoreError> {
+        self.take_interrupt();
+        let watchpoint = self.find_watchpoint();
+        let commit = self.begin_commit()?;
+        let pc = self.pc;
+        match self.execute() {
+            Ok(()) => {
+                if let Some(commit) = commit {
+                    self.end_commit(commit)?;
+                }
+                Ok(self.take_stop_reason().or(watchpoint))
+            },
+            Err(error) => {
+                let reason = self.handle_error(error)?;
+                self.log_exception(pc, reason)?;
+                Ok(reason)
+            }
+        }
+    }
+
+    /// Executes up to `max_instructions` instructions. Instructions raising an
+    /// exception count against the limit. Execution resumes past the breakpoint the
+    /// previous call stopped at, if the hart is still there. A hart the SBI stopped
+    /// returns `StopReason::WaitForInterrupt` right away.
+    pub fn run(&mut self, max_instructions: u64) -> Result<StopReason, CoreError> {
+        if self.stopped {
+            return Ok(StopReason::WaitForInterrupt)
+        }
+        let mut executed = 0;
+        while executed < max_instructions {
+            if !self.breakpoints.is_empty() || !self.watchpoints.is_empty() || self.commit_log.is_some() {
+                if let Some(reason) = self.check_breakpoint() {
+                    return Ok(reason)
+                }
+                executed += 1;
+                if let Some(reason) = self.step()? {
+                    return Ok(reason)
+                }
+                continue
+            }
+
+            self.stopped_at = None;
+            let retired = self.instructions_retired;
+            let result = self.execute_blocks(max_instructions - executed);
+            executed += self.instructions_retired - retired;
+            let reason = match result {
+                Ok(_) => self.take_stop_reason(),
+                Err(error) => {
+                    executed += 1;
+                    self.handle_error(error)?
+                }
+            };
+            if let Some(reason) = reason {
+                return Ok(reason)
+            }
+        }
+        Ok(StopReason::BudgetExhausted)
+    }
+
+    /// Single-steps until `predicate` returns true before an instruction, or
+    /// execution stops for another reason. There is no instruction limit.
+    pub fn run_until(&mut self, mut predicate: impl FnMut(&Core) -> bool)
+            -> Result<StopReason, CoreError> {
+        loop {
+            if predicate(self) {
+                return Ok(StopReason::Condition)
+            }
+            if let Some(reason) = self.check_breakpoint() {
+                return Ok(reason)
+            }
+            if let Some(reason) = self.step()? {
+                return Ok(reason)
+            }
+        }
+    }
+
+    /// Stops at a breakpoint on `pc`, unless execution is resuming from it.
+    fn check_breakpoint(&mut self) -> Option<StopReason> {
+        if self.breakpoints.contains(&self.pc) && self.stopped_at != Some(self.pc) {
+            self.stopped_at = Some(self.pc);
+            return Some(StopReason::Breakpoint { address: self.pc })
+        }
+        self.stopped_at = None;
+        None
+    }
+
+    /// Stops `run` and `run_until` before the instruction at `address` is executed.
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Returns false if there was no breakpoint at `address`.
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Stops `run` and `run_until` after an instruction accessing any of the `size`
+    /// bytes at `address` the way `kind` describes. Execution is single-stepped while
+    /// there are watchpoints.
+    pub fn add_watchpoint(&mut self, address: usize, size: usize, kind: WatchKind) {
+        self.watchpoints.push((address, size, kind));
+    }
+
+    /// Returns false if there was no such watchpoint.
+    pub fn remove_watchpoint(&mut self, address: usize, size: usize, kind: WatchKind) -> bool {
+        match self.watchpoints.iter().position(|x| *x == (address, size, kind)) {
+            Some(i) => { self.watchpoints.remove(i); true },
+            None => false
+        }
+    }
+
+    /// The watchpoint the instruction at `pc` is about to trigger, as a stop reason.
+    fn find_watchpoint(&mut self) -> Option<StopReason> {
+        if self.watchpoints.is_empty() {
+            return None
+        }
+        let address = self.translate(self.pc, AccessType::Fetch).ok()?;
+        let (instruction, _) = self.fetch(address).ok()?;
+        let (start, size, access) = self.get_memory_access(&instruction)?;
+        self.watchpoints.iter().find_map(|(address, length, kind)| {
+            let matches = match kind {
+                WatchKind::Access => true,
+                _ => access == *kind || access == WatchKind::Access
+            };
+            let first = start.max(*address);
+            if matches && first < (start + size).min(address + length) {
+                Some(StopReason::Watchpoint { address: first, kind: *kind })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Address, size and kind of the memory access `instruction` makes, if any. A
+    /// store conditional only counts if it holds a reservation for the address.
+    pub(crate) fn get_memory_access(&self, instruction: &Instruction) -> Option<(usize, usize, WatchKind)> {
+        let offset = |rs1: XRegister, imm: i64| self.x_registers[rs1].wrapping_add(imm as u64) as usize;
+        let (address, size, kind) = match *instruction {
+            Instruction::lb { rs1, imm, .. } | Instruction::lbu { rs1, imm, .. } =>
+                (offset(rs1, imm), 1, WatchKind::Read),
+            Instruction::lh { rs1, imm, .. } | Instruction::lhu { rs1, imm, .. } =>
+                (offset(rs1, imm), 2, WatchKind::Read),
+            Instruction::lw { rs1, imm, .. } | Instruction::lwu { rs1, imm, .. } =>
+                (offset(rs1, imm), 4, WatchKind::Read),
+            Instruction::ld { rs1, imm, .. } => (offset(rs1, imm), 8, WatchKind::Read),
+            Instruction::sb { rs1, imm, .. } => (offset(rs1, imm), 1, WatchKind::Write),
+            Instruction::sh { rs1, imm, .. } => (offset(rs1, imm), 2, WatchKind::Write),
+            Instruction::sw { rs1, imm, .. } => (offset(rs1, imm), 4, WatchKind::Write),
+            Instruction::sd { rs1, imm, .. } => (offset(rs1, imm), 8, WatchKind::Write),
+            Instruction::lr_w { rs1, .. } => (offset(rs1, 0), 4, WatchKind::Read),
+            Instruction::lr_d { rs1, .. } => (offset(rs1, 0), 8, WatchKind::Read),
+            Instruction::sc_w { rs1, .. } | Instruction::sc_d { rs1, .. } => {
+                let address = offset(rs1, 0);
+                if self.reservation != Some(address) {
+                    return None
+                }
+                let size = if let Instruction::sc_w { .. } = instruction { 4 } else { 8 };
+                (address, size, WatchKind::Write)
+            },
+            Instruction::amoswap_w { rs1, .. } | Instruction::amoadd_w { rs1, .. } |
+            Instruction::amoxor_w { rs1, .. } | Instruction::amoand_w { rs1, .. } |
+            Instruction::amoor_w { rs1, .. } | Instruction::amomin_w { rs1, .. } |
+            Instruction::amomax_w { rs1, .. } | Instruction::amominu_w { rs1, .. } |
+            Instruction::amomaxu_w { rs1, .. } => (offset(rs1, 0), 4, WatchKind::Access),
+            Instruction::amoswap_d { rs1, .. } | Instruction::amoadd_d { rs1, .. } |
+            Instruction::amoxor_d { rs1, .. } | Instruction::amoand_d { rs1, .. } |
+            Instruction::amoor_d { rs1, .. } | Instruction::amomin_d { rs1, .. } |
+            Instruction::amomax_d { rs1, .. } | Instruction::amominu_d { rs1, .. } |
+            Instruction::amomaxu_d { rs1, .. } => (offset(rs1, 0), 8, WatchKind::Access),
+            _ => return None
+        };
+        Some((address, size, kind))
+    }
+
+    /// Number of instructions completed since the core was created. Instructions
+    /// raising an exception aren't counted.
+    pub fn get_instructions_retired(&self) -> u64 {
+        self.instructions_retired
+    }
+
+    /// Number of instructions executed, including those that entered the trap handler
+    /// by raising an exception. Unlike the retired count, it advances on every
+    /// instruction, so it identifies a point in the execution of the hart.
+    pub fn get_steps(&self) -> u64 {
+        self.instructions_retired + self.exceptions_taken
+    }
+
+    fn take_stop_reason(&mut self) -> Option<StopReason> {
+        if let Some(code) = self.bus.lock().take_exit_request() {
+            return Some(StopReason::Exit { code })
+        }
+        if self.waiting {
+            self.waiting = false;
+            self.synchronize_interrupts();
+            // `wfi` doesn't wait if an enabled interrupt is pending, even with MIE clear.
+            if self.csr_registers[MIP] & self.csr_registers[MIE] == 0 {
+                return Some(StopReason::WaitForInterrupt)
+            }
+        }
+        None
+    }
+
+    /// Returns the instruction at the physical `address`, from the decode cache if possible, and
+    /// whether it's in memory. Instructions outside memory are decoded every time.
+    pub(crate) fn fetch(&mut self, address: usize) -> Result<(Instruction, bool), CoreError> {
+        let mut bus = self.bus.lock();
+        self.decode_cache.synchronize(&bus);
+        if let Some(instruction) = self.decode_cache.get(address) {
+            return Ok((instruction, true))
+        }
+
         let instruction = Instruction::decode(
-            self.bus.borrow().read_int(
-            self.pc,
+            bus.read_int(
+            address,
             4,
             Endianness::LittleEndian,
                 false
         )? as u32)?;
 
-        instruction.execute(self)?;
+        let is_memory = bus.mark_code_page(address);
+        if is_memory {
+            self.decode_cache.insert(address, instruction);
+        }
+        Ok((instruction, is_memory))
+    }
+
+    /// Writes the architectural state of the hart, and whether the SBI stopped it and
+    /// delivers its timer to S-mode, for `CPU::save_snapshot`. Caches and breakpoints aren't part of it.
+    pub fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_u64(self.pc as u64)?;
+        writer.write_u64(self.reset_vector as u64)?;
+        writer.write_u8(self.privilege as u8)?;
+        for i in 0..32u32 {
+            writer.write_u64(self.x_registers[XRegister::from(i)])?;
+        }
+        for i in 0..32u32 {
+            writer.write_u64(self.f_registers[FRegister::from(i)].to_bits())?;
+        }
+        for csr in 0..4096 {
+            writer.write_u64(self.csr_registers[csr])?;
+        }
+        writer.write_bool(self.waiting)?;
+        writer.write_bool(self.stopped)?;
+        writer.write_bool(self.supervisor_timer)?;
+        writer.write_bool(self.reservation.is_some())?;
+        writer.write_u64(self.reservation.unwrap_or(0) as u64)?;
+        writer.write_u64(self.instructions_retired)?;
+        writer.write_u64(self.exceptions_taken)
+    }
+
+    /// Restores the state written by `save_state`, and flushes the caches.
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.pc = reader.read_u64()? as usize;
+        self.reset_vector = reader.read_u64()? as usize;
+        self.privilege = Privilege::from_bits(reader.read_u8()? as u64)
+            .ok_or_else(|| SnapshotError::InvalidState { name: "privilege".to_string() })?;
+        for i in 0..32u32 {
+            self.x_registers[XRegister::from(i)] = reader.read_u64()?;
+        }
+        for i in 0..32u32 {
+            self.f_registers[FRegister::from(i)] = f64::from_bits(reader.read_u64()?);
+        }
+        for csr in 0..4096 {
+            self.csr_registers[csr] = reader.read_u64()?;
+        }
+        self.waiting = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.supervisor_timer = reader.read_bool()?;
+        let reserved = reader.read_bool()?;
+        let address = reader.read_u64()? as usize;
+        self.reservation = if reserved { Some(address) } else { None };
+        self.instructions_retired = reader.read_u64()?;
+        self.exceptions_taken = reader.read_u64()?;
+
+        self.decode_cache.flush();
+        self.block_cache.flush();
+        self.tlb.flush();
         Ok(())
     }
 
+    /// ISA string as used in the `riscv,isa` device tree property.
+    pub fn get_isa_string(&self) -> &str {
+        "rv64ima"
+    }
+
     pub fn add_to_pc(&mut self, delta: i64) {
         self.pc = self.pc.wrapping_add(delta as usize);
     }
@@ -1,21 +1,464 @@
-use crate::cpu::core::Core;
-use crate::device::Device;
-use crate::bus::Bus;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::clint::CLINT;
+use crate::plic::PLIC;
+use crate::cpu::core::{Core, CoreError};
+use crate::cpu::replay::{Clock, InputMode, Recorder};
+use crate::cpu::trap::StopReason;
+use crate::cpu::register::XRegister;
+use crate::device::{Device, DeviceError};
+use crate::bus::{Bus, BusError, SharedBus};
+use crate::dram::DRAM;
+use crate::uart::UART;
+use crate::boot_rom::BootROM;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 
+pub(crate) const TIMEBASE_FREQUENCY: u32 = 10_000_000;
 
-struct _CPU {
-    core: Core,
-    bus: Rc<RefCell<Bus>>
+const DEFAULT_QUANTUM: u64 = 1000;
+
+
+/// Harts sharing a bus, with hart `i` at index `i`.
+///
+/// `run` interleaves the harts deterministically: each one executes `quantum`
+/// instructions in turn, so a run only depends on the program, the quantum and the
+/// inputs, which can be recorded and replayed.
+pub struct CPU {
+    pub harts: Vec<Core>,
+    pub bus: SharedBus,
+    fdt_address: Option<usize>,
+    quantum: u64,
+    next_hart: usize,
+    /// Instructions executed by `next_hart` in its current quantum.
+    quantum_used: u64,
+    pub(crate) clock: Clock,
+    pub(crate) clint_address: Option<usize>,
+    pub(crate) plic_address: Option<usize>,
+    pub(crate) recorder: Recorder,
+    /// Kernel command line and initramfs, passed in `/chosen` of the device tree.
+    bootargs: Option<String>,
+    initrd: Option<Range<usize>>
+}
+
+#[derive(Debug)]
+pub enum CPUError {
+    BusError(BusError),
+    DeviceError(DeviceError),
+    FdtError(FdtError),
+    CoreError { hart: usize, error: CoreError },
+    SnapshotError(SnapshotError),
+    NoMemoryForFdt { size: usize },
+    NoHarts,
+    InvalidHart { hart: usize },
+    /// Reverse execution or `stop_recording` without a recording.
+    NotRecording,
+    /// `run_threaded` was called while recording or replaying.
+    NotDeterministic,
+    /// The replayed execution didn't reach the point of an input recorded for `hart`.
+    ReplayDiverged { hart: usize, step: u64 },
 }
 
-impl _CPU {
-    fn _new(devices: Vec<(usize, Box<dyn Device>)>) -> Self {
-        let bus = Rc::new(RefCell::new(Bus::new(devices)));
-        Self {
-            core: Core::new(bus.clone()),
-            bus
+impl CPU {
+    pub fn new(devices: Vec<(usize, Box<dyn Device>)>) -> Result<Self, CPUError> {
+        Self::with_harts(devices, 1)
+    }
+
+    pub fn with_harts(devices: Vec<(usize, Box<dyn Device>)>, hart_count: usize)
+            -> Result<Self, CPUError> {
+        if hart_count == 0 {
+            return Err(CPUError::NoHarts)
+        }
+
+        let bus = SharedBus::new(Bus::new(devices)?);
+        // Start executing from the boot ROM, if there is one.
+        let reset_vector = bus.lock().find_devices::<BootROM>().first()
+            .map_or(0, |address_range| address_range.start);
+
+        let mut harts: Vec<Core> = (0..hart_count).map(|hart_id| {
+            let mut core = Core::new(bus.clone());
+            core.set_hart_id(hart_id as u64);
+            core.reset_vector = reset_vector;
+            core
+        }).collect();
+
+        // Connect the harts to the CLINT, if there is one.
+        let clock = Clock::new();
+        let clint_address = bus.lock().find_devices::<CLINT>().first()
+            .map(|address_range| address_range.start);
+        if let Some(address) = clint_address {
+            if let Some((_, device)) = bus.lock().get_device_mut(address) {
+                if let Some(clint) = device.as_any_mut().downcast_mut::<CLINT>() {
+                    clint.set_clock(clock.clone());
+                    for (hart, core) in harts.iter_mut().enumerate() {
+                        core.clock = Some(clock.clone());
+                        if let Some(line) = clint.get_interrupt_line(hart) {
+                            core.set_interrupt_line(line);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Connect the harts to the PLIC, if there is one, with their own line if there's
+        // no CLINT.
+        let plic_address = bus.lock().find_devices::<PLIC>().first()
+            .map(|address_range| address_range.start);
+        if let Some(address) = plic_address {
+            if let Some((_, device)) = bus.lock().get_device_mut(address) {
+                if let Some(plic) = device.as_any_mut().downcast_mut::<PLIC>() {
+                    for (hart, core) in harts.iter_mut().enumerate() {
+                        let line = core.interrupt_line.get_or_insert_with(|| Arc::new(AtomicU64::new(0)));
+                        plic.set_interrupt_line(hart, line.clone());
+                    }
+                }
+            }
+        }
+
+        let mut cpu = Self {
+            harts,
+            bus,
+            fdt_address: None,
+            quantum: DEFAULT_QUANTUM,
+            next_hart: 0,
+            quantum_used: 0,
+            clock,
+            clint_address,
+            plic_address,
+            recorder: Recorder::new(),
+            bootargs: None,
+            initrd: None
+        };
+        cpu.reset();
+        Ok(cpu)
+    }
+
+    /// Resets all harts. Following the standard boot protocol, `a0` holds the hart
+    /// id and `a1` the address of the device tree blob, if one was loaded.
+    pub fn reset(&mut self) {
+        for core in &mut self.harts {
+            core.reset();
+            core.x_registers[XRegister::x10] = core.get_hart_id();
+            core.x_registers[XRegister::x11] = self.fdt_address.unwrap_or(0) as u64;
+        }
+        self.next_hart = 0;
+        self.quantum_used = 0;
+    }
+
+    /// Number of instructions a hart executes before `run` switches to the next one.
+    pub fn get_quantum(&self) -> u64 {
+        self.quantum
+    }
+
+    pub fn set_quantum(&mut self, quantum: u64) {
+        self.quantum = quantum.max(1);
+    }
+
+    /// Runs the harts round-robin for up to `max_instructions` instructions in total.
+    /// Returns the hart that stopped and why.
+    ///
+    /// A hart executing `wfi` gives up the rest of its quantum, and `run` only
+    /// returns `StopReason::WaitForInterrupt` once all harts are waiting. On other
+    /// stop reasons, the next call resumes with the same hart and the rest of its
+    /// quantum, so the interleaving doesn't depend on how execution is split in calls.
+    ///
+    /// Device interrupts and UART input are delivered at the start of a quantum.
+    pub fn run(&mut self, max_instructions: u64) -> Result<(usize, StopReason), CPUError> {
+        let mut executed = 0;
+        let mut waiting = 0;
+        let mut hart = self.next_hart;
+
+        while executed < max_instructions {
+            hart = self.next_hart;
+            let limit = self.deliver_inputs(hart, self.quantum_used == 0)?;
+            let core = &mut self.harts[hart];
+            let budget = (self.quantum - self.quantum_used).min(max_instructions - executed).min(limit);
+            let steps = core.get_steps();
+
+            let reason = core.run(budget)
+                .map_err(|error| CPUError::CoreError { hart, error })?;
+            let done = core.get_steps() - steps;
+            executed += done;
+            self.quantum_used += done;
+            match reason {
+                StopReason::BudgetExhausted => {
+                    if self.quantum_used >= self.quantum {
+                        waiting = 0;
+                    }
+                },
+                StopReason::WaitForInterrupt => {
+                    waiting += 1;
+                    self.quantum_used = self.quantum;
+                    if waiting == self.harts.len() {
+                        self.end_quantum();
+                        return Ok((hart, reason))
+                    }
+                },
+                _ => return Ok((hart, reason))
+            }
+            if self.quantum_used >= self.quantum {
+                self.end_quantum();
+            }
+        }
+
+        Ok((hart, StopReason::BudgetExhausted))
+    }
+
+    fn end_quantum(&mut self) {
+        self.next_hart = (self.next_hart + 1) % self.harts.len();
+        self.quantum_used = 0;
+    }
+
+    /// Runs every hart on its own host thread for up to `max_instructions` instructions,
+    /// and returns why each hart stopped.
+    ///
+    /// Once a hart stops for another reason than its budget, the others stop at the end
+    /// of their quantum and report `StopReason::BudgetExhausted`. A waiting hart, such
+    /// as one stopped through the SBI, reports the wait once every other hart is waiting
+    /// or has used up its budget. Unlike `run`, the interleaving depends on the host
+    /// scheduler.
+    pub fn run_threaded(&mut self, max_instructions: u64) -> Result<Vec<StopReason>, CPUError> {
+        if self.recorder.get_mode() != InputMode::Live {
+            return Err(CPUError::NotDeterministic)
+        }
+        let quantum = self.quantum;
+        let stop = AtomicBool::new(false);
+        let waiting: Vec<AtomicBool> = self.harts.iter().map(|_| AtomicBool::new(false)).collect();
+
+        thread::scope(|scope| {
+            let threads: Vec<_> = self.harts.iter_mut().enumerate().map(|(hart, core)| {
+                let stop = &stop;
+                let waiting = &waiting;
+                scope.spawn(move || {
+                    let result = run_hart(core, hart, max_instructions, quantum, stop, waiting);
+                    if !matches!(result, Ok(StopReason::BudgetExhausted)) {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    // A hart that used up its budget can't wake the others any more, so
+                    // harts waiting for it give up as well.
+                    waiting[hart].store(true, Ordering::Relaxed);
+                    result
+                })
+            }).collect();
+
+            threads.into_iter()
+                .map(|thread| thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        })
+    }
+
+    /// Writes the state of all harts and devices. Fails with
+    /// `SnapshotError::UnsupportedDevice` if a device doesn't support snapshots.
+    pub fn save_snapshot(&self, writer: &mut impl Write) -> Result<(), SnapshotError> {
+        let mut writer = SnapshotWriter::new(writer);
+        writer.write_header()?;
+        writer.write_u64(self.harts.len() as u64)?;
+        writer.write_u64(self.quantum)?;
+        writer.write_u64(self.next_hart as u64)?;
+        writer.write_u64(self.quantum_used)?;
+        writer.write_bool(self.fdt_address.is_some())?;
+        writer.write_u64(self.fdt_address.unwrap_or(0) as u64)?;
+        for core in &self.harts {
+            core.save_state(&mut writer)?;
+        }
+        self.bus.lock().save_state(&mut writer)
+    }
+
+    /// Restores a snapshot written by `save_snapshot` into a machine with the same
+    /// number of harts and the same devices. Execution then continues exactly as it
+    /// would have from the point the snapshot was taken. On error, the machine is
+    /// left partially restored.
+    pub fn restore_snapshot(&mut self, reader: &mut impl Read) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(reader);
+        reader.read_header()?;
+        let hart_count = reader.read_u64()? as usize;
+        if hart_count != self.harts.len() {
+            return Err(SnapshotError::HartCountMismatch {
+                expected: self.harts.len(),
+                found: hart_count
+            })
         }
+        self.quantum = reader.read_u64()?.max(1);
+        self.next_hart = reader.read_u64()? as usize % hart_count;
+        self.quantum_used = reader.read_u64()?.min(self.quantum - 1);
+        let has_fdt = reader.read_bool()?;
+        let fdt_address = reader.read_u64()? as usize;
+        self.fdt_address = if has_fdt { Some(fdt_address) } else { None };
+        for core in &mut self.harts {
+            core.restore_state(&mut reader)?;
+        }
+        self.bus.lock().restore_state(&mut reader)
+    }
+
+    /// Sets the kernel command line of the device tree.
+    pub fn set_bootargs(&mut self, bootargs: &str) {
+        self.bootargs = Some(bootargs.to_string());
+    }
+
+    /// Sets the memory holding the initramfs, for the device tree.
+    pub fn set_initrd(&mut self, initrd: Range<usize>) {
+        self.initrd = Some(initrd);
+    }
+
+    /// Generates a device tree describing the harts and every device on the bus.
+    pub fn generate_fdt(&self) -> Result<Vec<u8>, FdtError> {
+        let bus = self.bus.lock();
+        let mut fdt = FdtWriter::new();
+
+        fdt.begin_node("")?;
+        fdt.property_u32("#address-cells", 2)?;
+        fdt.property_u32("#size-cells", 2)?;
+        fdt.property_string("compatible", "yarve")?;
+        fdt.property_string("model", "yarve")?;
+
+        fdt.begin_node("chosen")?;
+        if let Some(address_range) = bus.find_devices::<UART>().first() {
+            fdt.property_string("stdout-path", &format!("/serial@{:x}", address_range.start))?;
+        }
+        if let Some(bootargs) = &self.bootargs {
+            fdt.property_string("bootargs", bootargs)?;
+        }
+        if let Some(initrd) = &self.initrd {
+            fdt.property_u64("linux,initrd-start", initrd.start as u64)?;
+            fdt.property_u64("linux,initrd-end", initrd.end as u64)?;
+        }
+        fdt.end_node()?;
+
+        fdt.begin_node("cpus")?;
+        fdt.property_u32("#address-cells", 1)?;
+        fdt.property_u32("#size-cells", 0)?;
+        fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY)?;
+        for core in &self.harts {
+            let hart_id = core.get_hart_id();
+            fdt.begin_node(&format!("cpu@{:x}", hart_id))?;
+            fdt.property_string("device_type", "cpu")?;
+            fdt.property_u32("reg", hart_id as u32)?;
+            fdt.property_string("status", "okay")?;
+            fdt.property_string("compatible", "riscv")?;
+            fdt.property_string("riscv,isa", core.get_isa_string())?;
+            fdt.property_string("mmu-type", "riscv,sv39")?;
+            fdt.begin_node("interrupt-controller")?;
+            let phandle = fdt.get_phandle(&interrupt_controller_label(hart_id));
+            fdt.property_u32("#interrupt-cells", 1)?;
+            fdt.property_null("interrupt-controller")?;
+            fdt.property_string("compatible", "riscv,cpu-intc")?;
+            fdt.property_u32("phandle", phandle)?;
+            fdt.end_node()?;
+            fdt.end_node()?;
+        }
+        fdt.end_node()?;
+
+        bus.fdt_node(0, &mut fdt)?;
+
+        fdt.end_node()?;
+        fdt.finish()
+    }
+
+    /// Generates the device tree and places it at the end of the highest DRAM
+    /// device that fits it. Returns the address of the blob, which is passed to the
+    /// guest in `a1` from now on.
+    pub fn load_fdt(&mut self) -> Result<usize, CPUError> {
+        let blob = self.generate_fdt()?;
+
+        let address = self.bus.lock().find_devices::<DRAM>().into_iter()
+            .filter(|address_range| address_range.len() >= blob.len())
+            .map(|address_range| (address_range.start, (address_range.end - blob.len()) & !0x7))
+            .filter(|(start, address)| address >= start)
+            .map(|(_, address)| address)
+            .next_back();
+
+        let address = match address {
+            Some(x) => x,
+            None => return Err(CPUError::NoMemoryForFdt { size: blob.len() })
+        };
+
+        let mut bus = self.bus.lock();
+        bus.write_bytes(address, &blob)?;
+        for address_range in bus.find_devices::<BootROM>() {
+            if let Some((_, device)) = bus.get_device_mut(address_range.start) {
+                if let Some(boot_rom) = device.as_any_mut().downcast_mut::<BootROM>() {
+                    boot_rom.set_fdt_address(address as u64);
+                }
+            }
+        }
+        drop(bus);
+
+        self.fdt_address = Some(address);
+        self.reset();
+        Ok(address)
+    }
+}
+
+/// Label of the interrupt controller node of a hart, for `FdtWriter::get_phandle`.
+pub(crate) fn interrupt_controller_label(hart_id: u64) -> String {
+    format!("cpu{}-intc", hart_id)
+}
+
+/// Body of a hart thread in `CPU::run_threaded`.
+fn run_hart(core: &mut Core, hart: usize, max_instructions: u64, quantum: u64,
+            stop: &AtomicBool, waiting: &[AtomicBool]) -> Result<StopReason, CPUError> {
+    let mut executed = 0;
+    while executed < max_instructions && !stop.load(Ordering::Relaxed) {
+        let quantum = quantum.min(max_instructions - executed);
+        let retired = core.get_instructions_retired();
+
+        let reason = core.run(quantum).map_err(|error| CPUError::CoreError { hart, error })?;
+        match reason {
+            StopReason::BudgetExhausted => {
+                executed += quantum;
+                waiting[hart].store(false, Ordering::Relaxed);
+            },
+            StopReason::WaitForInterrupt => {
+                executed += core.get_instructions_retired() - retired;
+                waiting[hart].store(true, Ordering::Relaxed);
+                if waiting.iter().all(|waiting| waiting.load(Ordering::Relaxed)) {
+                    return Ok(reason)
+                }
+                thread::yield_now();
+            },
+            _ => return Ok(reason)
+        }
+    }
+
+    if waiting.iter().all(|waiting| waiting.load(Ordering::Relaxed)) {
+        return Ok(StopReason::WaitForInterrupt)
+    }
+    Ok(StopReason::BudgetExhausted)
+}
+
+impl Display for CPUError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CPUError {}
+
+impl From<BusError> for CPUError {
+    fn from(error: BusError) -> Self {
+        Self::BusError(error)
+    }
+}
+
+impl From<DeviceError> for CPUError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
+
+impl From<SnapshotError> for CPUError {
+    fn from(error: SnapshotError) -> Self {
+        Self::SnapshotError(error)
+    }
+}
+
+impl From<FdtError> for CPUError {
+    fn from(error: FdtError) -> Self {
+        Self::FdtError(error)
     }
 }
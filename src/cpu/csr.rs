@@ -0,0 +1,210 @@
+use std::ops::{Index, IndexMut};
+
+use crate::cpu::core::{Core, Privilege};
+use crate::device::DeviceError;
+
+// Supervisor trap setup and handling
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+
+// Supervisor protection and translation
+pub const SATP: u16 = 0x180;
+
+// Unprivileged counters
+pub const TIME: u16 = 0xC01;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+// Machine trap setup and handling
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+
+// mip and mie fields
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+// satp fields
+pub const SATP_MODE: u64 = 0xF << 60;
+pub const SATP_MODE_SV39: u64 = 8 << 60;
+pub const SATP_PPN: u64 = (1 << 44) - 1;
+
+// mcounteren and scounteren fields
+pub const COUNTEREN_TM: u64 = 1 << 1;
+
+/// The fields of `mstatus` that `sstatus` shows.
+const SSTATUS_FIELDS: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+const MSTATUS_FIELDS: u64 = SSTATUS_FIELDS | MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP | MSTATUS_MPRV |
+    MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+pub const SUPERVISOR_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const MACHINE_INTERRUPTS: u64 = MIP_MSIP | MIP_MTIP | MIP_MEIP;
+/// Exceptions that can be delegated: all but `ecall` from M-mode and the reserved causes.
+const DELEGABLE_EXCEPTIONS: u64 = 0xB3FF;
+/// RV64 with the A, I, M, S and U extensions.
+const MISA_VALUE: u64 = (2 << 62) | (1 << 0) | (1 << 8) | (1 << 12) | (1 << 18) | (1 << 20);
+
+/// Names of the implemented CSRs, in address order.
+pub const CSR_NAMES: [(u16, &str); 27] = [
+    (SSTATUS, "sstatus"), (SIE, "sie"), (STVEC, "stvec"), (SCOUNTEREN, "scounteren"),
+    (SSCRATCH, "sscratch"), (SEPC, "sepc"), (SCAUSE, "scause"), (STVAL, "stval"), (SIP, "sip"),
+    (SATP, "satp"),
+    (MSTATUS, "mstatus"), (MISA, "misa"), (MEDELEG, "medeleg"), (MIDELEG, "mideleg"), (MIE, "mie"),
+    (MTVEC, "mtvec"), (MCOUNTEREN, "mcounteren"), (MSCRATCH, "mscratch"), (MEPC, "mepc"),
+    (MCAUSE, "mcause"), (MTVAL, "mtval"), (MIP, "mip"), (TIME, "time"), (MVENDORID, "mvendorid"),
+    (MARCHID, "marchid"), (MIMPID, "mimpid"), (MHARTID, "mhartid"),
+];
+
+pub fn get_csr_name(csr: u16) -> Option<&'static str> {
+    CSR_NAMES.iter().find(|(address, _)| *address == csr).map(|(_, name)| *name)
+}
+
+pub fn find_csr(name: &str) -> Option<u16> {
+    CSR_NAMES.iter().find(|(_, csr_name)| *csr_name == name).map(|(address, _)| *address)
+}
+
+
+pub struct CsrMap {
+    registers: Box<[u64; 4096]>,
+}
+
+impl CsrMap {
+    pub fn new() -> CsrMap {
+        CsrMap {
+            registers: Box::new([0; 4096])
+        }
+    }
+
+    /// CSRs with the top two address bits set are read-only. Writing to
+    /// them is an illegal instruction.
+    pub fn is_read_only(csr: u16) -> bool {
+        (csr >> 10) & 0b11 == 0b11
+    }
+}
+
+impl Default for CsrMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<u16> for CsrMap {
+    type Output = u64;
+    fn index(&self, csr: u16) -> &Self::Output {
+        &self.registers[(csr & 0xFFF) as usize]
+    }
+}
+
+impl IndexMut<u16> for CsrMap {
+    fn index_mut(&mut self, csr: u16) -> &mut Self::Output {
+        &mut self.registers[(csr & 0xFFF) as usize]
+    }
+}
+
+impl Core {
+    /// Whether a CSR instruction at the current privilege level may access `csr`.
+    /// The address encodes the lowest privilege level, and whether it's read-only.
+    /// Counters also need to be enabled in `mcounteren` and `scounteren`, and S-mode
+    /// can't access `satp` while `mstatus.TVM` is set.
+    pub(crate) fn can_access_csr(&self, csr: u16, write: bool) -> bool {
+        let privilege = self.privilege as u16;
+        if privilege < (csr >> 8) & 0b11 || (write && CsrMap::is_read_only(csr)) {
+            return false
+        }
+        if csr == SATP && self.privilege == Privilege::Supervisor && self.csr_registers[MSTATUS] & MSTATUS_TVM != 0 {
+            return false
+        }
+        if (0xC00..0xC20).contains(&csr) {
+            let bit = 1 << (csr - 0xC00);
+            if self.privilege < Privilege::Machine && self.csr_registers[MCOUNTEREN] & bit == 0 {
+                return false
+            }
+            if self.privilege < Privilege::Supervisor && self.csr_registers[SCOUNTEREN] & bit == 0 {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Reads `csr` as a CSR instruction does. The supervisor CSRs `sstatus`, `sie`
+    /// and `sip` are views of the machine ones, and `time` reads the clock of the CLINT.
+    pub(crate) fn read_csr(&self, csr: u16) -> Result<u64, DeviceError> {
+        let mideleg = self.csr_registers[MIDELEG];
+        Ok(match csr {
+            SSTATUS => self.csr_registers[MSTATUS] & SSTATUS_FIELDS,
+            SIE => self.csr_registers[MIE] & mideleg,
+            SIP => self.csr_registers[MIP] & mideleg,
+            MISA => MISA_VALUE,
+            TIME => match &self.clock {
+                Some(clock) => clock.read()
+                    .map_err(|error| DeviceError::InternalDeviceError(Box::new(error)))?,
+                None => self.csr_registers[TIME]
+            },
+            _ => self.csr_registers[csr]
+        })
+    }
+
+    /// Writes `csr` as a CSR instruction does. Fields that aren't implemented or are
+    /// read-only keep their value, and `mstatus.MPP` keeps its value if the new one
+    /// is reserved. `satp` keeps its value if the new mode isn't Bare or Sv39, and
+    /// writing it flushes the TLB.
+    pub(crate) fn write_csr(&mut self, csr: u16, value: u64) {
+        let mideleg = self.csr_registers[MIDELEG];
+        let (csr, fields) = match csr {
+            SSTATUS => (MSTATUS, SSTATUS_FIELDS),
+            SIE => (MIE, mideleg & SUPERVISOR_INTERRUPTS),
+            SIP => (MIP, mideleg & MIP_SSIP),
+            MSTATUS if value & MSTATUS_MPP == 2 << 11 => (MSTATUS, MSTATUS_FIELDS & !MSTATUS_MPP),
+            MSTATUS => (MSTATUS, MSTATUS_FIELDS),
+            MISA => return,
+            MEDELEG => (MEDELEG, DELEGABLE_EXCEPTIONS),
+            MIDELEG => (MIDELEG, SUPERVISOR_INTERRUPTS),
+            MIE => (MIE, SUPERVISOR_INTERRUPTS | MACHINE_INTERRUPTS),
+            MIP => (MIP, SUPERVISOR_INTERRUPTS),
+            SATP if value & SATP_MODE != 0 && value & SATP_MODE != SATP_MODE_SV39 => return,
+            SATP => {
+                self.tlb.flush();
+                (SATP, SATP_MODE | SATP_PPN)
+            }
+            _ => (csr, u64::MAX)
+        };
+        self.csr_registers[csr] = (self.csr_registers[csr] & !fields) | (value & fields);
+    }
+}
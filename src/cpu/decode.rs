@@ -84,6 +84,13 @@ impl InstructionFormat {
                         match imm {
                             0 => Ok(Instruction::ecall),
                             1 => Ok(Instruction::ebreak),
+                            0x102 => Ok(Instruction::sret),
+                            0x302 => Ok(Instruction::mret),
+                            0x105 => Ok(Instruction::wfi),
+                            _ if imm >> 5 == 0b0001001 && rd == XRegister::x0 => Ok(Instruction::sfence_vma{
+                                rs1,
+                                rs2: XRegister::from((imm & 0x1F) as u32)
+                            }),
                             _ => Err(
                                 InstructionDecodeError::UnknownIInstruction{opcode, rd, rs1, imm})
                         }
@@ -338,14 +345,15 @@ impl InstructionFormat {
             InstructionFormat::J => {
                 let rd = XRegister::from(bit_slice!(instruction, 11, 7));
                 let imm = bit_concat!(
-                    sized_bit_extend!(bit_slice!(instruction, 31) as u64, 64),
-                    sized_bit_slice!(instruction as u64, 18, 10),
-                    sized_bit_slice!(instruction as u64, 19),
-                    sized_bit_slice!(instruction as u64, 30, 20)
+                    sized_bit_extend!(bit_slice!(instruction, 31) as u64, 44),
+                    sized_bit_slice!(instruction as u64, 19, 12),
+                    sized_bit_slice!(instruction as u64, 20),
+                    sized_bit_slice!(instruction as u64, 30, 21),
+                    (0, 1)
                 ) as i64;
 
                 match opcode {
-                    1101111 => Ok(Instruction::jal{rd, imm}),
+                    0b1101111 => Ok(Instruction::jal{rd, imm}),
                     _ => Err(InstructionDecodeError::UnknownJInstruction{ opcode, imm })
                 }
             }
@@ -0,0 +1,87 @@
+use crate::bus::{Bus, CODE_PAGE_SIZE};
+use crate::cpu::instruction::Instruction;
+use std::collections::HashMap;
+
+const INSTRUCTIONS_PER_PAGE: usize = CODE_PAGE_SIZE / 4;
+
+type DecodedPage = Box<[Option<Instruction>; INSTRUCTIONS_PER_PAGE]>;
+
+
+/// Decoded instructions, keyed by physical address and grouped by code page.
+///
+/// Pages are registered with the bus, which counts stores to them. Before every lookup
+/// the cache drops pages that were written since they were decoded, so self-modifying
+/// code sees its stores. `fence.i` drops the whole cache.
+#[derive(Debug, Default)]
+pub struct DecodeCache {
+    pages: HashMap<usize, DecodedPage>,
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn get_misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// Drops all decoded instructions.
+    pub fn flush(&mut self) {
+        self.pages.clear();
+    }
+
+    /// Drops the pages written since the last call.
+    pub fn synchronize(&mut self, bus: &Bus) {
+        let generation = bus.get_code_generation();
+        if generation == self.generation {
+            return
+        }
+
+        if bus.get_code_flush_generation() > self.generation {
+            self.pages.clear();
+        } else {
+            let last_generation = self.generation;
+            self.pages.retain(|page, _|
+                bus.get_code_page_generation(page * CODE_PAGE_SIZE) <= last_generation);
+        }
+        self.generation = generation;
+    }
+
+    /// Looks up the instruction at `address`, counting the hit or miss.
+    pub fn get(&mut self, address: usize) -> Option<Instruction> {
+        let instruction = match self.pages.get(&(address / CODE_PAGE_SIZE)) {
+            Some(page) if address.is_multiple_of(4) => page[(address % CODE_PAGE_SIZE) / 4],
+            _ => None
+        };
+
+        match instruction {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1
+        }
+        instruction
+    }
+
+    /// Caches the instruction at `address`. The page holding it must have been
+    /// registered with `Bus::mark_code_page`.
+    pub fn insert(&mut self, address: usize, instruction: Instruction) {
+        if !address.is_multiple_of(4) {
+            return
+        }
+        let page = self.pages.entry(address / CODE_PAGE_SIZE)
+            .or_insert_with(|| Box::new([None; INSTRUCTIONS_PER_PAGE]));
+        page[(address % CODE_PAGE_SIZE) / 4] = Some(instruction);
+    }
+}
@@ -2,15 +2,24 @@ use std::fmt::{Display, Formatter, Debug};
 use std::error::Error;
 
 use crate::cpu::instruction::Instruction;
-use crate::cpu::core::Core;
+use crate::cpu::core::{Core, Privilege};
+use crate::cpu::mmu::AccessType;
+use crate::cpu::csr::{MEPC, MSTATUS, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_SIE,
+                      MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW, SEPC};
+use crate::cpu::trap::Exception;
+use crate::cpu::register::XRegister;
 use crate::device::{Device, DeviceError};
 use crate::endianness::Endianness;
+use crate::utilities::extend_sign;
 
 
 #[derive(Debug)]
 pub enum InstructionExecuteError {
     NotImplemented(Instruction),
+    IllegalInstruction(Instruction),
     DeviceError(DeviceError),
+    /// The instruction raises an exception, like `ecall`.
+    Exception { exception: Exception, tval: u64 },
 }
 
 impl Display for InstructionExecuteError {
@@ -21,6 +30,17 @@ impl Display for InstructionExecuteError {
 
 impl Error for InstructionExecuteError { }
 
+impl InstructionExecuteError {
+    /// The error of a load or store of the virtual `address`. Device errors that are
+    /// guest exceptions are raised with `address` as the trap value.
+    pub(crate) fn from_access(error: DeviceError, address: usize) -> Self {
+        match Exception::from_device_error(&error) {
+            Some(exception) => InstructionExecuteError::Exception { exception, tval: address as u64 },
+            None => InstructionExecuteError::DeviceError(error)
+        }
+    }
+}
+
 impl From<DeviceError> for InstructionExecuteError {
     fn from(error: DeviceError) -> Self {
         InstructionExecuteError::DeviceError(error)
@@ -61,17 +81,18 @@ impl Instruction {
             },
 
             Instruction::sll{rd, rs1, rs2} => {
-                core.x_registers[*rd] = core.x_registers[*rs1] << core.x_registers[*rs2];
+                core.x_registers[*rd] = core.x_registers[*rs1] << (core.x_registers[*rs2] & 0x3F);
                 true
             },
 
             Instruction::srl{rd, rs1, rs2} => {
-                core.x_registers[*rd] = core.x_registers[*rs1] >> core.x_registers[*rs2];
+                core.x_registers[*rd] = core.x_registers[*rs1] >> (core.x_registers[*rs2] & 0x3F);
                 true
             },
 
             Instruction::sra{rd, rs1, rs2} => {
-                core.x_registers[*rd] = core.x_registers[*rs1] >> (core.x_registers[*rs2] as i64);
+                core.x_registers[*rd] =
+                    ((core.x_registers[*rs1] as i64) >> (core.x_registers[*rs2] & 0x3F)) as u64;
                 true
             },
 
@@ -121,7 +142,7 @@ impl Instruction {
             },
 
             Instruction::srai {rd, rs1, shamt} => {
-                core.x_registers[*rd] = core.x_registers[*rs1] >> shamt;
+                core.x_registers[*rd] = ((core.x_registers[*rs1] as i64) >> shamt) as u64;
                 true
             },
 
@@ -137,82 +158,123 @@ impl Instruction {
                 true
             },
 
+            // RV64I word instructions, which operate on the low 32 bits and sign
+            // extend the result
+            Instruction::addiw {rd, rs1, imm} => {
+                core.x_registers[*rd] = sign_extend_word(core.x_registers[*rs1].wrapping_add(*imm as u64));
+                true
+            },
+
+            Instruction::slliw {rd, rs1, shamt} => {
+                core.x_registers[*rd] = sign_extend_word(core.x_registers[*rs1] << shamt);
+                true
+            },
+
+            Instruction::srliw {rd, rs1, shamt} => {
+                core.x_registers[*rd] = sign_extend_word((core.x_registers[*rs1] as u32 >> shamt) as u64);
+                true
+            },
+
+            Instruction::sraiw {rd, rs1, shamt} => {
+                core.x_registers[*rd] = ((core.x_registers[*rs1] as i32) >> shamt) as u64;
+                true
+            },
+
+            Instruction::addw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = sign_extend_word(core.x_registers[*rs1]
+                    .wrapping_add(core.x_registers[*rs2]));
+                true
+            },
+
+            Instruction::subw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = sign_extend_word(core.x_registers[*rs1]
+                    .wrapping_sub(core.x_registers[*rs2]));
+                true
+            },
+
+            Instruction::sllw {rd, rs1, rs2} => {
+                core.x_registers[*rd] =
+                    sign_extend_word(core.x_registers[*rs1] << (core.x_registers[*rs2] & 0x1F));
+                true
+            },
+
+            Instruction::srlw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = sign_extend_word(
+                    (core.x_registers[*rs1] as u32 >> (core.x_registers[*rs2] & 0x1F)) as u64);
+                true
+            },
+
+            Instruction::sraw {rd, rs1, rs2} => {
+                core.x_registers[*rd] =
+                    ((core.x_registers[*rs1] as i32) >> (core.x_registers[*rs2] & 0x1F)) as u64;
+                true
+            },
+
             // Load instructions 32 + 64
             Instruction::lb {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 1, Endianness::LittleEndian, true)?;
+                core.x_registers[*rd] = core.load(address, 1, true)?;
                 true
             }
 
             Instruction::lh {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 2, Endianness::LittleEndian, true)?;
+                core.x_registers[*rd] = core.load(address, 2, true)?;
                 true
             }
 
             Instruction::lw {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 4, Endianness::LittleEndian, true)?;
+                core.x_registers[*rd] = core.load(address, 4, true)?;
                 true
             }
 
             Instruction::ld {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 8, Endianness::LittleEndian, false)?;
+                core.x_registers[*rd] = core.load(address, 8, false)?;
                 true
             }
 
             Instruction::lbu {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 1, Endianness::LittleEndian, false)?;
+                core.x_registers[*rd] = core.load(address, 1, false)?;
                 true
             }
 
             Instruction::lhu {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 2, Endianness::LittleEndian, false)?;
+                core.x_registers[*rd] = core.load(address, 2, false)?;
                 true
             }
 
             Instruction::lwu {rd, rs1, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.x_registers[*rd] = core.bus.borrow()
-                    .read_int(address, 4, Endianness::LittleEndian, false)?;
+                core.x_registers[*rd] = core.load(address, 4, false)?;
                 true
             }
 
             // Store instructions 32 + 64
             Instruction::sb {rs1, rs2, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.bus.borrow_mut()
-                    .write_int(address, core.x_registers[*rs2], 1, Endianness::LittleEndian)?;
+                core.store(address, core.x_registers[*rs2], 1)?;
                 true
             }
 
             Instruction::sh {rs1, rs2, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.bus.borrow_mut()
-                    .write_int(address, core.x_registers[*rs2], 2, Endianness::LittleEndian)?;
+                core.store(address, core.x_registers[*rs2], 2)?;
                 true
             }
 
             Instruction::sw {rs1, rs2, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.bus.borrow_mut()
-                    .write_int(address, core.x_registers[*rs2], 4, Endianness::LittleEndian)?;
+                core.store(address, core.x_registers[*rs2], 4)?;
                 true
             }
 
             Instruction::sd {rs1, rs2, imm} => {
                 let address = (core.x_registers[*rs1] as i64).wrapping_add(*imm) as usize;
-                core.bus.borrow_mut()
-                    .write_int(address, core.x_registers[*rs2], 8, Endianness::LittleEndian)?;
+                core.store(address, core.x_registers[*rs2], 8)?;
                 true
             }
 
@@ -260,9 +322,9 @@ impl Instruction {
             },
 
             Instruction::jalr {rd,rs1, imm} => {
-                let rs1 = core.x_registers[*rs1] as i64;
+                let target = core.x_registers[*rs1].wrapping_add(*imm as u64) & !1;
                 core.x_registers[*rd] = core.pc as u64 + 4;
-                core.add_to_pc(rs1 + *imm);
+                core.pc = target as usize;
                 false
             },
 
@@ -276,8 +338,237 @@ impl Instruction {
                 true
             },
 
+            // Zicsr Standard Extension
+            Instruction::csrrw {rd, rs1, imm} => {
+                let csr = self.check_csr_access(core, *imm, true)?;
+                let value = core.x_registers[*rs1];
+                core.x_registers[*rd] = core.read_csr(csr)?;
+                core.write_csr(csr, value);
+                true
+            },
+
+            Instruction::csrrs {rd, rs1, imm} => {
+                let csr = self.check_csr_access(core, *imm, *rs1 != XRegister::x0)?;
+                let value = core.read_csr(csr)?;
+                if *rs1 != XRegister::x0 {
+                    core.write_csr(csr, value | core.x_registers[*rs1]);
+                }
+                core.x_registers[*rd] = value;
+                true
+            },
+
+            Instruction::csrrc {rd, rs1, imm} => {
+                let csr = self.check_csr_access(core, *imm, *rs1 != XRegister::x0)?;
+                let value = core.read_csr(csr)?;
+                if *rs1 != XRegister::x0 {
+                    core.write_csr(csr, value & !core.x_registers[*rs1]);
+                }
+                core.x_registers[*rd] = value;
+                true
+            },
+
+            Instruction::csrrwi {rd, uimm, imm} => {
+                let csr = self.check_csr_access(core, *imm, true)?;
+                core.x_registers[*rd] = core.read_csr(csr)?;
+                core.write_csr(csr, *uimm);
+                true
+            },
+
+            Instruction::csrrsi {rd, uimm, imm} => {
+                let csr = self.check_csr_access(core, *imm, *uimm != 0)?;
+                let value = core.read_csr(csr)?;
+                if *uimm != 0 {
+                    core.write_csr(csr, value | uimm);
+                }
+                core.x_registers[*rd] = value;
+                true
+            },
+
+            Instruction::csrrci {rd, uimm, imm} => {
+                let csr = self.check_csr_access(core, *imm, *uimm != 0)?;
+                let value = core.read_csr(csr)?;
+                if *uimm != 0 {
+                    core.write_csr(csr, value & !uimm);
+                }
+                core.x_registers[*rd] = value;
+                true
+            },
+
+            // RV32/RV64 Zifencei Standard Extension
+            Instruction::fence_i { .. } => {
+                core.decode_cache.flush();
+                core.block_cache.flush();
+                true
+            },
+
+            Instruction::ecall => {
+                return Err(InstructionExecuteError::Exception {
+                    exception: Exception::environment_call(core.privilege),
+                    tval: 0
+                })
+            },
+
+            Instruction::ebreak => {
+                return Err(InstructionExecuteError::Exception {
+                    exception: Exception::Breakpoint,
+                    tval: core.pc as u64
+                })
+            },
+
+            // RV32M & RV64M Standard Extension
+            Instruction::mul {rd, rs1, rs2} => {
+                core.x_registers[*rd] = core.x_registers[*rs1].wrapping_mul(core.x_registers[*rs2]);
+                true
+            },
+            Instruction::mulh {rd, rs1, rs2} => {
+                core.x_registers[*rd] = mulh(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::mulhsu {rd, rs1, rs2} => {
+                core.x_registers[*rd] = mulhsu(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::mulhu {rd, rs1, rs2} => {
+                core.x_registers[*rd] = mulhu(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::div {rd, rs1, rs2} => {
+                core.x_registers[*rd] = div(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::divu {rd, rs1, rs2} => {
+                core.x_registers[*rd] = divu(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::rem {rd, rs1, rs2} => {
+                core.x_registers[*rd] = rem(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::remu {rd, rs1, rs2} => {
+                core.x_registers[*rd] = remu(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+
+            Instruction::mulw {rd, rs1, rs2} => {
+                core.x_registers[*rd] =
+                    sign_extend_word(core.x_registers[*rs1].wrapping_mul(core.x_registers[*rs2]));
+                true
+            },
+            Instruction::divw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = divw(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::divuw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = divuw(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::remw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = remw(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+            Instruction::remuw {rd, rs1, rs2} => {
+                core.x_registers[*rd] = remuw(core.x_registers[*rs1], core.x_registers[*rs2]);
+                true
+            },
+
+            // RV32A & RV64A Standard Extension
+            Instruction::lr_w {rd, rs1, ..} => { load_reserved(core, *rd, *rs1, 4)?; true },
+            Instruction::lr_d {rd, rs1, ..} => { load_reserved(core, *rd, *rs1, 8)?; true },
+            Instruction::sc_w {rd, rs1, rs2, ..} =>
+                { store_conditional(core, *rd, *rs1, *rs2, 4)?; true },
+            Instruction::sc_d {rd, rs1, rs2, ..} =>
+                { store_conditional(core, *rd, *rs1, *rs2, 8)?; true },
+
+            Instruction::amoswap_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |_, b| b)?; true },
+            Instruction::amoadd_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| a.wrapping_add(b))?; true },
+            Instruction::amoxor_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| a ^ b)?; true },
+            Instruction::amoand_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| a & b)?; true },
+            Instruction::amoor_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| a | b)?; true },
+            Instruction::amomin_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| (a as i64).min(b as i64) as u64)?; true },
+            Instruction::amomax_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| (a as i64).max(b as i64) as u64)?; true },
+            Instruction::amominu_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| a.min(b))?; true },
+            Instruction::amomaxu_w {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 4, |a, b| a.max(b))?; true },
+
+            Instruction::amoswap_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |_, b| b)?; true },
+            Instruction::amoadd_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| a.wrapping_add(b))?; true },
+            Instruction::amoxor_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| a ^ b)?; true },
+            Instruction::amoand_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| a & b)?; true },
+            Instruction::amoor_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| a | b)?; true },
+            Instruction::amomin_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| (a as i64).min(b as i64) as u64)?; true },
+            Instruction::amomax_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| (a as i64).max(b as i64) as u64)?; true },
+            Instruction::amominu_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| a.min(b))?; true },
+            Instruction::amomaxu_d {rd, rs1, rs2, ..} =>
+                { amo(core, *rd, *rs1, *rs2, 8, |a, b| a.max(b))?; true },
+
+            // Privileged instructions
+            Instruction::mret => {
+                if core.privilege != Privilege::Machine {
+                    return Err(InstructionExecuteError::IllegalInstruction(*self))
+                }
+                let mstatus = core.csr_registers[MSTATUS];
+                let privilege = Privilege::from_bits((mstatus & MSTATUS_MPP) >> 11)
+                    .unwrap_or(Privilege::User);
+                let mie = if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+                let mprv = if privilege == Privilege::Machine { mstatus & MSTATUS_MPRV } else { 0 };
+                core.csr_registers[MSTATUS] =
+                    (mstatus & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPRV)) | mie | MSTATUS_MPIE | mprv;
+                core.privilege = privilege;
+                core.pc = core.csr_registers[MEPC] as usize;
+                false
+            },
+
+            Instruction::sret => {
+                let mstatus = core.csr_registers[MSTATUS];
+                if core.privilege == Privilege::User ||
+                        (core.privilege == Privilege::Supervisor && mstatus & MSTATUS_TSR != 0) {
+                    return Err(InstructionExecuteError::IllegalInstruction(*self))
+                }
+                let privilege = if mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+                let sie = if mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+                core.csr_registers[MSTATUS] =
+                    (mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | sie | MSTATUS_SPIE;
+                core.privilege = privilege;
+                core.pc = core.csr_registers[SEPC] as usize;
+                false
+            },
+
+            Instruction::wfi => {
+                if core.privilege == Privilege::User ||
+                        (core.privilege == Privilege::Supervisor && core.csr_registers[MSTATUS] & MSTATUS_TW != 0) {
+                    return Err(InstructionExecuteError::IllegalInstruction(*self))
+                }
+                core.waiting = true;
+                true
+            },
+
+            Instruction::sfence_vma {..} => {
+                if core.privilege == Privilege::User ||
+                        (core.privilege == Privilege::Supervisor && core.csr_registers[MSTATUS] & MSTATUS_TVM != 0) {
+                    return Err(InstructionExecuteError::IllegalInstruction(*self))
+                }
+                core.tlb.flush();
+                true
+            },
+
             // Ignore these instructions for now.
-            Instruction::ecall | Instruction::ebreak | Instruction::fence_tso => { true },
+            Instruction::fence_tso => { true },
             Instruction::fence { rd, rs1, succ, pred, fm } =>
                 { true },
 
@@ -290,4 +581,146 @@ impl Instruction {
 
         Ok(())
     }
+
+    /// Returns the CSR `imm` selects, or an illegal instruction if the hart can't
+    /// access it, or write it if `write` is set.
+    fn check_csr_access(&self, core: &Core, imm: i64, write: bool) -> Result<u16, InstructionExecuteError> {
+        let csr = csr_address(imm);
+        if core.can_access_csr(csr, write) {
+            Ok(csr)
+        } else {
+            Err(InstructionExecuteError::IllegalInstruction(*self))
+        }
+    }
+}
+
+/// The CSR address is decoded as a sign extended I-type immediate.
+fn csr_address(imm: i64) -> u16 {
+    (imm & 0xFFF) as u16
+}
+
+/// Checks that an atomic access is naturally aligned. Unlike other accesses, misaligned
+/// atomics are never emulated.
+fn check_atomic_alignment(address: usize, size: usize, exception: Exception)
+        -> Result<(), InstructionExecuteError> {
+    if address.is_multiple_of(size) {
+        Ok(())
+    } else {
+        Err(InstructionExecuteError::Exception { exception, tval: address as u64 })
+    }
+}
+
+/// Sign extends the low 32 bits of `value`, as the word instructions do with their result.
+pub(crate) fn sign_extend_word(value: u64) -> u64 {
+    value as i32 as u64
+}
+
+// The M extension never traps: division by zero returns all ones as the quotient and
+// the dividend as the remainder, and the signed overflow of the most negative value
+// divided by -1 returns the dividend as the quotient and zero as the remainder.
+
+/// Upper 64 bits of the product of two signed values.
+pub(crate) fn mulh(a: u64, b: u64) -> u64 {
+    ((a as i64 as i128 * b as i64 as i128) >> 64) as u64
+}
+
+/// Upper 64 bits of the product of a signed and an unsigned value.
+pub(crate) fn mulhsu(a: u64, b: u64) -> u64 {
+    ((a as i64 as i128 * b as i128) >> 64) as u64
+}
+
+/// Upper 64 bits of the product of two unsigned values.
+pub(crate) fn mulhu(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) >> 64) as u64
+}
+
+pub(crate) fn div(a: u64, b: u64) -> u64 {
+    if b == 0 { u64::MAX } else { (a as i64).wrapping_div(b as i64) as u64 }
+}
+
+pub(crate) fn divu(a: u64, b: u64) -> u64 {
+    a.checked_div(b).unwrap_or(u64::MAX)
+}
+
+pub(crate) fn rem(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { (a as i64).wrapping_rem(b as i64) as u64 }
+}
+
+pub(crate) fn remu(a: u64, b: u64) -> u64 {
+    a.checked_rem(b).unwrap_or(a)
+}
+
+pub(crate) fn divw(a: u64, b: u64) -> u64 {
+    let (a, b) = (a as i32, b as i32);
+    if b == 0 { u64::MAX } else { a.wrapping_div(b) as u64 }
+}
+
+pub(crate) fn divuw(a: u64, b: u64) -> u64 {
+    sign_extend_word((a as u32).checked_div(b as u32).unwrap_or(u32::MAX) as u64)
+}
+
+pub(crate) fn remw(a: u64, b: u64) -> u64 {
+    let (a, b) = (a as i32, b as i32);
+    if b == 0 { a as u64 } else { a.wrapping_rem(b) as u64 }
+}
+
+pub(crate) fn remuw(a: u64, b: u64) -> u64 {
+    let (a, b) = (a as u32, b as u32);
+    sign_extend_word(a.checked_rem(b).unwrap_or(a) as u64)
+}
+
+/// Loads the value at the address in `rs1` and reserves it for `store_conditional`.
+fn load_reserved(core: &mut Core, rd: XRegister, rs1: XRegister, size: usize)
+        -> Result<(), InstructionExecuteError> {
+    let address = core.x_registers[rs1] as usize;
+    check_atomic_alignment(address, size, Exception::LoadAddressMisaligned)?;
+    let physical = core.translate(address, AccessType::Load)?;
+    let mut bus = core.bus.lock();
+    let value = bus.read_int(physical, size, Endianness::LittleEndian, size == 4)
+        .map_err(|error| InstructionExecuteError::from_access(error, address))?;
+    bus.reserve(core.get_hart_id(), physical);
+    drop(bus);
+    core.reservation = Some(address);
+    core.x_registers[rd] = value;
+    Ok(())
+}
+
+/// Stores `rs2` if the hart holds a reservation for the address in `rs1`, and no
+/// store to the reserved granule broke it since. The reservation is always released.
+fn store_conditional(core: &mut Core, rd: XRegister, rs1: XRegister, rs2: XRegister, size: usize)
+        -> Result<(), InstructionExecuteError> {
+    let address = core.x_registers[rs1] as usize;
+    check_atomic_alignment(address, size, Exception::StoreAddressMisaligned)?;
+    let physical = core.translate(address, AccessType::Store)?;
+    let mut bus = core.bus.lock();
+    let held = bus.take_reservation(core.get_hart_id(), physical);
+    let success = core.reservation.take() == Some(address) && held;
+    if success {
+        bus.write_int(physical, core.x_registers[rs2], size, Endianness::LittleEndian)
+            .map_err(|error| InstructionExecuteError::from_access(error, address))?;
+    }
+    drop(bus);
+    core.x_registers[rd] = !success as u64;
+    Ok(())
+}
+
+/// Atomically replaces the value at the address in `rs1` by `operation(value, rs2)`
+/// and writes the old value to `rd`. Word operands are sign extended.
+fn amo(core: &mut Core, rd: XRegister, rs1: XRegister, rs2: XRegister, size: usize,
+       operation: impl Fn(u64, u64) -> u64) -> Result<(), InstructionExecuteError> {
+    let address = core.x_registers[rs1] as usize;
+    check_atomic_alignment(address, size, Exception::StoreAddressMisaligned)?;
+    let operand = match size {
+        4 => extend_sign(core.x_registers[rs2] & 0xFFFF_FFFF, 32),
+        _ => core.x_registers[rs2]
+    };
+    let physical = core.translate(address, AccessType::Store)?;
+    let mut bus = core.bus.lock();
+    let value = bus.read_int(physical, size, Endianness::LittleEndian, size == 4)
+        .map_err(|error| InstructionExecuteError::from_access(error, address))?;
+    bus.write_int(physical, operation(value, operand), size, Endianness::LittleEndian)
+        .map_err(|error| InstructionExecuteError::from_access(error, address))?;
+    drop(bus);
+    core.x_registers[rd] = value;
+    Ok(())
 }
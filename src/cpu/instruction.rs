@@ -1,4 +1,6 @@
+use crate::cpu::csr::get_csr_name;
 use crate::cpu::register::{XRegister, FRegister};
+use std::fmt::{Display, Formatter};
 
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -124,6 +126,13 @@ pub enum Instruction {
     ecall,
     ebreak,
 
+    // Privileged instructions
+    // I: 1110011
+    sret,
+    mret,
+    wfi,
+    sfence_vma {rs1: XRegister, rs2: XRegister},
+
     // ?: 0001111
     fence {rd: XRegister, rs1: XRegister, succ: u64, pred: u64, fm: u64},
     fence_tso,
@@ -293,3 +302,150 @@ pub enum Instruction {
     fmv_d_x {rd: FRegister, rs1: FRegister},
 
 }
+
+impl Instruction {
+    /// The assembler mnemonic, e.g. `amoadd.w` for `amoadd_w`.
+    pub fn get_mnemonic(&self) -> String {
+        let name = format!("{:?}", self);
+        let name = name.split([' ', '{']).next().unwrap_or_default();
+        match name {
+            // Named differently in the enum.
+            "fcv_tl_s" => "fcvt.l.s".to_string(),
+            "fcv_tlu_s" => "fcvt.lu.s".to_string(),
+            "fcv_ts_l" => "fcvt.s.l".to_string(),
+            "fcv_ts_lu" => "fcvt.s.lu".to_string(),
+            _ => name.replace('_', ".")
+        }
+    }
+
+    /// Address a jump or branch at `pc` goes to, if the target doesn't depend on
+    /// registers.
+    pub fn get_jump_target(&self, pc: usize) -> Option<usize> {
+        match *self {
+            Instruction::jal {imm, ..} | Instruction::beq {imm, ..} | Instruction::bne {imm, ..} |
+            Instruction::blt {imm, ..} | Instruction::bge {imm, ..} | Instruction::bltu {imm, ..} |
+            Instruction::bgeu {imm, ..} => Some((pc as i64).wrapping_add(imm) as usize),
+            _ => None
+        }
+    }
+}
+
+/// Integer register encoded in a field the enum stores as an `FRegister`.
+fn x(register: FRegister) -> XRegister {
+    XRegister::from(register as u32)
+}
+
+fn csr_name(imm: i64) -> String {
+    let csr = (imm & 0xFFF) as u16;
+    get_csr_name(csr).map_or_else(|| format!("{:#x}", csr), str::to_string)
+}
+
+fn fence_set(bits: u64) -> String {
+    "iorw".chars().enumerate()
+        .filter(|(i, _)| bits & (0b1000 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+fn ordering(aq: bool, rl: bool) -> &'static str {
+    match (aq, rl) {
+        (false, false) => "",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        (true, true) => ".aqrl",
+    }
+}
+
+/// Formats the instruction in assembler syntax with ABI register names. Jump and
+/// branch offsets are relative to the instruction.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+        let m = self.get_mnemonic();
+        match *self {
+            add {rd, rs1, rs2} | sub {rd, rs1, rs2} | xor {rd, rs1, rs2} | or {rd, rs1, rs2} |
+            and {rd, rs1, rs2} | sll {rd, rs1, rs2} | srl {rd, rs1, rs2} | sra {rd, rs1, rs2} |
+            slt {rd, rs1, rs2} | sltu {rd, rs1, rs2} | addw {rd, rs1, rs2} | subw {rd, rs1, rs2} |
+            sllw {rd, rs1, rs2} | srlw {rd, rs1, rs2} | sraw {rd, rs1, rs2} | mul {rd, rs1, rs2} |
+            mulh {rd, rs1, rs2} | mulhsu {rd, rs1, rs2} | mulhu {rd, rs1, rs2} | div {rd, rs1, rs2} |
+            divu {rd, rs1, rs2} | rem {rd, rs1, rs2} | remu {rd, rs1, rs2} | mulw {rd, rs1, rs2} |
+            divw {rd, rs1, rs2} | divuw {rd, rs1, rs2} | remw {rd, rs1, rs2} |
+            remuw {rd, rs1, rs2} => write!(f, "{} {}, {}, {}", m, rd, rs1, rs2),
+
+            addi {rd, rs1, imm} | slti {rd, rs1, imm} | addiw {rd, rs1, imm} |
+            slli {rd, rs1, shamt: imm} | srli {rd, rs1, shamt: imm} | srai {rd, rs1, shamt: imm} |
+            slliw {rd, rs1, shamt: imm} | srliw {rd, rs1, shamt: imm} |
+            sraiw {rd, rs1, shamt: imm} => write!(f, "{} {}, {}, {}", m, rd, rs1, imm),
+            xori {rd, rs1, imm} | ori {rd, rs1, imm} | andi {rd, rs1, imm} |
+            sltiu {rd, rs1, imm} => write!(f, "{} {}, {}, {}", m, rd, rs1, imm as i64),
+
+            lb {rd, rs1, imm} | lh {rd, rs1, imm} | lw {rd, rs1, imm} | lbu {rd, rs1, imm} |
+            lhu {rd, rs1, imm} | lwu {rd, rs1, imm} | ld {rd, rs1, imm} |
+            jalr {rd, rs1, imm} => write!(f, "{} {}, {}({})", m, rd, imm, rs1),
+            sb {rs1, rs2, imm} | sh {rs1, rs2, imm} | sw {rs1, rs2, imm} |
+            sd {rs1, rs2, imm} => write!(f, "{} {}, {}({})", m, rs2, imm, rs1),
+
+            beq {rs1, rs2, imm} | bne {rs1, rs2, imm} | blt {rs1, rs2, imm} | bge {rs1, rs2, imm} |
+            bltu {rs1, rs2, imm} | bgeu {rs1, rs2, imm} => write!(f, "{} {}, {}, {}", m, rs1, rs2, imm),
+            jal {rd, imm} => write!(f, "{} {}, {}", m, rd, imm),
+
+            lui {rd, uimm} => write!(f, "{} {}, {:#x}", m, rd, (uimm >> 12) & 0xFFFFF),
+            auipc {rd, imm} => write!(f, "{} {}, {:#x}", m, rd, (imm >> 12) & 0xFFFFF),
+
+            csrrw {rd, rs1, imm} | csrrs {rd, rs1, imm} |
+            csrrc {rd, rs1, imm} => write!(f, "{} {}, {}, {}", m, rd, csr_name(imm), rs1),
+            csrrwi {rd, uimm, imm} | csrrsi {rd, uimm, imm} |
+            csrrci {rd, uimm, imm} => write!(f, "{} {}, {}, {}", m, rd, csr_name(imm), uimm),
+
+            fence {succ, pred, ..} => write!(f, "{} {}, {}", m, fence_set(pred), fence_set(succ)),
+            sfence_vma {rs1, rs2} => write!(f, "{} {}, {}", m, rs1, rs2),
+            ecall | ebreak | sret | mret | wfi | fence_tso | pause | fence_i {..} => f.write_str(&m),
+
+            lr_w {rd, rs1, aq, rl} | lr_d {rd, rs1, aq, rl} =>
+                write!(f, "{}{} {}, ({})", m, ordering(aq, rl), rd, rs1),
+            sc_w {rd, rs1, rs2, aq, rl} | amoswap_w {rd, rs1, rs2, aq, rl} |
+            amoadd_w {rd, rs1, rs2, aq, rl} | amoxor_w {rd, rs1, rs2, aq, rl} |
+            amoand_w {rd, rs1, rs2, aq, rl} | amoor_w {rd, rs1, rs2, aq, rl} |
+            amomin_w {rd, rs1, rs2, aq, rl} | amomax_w {rd, rs1, rs2, aq, rl} |
+            amominu_w {rd, rs1, rs2, aq, rl} | amomaxu_w {rd, rs1, rs2, aq, rl} |
+            sc_d {rd, rs1, rs2, aq, rl} | amoswap_d {rd, rs1, rs2, aq, rl} |
+            amoadd_d {rd, rs1, rs2, aq, rl} | amoxor_d {rd, rs1, rs2, aq, rl} |
+            amoand_d {rd, rs1, rs2, aq, rl} | amoor_d {rd, rs1, rs2, aq, rl} |
+            amomin_d {rd, rs1, rs2, aq, rl} | amomax_d {rd, rs1, rs2, aq, rl} |
+            amominu_d {rd, rs1, rs2, aq, rl} | amomaxu_d {rd, rs1, rs2, aq, rl} =>
+                write!(f, "{}{} {}, {}, ({})", m, ordering(aq, rl), rd, rs2, rs1),
+
+            flw {rd, rs1, imm} | fld {rd, rs1, imm} => write!(f, "{} {}, {}({})", m, rd, imm, x(rs1)),
+            fsw {rs1, rs2, imm} | fsd {rs1, rs2, imm} => write!(f, "{} {}, {}({})", m, rs2, imm, x(rs1)),
+
+            fmadd_s {rd, rs1, rs2, rs3, ..} | fmsub_s {rd, rs1, rs2, rs3, ..} |
+            fnmsub_s {rd, rs1, rs2, rs3, ..} | fnmadd_s {rd, rs1, rs2, rs3, ..} |
+            fmadd_d {rd, rs1, rs2, rs3, ..} | fmsub_d {rd, rs1, rs2, rs3, ..} |
+            fnmsub_d {rd, rs1, rs2, rs3, ..} | fnmadd_d {rd, rs1, rs2, rs3, ..} =>
+                write!(f, "{} {}, {}, {}, {}", m, rd, rs1, rs2, rs3),
+
+            fadd_s {rd, rs1, rs2, ..} | fsub_s {rd, rs1, rs2, ..} | fmul_s {rd, rs1, rs2, ..} |
+            fdiv_s {rd, rs1, rs2, ..} | fsgnj_s {rd, rs1, rs2} | fsgnjn_s {rd, rs1, rs2} |
+            fsgnjx_s {rd, rs1, rs2} | fmin_s {rd, rs1, rs2} | fmax_s {rd, rs1, rs2} |
+            fadd_d {rd, rs1, rs2, ..} | fsub_d {rd, rs1, rs2, ..} | fmul_d {rd, rs1, rs2, ..} |
+            fdiv_d {rd, rs1, rs2, ..} | fsgnj_d {rd, rs1, rs2} | fsgnjn_d {rd, rs1, rs2} |
+            fsgnjx_d {rd, rs1, rs2} | fmin_d {rd, rs1, rs2} |
+            fmax_d {rd, rs1, rs2} => write!(f, "{} {}, {}, {}", m, rd, rs1, rs2),
+            feq_s {rd, rs1, rs2} | flt_s {rd, rs1, rs2} | fle_s {rd, rs1, rs2} |
+            feq_d {rd, rs1, rs2} | flt_d {rd, rs1, rs2} |
+            fle_d {rd, rs1, rs2} => write!(f, "{} {}, {}, {}", m, x(rd), rs1, rs2),
+
+            fsqrt_s {rd, rs1, ..} | fsqrt_d {rd, rs1, ..} | fcvt_s_d {rd, rs1, ..} |
+            fcvt_d_s {rd, rs1, ..} => write!(f, "{} {}, {}", m, rd, rs1),
+            fcvt_w_s {rd, rs1, ..} | fcvt_wu_s {rd, rs1, ..} | fmv_x_w {rd, rs1} |
+            fclass_s {rd, rs1} | fcv_tl_s {rd, rs1, ..} | fcv_tlu_s {rd, rs1, ..} |
+            fclass_d {rd, rs1} | fcvt_w_d {rd, rs1, ..} | fcvt_wu_d {rd, rs1, ..} |
+            fcvt_l_d {rd, rs1, ..} | fcvt_lu_d {rd, rs1, ..} |
+            fmv_x_d {rd, rs1} => write!(f, "{} {}, {}", m, x(rd), rs1),
+            fcvt_s_w {rd, rs1, ..} | fcvt_s_wu {rd, rs1, ..} | fmv_w_x {rd, rs1} |
+            fcv_ts_l {rd, rs1, ..} | fcv_ts_lu {rd, rs1, ..} | fcvt_d_w {rd, rs1, ..} |
+            fcvt_d_wu {rd, rs1, ..} | fcvt_d_l {rd, rs1, ..} | fcvt_d_lu {rd, rs1, ..} |
+            fmv_d_x {rd, rs1} => write!(f, "{} {}, {}", m, rd, x(rs1)),
+        }
+    }
+}
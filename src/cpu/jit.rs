@@ -0,0 +1,494 @@
+use crate::bus::SharedBus;
+use crate::cpu::block;
+use crate::cpu::execute;
+use crate::cpu::instruction::Instruction;
+use crate::cpu::register::{XRegister, XRegisterMap};
+use crate::cpu::execute::InstructionExecuteError;
+use memmap2::{Mmap, MmapMut};
+use std::fmt::{Debug, Formatter};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// x86-64 code generation for hot blocks.
+//
+// Blocks made of RV64I and RV64M arithmetic, loads and stores are compiled, everything
+// else keeps running in the block interpreter. The generated function takes a pointer
+// to the 32 integer registers in `rdi` and a `Context` in `rsi`, which it keeps in the
+// callee-saved `rbx` and `r12`. Loads, stores and divisions call back into the same
+// code as the interpreter, the first two through the bus and its cached memory region.
+// The function returns zero if it ran to the end of the block, and otherwise one more
+// than the index of the load or store that stopped it.
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the jit feature requires an x86-64 host");
+
+/// Number of executions after which a block is compiled.
+const HOT_THRESHOLD: u32 = 16;
+
+/// How blocks are executed once they're hot.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum JitMode {
+    /// Always use the block interpreter.
+    Disabled,
+    /// Run compiled blocks natively.
+    #[default]
+    Enabled,
+    /// Run compiled blocks both natively and in the interpreter, and report the first
+    /// register that differs as `CoreError::JitMismatch`, or the first memory access as
+    /// `CoreError::JitAccessMismatch`. The native code replays the memory accesses of the
+    /// interpreter instead of repeating them.
+    Differential,
+}
+
+/// A load or store made by a block.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemoryAccess {
+    Load { address: usize, size: usize, sign_extend: bool },
+    Store { address: usize, size: usize, value: u64 },
+}
+
+/// A memory access of the block interpreter, the value it loaded and whether the
+/// block was left at it, because it failed or a store must end the block.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TracedAccess {
+    pub access: MemoryAccess,
+    pub value: u64,
+    pub stop: bool,
+}
+
+/// Where the memory accesses of native code go.
+pub struct Context<'a> {
+    memory: Memory<'a>,
+}
+
+enum Memory<'a> {
+    /// Accesses go to the bus. The error of a failed access is kept for the caller.
+    Bus { bus: &'a SharedBus, error: Option<InstructionExecuteError> },
+    /// Accesses are checked against those the interpreter made for the same block
+    /// and get their results, so that they only happen once.
+    Replay { trace: &'a [TracedAccess], position: usize,
+             mismatch: Option<(Option<MemoryAccess>, Option<MemoryAccess>)> },
+}
+
+impl<'a> Context<'a> {
+    pub fn new(bus: &'a SharedBus) -> Self {
+        Self { memory: Memory::Bus { bus, error: None } }
+    }
+
+    pub fn replay(trace: &'a [TracedAccess]) -> Self {
+        Self { memory: Memory::Replay { trace, position: 0, mismatch: None } }
+    }
+
+    /// The error of the access that stopped the block, if it failed.
+    pub fn take_error(&mut self) -> Option<InstructionExecuteError> {
+        match &mut self.memory {
+            Memory::Bus { error, .. } => error.take(),
+            Memory::Replay { .. } => None
+        }
+    }
+
+    /// The first access that differs from the trace being replayed, as the expected
+    /// and the actual access. Either is `None` if the trace or the native code had
+    /// fewer accesses.
+    pub fn take_mismatch(&mut self) -> Option<(Option<MemoryAccess>, Option<MemoryAccess>)> {
+        match &mut self.memory {
+            Memory::Bus { .. } => None,
+            Memory::Replay { trace, position, mismatch } => mismatch.take()
+                .or_else(|| trace.get(*position).map(|expected| (Some(expected.access), None)))
+        }
+    }
+
+    fn access(&mut self, access: MemoryAccess) -> AccessResult {
+        let (value, stop) = match &mut self.memory {
+            Memory::Bus { bus, error } => {
+                let result = match access {
+                    MemoryAccess::Load { address, size, sign_extend } =>
+                        block::read(bus, address, size, sign_extend).map(|value| (value, false)),
+                    MemoryAccess::Store { address, size, value } =>
+                        block::write(bus, address, value, size).map(|leave| (0, leave)),
+                };
+                result.unwrap_or_else(|x| {
+                    *error = Some(x);
+                    (0, true)
+                })
+            },
+            Memory::Replay { trace, position, mismatch } => match trace.get(*position) {
+                Some(expected) if expected.access == access => {
+                    *position += 1;
+                    (expected.value, expected.stop)
+                },
+                expected => {
+                    *mismatch = Some((expected.map(|x| x.access), Some(access)));
+                    (0, true)
+                }
+            }
+        };
+        AccessResult { value, stop: stop as u64 }
+    }
+}
+
+/// Result of a load or store called from native code, returned in `rax` and `rdx`.
+#[repr(C)]
+struct AccessResult {
+    value: u64,
+    stop: u64,
+}
+
+extern "sysv64" fn load(context: &mut Context, address: u64, kind: u64) -> AccessResult {
+    context.access(MemoryAccess::Load {
+        address: address as usize,
+        size: (kind & 0xFF) as usize,
+        sign_extend: kind & 0x100 != 0,
+    })
+}
+
+extern "sysv64" fn store(context: &mut Context, address: u64, value: u64, size: u64) -> AccessResult {
+    context.access(MemoryAccess::Store { address: address as usize, size: size as usize, value })
+}
+
+/// `extern "sysv64"` wrappers of the division helpers of the interpreter.
+macro_rules! native_division {
+    ($($name:ident),*) => {
+        $(pub(super) extern "sysv64" fn $name(a: u64, b: u64) -> u64 {
+            execute::$name(a, b)
+        })*
+    };
+}
+
+mod division {
+    use super::execute;
+    native_division!(div, divu, rem, remu, divw, divuw, remw, remuw);
+}
+
+/// Native code for a block.
+pub struct NativeBlock {
+    // Keeps the code mapped as long as `function` can be called.
+    _code: Mmap,
+    function: extern "sysv64" fn(*mut u64, *mut Context) -> u64,
+}
+
+impl NativeBlock {
+    /// Runs the block. Returns the index of the instruction that stopped it if it
+    /// didn't run to the end, in which case `context` tells whether it failed.
+    pub fn execute(&self, registers: &mut XRegisterMap, context: &mut Context) -> Option<usize> {
+        match (self.function)(registers.as_mut_ptr(), context) {
+            0 => None,
+            x => Some(x as usize - 1)
+        }
+    }
+}
+
+impl Debug for NativeBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NativeBlock {{ size: {:?} }}", self._code.len())
+    }
+}
+
+/// Per-block JIT bookkeeping.
+#[derive(Debug)]
+pub(crate) struct BlockState {
+    start: usize,
+    instructions: Vec<Instruction>,
+    executions: AtomicU32,
+    native: OnceLock<Option<NativeBlock>>,
+}
+
+impl BlockState {
+    pub(crate) fn new(start: usize, instructions: Vec<Instruction>) -> Self {
+        Self {
+            start,
+            instructions,
+            executions: AtomicU32::new(0),
+            native: OnceLock::new(),
+        }
+    }
+
+    /// Counts an execution of the block and returns its native code once it's hot.
+    /// Returns true as the second value if the block was compiled by this call.
+    pub(crate) fn get_native(&self) -> (Option<&NativeBlock>, bool) {
+        if let Some(native) = self.native.get() {
+            return (native.as_ref(), false)
+        }
+
+        let executions = self.executions.fetch_add(1, Ordering::Relaxed) + 1;
+        if executions < HOT_THRESHOLD {
+            return (None, false)
+        }
+
+        let native = self.native.get_or_init(|| compile(self.start, &self.instructions));
+        (native.as_ref(), native.is_some())
+    }
+}
+
+/// Compiles the instructions of a block starting at `start`. Returns `None` if the
+/// block contains an instruction the JIT doesn't handle.
+pub fn compile(start: usize, instructions: &[Instruction]) -> Option<NativeBlock> {
+    if instructions.is_empty() {
+        return None
+    }
+
+    let mut emitter = Emitter { code: Vec::new() };
+    emitter.bytes(&[0x53,                     // push rbx
+                    0x41, 0x54,               // push r12
+                    0x41, 0x55,               // push r13, aligning the stack for calls
+                    0x48, 0x89, 0xFB,         // mov rbx, rdi
+                    0x49, 0x89, 0xF4]);       // mov r12, rsi
+    for (i, instruction) in instructions.iter().enumerate() {
+        emitter.instruction(*instruction, start + i * 4, i)?;
+    }
+    emitter.bytes(&[0x31, 0xC0]);             // xor eax, eax
+    emitter.epilogue();
+
+    let mut code = MmapMut::map_anon(emitter.code.len()).ok()?;
+    code.copy_from_slice(&emitter.code);
+    let code = code.make_exec().ok()?;
+
+    // SAFETY: the mapping holds a complete function following the System V calling
+    // convention, and it stays mapped as long as the `NativeBlock`.
+    let function = unsafe {
+        std::mem::transmute::<*const u8, extern "sysv64" fn(*mut u64, *mut Context) -> u64>(code.as_ptr())
+    };
+    Some(NativeBlock { _code: code, function })
+}
+
+#[derive(Copy, Clone)]
+enum Register {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+}
+
+struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn displacement(source: XRegister) -> [u8; 4] {
+        (source as u32 * 8).to_le_bytes()
+    }
+
+    /// mov reg, [rbx + 8 * source]
+    fn load(&mut self, register: Register, source: XRegister) {
+        if source == XRegister::x0 {
+            // xor reg, reg
+            self.bytes(&[0x31, 0xC0 | (register as u8) << 3 | register as u8]);
+        } else {
+            self.bytes(&[0x48, 0x8B, 0x83 | (register as u8) << 3]);
+            self.bytes(&Self::displacement(source));
+        }
+    }
+
+    /// mov reg, imm64
+    fn load_immediate(&mut self, register: Register, value: u64) {
+        self.bytes(&[0x48, 0xB8 | register as u8]);
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// mov [rbx + 8 * destination], rax
+    fn store(&mut self, destination: XRegister) {
+        if destination != XRegister::x0 {
+            self.bytes(&[0x48, 0x89, 0x83]);
+            self.bytes(&Self::displacement(destination));
+        }
+    }
+
+    fn epilogue(&mut self) {
+        self.bytes(&[0x41, 0x5D,                  // pop r13
+                     0x41, 0x5C,                  // pop r12
+                     0x5B,                        // pop rbx
+                     0xC3]);                      // ret
+    }
+
+    /// Calls `function` with the arguments already in place.
+    fn call(&mut self, function: *const ()) {
+        self.load_immediate(Register::Rax, function as u64);
+        self.bytes(&[0xFF, 0xD0]);                // call rax
+    }
+
+    /// rd = rs1 op rcx, where `operation` operates on rax and rcx.
+    fn binary(&mut self, rd: XRegister, rs1: XRegister, operation: &[u8]) {
+        self.load(Register::Rax, rs1);
+        self.bytes(operation);
+        self.store(rd);
+    }
+
+    fn register_operand(&mut self, rd: XRegister, rs1: XRegister, rs2: XRegister, operation: &[u8]) {
+        self.load(Register::Rcx, rs2);
+        self.binary(rd, rs1, operation);
+    }
+
+    fn immediate_operand(&mut self, rd: XRegister, rs1: XRegister, imm: u64, operation: &[u8]) {
+        self.load_immediate(Register::Rcx, imm);
+        self.binary(rd, rs1, operation);
+    }
+
+    /// rd = function(rs1, rs2)
+    fn call_operand(&mut self, rd: XRegister, rs1: XRegister, rs2: XRegister,
+                    function: extern "sysv64" fn(u64, u64) -> u64) {
+        self.load(Register::Rax, rs1);
+        self.load(Register::Rcx, rs2);
+        self.bytes(&[0x48, 0x89, 0xC7,            // mov rdi, rax
+                     0x48, 0x89, 0xCE]);          // mov rsi, rcx
+        self.call(function as *const ());
+        self.store(rd);
+    }
+
+    /// Leaves the function, returning `index + 1`, if the access just made returned a
+    /// nonzero `stop`.
+    fn check_stop(&mut self, index: usize) {
+        self.bytes(&[0x48, 0x85, 0xD2,            // test rdx, rdx
+                     0x74, 0x0B,                  // jz over the exit
+                     0xB8]);                      // mov eax, index + 1
+        self.bytes(&(index as u32 + 1).to_le_bytes());
+        self.epilogue();
+    }
+
+    /// Puts rs1 + imm in rsi and the context in rdi, for a call to `load` or `store`.
+    fn access_arguments(&mut self, rs1: XRegister, imm: i64) {
+        self.load(Register::Rax, rs1);
+        self.load_immediate(Register::Rcx, imm as u64);
+        self.bytes(&[0x48, 0x01, 0xC8,            // add rax, rcx
+                     0x48, 0x89, 0xC6,            // mov rsi, rax
+                     0x4C, 0x89, 0xE7]);          // mov rdi, r12
+    }
+
+    fn load_operand(&mut self, rd: XRegister, rs1: XRegister, imm: i64, size: u32, sign_extend: bool,
+                    index: usize) {
+        self.access_arguments(rs1, imm);
+        self.bytes(&[0xBA]);                      // mov edx, kind
+        self.bytes(&(size | (sign_extend as u32) << 8).to_le_bytes());
+        self.call(load as *const ());
+        self.check_stop(index);
+        self.store(rd);
+    }
+
+    fn store_operand(&mut self, rs1: XRegister, rs2: XRegister, imm: i64, size: u32, index: usize) {
+        self.access_arguments(rs1, imm);
+        self.load(Register::Rdx, rs2);
+        self.bytes(&[0xB9]);                      // mov ecx, size
+        self.bytes(&size.to_le_bytes());
+        self.call(store as *const ());
+        self.check_stop(index);
+    }
+
+    fn instruction(&mut self, instruction: Instruction, pc: usize, index: usize) -> Option<()> {
+        const ADD: &[u8] = &[0x48, 0x01, 0xC8];           // add rax, rcx
+        const SUB: &[u8] = &[0x48, 0x29, 0xC8];           // sub rax, rcx
+        const XOR: &[u8] = &[0x48, 0x31, 0xC8];           // xor rax, rcx
+        const OR: &[u8] = &[0x48, 0x09, 0xC8];            // or rax, rcx
+        const AND: &[u8] = &[0x48, 0x21, 0xC8];           // and rax, rcx
+        // x86 masks 64-bit shift counts to 6 bits, like RISC-V.
+        const SHL: &[u8] = &[0x48, 0xD3, 0xE0];           // shl rax, cl
+        const SHR: &[u8] = &[0x48, 0xD3, 0xE8];           // shr rax, cl
+        const SAR: &[u8] = &[0x48, 0xD3, 0xF8];           // sar rax, cl
+        const SLT: &[u8] = &[0x48, 0x39, 0xC8,            // cmp rax, rcx
+                             0x0F, 0x9C, 0xC0,            // setl al
+                             0x0F, 0xB6, 0xC0];           // movzx eax, al
+        const SLTU: &[u8] = &[0x48, 0x39, 0xC8,           // cmp rax, rcx
+                              0x0F, 0x92, 0xC0,           // setb al
+                              0x0F, 0xB6, 0xC0];          // movzx eax, al
+        // Word operations work on eax and sign extend it. 32-bit shift counts are
+        // masked to 5 bits, like RISC-V.
+        const ADDW: &[u8] = &[0x01, 0xC8,                 // add eax, ecx
+                              0x48, 0x63, 0xC0];          // movsxd rax, eax
+        const SUBW: &[u8] = &[0x29, 0xC8,                 // sub eax, ecx
+                              0x48, 0x63, 0xC0];          // movsxd rax, eax
+        const SHLW: &[u8] = &[0xD3, 0xE0,                 // shl eax, cl
+                              0x48, 0x63, 0xC0];          // movsxd rax, eax
+        const SHRW: &[u8] = &[0xD3, 0xE8,                 // shr eax, cl
+                              0x48, 0x63, 0xC0];          // movsxd rax, eax
+        const SARW: &[u8] = &[0xD3, 0xF8,                 // sar eax, cl
+                              0x48, 0x63, 0xC0];          // movsxd rax, eax
+        const MUL: &[u8] = &[0x48, 0x0F, 0xAF, 0xC1];     // imul rax, rcx
+        const MULW: &[u8] = &[0x0F, 0xAF, 0xC1,           // imul eax, ecx
+                              0x48, 0x63, 0xC0];          // movsxd rax, eax
+        const MULH: &[u8] = &[0x48, 0xF7, 0xE9,           // imul rcx
+                              0x48, 0x89, 0xD0];          // mov rax, rdx
+        const MULHU: &[u8] = &[0x48, 0xF7, 0xE1,          // mul rcx
+                               0x48, 0x89, 0xD0];         // mov rax, rdx
+        // The unsigned high product, minus rs2 if rs1 is negative.
+        const MULHSU: &[u8] = &[0x48, 0x89, 0xC6,         // mov rsi, rax
+                                0x48, 0xF7, 0xE1,         // mul rcx
+                                0x48, 0xC1, 0xFE, 0x3F,   // sar rsi, 63
+                                0x48, 0x21, 0xCE,         // and rsi, rcx
+                                0x48, 0x29, 0xF2,         // sub rdx, rsi
+                                0x48, 0x89, 0xD0];        // mov rax, rdx
+
+        match instruction {
+            Instruction::add { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, ADD),
+            Instruction::sub { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SUB),
+            Instruction::xor { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, XOR),
+            Instruction::or { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, OR),
+            Instruction::and { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, AND),
+            Instruction::sll { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SHL),
+            Instruction::srl { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SHR),
+            Instruction::sra { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SAR),
+            Instruction::slt { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SLT),
+            Instruction::sltu { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SLTU),
+
+            Instruction::addi { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm as u64, ADD),
+            Instruction::xori { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm, XOR),
+            Instruction::ori { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm, OR),
+            Instruction::andi { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm, AND),
+            Instruction::slli { rd, rs1, shamt } => self.immediate_operand(rd, rs1, shamt as u64, SHL),
+            Instruction::srli { rd, rs1, shamt } => self.immediate_operand(rd, rs1, shamt as u64, SHR),
+            Instruction::srai { rd, rs1, shamt } => self.immediate_operand(rd, rs1, shamt as u64, SAR),
+            Instruction::slti { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm as u64, SLT),
+            Instruction::sltiu { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm, SLTU),
+
+            Instruction::addw { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, ADDW),
+            Instruction::subw { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SUBW),
+            Instruction::sllw { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SHLW),
+            Instruction::srlw { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SHRW),
+            Instruction::sraw { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, SARW),
+            Instruction::addiw { rd, rs1, imm } => self.immediate_operand(rd, rs1, imm as u64, ADDW),
+            Instruction::slliw { rd, rs1, shamt } => self.immediate_operand(rd, rs1, shamt as u64, SHLW),
+            Instruction::srliw { rd, rs1, shamt } => self.immediate_operand(rd, rs1, shamt as u64, SHRW),
+            Instruction::sraiw { rd, rs1, shamt } => self.immediate_operand(rd, rs1, shamt as u64, SARW),
+
+            Instruction::mul { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, MUL),
+            Instruction::mulh { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, MULH),
+            Instruction::mulhsu { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, MULHSU),
+            Instruction::mulhu { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, MULHU),
+            Instruction::mulw { rd, rs1, rs2 } => self.register_operand(rd, rs1, rs2, MULW),
+            // x86 traps on division by zero and overflow, which RISC-V defines results for.
+            Instruction::div { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::div),
+            Instruction::divu { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::divu),
+            Instruction::rem { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::rem),
+            Instruction::remu { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::remu),
+            Instruction::divw { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::divw),
+            Instruction::divuw { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::divuw),
+            Instruction::remw { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::remw),
+            Instruction::remuw { rd, rs1, rs2 } => self.call_operand(rd, rs1, rs2, division::remuw),
+
+            Instruction::lui { rd, uimm } => {
+                self.load_immediate(Register::Rax, uimm);
+                self.store(rd);
+            },
+            Instruction::auipc { rd, imm } => {
+                self.load_immediate(Register::Rax, pc.wrapping_add(imm as usize) as u64);
+                self.store(rd);
+            },
+
+            Instruction::lb { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 1, true, index),
+            Instruction::lh { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 2, true, index),
+            Instruction::lw { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 4, true, index),
+            Instruction::ld { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 8, false, index),
+            Instruction::lbu { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 1, false, index),
+            Instruction::lhu { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 2, false, index),
+            Instruction::lwu { rd, rs1, imm } => self.load_operand(rd, rs1, imm, 4, false, index),
+
+            Instruction::sb { rs1, rs2, imm } => self.store_operand(rs1, rs2, imm, 1, index),
+            Instruction::sh { rs1, rs2, imm } => self.store_operand(rs1, rs2, imm, 2, index),
+            Instruction::sw { rs1, rs2, imm } => self.store_operand(rs1, rs2, imm, 4, index),
+            Instruction::sd { rs1, rs2, imm } => self.store_operand(rs1, rs2, imm, 8, index),
+
+            _ => return None
+        }
+        Some(())
+    }
+}
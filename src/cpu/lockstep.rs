@@ -0,0 +1,264 @@
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::trace::CommitLogOptions;
+use crate::cpu::trap::StopReason;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Steps a hart may take without retiring an instruction, e.g. when an instruction
+/// faults, before the harness reports that it didn't retire.
+const MAX_STEPS_PER_INSTRUCTION: usize = 4;
+
+/// Reference lines shown before and after a divergence by default.
+const DEFAULT_CONTEXT: usize = 3;
+
+#[derive(Debug)]
+pub enum LockstepError {
+    IoError(io::Error),
+    CPUError(CPUError),
+    /// The reference has no commit lines.
+    NoRecords,
+}
+
+/// An instruction retired in a commit log, as printed by `spike --log-commits` or
+/// `CPU::start_commit_log`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitRecord {
+    pub hart: usize,
+    pub privilege: u8,
+    pub pc: u64,
+    pub word: u32,
+    pub effects: Vec<Effect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    /// A register write, e.g. `x5`, `f1` or `c768` for `mstatus`.
+    Write { register: String, value: u64 },
+    Load { address: u64 },
+    Store { address: u64, size: usize, value: u64 },
+}
+
+/// What differs first between the reference and the hart.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mismatch {
+    Pc,
+    Instruction,
+    Privilege,
+    Writeback,
+    MemoryAccess,
+    /// The hart didn't retire an instruction, because it trapped or stopped.
+    NotRetired,
+}
+
+/// The first instruction where the hart differs from the reference.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Instructions of the reference that matched.
+    pub index: usize,
+    /// Line number of the reference, counting from 1.
+    pub line: usize,
+    pub mismatch: Mismatch,
+    pub expected: String,
+    pub actual: Option<String>,
+    /// Why the hart stopped, if it did.
+    pub stop: Option<StopReason>,
+    /// Reference lines before and after the divergence.
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// Runs harts in lockstep with a reference commit log, like one captured with
+/// `spike --log-commits`, and stops at the first instruction where the pc,
+/// instruction, register writes or memory accesses differ. Lines that aren't commit
+/// lines, such as disassembly and program output, are ignored.
+pub struct Lockstep {
+    /// Commit records with their line numbers.
+    records: Vec<(usize, CommitRecord)>,
+    lines: Vec<String>,
+    /// Reference lines shown before and after a divergence.
+    pub context: usize,
+}
+
+/// The record and line of the instruction a hart retired, and why it stopped.
+type Retired = (Option<(CommitRecord, String)>, Option<StopReason>);
+
+/// Commit log output, read back after each step.
+#[derive(Clone, Default)]
+struct Pipe(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CommitRecord {
+    /// Parses a commit line, e.g.
+    /// `core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000`.
+    pub fn parse(line: &str) -> Option<CommitRecord> {
+        let (core, rest) = line.strip_prefix("core")?.split_once(':')?;
+        let mut tokens = rest.split_whitespace().peekable();
+        let privilege = tokens.next()?.parse().ok()?;
+        let pc = parse_hex(tokens.next()?)?;
+        let word = parse_hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)? as u32;
+
+        let mut effects = Vec::new();
+        while let Some(token) = tokens.next() {
+            if token == "mem" {
+                let address = parse_hex(tokens.next()?)?;
+                match tokens.next_if(|token| token.starts_with("0x")) {
+                    Some(value) => effects.push(Effect::Store {
+                        address, size: (value.len() - 2) / 2, value: parse_hex(value)?,
+                    }),
+                    None => effects.push(Effect::Load { address })
+                }
+            } else {
+                // CSRs are compared by number, as `c768` of `c768_mstatus`.
+                let register = token.split('_').next()?.to_string();
+                effects.push(Effect::Write { register, value: parse_hex(tokens.next()?)? });
+            }
+        }
+        Some(CommitRecord { hart: core.trim().parse().ok()?, privilege, pc, word, effects })
+    }
+
+    fn compare(&self, actual: &CommitRecord) -> Option<Mismatch> {
+        let writes = |record: &CommitRecord| record.effects.iter()
+            .filter(|effect| matches!(effect, Effect::Write { .. })).cloned().collect::<Vec<_>>();
+        let accesses = |record: &CommitRecord| record.effects.iter()
+            .filter(|effect| !matches!(effect, Effect::Write { .. })).cloned().collect::<Vec<_>>();
+        if self.pc != actual.pc {
+            Some(Mismatch::Pc)
+        } else if self.word != actual.word {
+            Some(Mismatch::Instruction)
+        } else if self.privilege != actual.privilege {
+            Some(Mismatch::Privilege)
+        } else if writes(self) != writes(actual) {
+            Some(Mismatch::Writeback)
+        } else if accesses(self) != accesses(actual) {
+            Some(Mismatch::MemoryAccess)
+        } else {
+            None
+        }
+    }
+}
+
+impl Lockstep {
+    pub fn parse(text: &str) -> Result<Lockstep, LockstepError> {
+        let lines: Vec<String> = text.lines().map(|line| line.to_string()).collect();
+        let records: Vec<(usize, CommitRecord)> = lines.iter().enumerate()
+            .filter_map(|(i, line)| CommitRecord::parse(line).map(|record| (i, record)))
+            .collect();
+        if records.is_empty() {
+            return Err(LockstepError::NoRecords)
+        }
+        Ok(Lockstep { records, lines, context: DEFAULT_CONTEXT })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Lockstep, LockstepError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Steps the hart of each instruction of the reference in turn, and returns the
+    /// first divergence, if any. The harts log their commits to compare them, which
+    /// replaces a commit log started before.
+    pub fn run(&self, cpu: &mut CPU) -> Result<Option<Divergence>, LockstepError> {
+        let pipe = Pipe::default();
+        cpu.start_commit_log(pipe.clone(), CommitLogOptions::default());
+        let result = self.compare(cpu, &pipe);
+        cpu.stop_commit_log()?;
+        result
+    }
+
+    fn compare(&self, cpu: &mut CPU, pipe: &Pipe) -> Result<Option<Divergence>, LockstepError> {
+        for (index, (line, expected)) in self.records.iter().enumerate() {
+            let (actual, stop) = Self::retire(cpu, expected.hart, pipe)?;
+            let mismatch = match &actual {
+                Some((record, _)) => expected.compare(record),
+                None => Some(Mismatch::NotRetired)
+            };
+            if let Some(mismatch) = mismatch {
+                return Ok(Some(Divergence {
+                    index,
+                    line: line + 1,
+                    mismatch,
+                    expected: self.lines[*line].clone(),
+                    actual: actual.map(|(_, text)| text),
+                    stop,
+                    before: self.lines[line.saturating_sub(self.context)..*line].to_vec(),
+                    after: self.lines.iter().skip(line + 1).take(self.context).cloned().collect(),
+                }))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Steps `hart` until it retires an instruction, and returns its commit line.
+    fn retire(cpu: &mut CPU, hart: usize, pipe: &Pipe) -> Result<Retired, LockstepError> {
+        let core = cpu.harts.get_mut(hart).ok_or(CPUError::InvalidHart { hart })?;
+        for _ in 0..MAX_STEPS_PER_INSTRUCTION {
+            let stop = core.step().map_err(|error| CPUError::CoreError { hart, error })?;
+            if let Some(log) = &core.commit_log {
+                log.flush()?;
+            }
+            let output = String::from_utf8_lossy(&std::mem::take(&mut *pipe.0.lock().unwrap())).into_owned();
+            if let Some(line) = output.lines().find(|line| CommitRecord::parse(line).is_some()) {
+                return Ok((CommitRecord::parse(line).map(|record| (record, line.to_string())), stop))
+            }
+            if stop.is_some() {
+                return Ok((None, stop))
+            }
+        }
+        Ok((None, None))
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:?} mismatch after {} instructions, at line {} of the reference:",
+                 self.mismatch, self.index, self.line)?;
+        for line in &self.before {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "- {}", self.expected)?;
+        match (&self.actual, &self.stop) {
+            (Some(actual), _) => writeln!(f, "+ {}", actual)?,
+            (None, Some(stop)) => writeln!(f, "+ (stopped: {:?})", stop)?,
+            (None, None) => writeln!(f, "+ (no instruction retired)")?
+        }
+        for line in &self.after {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for LockstepError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for LockstepError {}
+
+impl From<io::Error> for LockstepError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<CPUError> for LockstepError {
+    fn from(error: CPUError) -> Self {
+        Self::CPUError(error)
+    }
+}
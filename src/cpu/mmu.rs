@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use crate::cpu::core::{Core, Privilege};
+use crate::cpu::csr::{MSTATUS, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM, SATP, SATP_MODE,
+                      SATP_MODE_SV39, SATP_PPN};
+use crate::cpu::execute::InstructionExecuteError;
+use crate::cpu::trap::Exception;
+use crate::device::Device;
+use crate::endianness::Endianness;
+use crate::utilities::extend_sign;
+
+const PAGE_SIZE: usize = 0x1000;
+const LEVELS: usize = 3;
+const PTE_SIZE: usize = 8;
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+// Page table entry fields
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+/// Bits 54 to 63, which extensions like Svpbmt and Svnapot use, must be zero.
+const PTE_RESERVED: u64 = 0x3FF << 54;
+
+/// Translations the TLB holds before it's flushed, to bound its size.
+const TLB_CAPACITY: usize = 4096;
+
+/// Kind of memory access, which selects the permission a page needs and the exception
+/// a failed translation raises. Atomic memory operations are stores.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(self) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionPageFault,
+            AccessType::Load => Exception::LoadPageFault,
+            AccessType::Store => Exception::StorePageFault
+        }
+    }
+
+    fn access_fault(self) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionAccessFault,
+            AccessType::Load => Exception::LoadAccessFault,
+            AccessType::Store => Exception::StoreAccessFault
+        }
+    }
+}
+
+/// Leaf page table entries of recent translations, keyed by virtual page number, with
+/// the physical address of the page. Permissions are checked on every access, and an
+/// access the cached entry doesn't allow walks the page table again, so only
+/// `sfence.vma` and writes to `satp` need to flush it.
+#[derive(Debug, Default)]
+pub struct Tlb {
+    entries: HashMap<u64, (u64, usize)>,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Core {
+    /// Privilege level the accesses of `access` are translated at, or `None` if they
+    /// aren't translated. Loads and stores use `mstatus.MPP` while `mstatus.MPRV` is set.
+    fn get_translation_privilege(&self, access: AccessType) -> Option<Privilege> {
+        if self.csr_registers[SATP] & SATP_MODE != SATP_MODE_SV39 {
+            return None
+        }
+        let mstatus = self.csr_registers[MSTATUS];
+        let privilege = match access {
+            AccessType::Load | AccessType::Store if mstatus & MSTATUS_MPRV != 0 =>
+                Privilege::from_bits((mstatus & MSTATUS_MPP) >> 11).unwrap_or(Privilege::Machine),
+            _ => self.privilege
+        };
+        (privilege != Privilege::Machine).then_some(privilege)
+    }
+
+    /// Whether fetches, loads or stores are translated. Blocks are only executed while
+    /// they aren't, as they access memory by physical address.
+    pub(crate) fn is_translating(&self) -> bool {
+        self.get_translation_privilege(AccessType::Fetch).is_some() ||
+            self.get_translation_privilege(AccessType::Load).is_some()
+    }
+
+    /// Translates the virtual `address` of an access through the Sv39 page table in
+    /// `satp`, or returns it unchanged in M-mode and while `satp` selects Bare. Raises a
+    /// page fault for invalid entries and missing permissions, and an access fault if
+    /// the page table can't be read.
+    ///
+    /// The A and D bits of entries aren't updated: an access to a page without A, or
+    /// a store to a page without D, raises a page fault so that software sets them.
+    pub(crate) fn translate(&mut self, address: usize, access: AccessType) -> Result<usize, InstructionExecuteError> {
+        let privilege = match self.get_translation_privilege(access) {
+            Some(privilege) => privilege,
+            None => return Ok(address)
+        };
+        let page_fault = InstructionExecuteError::Exception { exception: access.page_fault(), tval: address as u64 };
+        let offset = address & (PAGE_SIZE - 1);
+        let virtual_page = (address >> 12) as u64;
+
+        if let Some(&(pte, page)) = self.tlb.entries.get(&virtual_page) {
+            if self.is_allowed(pte, access, privilege) {
+                return Ok(page | offset)
+            }
+        }
+
+        // The address bits above the 39 translated ones must equal bit 38.
+        if ((address as i64) << 25 >> 25) as usize != address {
+            return Err(page_fault)
+        }
+        let (pte, page) = match self.walk(address, access)? {
+            Some(leaf) => leaf,
+            None => return Err(page_fault)
+        };
+        if !self.is_allowed(pte, access, privilege) {
+            return Err(page_fault)
+        }
+        if self.tlb.entries.len() >= TLB_CAPACITY {
+            self.tlb.flush();
+        }
+        self.tlb.entries.insert(virtual_page, (pte, page));
+        Ok(page | offset)
+    }
+
+    /// Walks the page table for `address`, and returns the leaf entry and the physical
+    /// address of the 4 KiB page `address` is in. Returns `None` for an invalid entry.
+    fn walk(&self, address: usize, access: AccessType) -> Result<Option<(u64, usize)>, InstructionExecuteError> {
+        let mut table = ((self.csr_registers[SATP] & SATP_PPN) as usize) * PAGE_SIZE;
+        for level in (0..LEVELS).rev() {
+            let index = (address >> (12 + 9 * level)) & 0x1FF;
+            let pte = self.bus.lock().read_int(table + index * PTE_SIZE, PTE_SIZE, Endianness::LittleEndian, false)
+                .map_err(|_| InstructionExecuteError::Exception {
+                    exception: access.access_fault(),
+                    tval: address as u64
+                })?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0 {
+                return Ok(None)
+            }
+
+            let ppn = (pte >> 10) & PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = (ppn as usize) * PAGE_SIZE;
+                continue
+            }
+            // Superpages must be aligned to their size.
+            let superpage_mask = (1 << (9 * level)) - 1;
+            if ppn & superpage_mask != 0 {
+                return Ok(None)
+            }
+            let page = ppn | ((address >> 12) as u64 & superpage_mask);
+            return Ok(Some((pte, (page as usize) * PAGE_SIZE)))
+        }
+        Ok(None)
+    }
+
+    /// Whether the leaf entry `pte` allows `access` at `privilege`. S-mode can't
+    /// execute user pages, and only accesses their data while `mstatus.SUM` is set.
+    fn is_allowed(&self, pte: u64, access: AccessType, privilege: Privilege) -> bool {
+        let mstatus = self.csr_registers[MSTATUS];
+        let permitted = match access {
+            AccessType::Fetch => pte & PTE_X != 0,
+            AccessType::Load => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+            AccessType::Store => pte & PTE_W != 0
+        };
+        let user_page = pte & PTE_U != 0;
+        let privileged = match privilege {
+            Privilege::User => user_page,
+            _ => !user_page || (access != AccessType::Fetch && mstatus & MSTATUS_SUM != 0)
+        };
+        let updated = pte & PTE_A != 0 && (access != AccessType::Store || pte & PTE_D != 0);
+        permitted && privileged && updated
+    }
+
+    /// Loads `size` bytes at the virtual `address`. An access crossing into another
+    /// page is split into bytes, which are translated one by one.
+    pub(crate) fn load(&mut self, address: usize, size: usize, sign_extend: bool) -> Result<u64, InstructionExecuteError> {
+        if !self.crosses_translated_page(address, size, AccessType::Load) {
+            let physical = self.translate(address, AccessType::Load)?;
+            return self.bus.lock().read_int(physical, size, Endianness::LittleEndian, sign_extend)
+                .map_err(|error| InstructionExecuteError::from_access(error, address))
+        }
+
+        let mut value = 0;
+        for i in 0..size {
+            let byte = address.wrapping_add(i);
+            let physical = self.translate(byte, AccessType::Load)?;
+            value |= self.bus.lock().read_int(physical, 1, Endianness::LittleEndian, false)
+                .map_err(|error| InstructionExecuteError::from_access(error, byte))? << (8 * i);
+        }
+        Ok(if sign_extend && size < 8 { extend_sign(value, size * 8) } else { value })
+    }
+
+    /// Stores the low `size` bytes of `value` at the virtual `address`. Both pages of
+    /// an access crossing into another page are translated before any byte is written.
+    pub(crate) fn store(&mut self, address: usize, value: u64, size: usize) -> Result<(), InstructionExecuteError> {
+        if !self.crosses_translated_page(address, size, AccessType::Store) {
+            let physical = self.translate(address, AccessType::Store)?;
+            return self.bus.lock().write_int(physical, value, size, Endianness::LittleEndian)
+                .map_err(|error| InstructionExecuteError::from_access(error, address))
+        }
+
+        let boundary = (address | (PAGE_SIZE - 1)).wrapping_add(1);
+        let first = self.translate(address, AccessType::Store)?;
+        let second = self.translate(boundary, AccessType::Store)?;
+        let mut bus = self.bus.lock();
+        for i in 0..size {
+            let byte = address.wrapping_add(i);
+            let physical = if byte < boundary { first + i } else { second + (byte - boundary) };
+            bus.write_int(physical, (value >> (8 * i)) & 0xFF, 1, Endianness::LittleEndian)
+                .map_err(|error| InstructionExecuteError::from_access(error, byte))?;
+        }
+        Ok(())
+    }
+
+    fn crosses_translated_page(&self, address: usize, size: usize, access: AccessType) -> bool {
+        (address & (PAGE_SIZE - 1)) + size > PAGE_SIZE && self.get_translation_privilege(access).is_some()
+    }
+}
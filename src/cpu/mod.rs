@@ -1,6 +1,16 @@
 pub mod instruction;
 pub mod core;
 pub mod decode;
+pub mod decode_cache;
+pub mod block;
+#[cfg(feature = "jit")]
+pub mod jit;
 pub mod execute;
 pub mod register;
-mod cpu;
+pub mod csr;
+pub mod trap;
+pub mod mmu;
+pub mod cpu;
+pub mod replay;
+pub mod trace;
+pub mod lockstep;
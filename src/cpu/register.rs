@@ -1,6 +1,7 @@
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use enum_map::EnumMap;
+use std::fmt::{Display, Formatter};
 use std::ops::{Index, IndexMut};
 
 // RISK-V registers.
@@ -116,7 +117,62 @@ impl From<u64> for FRegister {
     }
 }
 
+const X_ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
 
+const F_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+impl XRegister {
+    pub fn get_abi_name(&self) -> &'static str {
+        X_ABI_NAMES[*self as usize]
+    }
+
+    /// Parses an ABI name like `a0`, `fp`, or an architectural name like `x10`.
+    pub fn from_name(name: &str) -> Option<XRegister> {
+        if name == "fp" {
+            return Some(XRegister::x8)
+        }
+        let number = X_ABI_NAMES.iter().position(|abi_name| *abi_name == name)
+            .or_else(|| name.strip_prefix('x')?.parse().ok())?;
+        FromPrimitive::from_usize(number)
+    }
+}
+
+impl FRegister {
+    pub fn get_abi_name(&self) -> &'static str {
+        F_ABI_NAMES[*self as usize]
+    }
+
+    /// Parses an ABI name like `fa0`, or an architectural name like `f10`.
+    pub fn from_name(name: &str) -> Option<FRegister> {
+        let number = F_ABI_NAMES.iter().position(|abi_name| *abi_name == name)
+            .or_else(|| name.strip_prefix('f')?.parse().ok())?;
+        FromPrimitive::from_usize(number)
+    }
+}
+
+/// Formats the ABI name, e.g. `a0` for `x10`.
+impl Display for XRegister {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.get_abi_name())
+    }
+}
+
+impl Display for FRegister {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.get_abi_name())
+    }
+}
+
+
+#[derive(Clone)]
 pub struct XRegisterMap {
     registers: EnumMap<XRegister, u64>,
 }
@@ -125,6 +181,12 @@ pub struct FRegisterMap {
     registers: EnumMap<FRegister, f64>,
 }
 
+impl Default for XRegisterMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl XRegisterMap {
     pub fn new() -> XRegisterMap {
         XRegisterMap {
@@ -140,6 +202,18 @@ impl XRegisterMap {
             },
         }
     }
+
+    /// Pointer to the register values, indexed by register number. The value of
+    /// `x0` in memory is not guaranteed to be zero.
+    pub fn as_mut_ptr(&mut self) -> *mut u64 {
+        self.registers.as_mut_slice().as_mut_ptr()
+    }
+}
+
+impl Default for FRegisterMap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FRegisterMap {
@@ -0,0 +1,604 @@
+use crate::clint::CLINT;
+use crate::cpu::cpu::{CPU, CPUError, TIMEBASE_FREQUENCY};
+use crate::cpu::register::XRegister;
+use crate::cpu::trap::DEVICE_INTERRUPTS;
+use crate::device::Device;
+use crate::plic::PLIC;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::uart::UART;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+const RECORDING_MAGIC: &[u8; 8] = b"YARVEREC";
+
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000_000;
+
+const EVENT_UART_RECEIVE: u8 = 0;
+const EVENT_INTERRUPT: u8 = 1;
+
+
+/// Where nondeterministic inputs come from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputMode {
+    /// From the host, without logging them.
+    Live,
+    /// From the host, logging them.
+    Recording,
+    /// From the log. Input from the host is held back until the end of the log.
+    Replaying,
+}
+
+/// An input delivered by `CPU::run` before `hart` executed its instruction number
+/// `step`, as counted by `Core::get_steps`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputEvent {
+    /// A byte arrived in the UART receive FIFO.
+    UartReceive { hart: usize, step: u64, byte: u8 },
+    /// The interrupt line of the hart changed to `pending`, a mask of `mip` bits.
+    Interrupt { hart: usize, step: u64, pending: u64 },
+}
+
+/// The inputs of a recorded run, and the machine state it started from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    /// Snapshot taken when recording started.
+    pub snapshot: Vec<u8>,
+    pub events: Vec<InputEvent>,
+    /// Values of `mtime` read by the guest, in order.
+    pub timer_reads: Vec<u64>,
+    /// Position, as returned by `CPU::get_position`, where the recording ends.
+    pub end: u64,
+}
+
+/// Location watched by `CPU::reverse_continue`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Watch {
+    Register { hart: usize, register: XRegister },
+    /// Memory read through the bus, so it shouldn't be a device with read side effects.
+    Memory { address: usize, size: usize },
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The guest read the timer more often than during the recording.
+    MissingTimerRead,
+}
+
+/// Machine timer shared by the CLINT and the CPU. Counts ticks of the timebase
+/// frequency since it was created.
+///
+/// Reads by the guest go through `read`, which logs them while recording and returns
+/// them again while replaying.
+#[derive(Debug, Clone)]
+pub struct Clock(Arc<Mutex<ClockState>>);
+
+#[derive(Debug)]
+struct ClockState {
+    mode: InputMode,
+    start: Instant,
+    base: u64,
+    reads: Vec<u64>,
+    position: usize,
+}
+
+/// State kept by the CPU to record, replay and reverse execution.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    mode: InputMode,
+    events: Vec<InputEvent>,
+    /// Next event to replay, or the number of events while recording.
+    cursor: usize,
+    /// Furthest position recorded, where replaying switches back to recording.
+    end: u64,
+    checkpoints: Vec<Checkpoint>,
+    checkpoint_interval: u64,
+    uart_input: VecDeque<u8>,
+}
+
+#[derive(Debug)]
+struct Checkpoint {
+    position: u64,
+    snapshot: Vec<u8>,
+    cursor: usize,
+    timer_position: usize,
+}
+
+impl Recording {
+    pub fn save(&self, writer: &mut impl Write) -> Result<(), SnapshotError> {
+        let mut writer = SnapshotWriter::new(writer);
+        writer.write_magic(RECORDING_MAGIC)?;
+        writer.write_bytes(&self.snapshot)?;
+        writer.write_u64(self.events.len() as u64)?;
+        for event in &self.events {
+            let (tag, hart, step, value) = match *event {
+                InputEvent::UartReceive { hart, step, byte } =>
+                    (EVENT_UART_RECEIVE, hart, step, byte as u64),
+                InputEvent::Interrupt { hart, step, pending } =>
+                    (EVENT_INTERRUPT, hart, step, pending),
+            };
+            writer.write_u8(tag)?;
+            writer.write_u64(hart as u64)?;
+            writer.write_u64(step)?;
+            writer.write_u64(value)?;
+        }
+        writer.write_u64(self.timer_reads.len() as u64)?;
+        for value in &self.timer_reads {
+            writer.write_u64(*value)?;
+        }
+        writer.write_u64(self.end)
+    }
+
+    pub fn load(reader: &mut impl Read) -> Result<Self, SnapshotError> {
+        let mut reader = SnapshotReader::new(reader);
+        reader.read_magic(RECORDING_MAGIC)?;
+        let snapshot = reader.read_bytes(usize::MAX, "snapshot")?;
+        let mut events = Vec::new();
+        for _ in 0..reader.read_u64()? {
+            let tag = reader.read_u8()?;
+            let hart = reader.read_u64()? as usize;
+            let step = reader.read_u64()?;
+            let value = reader.read_u64()?;
+            events.push(match tag {
+                EVENT_UART_RECEIVE => InputEvent::UartReceive { hart, step, byte: value as u8 },
+                EVENT_INTERRUPT => InputEvent::Interrupt { hart, step, pending: value },
+                _ => return Err(SnapshotError::InvalidState { name: "event".to_string() })
+            });
+        }
+        let mut timer_reads = Vec::new();
+        for _ in 0..reader.read_u64()? {
+            timer_reads.push(reader.read_u64()?);
+        }
+        let end = reader.read_u64()?;
+        Ok(Self { snapshot, events, timer_reads, end })
+    }
+}
+
+impl InputEvent {
+    fn get_position(&self) -> (usize, u64) {
+        match *self {
+            InputEvent::UartReceive { hart, step, .. } |
+            InputEvent::Interrupt { hart, step, .. } => (hart, step)
+        }
+    }
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ClockState {
+            mode: InputMode::Live,
+            start: Instant::now(),
+            base: 0,
+            reads: Vec::new(),
+            position: 0,
+        })))
+    }
+
+    /// Current time, without logging it. Used for the timer interrupt, whose arrival
+    /// is recorded by the CPU instead.
+    pub fn now(&self) -> u64 {
+        self.lock().now()
+    }
+
+    /// The time as seen by the guest.
+    pub fn read(&self) -> Result<u64, ReplayError> {
+        let mut state = self.lock();
+        match state.mode {
+            InputMode::Live => Ok(state.now()),
+            InputMode::Recording => {
+                let value = state.now();
+                state.reads.push(value);
+                state.position += 1;
+                Ok(value)
+            },
+            InputMode::Replaying => {
+                let value = *state.reads.get(state.position).ok_or(ReplayError::MissingTimerRead)?;
+                state.position += 1;
+                Ok(value)
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ClockState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Switches to `mode`. Reads past the current position are dropped when recording
+    /// resumes, and the time continues from the last value read so it never goes back.
+    fn set_mode(&self, mode: InputMode) {
+        let mut state = self.lock();
+        if mode != InputMode::Replaying && state.mode == InputMode::Replaying {
+            let position = state.position;
+            state.reads.truncate(position);
+            let last = state.reads.last().copied().unwrap_or(0);
+            state.base = state.now().max(last);
+            state.start = Instant::now();
+        }
+        if mode == InputMode::Live {
+            state.reads.clear();
+            state.position = 0;
+        }
+        state.mode = mode;
+    }
+
+    fn get_position(&self) -> usize {
+        self.lock().position
+    }
+
+    fn set_position(&self, position: usize) {
+        self.lock().position = position;
+    }
+
+    fn get_reads(&self) -> Vec<u64> {
+        self.lock().reads.clone()
+    }
+
+    fn set_reads(&self, reads: Vec<u64>) {
+        let mut state = self.lock();
+        state.reads = reads;
+        state.position = 0;
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockState {
+    fn now(&self) -> u64 {
+        let elapsed = self.start.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000;
+        self.base + elapsed as u64
+    }
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            mode: InputMode::Live,
+            events: Vec::new(),
+            cursor: 0,
+            end: 0,
+            checkpoints: Vec::new(),
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            uart_input: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn get_mode(&self) -> InputMode {
+        self.mode
+    }
+
+    /// Position at which the next checkpoint is due, if it's ahead of the last one.
+    fn next_checkpoint(&self) -> Option<u64> {
+        self.checkpoints.last().map(|checkpoint| checkpoint.position + self.checkpoint_interval)
+    }
+}
+
+impl CPU {
+    /// Sum of `Core::get_steps` over all harts. As `run` interleaves the harts
+    /// deterministically, it identifies a point in the execution of the machine.
+    pub fn get_position(&self) -> u64 {
+        self.harts.iter().map(|core| core.get_steps()).sum()
+    }
+
+    pub fn get_input_mode(&self) -> InputMode {
+        self.recorder.mode
+    }
+
+    /// Queues input for the first UART. Bytes are moved to its receive FIFO at the
+    /// start of a quantum when there's room, so recordings can replay them at the
+    /// same point.
+    pub fn receive_uart_input(&mut self, bytes: &[u8]) {
+        self.recorder.uart_input.extend(bytes);
+    }
+
+    /// Number of instructions between the checkpoints taken while recording or
+    /// replaying. Stepping back restores the last checkpoint and replays from there.
+    pub fn set_checkpoint_interval(&mut self, interval: u64) {
+        self.recorder.checkpoint_interval = interval.max(1);
+    }
+
+    /// Starts logging nondeterministic inputs, and enables reverse execution. Takes a
+    /// checkpoint, so all devices have to support snapshots.
+    ///
+    /// Changes made by the host while recording aren't logged, except for input
+    /// passed to `receive_uart_input`.
+    pub fn start_recording(&mut self) -> Result<(), CPUError> {
+        self.recorder = Recorder {
+            mode: InputMode::Recording,
+            checkpoint_interval: self.recorder.checkpoint_interval,
+            uart_input: std::mem::take(&mut self.recorder.uart_input),
+            ..Recorder::new()
+        };
+        self.clock.set_reads(Vec::new());
+        self.clock.set_mode(InputMode::Recording);
+        self.add_checkpoint()
+    }
+
+    /// Stops recording or replaying, and returns everything recorded so far. The
+    /// machine keeps its current state.
+    pub fn stop_recording(&mut self) -> Result<Recording, CPUError> {
+        if self.recorder.mode == InputMode::Live {
+            return Err(CPUError::NotRecording)
+        }
+        let end = match self.recorder.mode {
+            InputMode::Recording => self.get_position(),
+            _ => self.recorder.end.max(self.get_position())
+        };
+        let recording = Recording {
+            snapshot: std::mem::take(&mut self.recorder.checkpoints[0].snapshot),
+            events: std::mem::take(&mut self.recorder.events),
+            timer_reads: self.clock.get_reads(),
+            end
+        };
+        self.recorder = Recorder {
+            checkpoint_interval: self.recorder.checkpoint_interval,
+            uart_input: std::mem::take(&mut self.recorder.uart_input),
+            ..Recorder::new()
+        };
+        self.clock.set_mode(InputMode::Live);
+        Ok(recording)
+    }
+
+    /// Restores the state `recording` started from and replays its inputs. Once
+    /// execution reaches its end, the machine goes on recording.
+    pub fn start_replay(&mut self, recording: Recording) -> Result<(), CPUError> {
+        self.restore_snapshot(&mut recording.snapshot.as_slice())?;
+        self.recorder = Recorder {
+            mode: InputMode::Replaying,
+            events: recording.events,
+            end: recording.end,
+            checkpoints: vec![Checkpoint {
+                position: self.get_position(),
+                snapshot: recording.snapshot,
+                cursor: 0,
+                timer_position: 0
+            }],
+            checkpoint_interval: self.recorder.checkpoint_interval,
+            uart_input: std::mem::take(&mut self.recorder.uart_input),
+            ..Recorder::new()
+        };
+        self.clock.set_reads(recording.timer_reads);
+        self.clock.set_mode(InputMode::Replaying);
+        Ok(())
+    }
+
+    /// Goes back one instruction. Returns false if execution is already at the start
+    /// of the recording.
+    pub fn reverse_step(&mut self) -> Result<bool, CPUError> {
+        let start = self.get_history_start()?;
+        let position = self.get_position();
+        if position <= start {
+            return Ok(false)
+        }
+        self.seek(position - 1)?;
+        Ok(true)
+    }
+
+    /// Goes back to the last instruction that changed `watch`, and stops before it.
+    /// Returns false, at the start of the recording, if there's no such instruction.
+    pub fn reverse_continue(&mut self, watch: Watch) -> Result<bool, CPUError> {
+        let start = self.get_history_start()?;
+        let mut window_end = self.get_position();
+
+        // Search the intervals between checkpoints, from the latest to the earliest.
+        while window_end > start {
+            let window_start = self.recorder.checkpoints.iter().rev()
+                .map(|checkpoint| checkpoint.position)
+                .find(|position| *position < window_end)
+                .unwrap_or(start);
+            self.seek(window_start)?;
+
+            let mut value = self.read_watch(watch)?;
+            let mut last_change = None;
+            while self.get_position() < window_end {
+                self.run_to(self.get_position() + 1)?;
+                let new_value = self.read_watch(watch)?;
+                if new_value != value {
+                    last_change = Some(self.get_position());
+                    value = new_value;
+                }
+            }
+
+            if let Some(position) = last_change {
+                self.seek(position - 1)?;
+                return Ok(true)
+            }
+            window_end = window_start;
+        }
+
+        self.seek(start)?;
+        Ok(false)
+    }
+
+    /// Records or replays inputs for `hart` before `run` executes it, and takes a
+    /// checkpoint if one is due. `turn_start` is true at the start of its quantum.
+    /// Returns how many instructions can be executed until this has to be called again.
+    pub(crate) fn deliver_inputs(&mut self, hart: usize, turn_start: bool) -> Result<u64, CPUError> {
+        if self.recorder.mode == InputMode::Replaying && self.get_position() >= self.recorder.end {
+            self.recorder.events.truncate(self.recorder.cursor);
+            self.recorder.mode = InputMode::Recording;
+            self.clock.set_mode(InputMode::Recording);
+        }
+        if self.recorder.mode != InputMode::Live &&
+                self.recorder.next_checkpoint().is_some_and(|x| self.get_position() >= x) {
+            self.add_checkpoint()?;
+        }
+
+        let step = self.harts[hart].get_steps();
+        let mut limit = u64::MAX;
+        if self.recorder.mode == InputMode::Replaying {
+            while let Some(event) = self.recorder.events.get(self.recorder.cursor).copied() {
+                match event.get_position() {
+                    (event_hart, event_step) if event_hart == hart && event_step == step =>
+                        self.apply_input(event),
+                    (event_hart, event_step) if event_hart == hart && event_step < step =>
+                        return Err(CPUError::ReplayDiverged { hart, step: event_step }),
+                    (event_hart, event_step) => {
+                        if event_hart == hart {
+                            limit = event_step - step;
+                        }
+                        break
+                    }
+                }
+                self.recorder.cursor += 1;
+            }
+            limit = limit.min(self.recorder.end - self.get_position());
+        } else if turn_start {
+            self.poll_inputs(hart);
+        }
+
+        if let Some(next_checkpoint) = self.recorder.next_checkpoint() {
+            if self.recorder.mode != InputMode::Live && next_checkpoint > self.get_position() {
+                limit = limit.min(next_checkpoint - self.get_position());
+            }
+        }
+        Ok(limit)
+    }
+
+    /// Delivers queued UART input and updates the interrupt line of `hart`, logging
+    /// both while recording.
+    fn poll_inputs(&mut self, hart: usize) {
+        let step = self.harts[hart].get_steps();
+        let mut events = Vec::new();
+        let mut bus = self.bus.lock();
+
+        if !self.recorder.uart_input.is_empty() {
+            if let Some(address_range) = bus.find_devices::<UART>().first() {
+                if let Some((_, device)) = bus.get_device_mut(address_range.start) {
+                    if let Some(uart) = device.as_any_mut().downcast_mut::<UART>() {
+                        while let Some(byte) = self.recorder.uart_input.front().copied() {
+                            if !uart.receive(byte) {
+                                break
+                            }
+                            self.recorder.uart_input.pop_front();
+                            events.push(InputEvent::UartReceive { hart, step, byte });
+                        }
+                    }
+                }
+            }
+        }
+
+        let clint_pending = self.clint_address
+            .and_then(|address| bus.get_device(address))
+            .and_then(|(_, device)| device.as_any().downcast_ref::<CLINT>())
+            .map_or(0, |clint| clint.get_pending(hart));
+        let plic_pending = self.plic_address
+            .and_then(|address| bus.get_device(address))
+            .and_then(|(_, device)| device.as_any().downcast_ref::<PLIC>())
+            .map_or(0, |plic| plic.get_pending(hart));
+        let pending = clint_pending | plic_pending;
+        drop(bus);
+        if let Some(line) = &self.harts[hart].interrupt_line {
+            if line.load(Ordering::Relaxed) & DEVICE_INTERRUPTS != pending {
+                line.store(pending, Ordering::Relaxed);
+                events.push(InputEvent::Interrupt { hart, step, pending });
+            }
+        }
+
+        if self.recorder.mode == InputMode::Recording {
+            self.recorder.cursor += events.len();
+            self.recorder.events.extend(events);
+        }
+    }
+
+    fn apply_input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::UartReceive { byte, .. } => {
+                let mut bus = self.bus.lock();
+                if let Some(address_range) = bus.find_devices::<UART>().first() {
+                    if let Some((_, device)) = bus.get_device_mut(address_range.start) {
+                        if let Some(uart) = device.as_any_mut().downcast_mut::<UART>() {
+                            uart.receive(byte);
+                        }
+                    }
+                }
+            },
+            InputEvent::Interrupt { hart, pending, .. } => {
+                if let Some(line) = self.harts.get(hart).and_then(|core| core.interrupt_line.as_ref()) {
+                    line.store(pending & DEVICE_INTERRUPTS, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn add_checkpoint(&mut self) -> Result<(), CPUError> {
+        let mut snapshot = Vec::new();
+        self.save_snapshot(&mut snapshot)?;
+        self.recorder.checkpoints.push(Checkpoint {
+            position: self.get_position(),
+            snapshot,
+            cursor: self.recorder.cursor,
+            timer_position: self.clock.get_position()
+        });
+        Ok(())
+    }
+
+    fn get_history_start(&self) -> Result<u64, CPUError> {
+        match self.recorder.checkpoints.first() {
+            Some(checkpoint) if self.recorder.mode != InputMode::Live => Ok(checkpoint.position),
+            _ => Err(CPUError::NotRecording)
+        }
+    }
+
+    /// Restores the last checkpoint at or before `target`, and replays up to it.
+    fn seek(&mut self, target: u64) -> Result<(), CPUError> {
+        if self.recorder.mode == InputMode::Recording {
+            self.recorder.end = self.get_position();
+        }
+        let checkpoint = match self.recorder.checkpoints.iter().rev()
+                .find(|checkpoint| checkpoint.position <= target) {
+            Some(x) => x,
+            None => return Err(CPUError::NotRecording)
+        };
+        let (cursor, timer_position) = (checkpoint.cursor, checkpoint.timer_position);
+        let snapshot = checkpoint.snapshot.clone();
+        self.restore_snapshot(&mut snapshot.as_slice())?;
+
+        self.recorder.mode = InputMode::Replaying;
+        self.recorder.cursor = cursor;
+        self.clock.set_mode(InputMode::Replaying);
+        self.clock.set_position(timer_position);
+        self.run_to(target)
+    }
+
+    /// Runs until the position reaches `target`, through breakpoints and stops.
+    fn run_to(&mut self, target: u64) -> Result<(), CPUError> {
+        loop {
+            let position = self.get_position();
+            if position >= target {
+                return Ok(())
+            }
+            let (hart, _) = self.run(target - position)?;
+            if self.get_position() == position {
+                let step = self.harts[hart].get_steps();
+                return Err(CPUError::ReplayDiverged { hart, step })
+            }
+        }
+    }
+
+    fn read_watch(&self, watch: Watch) -> Result<Vec<u8>, CPUError> {
+        match watch {
+            Watch::Register { hart, register } => match self.harts.get(hart) {
+                Some(core) => Ok(core.x_registers[register].to_le_bytes().to_vec()),
+                None => Err(CPUError::InvalidHart { hart })
+            },
+            Watch::Memory { address, size } =>
+                Ok(self.bus.lock().read_bytes(address, size)?.into_owned())
+        }
+    }
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ReplayError {}
@@ -0,0 +1,299 @@
+use crate::cpu::core::{Core, CoreError, Privilege};
+use crate::cpu::cpu::CPU;
+use crate::cpu::csr::{get_csr_name, MCAUSE, MSTATUS, MTVAL};
+use crate::cpu::instruction::Instruction;
+use crate::cpu::mmu::AccessType;
+use crate::cpu::register::{FRegister, XRegister};
+use crate::cpu::trap::{Exception, StopReason, WatchKind};
+use crate::device::Device;
+use crate::endianness::Endianness;
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Which instructions `CPU::start_commit_log` logs.
+#[derive(Debug, Clone, Default)]
+pub struct CommitLogOptions {
+    /// Only log instructions at these addresses.
+    pub address_range: Option<Range<usize>>,
+    /// Only log instructions whose index in the retired instructions of their hart
+    /// is in this range.
+    pub window: Option<Range<u64>>,
+    /// Print the disassembly of each instruction and the exceptions raised, like
+    /// `spike -l`, before the commit line.
+    pub disassembly: bool,
+}
+
+/// Writes a line per retired instruction in the format of `spike --log-commits`:
+///
+/// ```text
+/// core   0: 3 0x0000000080000010 (0x00a53023) mem 0x0000000080001000 0x0000000000000001
+/// ```
+///
+/// The fields are the privilege level, pc and instruction, followed by the register
+/// writes (`x5  0x...`, `f1  0x...` or `c768_mstatus 0x...`), the addresses loaded
+/// from and the stores with their values. Writes to `x0` aren't logged. Harts share
+/// the log, and a hart single-steps while it's logging.
+#[derive(Clone)]
+pub struct CommitLog {
+    writer: Arc<Mutex<BufWriter<Box<dyn Write + Send>>>>,
+    options: CommitLogOptions,
+}
+
+/// State of an instruction about to execute, completed into a log line afterwards.
+pub(crate) struct PendingCommit {
+    privilege: Privilege,
+    pc: usize,
+    word: u32,
+    instruction: Instruction,
+    memory_access: Option<(usize, usize, WatchKind)>,
+    store_value: Option<u64>,
+}
+
+enum Destination {
+    X(XRegister),
+    F(FRegister),
+}
+
+impl CommitLog {
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl CPU {
+    /// Starts logging the instructions retired by all harts to `writer`. Harts run
+    /// at full speed again once the log is stopped.
+    pub fn start_commit_log(&mut self, writer: impl Write + Send + 'static, options: CommitLogOptions) {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let log = CommitLog { writer: Arc::new(Mutex::new(BufWriter::new(writer))), options };
+        for core in &mut self.harts {
+            core.commit_log = Some(log.clone());
+        }
+    }
+
+    /// Stops logging and flushes the log.
+    pub fn stop_commit_log(&mut self) -> io::Result<()> {
+        let mut log = None;
+        for core in &mut self.harts {
+            log = core.commit_log.take().or(log);
+        }
+        match log {
+            Some(log) => log.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+impl Core {
+    /// Captures what the log needs from before the instruction at `pc` executes, if
+    /// it is to be logged.
+    pub(crate) fn begin_commit(&mut self) -> Result<Option<PendingCommit>, CoreError> {
+        if !self.is_logged(self.pc) {
+            return Ok(None)
+        }
+
+        // The fetch fails, which the log shows as an exception.
+        let address = match self.translate(self.pc, AccessType::Fetch) {
+            Ok(address) => address,
+            Err(_) => return Ok(None)
+        };
+        let word = match self.bus.lock().read_int(address, 4, Endianness::LittleEndian, false) {
+            Ok(word) => word as u32,
+            Err(_) => return Ok(None)
+        };
+        let instruction = match Instruction::decode(word) {
+            Ok(instruction) => instruction,
+            Err(_) => {
+                self.log_disassembly(word, &format!(".word {:#010x}", word))?;
+                return Ok(None)
+            }
+        };
+        self.log_disassembly(word, &instruction.to_string())?;
+
+        let store_value = match instruction {
+            Instruction::sb { rs2, .. } | Instruction::sh { rs2, .. } | Instruction::sw { rs2, .. } |
+            Instruction::sd { rs2, .. } | Instruction::sc_w { rs2, .. } |
+            Instruction::sc_d { rs2, .. } => Some(self.x_registers[rs2]),
+            _ => None
+        };
+        Ok(Some(PendingCommit {
+            privilege: self.privilege,
+            pc: self.pc,
+            word,
+            instruction,
+            memory_access: self.get_memory_access(&instruction),
+            store_value,
+        }))
+    }
+
+    /// Logs the instruction captured by `begin_commit`, which has retired.
+    pub(crate) fn end_commit(&mut self, pending: PendingCommit) -> Result<(), CoreError> {
+        let mut line = format!("core{:4}: {} {:#018x} ({:#010x})",
+                               self.get_hart_id(), pending.privilege as u8, pending.pc, pending.word);
+
+        match get_destination(&pending.instruction) {
+            Some(Destination::X(XRegister::x0)) | None => {},
+            Some(Destination::X(rd)) => {
+                let _ = write!(line, " x{:<2} {:#018x}", rd as usize, self.x_registers[rd]);
+            },
+            Some(Destination::F(rd)) => {
+                let _ = write!(line, " f{:<2} {:#018x}", rd as usize, self.f_registers[rd].to_bits());
+            }
+        }
+        if let Some(csr) = get_written_csr(&pending.instruction) {
+            let name = get_csr_name(csr).unwrap_or("unknown");
+            // Views like `sstatus` are read through the CSR they show, the written CSR
+            // can't be `time`, the only one with a side effect.
+            let value = self.read_csr(csr).unwrap_or_default();
+            let _ = write!(line, " c{}_{} {:#018x}", csr, name, value);
+        }
+
+        if let Some((address, size, kind)) = pending.memory_access {
+            if kind != WatchKind::Write {
+                let _ = write!(line, " mem {:#018x}", address);
+            }
+            let value = match kind {
+                WatchKind::Read => None,
+                WatchKind::Write => pending.store_value,
+                // The value an AMO stored is in memory now.
+                WatchKind::Access => self.translate(address, AccessType::Store).ok()
+                    .and_then(|address| self.bus.lock().read_int(address, size, Endianness::LittleEndian, false).ok())
+            };
+            if let Some(value) = value {
+                let mask = if size == 8 { u64::MAX } else { (1 << (size * 8)) - 1 };
+                let _ = write!(line, " mem {:#018x} 0x{:0width$x}", address, value & mask, width = size * 2);
+            }
+        }
+        line.push('\n');
+        self.write_log(&line)
+    }
+
+    /// Logs an exception raised by the instruction at `pc`, after `handle_error`
+    /// returned `reason`.
+    pub(crate) fn log_exception(&mut self, pc: usize, reason: Option<StopReason>) -> Result<(), CoreError> {
+        if !self.commit_log.as_ref().is_some_and(|log| log.options.disassembly) || !self.is_logged(pc) {
+            return Ok(())
+        }
+        let (cause, tval) = match reason {
+            Some(StopReason::Trap { exception, tval, .. }) => (exception.get_cause(), tval),
+            _ => (self.csr_registers[MCAUSE], self.csr_registers[MTVAL])
+        };
+        let hart_id = self.get_hart_id();
+        self.write_log(&format!("core{:4}: exception {}, epc {:#018x}\ncore{:4}:           tval {:#018x}\n",
+                                hart_id, get_trap_name(cause), pc, hart_id, tval))
+    }
+
+    /// Whether the instruction at `pc` is in the range and window of the log.
+    fn is_logged(&self, pc: usize) -> bool {
+        match &self.commit_log {
+            Some(log) => log.options.address_range.as_ref().is_none_or(|range| range.contains(&pc)) &&
+                log.options.window.as_ref().is_none_or(|window| window.contains(&self.instructions_retired)),
+            None => false
+        }
+    }
+
+    fn log_disassembly(&mut self, word: u32, text: &str) -> Result<(), CoreError> {
+        if !self.commit_log.as_ref().is_some_and(|log| log.options.disassembly) {
+            return Ok(())
+        }
+        // Spike pads the mnemonic to 8 characters.
+        let text = match text.split_once(' ') {
+            Some((mnemonic, operands)) => format!("{:<7} {}", mnemonic, operands),
+            None => text.to_string()
+        };
+        self.write_log(&format!("core{:4}: {:#018x} ({:#010x}) {}\n", self.get_hart_id(), self.pc, word, text))
+    }
+
+    fn write_log(&mut self, text: &str) -> Result<(), CoreError> {
+        if let Some(log) = &self.commit_log {
+            log.writer.lock().unwrap().write_all(text.as_bytes()).map_err(CoreError::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+/// Register an instruction writes.
+fn get_destination(instruction: &Instruction) -> Option<Destination> {
+    use Instruction::*;
+    let rd = match *instruction {
+        sb {..} | sh {..} | sw {..} | sd {..} | beq {..} | bne {..} | blt {..} | bge {..} |
+        bltu {..} | bgeu {..} | ecall | ebreak | mret | sret | wfi | sfence_vma {..} | fence {..} |
+        fence_tso | pause | fence_i {..} | fsw {..} | fsd {..} => return None,
+
+        flw {rd, ..} | fld {rd, ..} | fmadd_s {rd, ..} | fmsub_s {rd, ..} | fnmsub_s {rd, ..} |
+        fnmadd_s {rd, ..} | fadd_s {rd, ..} | fsub_s {rd, ..} | fmul_s {rd, ..} |
+        fdiv_s {rd, ..} | fsqrt_s {rd, ..} | fsgnj_s {rd, ..} | fsgnjn_s {rd, ..} |
+        fsgnjx_s {rd, ..} | fmin_s {rd, ..} | fmax_s {rd, ..} | fcvt_s_w {rd, ..} |
+        fcvt_s_wu {rd, ..} | fmv_w_x {rd, ..} | fcv_ts_l {rd, ..} | fcv_ts_lu {rd, ..} |
+        fmadd_d {rd, ..} | fmsub_d {rd, ..} | fnmsub_d {rd, ..} | fnmadd_d {rd, ..} |
+        fadd_d {rd, ..} | fsub_d {rd, ..} | fmul_d {rd, ..} | fdiv_d {rd, ..} |
+        fsqrt_d {rd, ..} | fsgnj_d {rd, ..} | fsgnjn_d {rd, ..} | fsgnjx_d {rd, ..} |
+        fmin_d {rd, ..} | fmax_d {rd, ..} | fcvt_s_d {rd, ..} | fcvt_d_s {rd, ..} |
+        fcvt_d_w {rd, ..} | fcvt_d_wu {rd, ..} | fcvt_d_l {rd, ..} | fcvt_d_lu {rd, ..} |
+        fmv_d_x {rd, ..} => return Some(Destination::F(rd)),
+
+        // Floating point instructions with an integer result.
+        fcvt_w_s {rd, ..} | fcvt_wu_s {rd, ..} | fmv_x_w {rd, ..} | feq_s {rd, ..} |
+        flt_s {rd, ..} | fle_s {rd, ..} | fclass_s {rd, ..} | fcv_tl_s {rd, ..} |
+        fcv_tlu_s {rd, ..} | feq_d {rd, ..} | flt_d {rd, ..} | fle_d {rd, ..} |
+        fclass_d {rd, ..} | fcvt_w_d {rd, ..} | fcvt_wu_d {rd, ..} | fcvt_l_d {rd, ..} |
+        fcvt_lu_d {rd, ..} | fmv_x_d {rd, ..} => XRegister::from(rd as u32),
+
+        add {rd, ..} | sub {rd, ..} | xor {rd, ..} | or {rd, ..} | and {rd, ..} | sll {rd, ..} |
+        srl {rd, ..} | sra {rd, ..} | slt {rd, ..} | sltu {rd, ..} | addi {rd, ..} |
+        xori {rd, ..} | ori {rd, ..} | andi {rd, ..} | slli {rd, ..} | srli {rd, ..} |
+        srai {rd, ..} | slti {rd, ..} | sltiu {rd, ..} | lb {rd, ..} | lh {rd, ..} |
+        lw {rd, ..} | lbu {rd, ..} | lhu {rd, ..} | jal {rd, ..} | jalr {rd, ..} |
+        lui {rd, ..} | auipc {rd, ..} | lwu {rd, ..} | ld {rd, ..} | addiw {rd, ..} |
+        slliw {rd, ..} | srliw {rd, ..} | sraiw {rd, ..} | addw {rd, ..} | subw {rd, ..} |
+        sllw {rd, ..} | srlw {rd, ..} | sraw {rd, ..} | csrrw {rd, ..} | csrrs {rd, ..} |
+        csrrc {rd, ..} | csrrwi {rd, ..} | csrrsi {rd, ..} | csrrci {rd, ..} | mul {rd, ..} |
+        mulh {rd, ..} | mulhsu {rd, ..} | mulhu {rd, ..} | div {rd, ..} | divu {rd, ..} |
+        rem {rd, ..} | remu {rd, ..} | mulw {rd, ..} | divw {rd, ..} | divuw {rd, ..} |
+        remw {rd, ..} | remuw {rd, ..} | lr_w {rd, ..} | sc_w {rd, ..} | amoswap_w {rd, ..} |
+        amoadd_w {rd, ..} | amoxor_w {rd, ..} | amoand_w {rd, ..} | amoor_w {rd, ..} |
+        amomin_w {rd, ..} | amomax_w {rd, ..} | amominu_w {rd, ..} | amomaxu_w {rd, ..} |
+        lr_d {rd, ..} | sc_d {rd, ..} | amoswap_d {rd, ..} | amoadd_d {rd, ..} |
+        amoxor_d {rd, ..} | amoand_d {rd, ..} | amoor_d {rd, ..} | amomin_d {rd, ..} |
+        amomax_d {rd, ..} | amominu_d {rd, ..} | amomaxu_d {rd, ..} => rd,
+    };
+    Some(Destination::X(rd))
+}
+
+/// CSR an instruction writes. `csrrs` and `csrrc` only write when they set or clear
+/// bits, and `mret` writes `mstatus`.
+fn get_written_csr(instruction: &Instruction) -> Option<u16> {
+    match *instruction {
+        Instruction::csrrw {imm, ..} | Instruction::csrrwi {imm, ..} => Some((imm & 0xFFF) as u16),
+        Instruction::csrrs {rs1, imm, ..} | Instruction::csrrc {rs1, imm, ..} if rs1 != XRegister::x0 =>
+            Some((imm & 0xFFF) as u16),
+        Instruction::csrrsi {uimm, imm, ..} | Instruction::csrrci {uimm, imm, ..} if uimm != 0 =>
+            Some((imm & 0xFFF) as u16),
+        Instruction::mret => Some(MSTATUS),
+        _ => None
+    }
+}
+
+/// Spike's name for an exception cause.
+fn get_trap_name(cause: u64) -> &'static str {
+    const EXCEPTIONS: [(Exception, &str); 14] = [
+        (Exception::InstructionAddressMisaligned, "trap_instruction_address_misaligned"),
+        (Exception::InstructionAccessFault, "trap_instruction_access_fault"),
+        (Exception::IllegalInstruction, "trap_illegal_instruction"),
+        (Exception::Breakpoint, "trap_breakpoint"),
+        (Exception::LoadAddressMisaligned, "trap_load_address_misaligned"),
+        (Exception::LoadAccessFault, "trap_load_access_fault"),
+        (Exception::StoreAddressMisaligned, "trap_store_address_misaligned"),
+        (Exception::StoreAccessFault, "trap_store_access_fault"),
+        (Exception::EnvironmentCallFromUMode, "trap_user_ecall"),
+        (Exception::EnvironmentCallFromSMode, "trap_supervisor_ecall"),
+        (Exception::EnvironmentCallFromMMode, "trap_machine_ecall"),
+        (Exception::InstructionPageFault, "trap_instruction_page_fault"),
+        (Exception::LoadPageFault, "trap_load_page_fault"),
+        (Exception::StorePageFault, "trap_store_page_fault"),
+    ];
+    EXCEPTIONS.iter().find(|(exception, _)| exception.get_cause() == cause)
+        .map_or("trap_unknown", |(_, name)| *name)
+}
@@ -0,0 +1,237 @@
+use crate::cpu::core::{Core, CoreError, Privilege};
+use crate::cpu::csr::{MCAUSE, MEDELEG, MEPC, MIDELEG, MIE, MIP, MIP_MEIP, MIP_MSIP, MIP_MTIP, MIP_SEIP, MIP_STIP,
+                      MSTATUS, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_SIE, MSTATUS_SPIE,
+                      MSTATUS_SPP, MTVAL, MTVEC, SCAUSE, SEPC, STVAL, STVEC};
+use crate::cpu::execute::InstructionExecuteError;
+use crate::device::DeviceError;
+use std::sync::atomic::Ordering;
+
+/// Synchronous exceptions. The discriminant is the `mcause` code.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EnvironmentCallFromUMode = 8,
+    EnvironmentCallFromSMode = 9,
+    EnvironmentCallFromMMode = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+/// Interrupts. The discriminant is the `mcause` code and the bit in `mip` and `mie`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+
+/// Interrupts in decreasing priority.
+const INTERRUPT_PRIORITY: [Interrupt; 6] = [
+    Interrupt::MachineExternal, Interrupt::MachineSoftware, Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal, Interrupt::SupervisorSoftware, Interrupt::SupervisorTimer,
+];
+
+const INTERRUPT_CAUSE: u64 = 1 << 63;
+
+/// Bits of `mip` that follow the interrupt line of the hart, driven by the CLINT and
+/// the PLIC.
+pub(crate) const DEVICE_INTERRUPTS: u64 = MIP_MEIP | MIP_MSIP | MIP_MTIP | MIP_SEIP;
+
+/// Why `Core::run`, `Core::run_until` or `Core::step` stopped.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StopReason {
+    /// The instruction limit was reached.
+    BudgetExhausted,
+    /// The predicate passed to `run_until` returned true.
+    Condition,
+    /// Execution reached a breakpoint set with `Core::add_breakpoint`. The instruction
+    /// at `address` hasn't been executed.
+    Breakpoint { address: usize },
+    /// An instruction accessed memory watched with `Core::add_watchpoint`. It has been
+    /// executed, and `address` is the first watched byte it accessed.
+    Watchpoint { address: usize, kind: WatchKind },
+    /// The guest asked to stop, e.g. through a `TestFinisher`.
+    Exit { code: u64 },
+    /// The hart executed `wfi`. Execution resumes after it on the next call.
+    WaitForInterrupt,
+    /// An exception was raised while the trap vector it goes to, `mtvec` or `stvec`
+    /// if it's delegated, is zero. `pc` points to the instruction that caused it, and
+    /// no CSRs have been changed.
+    Trap { exception: Exception, pc: usize, tval: u64 },
+}
+
+/// Accesses that trigger a watchpoint. Atomic memory operations count as both.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl Exception {
+    pub fn get_cause(&self) -> u64 {
+        *self as u64
+    }
+
+    /// The exception `ecall` raises at `privilege`.
+    pub fn environment_call(privilege: Privilege) -> Exception {
+        match privilege {
+            Privilege::User => Exception::EnvironmentCallFromUMode,
+            Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+            Privilege::Machine => Exception::EnvironmentCallFromMMode
+        }
+    }
+
+    /// The exception raised by a failed load or store. Returns `None` for errors that
+    /// stop the emulator instead.
+    pub fn from_device_error(error: &DeviceError) -> Option<Exception> {
+        match error.trap_cause()? {
+            4 => Some(Exception::LoadAddressMisaligned),
+            5 => Some(Exception::LoadAccessFault),
+            6 => Some(Exception::StoreAddressMisaligned),
+            7 => Some(Exception::StoreAccessFault),
+            _ => None
+        }
+    }
+}
+
+impl Interrupt {
+    pub fn get_cause(&self) -> u64 {
+        INTERRUPT_CAUSE | *self as u64
+    }
+
+    pub fn get_mask(&self) -> u64 {
+        1 << *self as u64
+    }
+}
+
+impl Core {
+    /// Enters the trap handler for an exception raised by the instruction at `pc`,
+    /// in S-mode if `medeleg` delegates it and the hart isn't in M-mode. Returns
+    /// `StopReason::Trap` instead if the trap vector is zero, or for the `ebreak` of a
+    /// semihosting call while semihosting is enabled.
+    pub fn raise_exception(&mut self, exception: Exception, tval: u64) -> Option<StopReason> {
+        let delegated = self.privilege < Privilege::Machine &&
+            self.csr_registers[MEDELEG] & (1 << exception.get_cause()) != 0;
+        let vector = self.csr_registers[if delegated { STVEC } else { MTVEC }];
+        if vector == 0 || (exception == Exception::Breakpoint && self.semihosting && self.is_semihosting_call()) {
+            return Some(StopReason::Trap { exception, pc: self.pc, tval })
+        }
+
+        self.exceptions_taken += 1;
+        // Synchronous exceptions always go to the base address, even in vectored mode.
+        self.enter_trap(exception.get_cause(), tval, (vector & !0b11) as usize, delegated);
+        None
+    }
+
+    /// Enters the trap handler for the highest priority interrupt that is pending and
+    /// enabled, if any. Returns true if one was taken. Interrupts delegated by
+    /// `mideleg` go to S-mode, and are never taken in M-mode. Pending interrupts stay
+    /// pending while the trap vector they go to is zero.
+    pub fn take_interrupt(&mut self) -> bool {
+        self.synchronize_interrupts();
+        let pending = self.csr_registers[MIP] & self.csr_registers[MIE];
+        if pending == 0 {
+            return false
+        }
+
+        let mstatus = self.csr_registers[MSTATUS];
+        let delegated = self.csr_registers[MIDELEG];
+        let mut enabled = 0;
+        if (self.privilege < Privilege::Machine || mstatus & MSTATUS_MIE != 0) &&
+                self.csr_registers[MTVEC] != 0 {
+            enabled |= pending & !delegated;
+        }
+        if (self.privilege < Privilege::Supervisor ||
+                (self.privilege == Privilege::Supervisor && mstatus & MSTATUS_SIE != 0)) &&
+                self.csr_registers[STVEC] != 0 {
+            enabled |= pending & delegated;
+        }
+
+        let interrupt = match INTERRUPT_PRIORITY.iter().find(|x| enabled & x.get_mask() != 0) {
+            Some(x) => *x,
+            None => return false
+        };
+        let supervisor = delegated & interrupt.get_mask() != 0;
+        let vector = self.csr_registers[if supervisor { STVEC } else { MTVEC }];
+        let base = (vector & !0b11) as usize;
+        let target = if vector & 0b11 == 1 { base + 4 * interrupt as usize } else { base };
+        self.enter_trap(interrupt.get_cause(), 0, target, supervisor);
+        true
+    }
+
+    /// Copies the interrupt line of the hart to `mip`. The timer interrupt becomes the
+    /// supervisor one if the SBI forwards it.
+    pub(crate) fn synchronize_interrupts(&mut self) {
+        if let Some(line) = &self.interrupt_line {
+            let mut pending = line.load(Ordering::Relaxed) & DEVICE_INTERRUPTS;
+            let mut driven = DEVICE_INTERRUPTS;
+            if self.supervisor_timer {
+                if pending & MIP_MTIP != 0 {
+                    pending = (pending & !MIP_MTIP) | MIP_STIP;
+                }
+                driven |= MIP_STIP;
+            }
+            let mip = self.csr_registers[MIP];
+            self.csr_registers[MIP] = (mip & !driven) | pending;
+        }
+    }
+
+    /// Saves the state of the interrupted hart in the CSRs of the privilege level the
+    /// trap goes to, M-mode or S-mode if `supervisor`, and jumps to `target`.
+    fn enter_trap(&mut self, cause: u64, tval: u64, target: usize, supervisor: bool) {
+        self.reservation = None;
+        self.waiting = false;
+        let mstatus = self.csr_registers[MSTATUS];
+
+        if supervisor {
+            self.csr_registers[SEPC] = self.pc as u64;
+            self.csr_registers[SCAUSE] = cause;
+            self.csr_registers[STVAL] = tval;
+            let spie = if mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if self.privilege == Privilege::Supervisor { MSTATUS_SPP } else { 0 };
+            self.csr_registers[MSTATUS] =
+                (mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp;
+            self.privilege = Privilege::Supervisor;
+        } else {
+            self.csr_registers[MEPC] = self.pc as u64;
+            self.csr_registers[MCAUSE] = cause;
+            self.csr_registers[MTVAL] = tval;
+            let mpie = if mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            let mpp = (self.privilege as u64) << 11;
+            self.csr_registers[MSTATUS] =
+                (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+            self.privilege = Privilege::Machine;
+        }
+        self.pc = target;
+    }
+
+    /// Raises the exception matching a failed `execute`. Errors that aren't guest
+    /// exceptions, like unimplemented instructions, are returned.
+    pub(crate) fn handle_error(&mut self, error: CoreError) -> Result<Option<StopReason>, CoreError> {
+        let (exception, tval) = match &error {
+            CoreError::DeviceError(DeviceError::MisalignedAddressReadTrap { address }) =>
+                (Exception::InstructionAddressMisaligned, *address as u64),
+            CoreError::DeviceError(DeviceError::InvalidAddressReadFault) =>
+                (Exception::InstructionAccessFault, self.pc as u64),
+            CoreError::InstructionDecodeError(_) |
+            CoreError::InstructionExecuteError(InstructionExecuteError::IllegalInstruction(_)) =>
+                (Exception::IllegalInstruction, 0),
+            CoreError::InstructionExecuteError(InstructionExecuteError::Exception { exception, tval }) =>
+                (*exception, *tval),
+            _ => return Err(error)
+        };
+        Ok(self.raise_exception(exception, tval))
+    }
+}
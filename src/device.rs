@@ -1,7 +1,11 @@
 use std::error::Error;
+use crate::bus::Bus;
 use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 use std::fmt::{Display, Formatter, Debug};
 use std::any::Any;
+use std::borrow::Cow;
 
 #[derive(Debug)]
 pub enum DeviceError {
@@ -9,7 +13,27 @@ pub enum DeviceError {
     InvalidAddressReadFault,
     InvalidSizeWriteFault,
     InvalidSizeReadFault,
-    InternalDeviceError(Box<dyn Error>),
+    ReadOnlyWriteFault { address: usize },
+    StraddlingAccessFault { address: usize, size: usize },
+    MisalignedAddressReadTrap { address: usize },
+    MisalignedAddressWriteTrap { address: usize },
+    MisalignedAddressReadFault { address: usize },
+    MisalignedAddressWriteFault { address: usize },
+    InternalDeviceError(Box<dyn Error + Send + Sync>),
+}
+
+impl DeviceError {
+    /// Exception cause code for errors that should be raised as a trap in the guest
+    /// instead of stopping the emulator.
+    pub fn trap_cause(&self) -> Option<u64> {
+        match self {
+            DeviceError::MisalignedAddressReadTrap { .. } => Some(4),
+            DeviceError::InvalidAddressReadFault => Some(5),
+            DeviceError::MisalignedAddressWriteTrap { .. } => Some(6),
+            DeviceError::InvalidAddressWriteFault | DeviceError::ReadOnlyWriteFault { .. } => Some(7),
+            _ => None
+        }
+    }
 }
 
 impl Display for DeviceError {
@@ -20,13 +44,72 @@ impl Display for DeviceError {
 
 impl Error for DeviceError {}
 
-pub trait Device: Debug {
+pub trait Device: Debug + Send {
     fn get_address_space_size(&self) -> usize;
-    fn read_bytes(&self, address: usize, size: usize) -> Result<&[u8], DeviceError>;
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError>;
     fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError>;
     fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
         -> Result<u64, DeviceError>;
     fn write_int(&mut self, address: usize, value: u64, size: usize, endianness: Endianness)
                  -> Result<(), DeviceError>;
+    fn get_name(&self) -> &str;
+
+    /// Host memory backing the device at `address`, for devices that are plain memory.
+    /// Returns the device address at which the slice starts together with the slice.
+    /// The bus caches the region and accesses it directly instead of calling the other
+    /// methods. MMIO devices keep the default, which returns `None`.
+    fn get_memory_region(&self, _address: usize) -> Option<(usize, &[u8])> {
+        None
+    }
+
+    /// Same as `get_memory_region`, for writes. Read-only memories return `None`.
+    fn get_memory_region_mut(&mut self, _address: usize) -> Option<(usize, &mut [u8])> {
+        None
+    }
+
+    /// Exit code the guest asked for through the device since the last call. The bus
+    /// polls it after every write it forwards to the device.
+    fn take_exit_request(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Returns true if a write to the device raised an interrupt since the last call.
+    /// The bus polls it after every write it forwards to the device, so the harts can
+    /// take the interrupt right after the write.
+    fn take_interrupt_raised(&mut self) -> bool {
+        false
+    }
+
+    /// Returns true if a write to the device started a transfer that needs the rest of
+    /// the bus, such as a DMA. The bus polls it after every write it forwards to the
+    /// device, and then calls `access_bus`.
+    fn take_bus_request(&mut self) -> bool {
+        false
+    }
+
+    /// Performs the transfers asked for through `take_bus_request`. The device is
+    /// detached from `bus` during the call, so accesses to its own range fail.
+    fn access_bus(&mut self, _bus: &mut Bus) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    /// Writes the device's state for `CPU::save_snapshot`. Devices that don't support
+    /// snapshots keep the default, which returns `SnapshotError::UnsupportedDevice`.
+    fn save_state(&self, _writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        Err(SnapshotError::UnsupportedDevice { name: self.get_name().to_string() })
+    }
+
+    /// Restores the state written by `save_state` into a device configured the same way.
+    fn restore_state(&mut self, _reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        Err(SnapshotError::UnsupportedDevice { name: self.get_name().to_string() })
+    }
+
+    /// Adds the device's node to the device tree passed to the guest. Devices that
+    /// are not described in the device tree keep the default, which adds nothing.
+    fn fdt_node(&self, _base_address: usize, _fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        Ok(())
+    }
+
     fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
 }
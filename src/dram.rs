@@ -1,89 +1,143 @@
 use crate::device::{Device, DeviceError};
 use crate::endianness::Endianness;
-use crate::utilities::extend_sign;
-use std::convert::TryInto;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use std::borrow::Cow;
 use std::fmt::{Debug, Formatter};
 use std::any::Any;
 
+pub const PAGE_SIZE: usize = 4096;
+const PAGES_PER_TABLE: usize = 512;
+const TABLE_SIZE: usize = PAGE_SIZE * PAGES_PER_TABLE;
 
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+type Page = Box<[u8; PAGE_SIZE]>;
+type PageTable = Box<[Option<Page>; PAGES_PER_TABLE]>;
+
+
+/// Sparse memory. Pages are allocated on the first write to them, reading
+/// from a page that was never written returns zeros.
+///
+/// Pages are kept in a two level table. Each second level table covers 2 MiB of
+/// memory and is allocated together with the first page in it.
 pub struct DRAM {
     size: usize,
-    memory: Vec<u8>
+    tables: Vec<Option<PageTable>>,
+    resident_pages: usize
 }
 
 impl DRAM {
     pub fn new(size: usize) -> Self {
+        let mut tables = Vec::new();
+        tables.resize_with(size.div_ceil(TABLE_SIZE), || None);
         Self {
             size,
-            memory: vec![0; size + 7]
+            tables,
+            resident_pages: 0
+        }
+    }
+
+    /// Number of pages that have been allocated.
+    pub fn get_resident_pages(&self) -> usize {
+        self.resident_pages
+    }
+
+    /// Amount of host memory used for the guest's memory contents in bytes.
+    pub fn get_resident_size(&self) -> usize {
+        self.resident_pages * PAGE_SIZE
+    }
+
+    fn get_page(&self, page_number: usize) -> &[u8; PAGE_SIZE] {
+        match &self.tables[page_number / PAGES_PER_TABLE] {
+            Some(table) => match &table[page_number % PAGES_PER_TABLE] {
+                Some(page) => page,
+                None => &ZERO_PAGE
+            },
+            None => &ZERO_PAGE
+        }
+    }
+
+    fn get_page_mut(&mut self, page_number: usize) -> &mut [u8; PAGE_SIZE] {
+        let table = self.tables[page_number / PAGES_PER_TABLE]
+            .get_or_insert_with(|| Box::new(std::array::from_fn(|_| None)));
+        let page = &mut table[page_number % PAGES_PER_TABLE];
+
+        if page.is_none() {
+            self.resident_pages += 1;
+        }
+        page.get_or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    fn is_in_range(&self, address: usize, size: usize) -> bool {
+        match address.checked_add(size) {
+            Some(end_address) => end_address <= self.size,
+            None => false
         }
     }
 }
 
 impl Debug for DRAM {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "DRAM {{ size: {:?} }}", self.size)
+        write!(f, "DRAM {{ size: {:?}, resident_size: {:?} }}", self.size, self.get_resident_size())
     }
 }
 
 impl Device for DRAM {
     fn get_address_space_size(&self) -> usize { self.size }
 
-    fn read_bytes(&self, address: usize, size: usize) -> Result<&[u8], DeviceError> {
-        let end_address = address + size;
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        if !self.is_in_range(address, size) {
+            return Err(DeviceError::InvalidAddressReadFault)
+        }
+        // An empty read at the end of memory would look up the page past it.
+        if size == 0 {
+            return Ok(Cow::Borrowed(&[]))
+        }
 
-        if end_address <= self.size {
-            Ok(&self.memory[address..(address+size)])
-        } else {
-            Err(DeviceError::InvalidAddressReadFault)
+        let offset = address % PAGE_SIZE;
+        if offset + size <= PAGE_SIZE {
+            return Ok(Cow::Borrowed(&self.get_page(address / PAGE_SIZE)[offset..(offset + size)]))
         }
+
+        // The access crosses a page boundary, so it has to be copied.
+        let mut bytes = Vec::with_capacity(size);
+        let mut address = address;
+        let end_address = address + size;
+        while address < end_address {
+            let offset = address % PAGE_SIZE;
+            let length = (PAGE_SIZE - offset).min(end_address - address);
+            bytes.extend_from_slice(&self.get_page(address / PAGE_SIZE)[offset..(offset + length)]);
+            address += length;
+        }
+        Ok(Cow::Owned(bytes))
     }
 
     fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
-        let end_address = address + binary.len();
-
-        if end_address <= self.size {
-            self.memory.splice(
-                address..end_address,
-                binary.iter().cloned());
-            Ok(())
+        if !self.is_in_range(address, binary.len()) {
+            return Err(DeviceError::InvalidAddressWriteFault)
+        }
 
-        } else {
-            Err(DeviceError::InvalidAddressWriteFault)
+        let mut address = address;
+        let mut binary = binary;
+        while !binary.is_empty() {
+            let offset = address % PAGE_SIZE;
+            let length = (PAGE_SIZE - offset).min(binary.len());
+            self.get_page_mut(address / PAGE_SIZE)[offset..(offset + length)]
+                .copy_from_slice(&binary[..length]);
+            address += length;
+            binary = &binary[length..];
         }
+        Ok(())
     }
 
     fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
             -> Result<u64, DeviceError> {
-        if (address + size) <= self.size {
+        if self.is_in_range(address, size) {
             match size {
-                1..=8 => {
-                    let bytes = &self.memory[address..(address+8)];
-
-                    let bytes = match bytes.try_into() {
-                        Ok(i) => i,
-                        Err(e) => {
-                            return Err(DeviceError::InternalDeviceError(Box::new(e)))
-                        }
-                    };
-
-                    let mut value = match endianness {
-                        Endianness::LittleEndian => {
-                            let mask = u64::MAX >> ((8 - size) * 8);
-                            u64::from_le_bytes(bytes) & mask
-                        },
-                        Endianness::BigEndian => {
-                            let mask = u64::MAX << ((8 - size) * 8);
-                            (u64::from_be_bytes(bytes) & mask) >> ((8 - size) * 8)
-                        }
-                    };
-
-                    if sign_extend {
-                        value = extend_sign(value, size * 8);
-                    }
-
-                    Ok(value)
-                },
+                1..=8 => Ok(int_from_bytes(
+                    &self.read_bytes(address, size)?, endianness, sign_extend)),
                 _ => Err(DeviceError::InvalidSizeReadFault)
             }
         } else {
@@ -93,7 +147,7 @@ impl Device for DRAM {
 
     fn write_int(&mut self, address: usize, value: u64, size: usize, endianness: Endianness)
                  -> Result<(), DeviceError> {
-        if (address + size) <= self.size {
+        if self.is_in_range(address, size) {
             match size {
                 1..=8 => {
                     match endianness {
@@ -112,5 +166,66 @@ impl Device for DRAM {
         }
     }
 
+    fn get_name(&self) -> &str { "dram" }
+
+    fn get_memory_region(&self, address: usize) -> Option<(usize, &[u8])> {
+        if address >= self.size {
+            return None
+        }
+        let page_address = address - address % PAGE_SIZE;
+        let length = PAGE_SIZE.min(self.size - page_address);
+        Some((page_address, &self.get_page(address / PAGE_SIZE)[..length]))
+    }
+
+    fn get_memory_region_mut(&mut self, address: usize) -> Option<(usize, &mut [u8])> {
+        if address >= self.size {
+            return None
+        }
+        let page_address = address - address % PAGE_SIZE;
+        let length = PAGE_SIZE.min(self.size - page_address);
+        Some((page_address, &mut self.get_page_mut(address / PAGE_SIZE)[..length]))
+    }
+
+    /// Only resident pages are saved.
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_u64(self.size as u64)?;
+        writer.write_u64(self.resident_pages as u64)?;
+        for (table_number, table) in self.tables.iter().enumerate() {
+            let pages = table.iter().flat_map(|table| table.iter().enumerate());
+            for (i, page) in pages {
+                if let Some(page) = page {
+                    writer.write_u64((table_number * PAGES_PER_TABLE + i) as u64)?;
+                    writer.write_bytes(&page[..])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        if reader.read_u64()? != self.size as u64 {
+            return Err(SnapshotError::InvalidState { name: self.get_name().to_string() })
+        }
+
+        *self = Self::new(self.size);
+        for _ in 0..reader.read_u64()? {
+            let page_number = reader.read_u64()? as usize;
+            if page_number >= self.size.div_ceil(PAGE_SIZE) {
+                return Err(SnapshotError::InvalidState { name: self.get_name().to_string() })
+            }
+            reader.read_bytes_into(&mut self.get_page_mut(page_number)[..], "dram")?;
+        }
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        fdt.begin_node(&format!("memory@{:x}", base_address))?;
+        fdt.property_string("device_type", "memory")?;
+        fdt.property_reg("reg", &[(base_address as u64, self.size as u64)])?;
+        fdt.end_node()
+    }
+
     fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
 }
@@ -0,0 +1,229 @@
+use crate::device::{Device, DeviceError};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+
+#[derive(Debug)]
+pub enum ElfError {
+    IoError(io::Error),
+    DeviceError(DeviceError),
+    InvalidMagic,
+    /// Only little-endian 64-bit RISC-V files are supported.
+    UnsupportedFormat { class: u8, data: u8, machine: u16 },
+    /// A header or table points outside the file.
+    Truncated { offset: usize, size: usize },
+}
+
+/// A segment the program loads into memory. The part of `memory_size` past
+/// `file_range` is zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: usize,
+    pub memory_size: usize,
+    pub file_range: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// Defined function, object and untyped symbols, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+/// The parts of a RISC-V ELF64 executable needed to run it.
+pub struct Elf {
+    pub entry: usize,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+    program_headers: usize,
+    program_header_count: usize,
+    data: Vec<u8>,
+}
+
+impl Elf {
+    pub fn parse(data: Vec<u8>) -> Result<Self, ElfError> {
+        let header = get(&data, 0, HEADER_SIZE)?;
+        if &header[0..4] != ELF_MAGIC {
+            return Err(ElfError::InvalidMagic)
+        }
+        let (class, encoding, machine) = (header[4], header[5], read_u16(header, 18));
+        if class != ELFCLASS64 || encoding != ELFDATA2LSB || machine != EM_RISCV {
+            return Err(ElfError::UnsupportedFormat { class, data: encoding, machine })
+        }
+
+        let entry = read_u64(header, 24) as usize;
+        let program_headers = read_u64(header, 32) as usize;
+        let section_headers = read_u64(header, 40) as usize;
+        let program_header_count = read_u16(header, 56) as usize;
+        let section_header_count = read_u16(header, 60) as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..program_header_count {
+            let program_header = get(&data, program_headers.saturating_add(i * PROGRAM_HEADER_SIZE), PROGRAM_HEADER_SIZE)?;
+            if read_u32(program_header, 0) != PT_LOAD {
+                continue
+            }
+            let offset = read_u64(program_header, 8) as usize;
+            let file_size = read_u64(program_header, 32) as usize;
+            get(&data, offset, file_size)?;
+            segments.push(Segment {
+                address: read_u64(program_header, 24) as usize,
+                memory_size: read_u64(program_header, 40) as usize,
+                file_range: offset..offset + file_size,
+            });
+        }
+
+        let mut symbols = Vec::new();
+        for i in 0..section_header_count {
+            let section_header = get(&data, section_headers.saturating_add(i * SECTION_HEADER_SIZE), SECTION_HEADER_SIZE)?;
+            if read_u32(section_header, 4) != SHT_SYMTAB {
+                continue
+            }
+            // The linked section holds the symbol names.
+            let link = read_u32(section_header, 40) as usize;
+            let string_header = get(&data, section_headers.saturating_add(link * SECTION_HEADER_SIZE), SECTION_HEADER_SIZE)?;
+            let strings = get(&data, read_u64(string_header, 24) as usize, read_u64(string_header, 32) as usize)?;
+            let table = get(&data, read_u64(section_header, 24) as usize, read_u64(section_header, 32) as usize)?;
+
+            for symbol in table.chunks_exact(SYMBOL_SIZE) {
+                let kind = symbol[4] & 0xF;
+                let name = strings.get(read_u32(symbol, 0) as usize..).unwrap_or_default();
+                let name = &name[..name.iter().position(|byte| *byte == 0).unwrap_or(name.len())];
+                if ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&kind) ||
+                        read_u16(symbol, 6) == SHN_UNDEF || name.is_empty() {
+                    continue
+                }
+                symbols.push(Symbol {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    address: read_u64(symbol, 8) as usize,
+                    size: read_u64(symbol, 16) as usize,
+                });
+            }
+        }
+
+        Ok(Self { entry, segments, symbols: SymbolTable::new(symbols), program_headers, program_header_count, data })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ElfError> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Returns true if `data` starts like an ELF file.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    /// Address of the program headers in memory, if a segment loads them, and their
+    /// number. Programs find them through the `AT_PHDR` auxiliary vector entry.
+    pub fn get_program_headers(&self) -> Option<(usize, usize)> {
+        self.segments.iter()
+            .find(|segment| segment.file_range.contains(&self.program_headers))
+            .map(|segment| (segment.address + self.program_headers - segment.file_range.start, self.program_header_count))
+    }
+
+    /// Writes the segments to `bus` at their physical addresses.
+    pub fn load(&self, bus: &mut dyn Device) -> Result<(), ElfError> {
+        for segment in &self.segments {
+            let contents = &self.data[segment.file_range.clone()];
+            bus.write_bytes(segment.address, contents)?;
+            if segment.memory_size > contents.len() {
+                let zeros = vec![0; segment.memory_size - contents.len()];
+                bus.write_bytes(segment.address + contents.len(), &zeros)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Self { symbols }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The symbol containing `address` and the offset into it. Symbols without a
+    /// size only match their own address.
+    pub fn lookup(&self, address: usize) -> Option<(&Symbol, usize)> {
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address);
+        self.symbols[..index].iter().rev()
+            .find(|symbol| address - symbol.address < symbol.size.max(1))
+            .map(|symbol| (symbol, address - symbol.address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+fn get(data: &[u8], offset: usize, size: usize) -> Result<&[u8], ElfError> {
+    offset.checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(ElfError::Truncated { offset, size })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ElfError {}
+
+impl From<io::Error> for ElfError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<DeviceError> for ElfError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
@@ -1,4 +1,4 @@
-
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Endianness {
     LittleEndian,
     BigEndian
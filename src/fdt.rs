@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+// Flattened device tree (FDT/DTB) writer.
+// Format reference: Devicetree Specification v0.3, chapter 5.
+// https://github.com/devicetree-org/devicetree-specification
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+
+#[derive(Debug, PartialEq)]
+pub enum FdtError {
+    UnclosedNodes { depth: usize },
+    UnexpectedEndNode,
+    PropertyOutsideNode { name: String },
+    MultipleRootNodes,
+}
+
+/// Builds a flattened device tree blob one node at a time.
+///
+/// Nodes are opened with `begin_node` and closed with `end_node`. Properties are added
+/// to the node that is currently open. The root node is the first node opened and has
+/// an empty name.
+#[derive(Debug)]
+pub struct FdtWriter {
+    struct_block: Vec<u8>,
+    strings_block: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    memory_reservations: Vec<(u64, u64)>,
+    boot_cpuid: u32,
+    depth: usize,
+    root_closed: bool,
+    next_phandle: u32,
+    labels: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self {
+            struct_block: Vec::new(),
+            strings_block: Vec::new(),
+            string_offsets: HashMap::new(),
+            memory_reservations: Vec::new(),
+            boot_cpuid: 0,
+            depth: 0,
+            root_closed: false,
+            next_phandle: 1,
+            labels: HashMap::new(),
+        }
+    }
+
+    pub fn set_boot_cpuid(&mut self, boot_cpuid: u32) {
+        self.boot_cpuid = boot_cpuid;
+    }
+
+    pub fn add_memory_reservation(&mut self, address: u64, size: u64) {
+        self.memory_reservations.push((address, size));
+    }
+
+    /// Returns a new unique phandle, to be used with `property_u32("phandle", ...)`.
+    pub fn allocate_phandle(&mut self) -> u32 {
+        let phandle = self.next_phandle;
+        self.next_phandle += 1;
+        phandle
+    }
+
+    /// Returns the phandle of the node known as `label`, allocating it on first use.
+    /// Lets a node refer to another one regardless of which is written first.
+    pub fn get_phandle(&mut self, label: &str) -> u32 {
+        if let Some(phandle) = self.labels.get(label) {
+            return *phandle
+        }
+        let phandle = self.allocate_phandle();
+        self.labels.insert(label.to_string(), phandle);
+        phandle
+    }
+
+    pub fn begin_node(&mut self, name: &str) -> Result<(), FdtError> {
+        if self.root_closed {
+            return Err(FdtError::MultipleRootNodes)
+        }
+        self.push_u32(FDT_BEGIN_NODE);
+        self.struct_block.extend_from_slice(name.as_bytes());
+        self.struct_block.push(0);
+        self.align_struct_block();
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn end_node(&mut self) -> Result<(), FdtError> {
+        if self.depth == 0 {
+            return Err(FdtError::UnexpectedEndNode)
+        }
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+        self.root_closed = self.depth == 0;
+        Ok(())
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) -> Result<(), FdtError> {
+        if self.depth == 0 {
+            return Err(FdtError::PropertyOutsideNode { name: name.to_string() })
+        }
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.struct_block.extend_from_slice(value);
+        self.align_struct_block();
+        Ok(())
+    }
+
+    pub fn property_null(&mut self, name: &str) -> Result<(), FdtError> {
+        self.property(name, &[])
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> Result<(), FdtError> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) -> Result<(), FdtError> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) -> Result<(), FdtError> {
+        let mut value = Vec::with_capacity(cells.len() * 4);
+        for cell in cells {
+            value.extend_from_slice(&cell.to_be_bytes());
+        }
+        self.property(name, &value)
+    }
+
+    /// Writes a list of (address, size) pairs, each value as two 32-bit cells, so four
+    /// cells per pair. This matches `#address-cells = <2>` and `#size-cells = <2>`.
+    pub fn property_reg(&mut self, name: &str, regions: &[(u64, u64)]) -> Result<(), FdtError> {
+        let mut value = Vec::with_capacity(regions.len() * 16);
+        for (address, size) in regions {
+            value.extend_from_slice(&address.to_be_bytes());
+            value.extend_from_slice(&size.to_be_bytes());
+        }
+        self.property(name, &value)
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) -> Result<(), FdtError> {
+        self.property_string_list(name, &[value])
+    }
+
+    pub fn property_string_list(&mut self, name: &str, values: &[&str]) -> Result<(), FdtError> {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value)
+    }
+
+    /// Assembles the final blob. All nodes must have been closed.
+    pub fn finish(mut self) -> Result<Vec<u8>, FdtError> {
+        if self.depth != 0 {
+            return Err(FdtError::UnclosedNodes { depth: self.depth })
+        }
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + (self.memory_reservations.len() + 1) * 16;
+        let off_dt_strings = off_dt_struct + self.struct_block.len();
+        let total_size = off_dt_strings + self.strings_block.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+                FDT_MAGIC,
+                total_size as u32,
+                off_dt_struct as u32,
+                off_dt_strings as u32,
+                off_mem_rsvmap as u32,
+                FDT_VERSION,
+                FDT_LAST_COMP_VERSION,
+                self.boot_cpuid,
+                self.strings_block.len() as u32,
+                self.struct_block.len() as u32] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+
+        for (address, size) in self.memory_reservations.iter().chain([(0, 0)].iter()) {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+
+        blob.extend_from_slice(&self.struct_block);
+        blob.extend_from_slice(&self.strings_block);
+        Ok(blob)
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.struct_block.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align_struct_block(&mut self) {
+        while !self.struct_block.len().is_multiple_of(4) {
+            self.struct_block.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset
+        }
+        let offset = self.strings_block.len() as u32;
+        self.strings_block.extend_from_slice(name.as_bytes());
+        self.strings_block.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+}
+
+impl Default for FdtWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for FdtError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for FdtError {}
@@ -0,0 +1,114 @@
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use std::any::Any;
+use std::borrow::Cow;
+use std::fmt::{Debug, Formatter};
+
+const TEST_FINISHER_SIZE: usize = 0x1000;
+
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_FAIL: u64 = 0x3333;
+
+
+/// SiFive test device, used by guests to stop the emulator.
+///
+/// Writing `0x5555` to offset 0 exits with code 0, writing `0x3333 | code << 16`
+/// exits with `code`. Other values are ignored.
+pub struct TestFinisher {
+    exit_request: Option<u64>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self { exit_request: None }
+    }
+}
+
+impl Default for TestFinisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for TestFinisher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TestFinisher")
+    }
+}
+
+impl Device for TestFinisher {
+    fn get_address_space_size(&self) -> usize { TEST_FINISHER_SIZE }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        if address + size <= TEST_FINISHER_SIZE {
+            Ok(Cow::Owned(vec![0; size]))
+        } else {
+            Err(DeviceError::InvalidAddressReadFault)
+        }
+    }
+
+    fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
+        match binary.len() {
+            1..=8 => {
+                let value = int_from_bytes(binary, Endianness::LittleEndian, false);
+                self.write_int(address, value, binary.len(), Endianness::LittleEndian)
+            },
+            _ => Err(DeviceError::InvalidSizeWriteFault)
+        }
+    }
+
+    fn read_int(&self, address: usize, size: usize, _endianness: Endianness, _sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        match size {
+            1..=8 => { self.read_bytes(address, size)?; Ok(0) },
+            _ => Err(DeviceError::InvalidSizeReadFault)
+        }
+    }
+
+    fn write_int(&mut self, address: usize, value: u64, size: usize, _endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        if address + size > TEST_FINISHER_SIZE {
+            return Err(DeviceError::InvalidAddressWriteFault)
+        }
+        if address == 0 && size >= 4 {
+            match value & 0xFFFF {
+                FINISHER_PASS => self.exit_request = Some(0),
+                FINISHER_FAIL => self.exit_request = Some((value >> 16) & 0xFFFF),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn get_name(&self) -> &str { "test_finisher" }
+
+    fn take_exit_request(&mut self) -> Option<u64> {
+        self.exit_request.take()
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_bool(self.exit_request.is_some())?;
+        writer.write_u64(self.exit_request.unwrap_or(0))
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let pending = reader.read_bool()?;
+        let code = reader.read_u64()?;
+        self.exit_request = if pending { Some(code) } else { None };
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        fdt.begin_node(&format!("test@{:x}", base_address))?;
+        fdt.property_string_list("compatible", &["sifive,test1", "sifive,test0", "syscon"])?;
+        fdt.property_reg("reg", &[(base_address as u64, TEST_FINISHER_SIZE as u64)])?;
+        fdt.end_node()
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
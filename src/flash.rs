@@ -0,0 +1,170 @@
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use std::fmt::{Debug, Formatter};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::any::Any;
+use std::borrow::Cow;
+use std::io;
+
+
+/// How writes to a `Flash` device are handled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FlashMode {
+    /// True ROM. Writes fail with `DeviceError::ReadOnlyWriteFault`.
+    ReadOnly,
+    /// Writes are visible to the guest, but never reach the file.
+    CopyOnWrite,
+    /// Writes go to the file, which makes the contents persistent like NOR flash.
+    WriteThrough,
+}
+
+enum FlashMemory {
+    ReadOnly(Mmap),
+    Writable(MmapMut),
+}
+
+/// Memory device backed by a memory mapped host file.
+///
+/// The file is mapped instead of read, so large images don't have to be copied
+/// into memory up front. The file must not be resized by another process while it
+/// is mapped.
+pub struct Flash {
+    mode: FlashMode,
+    memory: FlashMemory,
+}
+
+impl Flash {
+    pub fn open<P: AsRef<Path>>(path: P, mode: FlashMode) -> io::Result<Self> {
+        let memory = match mode {
+            FlashMode::ReadOnly => {
+                let file = File::open(path)?;
+                FlashMemory::ReadOnly(unsafe { MmapOptions::new().map(&file)? })
+            },
+            FlashMode::CopyOnWrite => {
+                let file = File::open(path)?;
+                FlashMemory::Writable(unsafe { MmapOptions::new().map_copy(&file)? })
+            },
+            FlashMode::WriteThrough => {
+                let file = OpenOptions::new().read(true).write(true).open(path)?;
+                FlashMemory::Writable(unsafe { MmapOptions::new().map_mut(&file)? })
+            }
+        };
+
+        Ok(Self { mode, memory })
+    }
+
+    pub fn get_mode(&self) -> FlashMode {
+        self.mode
+    }
+
+    /// Writes outstanding changes back to the file. Only has an effect in write-through mode.
+    pub fn flush(&self) -> io::Result<()> {
+        match (&self.memory, self.mode) {
+            (FlashMemory::Writable(memory), FlashMode::WriteThrough) => memory.flush(),
+            _ => Ok(())
+        }
+    }
+
+    fn memory(&self) -> &[u8] {
+        match &self.memory {
+            FlashMemory::ReadOnly(memory) => memory,
+            FlashMemory::Writable(memory) => memory
+        }
+    }
+}
+
+impl Debug for Flash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Flash {{ size: {:?}, mode: {:?} }}", self.memory().len(), self.mode)
+    }
+}
+
+impl Device for Flash {
+    fn get_address_space_size(&self) -> usize { self.memory().len() }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        let memory = self.memory();
+        match address.checked_add(size) {
+            Some(end_address) if end_address <= memory.len() =>
+                Ok(Cow::Borrowed(&memory[address..end_address])),
+            _ => Err(DeviceError::InvalidAddressReadFault)
+        }
+    }
+
+    fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
+        match &mut self.memory {
+            FlashMemory::ReadOnly(_) => Err(DeviceError::ReadOnlyWriteFault { address }),
+            FlashMemory::Writable(memory) => {
+                match address.checked_add(binary.len()) {
+                    Some(end_address) if end_address <= memory.len() => {
+                        memory[address..end_address].copy_from_slice(binary);
+                        Ok(())
+                    },
+                    _ => Err(DeviceError::InvalidAddressWriteFault)
+                }
+            }
+        }
+    }
+
+    fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        match size {
+            1..=8 => Ok(int_from_bytes(&self.read_bytes(address, size)?, endianness, sign_extend)),
+            _ => Err(DeviceError::InvalidSizeReadFault)
+        }
+    }
+
+    fn write_int(&mut self, address: usize, value: u64, size: usize, endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        match size {
+            1..=8 => match endianness {
+                Endianness::LittleEndian => self.write_bytes(address, &value.to_le_bytes()[0..size]),
+                Endianness::BigEndian => self.write_bytes(address, &value.to_be_bytes()[(8-size)..])
+            },
+            _ => Err(DeviceError::InvalidSizeWriteFault)
+        }
+    }
+
+    fn get_name(&self) -> &str { "flash" }
+
+    /// Read-only flash only saves its size, the contents are expected to be unchanged
+    /// when the snapshot is restored. Writable flash saves its contents, and restoring
+    /// them in write-through mode writes them to the file.
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        match &self.memory {
+            FlashMemory::ReadOnly(memory) => writer.write_u64(memory.len() as u64),
+            FlashMemory::Writable(memory) => writer.write_bytes(memory)
+        }
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        match &mut self.memory {
+            FlashMemory::ReadOnly(memory) => {
+                if reader.read_u64()? != memory.len() as u64 {
+                    return Err(SnapshotError::InvalidState { name: "flash".to_string() })
+                }
+                Ok(())
+            },
+            FlashMemory::Writable(memory) => reader.read_bytes_into(memory, "flash")
+        }
+    }
+
+    fn get_memory_region(&self, _address: usize) -> Option<(usize, &[u8])> {
+        Some((0, self.memory()))
+    }
+
+    fn get_memory_region_mut(&mut self, _address: usize) -> Option<(usize, &mut [u8])> {
+        match &mut self.memory {
+            FlashMemory::ReadOnly(_) => None,
+            FlashMemory::Writable(memory) => Some((0, memory))
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
@@ -0,0 +1,747 @@
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::csr::CSR_NAMES;
+use crate::cpu::register::{FRegister, XRegister};
+use crate::cpu::trap::{Exception, StopReason, WatchKind};
+use crate::device::Device;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Write as _};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const PACKET_SIZE: usize = 0x4000;
+
+/// Instructions executed between checks for an interrupt from the debugger.
+const RESUME_CHUNK: u64 = 100_000;
+
+// Register numbers GDB uses for RISC-V. CSR `n` is register `REGISTER_CSR0 + n`.
+const REGISTER_PC: usize = 32;
+const REGISTER_F0: usize = 33;
+const REGISTER_CSR0: usize = 65;
+
+// CSRs described in the floating point feature, with 32-bit registers.
+const FLOAT_CSRS: [(u16, &str); 3] = [(1, "fflags"), (2, "frm"), (3, "fcsr")];
+
+/// ABI names, except that GDB expects `fp` for `x8`.
+const X_REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// Signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+const INTERRUPT: u8 = 0x03;
+
+
+/// Byte stream to the debugger.
+pub trait Connection: Read + Write {
+    /// Used to check for an interrupt from the debugger while the guest runs.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+#[derive(Debug)]
+pub enum GdbError {
+    IoError(io::Error),
+}
+
+/// Serves the GDB Remote Serial Protocol over `connection`, until the debugger
+/// detaches, kills the target or disconnects.
+///
+/// Harts are GDB threads, with thread id `hart + 1`. Continuing runs all harts with
+/// `CPU::run`, while single-stepping only executes the selected hart. Software and
+/// hardware breakpoints both use `Core::add_breakpoint`, so memory is never patched.
+pub struct GdbServer<'a, C: Connection> {
+    cpu: &'a mut CPU,
+    connection: C,
+    /// Bytes received but not processed yet.
+    received: VecDeque<u8>,
+    last_sent: Vec<u8>,
+    no_ack: bool,
+    /// Hart selected for register and memory accesses with `Hg`.
+    current_hart: usize,
+    /// Hart selected for single-stepping with `Hc`.
+    step_hart: usize,
+    last_stop: String,
+    software_breakpoints: HashSet<usize>,
+    hardware_breakpoints: HashSet<usize>,
+    watchpoints: Vec<(usize, usize, WatchKind)>,
+}
+
+enum Stop {
+    Reason { hart: usize, reason: StopReason },
+    Signal { hart: usize, signal: u8 },
+}
+
+/// Waits for a debugger on `address`, e.g. "127.0.0.1:1234", and serves it.
+pub fn serve_tcp(cpu: &mut CPU, address: impl ToSocketAddrs) -> Result<(), GdbError> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbServer::new(cpu, stream).serve()
+}
+
+/// Waits for a debugger on the Unix socket at `path`, which must not exist yet, and
+/// serves it.
+#[cfg(unix)]
+pub fn serve_unix(cpu: &mut CPU, path: impl AsRef<Path>) -> Result<(), GdbError> {
+    let listener = UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    GdbServer::new(cpu, stream).serve()
+}
+
+impl<'a, C: Connection> GdbServer<'a, C> {
+    pub fn new(cpu: &'a mut CPU, connection: C) -> Self {
+        Self {
+            cpu,
+            connection,
+            received: VecDeque::new(),
+            last_sent: Vec::new(),
+            no_ack: false,
+            current_hart: 0,
+            step_hart: 0,
+            last_stop: format!("T{:02x}thread:1;", SIGTRAP),
+            software_breakpoints: HashSet::new(),
+            hardware_breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Handles packets until the session ends. Breakpoints and watchpoints set by the
+    /// debugger are removed before returning.
+    pub fn serve(mut self) -> Result<(), GdbError> {
+        let result = self.serve_packets();
+        for core in &mut self.cpu.harts {
+            for address in self.software_breakpoints.union(&self.hardware_breakpoints) {
+                core.remove_breakpoint(*address);
+            }
+            for (address, size, kind) in &self.watchpoints {
+                core.remove_watchpoint(*address, *size, *kind);
+            }
+        }
+        result
+    }
+
+    fn serve_packets(&mut self) -> Result<(), GdbError> {
+        while let Some(packet) = self.read_packet()? {
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_str() {
+                "k" => return Ok(()),
+                "D" | "D;1" => {
+                    self.send_packet("OK")?;
+                    return self.wait_for_ack()
+                },
+                "QStartNoAckMode" => {
+                    self.send_packet("OK")?;
+                    self.no_ack = true;
+                },
+                _ => {
+                    let reply = self.handle_packet(&packet)?;
+                    self.send_packet(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reply to `packet`. Unsupported packets get an empty reply.
+    fn handle_packet(&mut self, packet: &str) -> Result<String, GdbError> {
+        let command = packet.get(..1).unwrap_or("");
+        let arguments = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(arguments),
+            "p" => self.read_register(arguments),
+            "P" => self.write_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "Z" | "z" => self.update_breakpoint(command == "Z", arguments),
+            "H" => self.select_thread(arguments),
+            "T" => match parse_thread(arguments, self.cpu.harts.len()) {
+                Some(_) => "OK".to_string(),
+                None => "E01".to_string()
+            },
+            "c" | "s" => {
+                let hart = if command == "s" { Some(self.step_hart) } else { None };
+                if !arguments.is_empty() {
+                    match u64::from_str_radix(arguments, 16) {
+                        Ok(address) => self.cpu.harts[hart.unwrap_or(self.current_hart)].pc = address as usize,
+                        Err(_) => return Ok("E01".to_string())
+                    }
+                }
+                self.resume(hart)?
+            },
+            "v" => self.handle_v_packet(packet)?,
+            "q" => self.handle_query(packet),
+            _ => String::new()
+        };
+        Ok(reply)
+    }
+
+    fn handle_v_packet(&mut self, packet: &str) -> Result<String, GdbError> {
+        if packet == "vCont?" {
+            return Ok("vCont;c;C;s;S".to_string())
+        }
+        let actions = match packet.strip_prefix("vCont;") {
+            Some(x) => x,
+            None => return Ok(String::new())
+        };
+
+        // Only one hart runs when any is stepped, the others stay stopped.
+        let mut step = None;
+        for action in actions.split(';') {
+            let (action, thread) = match action.split_once(':') {
+                Some((action, thread)) => (action, parse_thread(thread, self.cpu.harts.len())),
+                None => (action, Some(None))
+            };
+            let thread = match thread {
+                Some(x) => x,
+                None => return Ok("E01".to_string())
+            };
+            if action.starts_with('s') || action.starts_with('S') {
+                step = step.or(Some(thread.unwrap_or(self.step_hart)));
+            }
+        }
+        self.resume(step)
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;\
+                            vContSupported+;QStartNoAckMode+", PACKET_SIZE)
+        }
+        if let Some(arguments) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_pair(arguments) {
+                Some((offset, length)) => {
+                    let description = target_description();
+                    let start = offset.min(description.len());
+                    let end = (start + length.min(PACKET_SIZE / 2)).min(description.len());
+                    let prefix = if end == description.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, escape(&description[start..end]))
+                },
+                None => "E01".to_string()
+            }
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => format!("QC{:x}", self.current_hart + 1),
+            "qfThreadInfo" => {
+                let threads: Vec<String> = (1..=self.cpu.harts.len()).map(|x| format!("{:x}", x)).collect();
+                format!("m{}", threads.join(","))
+            },
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new()
+        }
+    }
+
+    fn select_thread(&mut self, arguments: &str) -> String {
+        if arguments.is_empty() {
+            return "E01".to_string()
+        }
+        let (operation, thread) = arguments.split_at(1);
+        let hart = match parse_thread(thread, self.cpu.harts.len()) {
+            Some(hart) => hart,
+            None => return "E01".to_string()
+        };
+        match operation {
+            "g" => self.current_hart = hart.unwrap_or(self.current_hart),
+            "c" => self.step_hart = hart.unwrap_or(self.current_hart),
+            _ => return "E01".to_string()
+        }
+        "OK".to_string()
+    }
+
+    /// Continues all harts, or single-steps `step`, and returns the stop reply.
+    fn resume(&mut self, step: Option<usize>) -> Result<String, GdbError> {
+        let stop = match step {
+            Some(hart) => match self.cpu.harts[hart].step() {
+                Ok(reason) => Stop::Reason { hart, reason: reason.unwrap_or(StopReason::BudgetExhausted) },
+                Err(error) => self.report_error(CPUError::CoreError { hart, error })?
+            },
+            None => self.continue_all()?
+        };
+
+        let (hart, reply) = match stop {
+            Stop::Signal { hart, signal } => (hart, format!("T{:02x}thread:{:x};", signal, hart + 1)),
+            Stop::Reason { hart, reason } => (hart, self.format_stop_reason(hart, reason))
+        };
+        self.current_hart = hart;
+        self.step_hart = hart;
+        self.last_stop = reply.clone();
+        Ok(reply)
+    }
+
+    fn continue_all(&mut self) -> Result<Stop, GdbError> {
+        loop {
+            if self.poll_interrupt()? {
+                return Ok(Stop::Signal { hart: self.current_hart, signal: SIGINT })
+            }
+            match self.cpu.run(RESUME_CHUNK) {
+                Ok((_, StopReason::BudgetExhausted)) => {},
+                // Wait for an interrupt from a device, or the debugger.
+                Ok((_, StopReason::WaitForInterrupt)) => thread::sleep(Duration::from_millis(1)),
+                Ok((hart, reason)) => return Ok(Stop::Reason { hart, reason }),
+                Err(error) => return self.report_error(error)
+            }
+        }
+    }
+
+    /// Prints `error` on the debugger console, and stops as if the hart that caused it
+    /// received a signal.
+    fn report_error(&mut self, error: CPUError) -> Result<Stop, GdbError> {
+        let message = format!("{}\n", error);
+        self.send_packet(&format!("O{}", encode_hex(message.as_bytes())))?;
+        Ok(match error {
+            CPUError::CoreError { hart, .. } => Stop::Signal { hart, signal: SIGILL },
+            _ => Stop::Signal { hart: self.current_hart, signal: SIGABRT }
+        })
+    }
+
+    fn format_stop_reason(&self, hart: usize, reason: StopReason) -> String {
+        let thread = hart + 1;
+        match reason {
+            StopReason::Breakpoint { address } => {
+                let kind = if self.software_breakpoints.contains(&address) { "swbreak" } else { "hwbreak" };
+                format!("T{:02x}thread:{:x};{}:;", SIGTRAP, thread, kind)
+            },
+            StopReason::Watchpoint { address, kind } => {
+                let kind = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch"
+                };
+                format!("T{:02x}thread:{:x};{}:{:x};", SIGTRAP, thread, kind, address)
+            },
+            StopReason::Exit { code } => format!("W{:02x}", code & 0xFF),
+            StopReason::Trap { exception, .. } => {
+                let signal = match exception {
+                    Exception::IllegalInstruction => SIGILL,
+                    Exception::InstructionAddressMisaligned |
+                    Exception::LoadAddressMisaligned |
+                    Exception::StoreAddressMisaligned => SIGBUS,
+                    Exception::InstructionAccessFault |
+                    Exception::LoadAccessFault |
+                    Exception::StoreAccessFault |
+                    Exception::InstructionPageFault |
+                    Exception::LoadPageFault |
+                    Exception::StorePageFault => SIGSEGV,
+                    Exception::Breakpoint | Exception::EnvironmentCallFromUMode |
+                    Exception::EnvironmentCallFromSMode | Exception::EnvironmentCallFromMMode => SIGTRAP
+                };
+                format!("T{:02x}thread:{:x};", signal, thread)
+            },
+            StopReason::BudgetExhausted | StopReason::Condition | StopReason::WaitForInterrupt =>
+                format!("T{:02x}thread:{:x};", SIGTRAP, thread)
+        }
+    }
+
+    /// Returns true if the debugger sent an interrupt. Other bytes are kept for
+    /// `read_packet`.
+    fn poll_interrupt(&mut self) -> Result<bool, GdbError> {
+        let mut bytes = [0; 256];
+        self.connection.set_nonblocking(true)?;
+        let result = self.connection.read(&mut bytes);
+        self.connection.set_nonblocking(false)?;
+        match result {
+            // The debugger is gone, stop so that `serve` notices.
+            Ok(0) => Ok(true),
+            Ok(length) => {
+                let bytes = &bytes[..length];
+                self.received.extend(bytes.iter().filter(|x| **x != INTERRUPT));
+                Ok(bytes.contains(&INTERRUPT))
+            },
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error.into())
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let mut reply = String::new();
+        for register in 0..=REGISTER_PC {
+            if let Some(value) = self.get_register(register) {
+                reply.push_str(&value);
+            }
+        }
+        reply
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> String {
+        let bytes = match decode_hex(arguments) {
+            Some(x) if x.len() >= 8 * (REGISTER_PC + 1) => x,
+            _ => return "E01".to_string()
+        };
+        for (register, value) in bytes.chunks(8).take(REGISTER_PC + 1).enumerate() {
+            self.set_register(register, value);
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, arguments: &str) -> String {
+        usize::from_str_radix(arguments, 16).ok()
+            .and_then(|register| self.get_register(register))
+            .unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn write_register(&mut self, arguments: &str) -> String {
+        let (register, value) = match arguments.split_once('=') {
+            Some(x) => x,
+            None => return "E01".to_string()
+        };
+        match (usize::from_str_radix(register, 16), decode_hex(value)) {
+            (Ok(register), Some(value)) if self.set_register(register, &value) => "OK".to_string(),
+            _ => "E01".to_string()
+        }
+    }
+
+    /// Register `register` of the current hart, in target byte order.
+    fn get_register(&self, register: usize) -> Option<String> {
+        let core = &self.cpu.harts[self.current_hart];
+        let (value, size) = match register {
+            0..=31 => (core.x_registers[XRegister::from(register as u32)], 8),
+            REGISTER_PC => (core.pc as u64, 8),
+            REGISTER_F0..=64 => (core.f_registers[FRegister::from((register - REGISTER_F0) as u32)].to_bits(), 8),
+            _ if register < REGISTER_CSR0 + 4096 => {
+                let csr = (register - REGISTER_CSR0) as u16;
+                let size = if FLOAT_CSRS.iter().any(|(x, _)| *x == csr) { 4 } else { 8 };
+                (core.csr_registers[csr], size)
+            },
+            _ => return None
+        };
+        Some(encode_hex(&value.to_le_bytes()[..size]))
+    }
+
+    /// Sets `register` of the current hart from bytes in target byte order. Returns
+    /// false if there's no such register.
+    fn set_register(&mut self, register: usize, bytes: &[u8]) -> bool {
+        let mut value = [0; 8];
+        let length = bytes.len().min(8);
+        value[..length].copy_from_slice(&bytes[..length]);
+        let value = u64::from_le_bytes(value);
+
+        let core = &mut self.cpu.harts[self.current_hart];
+        match register {
+            0 => {},
+            1..=31 => core.x_registers[XRegister::from(register as u32)] = value,
+            REGISTER_PC => core.pc = value as usize,
+            REGISTER_F0..=64 => core.f_registers[FRegister::from((register - REGISTER_F0) as u32)] = f64::from_bits(value),
+            _ if register < REGISTER_CSR0 + 4096 => core.csr_registers[(register - REGISTER_CSR0) as u16] = value,
+            _ => return false
+        }
+        true
+    }
+
+    /// Reads through the bus, one byte at a time if the whole range can't be read at
+    /// once. Returns the bytes up to the first that can't be read.
+    fn read_memory(&self, arguments: &str) -> String {
+        let (address, length) = match parse_pair(arguments) {
+            Some((address, length)) => (address, length.min(PACKET_SIZE / 2)),
+            None => return "E01".to_string()
+        };
+        let bus = self.cpu.bus.lock();
+        let bytes = match bus.read_bytes(address, length) {
+            Ok(bytes) => bytes.into_owned(),
+            Err(_) => (0..length)
+                .map_while(|i| bus.read_bytes(address.wrapping_add(i), 1).ok().map(|x| x[0]))
+                .collect()
+        };
+        if bytes.is_empty() && length > 0 {
+            return "E01".to_string()
+        }
+        encode_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let (range, data) = match arguments.split_once(':') {
+            Some(x) => x,
+            None => return "E01".to_string()
+        };
+        let (address, bytes) = match (parse_pair(range), decode_hex(data)) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length => (address, bytes),
+            _ => return "E01".to_string()
+        };
+        let mut bus = self.cpu.bus.lock();
+        if bus.write_bytes(address, &bytes).is_err() {
+            for (i, byte) in bytes.iter().enumerate() {
+                if bus.write_bytes(address.wrapping_add(i), &[*byte]).is_err() {
+                    return "E01".to_string()
+                }
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Handles `Z` and `z` packets, setting breakpoints and watchpoints on all harts.
+    fn update_breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.splitn(3, ',');
+        let kind = fields.next();
+        let address = fields.next().and_then(|x| usize::from_str_radix(x, 16).ok());
+        let size = fields.next().and_then(|x| usize::from_str_radix(x.split(';').next()?, 16).ok());
+        let (kind, address, size) = match (kind, address, size) {
+            (Some(kind), Some(address), Some(size)) => (kind, address, size),
+            _ => return "E01".to_string()
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                let breakpoints = if kind == "0" {
+                    &mut self.software_breakpoints
+                } else {
+                    &mut self.hardware_breakpoints
+                };
+                if insert { breakpoints.insert(address); } else { breakpoints.remove(&address); }
+                let set = self.software_breakpoints.contains(&address) ||
+                    self.hardware_breakpoints.contains(&address);
+                for core in &mut self.cpu.harts {
+                    if set { core.add_breakpoint(address); } else { core.remove_breakpoint(address); }
+                }
+                return "OK".to_string()
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new()
+        };
+
+        let watchpoint = (address, size, watch_kind);
+        let position = self.watchpoints.iter().position(|x| *x == watchpoint);
+        match (insert, position) {
+            (true, None) => {
+                self.watchpoints.push(watchpoint);
+                for core in &mut self.cpu.harts {
+                    core.add_watchpoint(address, size, watch_kind);
+                }
+            },
+            (false, Some(i)) => {
+                self.watchpoints.remove(i);
+                for core in &mut self.cpu.harts {
+                    core.remove_watchpoint(address, size, watch_kind);
+                }
+            },
+            _ => {}
+        }
+        "OK".to_string()
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, GdbError> {
+        if self.received.is_empty() {
+            let mut bytes = [0; 4096];
+            let length = loop {
+                match self.connection.read(&mut bytes) {
+                    Ok(length) => break length,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {},
+                    Err(error) => return Err(error.into())
+                }
+            };
+            self.received.extend(&bytes[..length]);
+        }
+        Ok(self.received.pop_front())
+    }
+
+    /// Returns the next packet with a valid checksum, or `None` once the debugger
+    /// disconnects. Acknowledges packets unless acknowledgments were turned off.
+    fn read_packet(&mut self) -> Result<Option<Vec<u8>>, GdbError> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                Some(b'-') if !self.no_ack => {
+                    let packet = self.last_sent.clone();
+                    self.connection.write_all(&packet)?;
+                    continue
+                },
+                // Acknowledgments and interrupts while the target is stopped.
+                Some(_) => continue
+            }
+
+            let mut packet = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => packet.push(byte)
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in &mut checksum {
+                *digit = match self.read_byte()? {
+                    Some(x) => x,
+                    None => return Ok(None)
+                };
+            }
+
+            let expected = std::str::from_utf8(&checksum).ok()
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            if self.no_ack {
+                return Ok(Some(unescape(&packet)))
+            }
+            if expected == Some(get_checksum(&packet)) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(unescape(&packet)))
+            }
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    /// Waits for the debugger to acknowledge the last packet, so it isn't writing to a
+    /// closed connection when the session ends. Resends the packet if it's rejected.
+    fn wait_for_ack(&mut self) -> Result<(), GdbError> {
+        if self.no_ack {
+            return Ok(())
+        }
+        loop {
+            match self.read_byte()? {
+                None | Some(b'+') => return Ok(()),
+                Some(b'-') => {
+                    let packet = self.last_sent.clone();
+                    self.connection.write_all(&packet)?;
+                },
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), GdbError> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data.as_bytes());
+        packet.extend_from_slice(format!("#{:02x}", get_checksum(data.as_bytes())).as_bytes());
+        self.connection.write_all(&packet)?;
+        self.connection.flush()?;
+        self.last_sent = packet;
+        Ok(())
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Parses a thread id. Returns `Some(None)` for "any thread".
+fn parse_thread(thread: &str, hart_count: usize) -> Option<Option<usize>> {
+    match thread {
+        "-1" | "0" => Some(None),
+        _ => match usize::from_str_radix(thread, 16) {
+            Ok(thread) if (1..=hart_count).contains(&thread) => Some(Some(thread - 1)),
+            _ => None
+        }
+    }
+}
+
+/// Parses "address,length" in hexadecimal.
+fn parse_pair(arguments: &str) -> Option<(usize, usize)> {
+    let (first, second) = arguments.split_once(',')?;
+    Some((usize::from_str_radix(first, 16).ok()?, usize::from_str_radix(second, 16).ok()?))
+}
+
+fn get_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, x| sum.wrapping_add(*x))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Escapes the characters with a special meaning in packets.
+fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '#' | '$' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            },
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn unescape(packet: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(packet.len());
+    let mut bytes = packet.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => if let Some(next) = bytes.next() { unescaped.push(next ^ 0x20) },
+            _ => unescaped.push(*byte)
+        }
+    }
+    unescaped
+}
+
+/// Target description, with the registers in GDB's numbering.
+fn target_description() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n\
+        <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+        <target version=\"1.0\">\n\
+        <architecture>riscv:rv64</architecture>\n\
+        <feature name=\"org.gnu.gdb.riscv.cpu\">\n");
+    for (i, name) in X_REGISTER_NAMES.iter().enumerate() {
+        let _ = writeln!(xml, "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>", name, i);
+    }
+    let _ = writeln!(xml, "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>", REGISTER_PC);
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.riscv.fpu\">\n");
+    for i in 0..32 {
+        let _ = writeln!(xml, "<reg name=\"f{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>",
+                         i, REGISTER_F0 + i);
+    }
+    for (csr, name) in FLOAT_CSRS.iter() {
+        let _ = writeln!(xml, "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>",
+                         name, REGISTER_CSR0 + *csr as usize);
+    }
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.riscv.csr\">\n");
+    for (csr, name) in CSR_NAMES.iter() {
+        let _ = writeln!(xml, "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>",
+                         name, REGISTER_CSR0 + *csr as usize);
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+impl Display for GdbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for GdbError {}
+
+impl From<io::Error> for GdbError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
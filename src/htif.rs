@@ -0,0 +1,180 @@
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::trap::StopReason;
+use crate::device::{Device, DeviceError};
+use crate::elf::{ElfError, SymbolTable};
+use crate::endianness::Endianness;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+
+/// Instructions executed between polls of `tohost`, as in Spike.
+const POLL_INTERVAL: u64 = 5000;
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const EBADF: i64 = 9;
+const ENOSYS: i64 = 38;
+
+/// Words of the syscall proxy's argument block: the syscall number and 7 arguments.
+const SYSCALL_WORDS: usize = 8;
+
+#[derive(Debug)]
+pub enum HtifError {
+    IoError(io::Error),
+    DeviceError(DeviceError),
+    CPUError(CPUError),
+    ElfError(ElfError),
+    /// The program doesn't define a symbol HTIF needs.
+    MissingSymbol { name: String },
+}
+
+/// Host-target interface of Spike, through which the ISA test suites and proxy
+/// kernel programs exit, print and make system calls.
+///
+/// The target writes a command to `tohost`: the device in bits 63..56, the command in
+/// 55..48 and a payload below. The host clears `tohost`, handles the command, and for
+/// commands that wait for a reply, writes it to `fromhost`. The syscall device
+/// exits when the payload is odd, with code `payload >> 1`, and otherwise proxies
+/// the system call whose number and arguments are in the 8 words at `payload`,
+/// storing the result in the first word. The console device prints the low byte.
+///
+/// `tohost` usually sits in DRAM, so like Spike, the host polls it between runs of
+/// `POLL_INTERVAL` instructions rather than mapping it as a device.
+#[derive(Debug, Clone)]
+pub struct Htif {
+    tohost: usize,
+    fromhost: Option<usize>,
+    /// Printed by the target and not taken yet.
+    output: Vec<u8>,
+}
+
+impl Htif {
+    pub fn new(tohost: usize, fromhost: Option<usize>) -> Self {
+        Self { tohost, fromhost, output: Vec::new() }
+    }
+
+    /// Finds `tohost` and `fromhost` in the symbols of the program, if it uses HTIF.
+    pub fn from_symbols(symbols: &SymbolTable) -> Option<Self> {
+        let tohost = symbols.find("tohost")?.address;
+        Some(Self::new(tohost, symbols.find("fromhost").map(|symbol| symbol.address)))
+    }
+
+    /// Runs `cpu` for up to `max_instructions`, handling the commands written to
+    /// `tohost`. Returns `StopReason::Exit` when the program exits through HTIF.
+    pub fn run(&mut self, cpu: &mut CPU, max_instructions: u64) -> Result<StopReason, HtifError> {
+        let mut executed = 0;
+        while executed < max_instructions {
+            let budget = POLL_INTERVAL.min(max_instructions - executed);
+            let (_, reason) = cpu.run(budget)?;
+            executed += budget;
+            if let Some(code) = self.poll(&mut *cpu.bus.lock())? {
+                return Ok(StopReason::Exit { code })
+            }
+            if reason != StopReason::BudgetExhausted {
+                return Ok(reason)
+            }
+        }
+        Ok(StopReason::BudgetExhausted)
+    }
+
+    /// Handles a command written to `tohost`, if any. Returns the exit code if the
+    /// program exited.
+    pub fn poll(&mut self, bus: &mut dyn Device) -> Result<Option<u64>, HtifError> {
+        let command = bus.read_int(self.tohost, 8, Endianness::LittleEndian, false)?;
+        if command == 0 {
+            return Ok(None)
+        }
+        bus.write_int(self.tohost, 0, 8, Endianness::LittleEndian)?;
+
+        let (device, code, payload) = (command >> 56, (command >> 48) & 0xFF, command & 0xFFFF_FFFF_FFFF);
+        match (device, code) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => return Ok(Some(payload >> 1)),
+            (DEVICE_SYSCALL, 0) => {
+                let mut arguments = [0; SYSCALL_WORDS];
+                for (i, argument) in arguments.iter_mut().enumerate() {
+                    *argument = bus.read_int(payload as usize + i * 8, 8, Endianness::LittleEndian, false)?;
+                }
+                if arguments[0] == SYS_EXIT {
+                    return Ok(Some(arguments[1]))
+                }
+                let result = self.syscall(bus, &arguments)?;
+                bus.write_int(payload as usize, result as u64, 8, Endianness::LittleEndian)?;
+            },
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => self.output.push(payload as u8),
+            // Other devices and commands, such as console input, are ignored.
+            _ => return Ok(None)
+        }
+        if let Some(fromhost) = self.fromhost {
+            bus.write_int(fromhost, (device << 56) | (code << 48) | 1, 8, Endianness::LittleEndian)?;
+        }
+        Ok(None)
+    }
+
+    /// Takes what the program printed since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn syscall(&mut self, bus: &mut dyn Device, arguments: &[u64; SYSCALL_WORDS]) -> Result<i64, HtifError> {
+        match arguments[0] {
+            SYS_WRITE if arguments[1] == 1 || arguments[1] == 2 => {
+                let data = bus.read_bytes(arguments[2] as usize, arguments[3] as usize)?;
+                self.output.extend_from_slice(&data);
+                Ok(arguments[3] as i64)
+            },
+            SYS_WRITE => Ok(-EBADF),
+            _ => Ok(-ENOSYS)
+        }
+    }
+}
+
+/// Writes the memory between the `begin_signature` and `end_signature` symbols as
+/// hexadecimal words of `granularity` bytes, one per line, as riscof expects. The
+/// granularity is 1 to 8 bytes, usually 4.
+pub fn write_signature(bus: &dyn Device, symbols: &SymbolTable, granularity: usize, writer: &mut dyn Write)
+        -> Result<(), HtifError> {
+    let find = |name: &str| symbols.find(name).map(|symbol| symbol.address)
+        .ok_or_else(|| HtifError::MissingSymbol { name: name.to_string() });
+    let (begin, end) = (find("begin_signature")?, find("end_signature")?);
+    for address in (begin..end).step_by(granularity) {
+        let value = bus.read_int(address, granularity, Endianness::LittleEndian, false)?;
+        writeln!(writer, "{:0width$x}", value, width = granularity * 2)?;
+    }
+    Ok(())
+}
+
+impl Display for HtifError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for HtifError {}
+
+impl From<io::Error> for HtifError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<DeviceError> for HtifError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
+
+impl From<CPUError> for HtifError {
+    fn from(error: CPUError) -> Self {
+        Self::CPUError(error)
+    }
+}
+
+impl From<ElfError> for HtifError {
+    fn from(error: ElfError) -> Self {
+        Self::ElfError(error)
+    }
+}
@@ -0,0 +1,85 @@
+use crate::clint::CLINT;
+use crate::cpu::cpu::CPU;
+use crate::cpu::trap::StopReason;
+use crate::dram::DRAM;
+use crate::elf::Elf;
+use crate::htif::{Htif, HtifError};
+use std::io;
+use std::path::Path;
+
+/// Instructions a test may run before it's reported as timed out.
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 10_000_000;
+
+// Memory map of Spike, which the test environments assume.
+const CLINT_ADDRESS: usize = 0x200_0000;
+const DRAM_ADDRESS: usize = 0x8000_0000;
+const DRAM_SIZE: usize = 64 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestOutcome {
+    Pass,
+    /// The test reported the number of the case that failed.
+    Fail { test: u64 },
+    Timeout,
+    /// The hart stopped without the test reporting a result, e.g. on a trap without
+    /// a handler.
+    Stopped(StopReason),
+    /// The test couldn't be loaded or run.
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+}
+
+/// Runs a test of riscv-tests or riscv-arch-test, which reports its result through
+/// HTIF: exit code 0 if it passes, or the number of the failed case.
+pub fn run_test(elf: &Elf, max_instructions: u64) -> Result<TestOutcome, HtifError> {
+    let mut htif = match Htif::from_symbols(&elf.symbols) {
+        Some(htif) => htif,
+        None => return Err(HtifError::MissingSymbol { name: "tohost".to_string() })
+    };
+    let mut cpu = CPU::new(vec![
+        (CLINT_ADDRESS, Box::new(CLINT::new(1))),
+        (DRAM_ADDRESS, Box::new(DRAM::new(DRAM_SIZE))),
+    ])?;
+    elf.load(&mut *cpu.bus.lock())?;
+    cpu.harts[0].pc = elf.entry;
+
+    Ok(match htif.run(&mut cpu, max_instructions)? {
+        StopReason::Exit { code: 0 } => TestOutcome::Pass,
+        StopReason::Exit { code } => TestOutcome::Fail { test: code },
+        StopReason::BudgetExhausted => TestOutcome::Timeout,
+        reason => TestOutcome::Stopped(reason)
+    })
+}
+
+/// Runs every ELF file in `directory`, such as the `rv64ui-p-*` and `rv64um-p-*`
+/// binaries of riscv-tests, in name order. Other files, like the `.dump`
+/// disassemblies, are skipped.
+pub fn run_tests(directory: &Path, max_instructions: u64) -> io::Result<Vec<TestResult>> {
+    let mut paths: Vec<_> = std::fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in paths {
+        if !path.is_file() {
+            continue
+        }
+        let data = std::fs::read(&path)?;
+        if !Elf::is_elf(&data) {
+            continue
+        }
+        let outcome = match Elf::parse(data) {
+            Ok(elf) => run_test(&elf, max_instructions).unwrap_or_else(|error| TestOutcome::Error(error.to_string())),
+            Err(error) => TestOutcome::Error(error.to_string())
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        results.push(TestResult { name, outcome });
+    }
+    Ok(results)
+}
@@ -0,0 +1,118 @@
+use crate::cpu::cpu::CPU;
+use crate::device::{Device, DeviceError};
+use crate::dram::DRAM;
+use crate::endianness::Endianness;
+use crate::utilities::int_from_bytes;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+
+const HEADER_SIZE: usize = 64;
+const TEXT_OFFSET_OFFSET: usize = 8;
+const IMAGE_SIZE_OFFSET: usize = 16;
+const MAGIC2_OFFSET: usize = 56;
+const MAGIC2: &[u8] = b"RSC\x05";
+/// Offset of the kernel in memory when the header leaves it 0, as for old kernels.
+const DEFAULT_TEXT_OFFSET: usize = 0x20_0000;
+/// Most memory between the kernel and the initramfs, as QEMU places it.
+const MAX_INITRD_OFFSET: usize = 128 << 20;
+const INITRD_ALIGNMENT: usize = 0x1000;
+
+#[derive(Debug)]
+pub enum KernelError {
+    IoError(io::Error),
+    DeviceError(DeviceError),
+    /// The file doesn't start with the header of a RISC-V `Image`.
+    InvalidHeader,
+    NoMemory,
+    /// The kernel or the initramfs doesn't fit in the first DRAM device.
+    TooLarge { size: usize },
+}
+
+/// A RISC-V Linux kernel `Image`, which is loaded `text_offset` bytes into memory and
+/// entered at its start with the hart id in `a0` and the device tree in `a1`.
+#[derive(Debug, Clone)]
+pub struct KernelImage {
+    data: Vec<u8>,
+    pub text_offset: usize,
+    /// Memory the kernel occupies, including its uninitialized data.
+    pub image_size: usize,
+}
+
+impl KernelImage {
+    /// Whether `data` starts with the header of an `Image`.
+    pub fn is_image(data: &[u8]) -> bool {
+        data.len() >= HEADER_SIZE && &data[MAGIC2_OFFSET..MAGIC2_OFFSET + 4] == MAGIC2
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self, KernelError> {
+        if !Self::is_image(&data) {
+            return Err(KernelError::InvalidHeader)
+        }
+        let field = |offset: usize| int_from_bytes(&data[offset..offset + 8], Endianness::LittleEndian, false) as usize;
+        let text_offset = match field(TEXT_OFFSET_OFFSET) {
+            0 => DEFAULT_TEXT_OFFSET,
+            offset => offset
+        };
+        let image_size = field(IMAGE_SIZE_OFFSET).max(data.len());
+        Ok(Self { data, text_offset, image_size })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KernelError> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Address of the kernel in a machine whose memory starts at `memory_start`.
+    pub fn get_entry(&self, memory_start: usize) -> usize {
+        memory_start + self.text_offset
+    }
+
+    /// Loads the kernel into the first DRAM device of `cpu`, and the initramfs after
+    /// it, halfway through the memory or 128 MiB past the kernel, whichever is lower.
+    /// The command line and initramfs are passed in the device tree, which is loaded
+    /// afterwards with `CPU::load_fdt`. Returns the entry address.
+    pub fn load(&self, cpu: &mut CPU, initrd: Option<&[u8]>, bootargs: Option<&str>) -> Result<usize, KernelError> {
+        let memory = cpu.bus.lock().find_devices::<DRAM>().first().cloned().ok_or(KernelError::NoMemory)?;
+        let entry = self.get_entry(memory.start);
+        if entry + self.image_size > memory.end {
+            return Err(KernelError::TooLarge { size: self.image_size })
+        }
+        cpu.bus.lock().write_bytes(entry, &self.data)?;
+
+        if let Some(initrd) = initrd {
+            let kernel_end = entry + self.image_size;
+            let start = (kernel_end.max(memory.start + memory.len() / 2).min(kernel_end + MAX_INITRD_OFFSET)
+                + INITRD_ALIGNMENT - 1) & !(INITRD_ALIGNMENT - 1);
+            if start + initrd.len() > memory.end {
+                return Err(KernelError::TooLarge { size: initrd.len() })
+            }
+            cpu.bus.lock().write_bytes(start, initrd)?;
+            cpu.set_initrd(start..start + initrd.len());
+        }
+        if let Some(bootargs) = bootargs {
+            cpu.set_bootargs(bootargs);
+        }
+        Ok(entry)
+    }
+}
+
+impl Display for KernelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for KernelError {}
+
+impl From<io::Error> for KernelError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<DeviceError> for KernelError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
@@ -10,11 +10,29 @@ extern crate assert_hex;
 extern crate num;
 
 pub mod cpu;
-mod bus;
-mod device;
-mod dram;
+pub mod bus;
+pub mod device;
+pub mod dram;
 mod test;
-mod endianness;
-mod uart;
+pub mod endianness;
+pub mod uart;
+pub mod fdt;
+pub mod boot_rom;
+pub mod flash;
+pub mod finisher;
+pub mod clint;
+pub mod plic;
+pub mod snapshot;
+pub mod gdb;
+pub mod elf;
+pub mod monitor;
+pub mod htif;
+pub mod isa_tests;
+pub mod linux;
+pub mod semihosting;
+pub mod sbi;
+pub mod kernel;
+pub mod virtio;
+pub mod virtio_blk;
 mod utilities;
 mod bits;
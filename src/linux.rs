@@ -0,0 +1,564 @@
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::register::XRegister;
+use crate::cpu::trap::{Exception, StopReason};
+use crate::device::{Device, DeviceError};
+use crate::dram::{DRAM, PAGE_SIZE};
+use crate::elf::{Elf, ElfError};
+use crate::endianness::Endianness;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Address space of the process. The first page isn't mapped, so null pointers fault.
+const MEMORY_START: usize = PAGE_SIZE;
+const MEMORY_END: usize = 1 << 32;
+const STACK_TOP: usize = 0xF000_0000;
+/// Mappings are allocated downwards from here, leaving 8 MiB for the stack.
+const MMAP_TOP: usize = STACK_TOP - (8 << 20);
+
+// System call numbers of RV64 Linux.
+const SYS_IOCTL: u64 = 29;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_WRITEV: u64 = 66;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETPID: u64 = 172;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_GETRANDOM: u64 = 278;
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const O_ACCMODE: u64 = 0b11;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_CREAT: u64 = 0x40;
+const O_EXCL: u64 = 0x80;
+const O_TRUNC: u64 = 0x200;
+const O_APPEND: u64 = 0x400;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const CLOCK_REALTIME: u64 = 0;
+const S_IFCHR: u32 = 0o020000;
+const STAT_SIZE: usize = 128;
+const UTSNAME_FIELD_SIZE: usize = 65;
+/// Longest path read from the process.
+const PATH_MAX: usize = 4096;
+/// Most bytes a single `read` or `getrandom` returns.
+const MAX_TRANSFER: usize = 1 << 20;
+
+// Auxiliary vector entries.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+const PROGRAM_HEADER_SIZE: u64 = 56;
+/// `AT_HWCAP` bits of the I, M and A extensions, one per letter from bit 0 for A.
+const HWCAP: u64 = 1 << (b'i' - b'a') | 1 << (b'm' - b'a') | 1;
+
+#[derive(Debug)]
+pub enum LinuxError {
+    IoError(io::Error),
+    DeviceError(DeviceError),
+    CPUError(CPUError),
+    ElfError(ElfError),
+    /// The arguments, environment and auxiliary vector don't fit on the stack.
+    StackOverflow,
+}
+
+/// An open file of the process.
+pub enum FileDescriptor {
+    Input(Box<dyn Read + Send>),
+    Output(Box<dyn Write + Send>),
+    File(File),
+}
+
+/// A static Linux RV64 executable running in user mode, like under qemu-user.
+///
+/// The process runs in M-mode with `mtvec` clear, so `ecall` stops the hart with a
+/// trap, and `run` services the system call on the host before resuming past it.
+/// Files are host files, and standard input and output are the host's unless
+/// replaced with `set_file`. Unsupported system calls return `-ENOSYS`.
+pub struct Process {
+    files: HashMap<u64, FileDescriptor>,
+    /// Start and current end of the heap.
+    brk_start: usize,
+    brk: usize,
+    /// Highest end of the heap so far. Memory below it is zeroed when reused.
+    brk_max: usize,
+    /// Lowest address mapped so far, and the lowest address of the current mappings.
+    mmap_lowest: usize,
+    mmap_next: usize,
+    start_time: Instant,
+    random: RandomState,
+    random_counter: u64,
+}
+
+impl Process {
+    /// Creates a hart with the address space of a process running `elf` with the
+    /// arguments `args`, the first of which is the program name, and the
+    /// environment `env` of `NAME=value` strings.
+    pub fn new(elf: &Elf, args: &[String], env: &[String]) -> Result<(CPU, Process), LinuxError> {
+        let mut cpu = CPU::new(vec![(MEMORY_START, Box::new(DRAM::new(MEMORY_END - MEMORY_START)))])?;
+        elf.load(&mut *cpu.bus.lock())?;
+
+        let end = elf.segments.iter().map(|segment| segment.address + segment.memory_size).max().unwrap_or(0);
+        let brk_start = end.next_multiple_of(PAGE_SIZE);
+        let mut process = Process {
+            files: HashMap::new(),
+            brk_start,
+            brk: brk_start,
+            brk_max: brk_start,
+            mmap_lowest: MMAP_TOP,
+            mmap_next: MMAP_TOP,
+            start_time: Instant::now(),
+            random: RandomState::new(),
+            random_counter: 0,
+        };
+        process.files.insert(0, FileDescriptor::Input(Box::new(io::stdin())));
+        process.files.insert(1, FileDescriptor::Output(Box::new(io::stdout())));
+        process.files.insert(2, FileDescriptor::Output(Box::new(io::stderr())));
+
+        let sp = process.build_stack(&mut *cpu.bus.lock(), elf, args, env)?;
+        let core = &mut cpu.harts[0];
+        core.x_registers[XRegister::x2] = sp as u64;
+        core.pc = elf.entry;
+        Ok((cpu, process))
+    }
+
+    /// Replaces the file open as `fd`, e.g. to capture the standard output.
+    pub fn set_file(&mut self, fd: u64, file: FileDescriptor) {
+        self.files.insert(fd, file);
+    }
+
+    /// Runs the process for up to `max_instructions`, servicing its system calls.
+    /// Returns `StopReason::Exit` when it exits.
+    pub fn run(&mut self, cpu: &mut CPU, max_instructions: u64) -> Result<StopReason, LinuxError> {
+        let start = cpu.harts[0].instructions_retired;
+        loop {
+            let executed = cpu.harts[0].instructions_retired - start;
+            if executed >= max_instructions {
+                return Ok(StopReason::BudgetExhausted)
+            }
+            match cpu.run(max_instructions - executed)?.1 {
+                StopReason::Trap { exception: Exception::EnvironmentCallFromMMode, pc, .. } => {
+                    if let Some(code) = self.syscall(cpu)? {
+                        return Ok(StopReason::Exit { code })
+                    }
+                    cpu.harts[0].pc = pc + 4;
+                },
+                reason => return Ok(reason)
+            }
+        }
+    }
+
+    /// Writes the strings, argument and environment pointers and auxiliary vector on
+    /// the stack as the psABI specifies, and returns the stack pointer.
+    fn build_stack(&mut self, bus: &mut dyn Device, elf: &Elf, args: &[String], env: &[String])
+            -> Result<usize, LinuxError> {
+        let mut sp = STACK_TOP;
+        let mut push_bytes = |bus: &mut dyn Device, bytes: &[u8]| -> Result<usize, DeviceError> {
+            sp -= bytes.len();
+            bus.write_bytes(sp, bytes)?;
+            Ok(sp)
+        };
+        let mut push_string = |bus: &mut dyn Device, string: &str| {
+            let mut bytes = string.as_bytes().to_vec();
+            bytes.push(0);
+            push_bytes(bus, &bytes)
+        };
+        let arg_pointers = args.iter().map(|arg| push_string(bus, arg)).collect::<Result<Vec<_>, _>>()?;
+        let env_pointers = env.iter().map(|var| push_string(bus, var)).collect::<Result<Vec<_>, _>>()?;
+        let execfn = push_string(bus, args.first().map_or("", |arg| arg.as_str()))?;
+        let mut random = [0; 16];
+        self.fill_random(&mut random);
+        let random = push_bytes(bus, &random)?;
+
+        let (program_headers, program_header_count) = elf.get_program_headers().unwrap_or((0, 0));
+        let auxv = [
+            (AT_PHDR, program_headers as u64), (AT_PHENT, PROGRAM_HEADER_SIZE),
+            (AT_PHNUM, program_header_count as u64), (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_ENTRY, elf.entry as u64), (AT_UID, 0), (AT_EUID, 0), (AT_GID, 0), (AT_EGID, 0),
+            (AT_HWCAP, HWCAP), (AT_CLKTCK, 100), (AT_RANDOM, random as u64),
+            (AT_EXECFN, execfn as u64), (AT_NULL, 0),
+        ];
+        let mut words = vec![args.len() as u64];
+        words.extend(arg_pointers.iter().map(|pointer| *pointer as u64));
+        words.push(0);
+        words.extend(env_pointers.iter().map(|pointer| *pointer as u64));
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+
+        let sp = (sp - words.len() * 8) & !0xF;
+        if sp < MMAP_TOP {
+            return Err(LinuxError::StackOverflow)
+        }
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        bus.write_bytes(sp, &bytes)?;
+        Ok(sp)
+    }
+
+    /// Services the system call of the hart. Returns the exit code if the process exits.
+    fn syscall(&mut self, cpu: &mut CPU) -> Result<Option<u64>, LinuxError> {
+        let core = &cpu.harts[0];
+        let number = core.x_registers[XRegister::x17];
+        let a = [XRegister::x10, XRegister::x11, XRegister::x12, XRegister::x13, XRegister::x14, XRegister::x15]
+            .map(|register| core.x_registers[register]);
+        if number == SYS_EXIT || number == SYS_EXIT_GROUP {
+            return Ok(Some(a[0] & 0xFF))
+        }
+
+        let mut bus = cpu.bus.lock();
+        let bus: &mut dyn Device = &mut *bus;
+        let result = match number {
+            SYS_READ => self.read(bus, a[0], a[1] as usize, a[2] as usize),
+            SYS_WRITE => self.write(bus, a[0], a[1] as usize, a[2] as usize),
+            SYS_WRITEV => self.writev(bus, a[0], a[1] as usize, a[2] as usize),
+            SYS_OPENAT => self.openat(bus, a[0] as i64, a[1] as usize, a[2]),
+            SYS_CLOSE => Ok(if self.files.remove(&a[0]).is_some() { 0 } else { -EBADF }),
+            SYS_LSEEK => Ok(self.lseek(a[0], a[1] as i64, a[2])),
+            SYS_FSTAT => self.fstat(bus, a[0], a[1] as usize),
+            SYS_BRK => Ok(self.brk(bus, a[0] as usize)),
+            SYS_MMAP => self.mmap(bus, a[0] as usize, a[1] as usize, a[3], a[4], a[5]),
+            SYS_MUNMAP => Ok(self.munmap(a[0] as usize, a[1] as usize)),
+            SYS_CLOCK_GETTIME => self.clock_gettime(bus, a[0], a[1] as usize),
+            SYS_GETRANDOM => {
+                let mut buffer = vec![0; (a[1] as usize).min(MAX_TRANSFER)];
+                self.fill_random(&mut buffer);
+                bus.write_bytes(a[0] as usize, &buffer).map(|_| buffer.len() as i64)
+            },
+            SYS_UNAME => Self::uname(bus, a[0] as usize),
+            SYS_IOCTL => Ok(if self.files.contains_key(&a[0]) { -ENOTTY } else { -EBADF }),
+            SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_SET_TID_ADDRESS => Ok(1),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK | SYS_MPROTECT => Ok(0),
+            _ => Ok(-ENOSYS)
+        };
+        // Bad pointers fail the call rather than the emulator.
+        let result = result.unwrap_or(-EFAULT);
+        cpu.harts[0].x_registers[XRegister::x10] = result as u64;
+        Ok(None)
+    }
+
+    fn read(&mut self, bus: &mut dyn Device, fd: u64, address: usize, size: usize) -> Result<i64, DeviceError> {
+        let mut buffer = vec![0; size.min(MAX_TRANSFER)];
+        let count = match self.files.get_mut(&fd) {
+            Some(FileDescriptor::Input(input)) => input.read(&mut buffer),
+            Some(FileDescriptor::File(file)) => file.read(&mut buffer),
+            _ => return Ok(-EBADF)
+        };
+        match count {
+            Ok(count) => {
+                bus.write_bytes(address, &buffer[..count])?;
+                Ok(count as i64)
+            },
+            Err(error) => Ok(errno(&error))
+        }
+    }
+
+    fn write(&mut self, bus: &mut dyn Device, fd: u64, address: usize, size: usize) -> Result<i64, DeviceError> {
+        let data = bus.read_bytes(address, size)?;
+        let result = match self.files.get_mut(&fd) {
+            Some(FileDescriptor::Output(output)) => output.write_all(&data).and_then(|_| output.flush()),
+            Some(FileDescriptor::File(file)) => file.write_all(&data),
+            _ => return Ok(-EBADF)
+        };
+        Ok(result.map_or_else(|error| errno(&error), |_| size as i64))
+    }
+
+    fn writev(&mut self, bus: &mut dyn Device, fd: u64, vectors: usize, count: usize) -> Result<i64, DeviceError> {
+        let mut total = 0;
+        for i in 0..count {
+            let base = bus.read_int(vectors + i * 16, 8, Endianness::LittleEndian, false)?;
+            let size = bus.read_int(vectors + i * 16 + 8, 8, Endianness::LittleEndian, false)?;
+            let written = self.write(bus, fd, base as usize, size as usize)?;
+            if written < 0 {
+                return Ok(written)
+            }
+            total += written;
+        }
+        Ok(total)
+    }
+
+    fn openat(&mut self, bus: &mut dyn Device, dirfd: i64, path: usize, flags: u64) -> Result<i64, DeviceError> {
+        let path = read_string(bus, path)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Ok(-EBADF)
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true)
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 { options.create_new(true) } else { options.create(true) };
+        }
+        match options.open(&path) {
+            Ok(file) => {
+                let fd = (0..).find(|fd| !self.files.contains_key(fd)).unwrap_or_default();
+                self.files.insert(fd, FileDescriptor::File(file));
+                Ok(fd as i64)
+            },
+            Err(error) => Ok(errno(&error))
+        }
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> i64 {
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -EINVAL
+        };
+        match self.files.get_mut(&fd) {
+            Some(FileDescriptor::File(file)) => file.seek(position).map_or_else(|error| errno(&error), |offset| offset as i64),
+            Some(_) => -ESPIPE,
+            None => -EBADF
+        }
+    }
+
+    /// Writes a `struct stat` for `fd`, with the fields the host provides.
+    fn fstat(&mut self, bus: &mut dyn Device, fd: u64, address: usize) -> Result<i64, DeviceError> {
+        let mut stat = [0u8; STAT_SIZE];
+        let mut put = |offset: usize, value: u64, size: usize| {
+            stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        };
+        match self.files.get(&fd) {
+            Some(FileDescriptor::File(file)) => {
+                let metadata = match file.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(error) => return Ok(errno(&error))
+                };
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    put(0, metadata.dev(), 8);
+                    put(8, metadata.ino(), 8);
+                    put(16, metadata.mode() as u64, 4);
+                    put(20, metadata.nlink(), 4);
+                    put(24, metadata.uid() as u64, 4);
+                    put(28, metadata.gid() as u64, 4);
+                    put(32, metadata.rdev(), 8);
+                    put(56, metadata.blksize(), 4);
+                    put(64, metadata.blocks(), 8);
+                    put(72, metadata.atime() as u64, 8);
+                    put(88, metadata.mtime() as u64, 8);
+                    put(104, metadata.ctime() as u64, 8);
+                }
+                #[cfg(not(unix))]
+                put(16, if metadata.is_dir() { 0o040755 } else { 0o100644 }, 4);
+                put(48, metadata.len(), 8);
+            },
+            Some(_) => {
+                put(16, (S_IFCHR | 0o620) as u64, 4);
+                put(20, 1, 4);
+                put(56, 1024, 4);
+            },
+            None => return Ok(-EBADF)
+        }
+        bus.write_bytes(address, &stat)?;
+        Ok(0)
+    }
+
+    fn brk(&mut self, bus: &mut dyn Device, address: usize) -> i64 {
+        if address >= self.brk_start && address <= self.mmap_next {
+            // Memory freed by shrinking the heap is zero again when it grows back.
+            let reused = self.brk..address.min(self.brk_max);
+            if !reused.is_empty() && bus.write_bytes(reused.start, &vec![0; reused.len()]).is_err() {
+                return self.brk as i64
+            }
+            self.brk = address;
+            self.brk_max = self.brk_max.max(address);
+        }
+        self.brk as i64
+    }
+
+    fn mmap(&mut self, bus: &mut dyn Device, address: usize, size: usize, flags: u64, fd: u64, offset: u64)
+            -> Result<i64, DeviceError> {
+        if size == 0 {
+            return Ok(-EINVAL)
+        }
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let start = if flags & MAP_FIXED != 0 {
+            if !address.is_multiple_of(PAGE_SIZE) || address < MEMORY_START || address + size > STACK_TOP {
+                return Ok(-EINVAL)
+            }
+            bus.write_bytes(address, &vec![0; size])?;
+            address
+        } else {
+            let start = match self.mmap_next.checked_sub(size) {
+                Some(start) if start >= self.brk => start,
+                _ => return Ok(-ENOMEM)
+            };
+            // Only memory that was mapped before may hold data.
+            if start + size > self.mmap_lowest {
+                let reused = self.mmap_lowest.max(start)..start + size;
+                bus.write_bytes(reused.start, &vec![0; reused.len()])?;
+            }
+            self.mmap_next = start;
+            self.mmap_lowest = self.mmap_lowest.min(start);
+            start
+        };
+
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = match self.files.get_mut(&fd) {
+                Some(FileDescriptor::File(file)) => file,
+                _ => return Ok(-EBADF)
+            };
+            // Mappings of files are private copies.
+            let mut contents = Vec::new();
+            let result = file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.take(size as u64).read_to_end(&mut contents));
+            if let Err(error) = result {
+                return Ok(errno(&error))
+            }
+            bus.write_bytes(start, &contents)?;
+        }
+        Ok(start as i64)
+    }
+
+    /// Unmaps memory. Only the lowest mapping is given back for reuse.
+    fn munmap(&mut self, address: usize, size: usize) -> i64 {
+        if !address.is_multiple_of(PAGE_SIZE) {
+            return -EINVAL
+        }
+        if address == self.mmap_next {
+            self.mmap_next = (address + size.next_multiple_of(PAGE_SIZE)).min(MMAP_TOP);
+        }
+        0
+    }
+
+    fn clock_gettime(&self, bus: &mut dyn Device, clock: u64, address: usize) -> Result<i64, DeviceError> {
+        let time = if clock == CLOCK_REALTIME {
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+        } else {
+            self.start_time.elapsed()
+        };
+        bus.write_int(address, time.as_secs(), 8, Endianness::LittleEndian)?;
+        bus.write_int(address + 8, time.subsec_nanos() as u64, 8, Endianness::LittleEndian)?;
+        Ok(0)
+    }
+
+    fn uname(bus: &mut dyn Device, address: usize) -> Result<i64, DeviceError> {
+        let fields = ["Linux", "yarve", "6.1.0", "#1", "riscv64", "(none)"];
+        let mut utsname = vec![0; fields.len() * UTSNAME_FIELD_SIZE];
+        for (i, field) in fields.iter().enumerate() {
+            utsname[i * UTSNAME_FIELD_SIZE..][..field.len()].copy_from_slice(field.as_bytes());
+        }
+        bus.write_bytes(address, &utsname)?;
+        Ok(0)
+    }
+
+    fn fill_random(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let mut hasher = self.random.build_hasher();
+            hasher.write_u64(self.random_counter);
+            self.random_counter += 1;
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/// Negated Linux error number of a host error.
+fn errno(error: &io::Error) -> i64 {
+    match error.raw_os_error() {
+        #[cfg(target_os = "linux")]
+        Some(code) => -(code as i64),
+        _ => match error.kind() {
+            io::ErrorKind::NotFound => -ENOENT,
+            io::ErrorKind::PermissionDenied => -EPERM,
+            _ => -EIO
+        }
+    }
+}
+
+/// Reads a null-terminated string from the process.
+fn read_string(bus: &dyn Device, address: usize) -> Result<String, DeviceError> {
+    let mut bytes = Vec::new();
+    while bytes.len() < PATH_MAX {
+        match bus.read_int(address + bytes.len(), 1, Endianness::LittleEndian, false)? {
+            0 => break,
+            byte => bytes.push(byte as u8)
+        }
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+impl Display for LinuxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for LinuxError {}
+
+impl From<io::Error> for LinuxError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<DeviceError> for LinuxError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
+
+impl From<CPUError> for LinuxError {
+    fn from(error: CPUError) -> Self {
+        Self::CPUError(error)
+    }
+}
+
+impl From<ElfError> for LinuxError {
+    fn from(error: ElfError) -> Self {
+        Self::ElfError(error)
+    }
+}
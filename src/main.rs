@@ -0,0 +1,416 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use yarve::boot_rom::BootROM;
+use yarve::clint::CLINT;
+use yarve::cpu::cpu::CPU;
+use yarve::cpu::lockstep::Lockstep;
+use yarve::cpu::trace::CommitLogOptions;
+use yarve::cpu::trap::StopReason;
+use yarve::device::Device;
+use yarve::dram::DRAM;
+use yarve::elf::{Elf, SymbolTable};
+use yarve::finisher::TestFinisher;
+use yarve::gdb;
+use yarve::htif::{write_signature, Htif};
+use yarve::isa_tests::{self, TestOutcome};
+use yarve::kernel::KernelImage;
+use yarve::linux::Process;
+use yarve::monitor::Monitor;
+use yarve::plic::PLIC;
+use yarve::sbi::Sbi;
+use yarve::semihosting::Semihosting;
+use yarve::uart::UART;
+use yarve::virtio::VirtioMmio;
+use yarve::virtio_blk::VirtioBlock;
+
+// Memory map, following the QEMU virt machine.
+const BOOT_ROM_ADDRESS: usize = 0x1000;
+const FINISHER_ADDRESS: usize = 0x10_0000;
+const CLINT_ADDRESS: usize = 0x200_0000;
+const PLIC_ADDRESS: usize = 0xC00_0000;
+const UART_ADDRESS: usize = 0x1000_0000;
+const VIRTIO_ADDRESS: usize = 0x1000_1000;
+/// PLIC source of the virtio-blk device.
+const VIRTIO_INTERRUPT: u32 = 1;
+const DRAM_ADDRESS: usize = 0x8000_0000;
+
+/// Instructions executed between polls for console input.
+const RUN_CHUNK: u64 = 100_000;
+
+const USAGE: &str = "\
+usage: yarve [options] <program>
+       yarve --user <program> [arguments...]
+       yarve --semihosting <directory> <program> [arguments...]
+       yarve --isa-tests <directory>
+
+Runs an ELF executable, a Linux kernel Image, or a raw binary loaded at
+0x80000000. Executables that define `tohost` can exit and print through HTIF, as
+in Spike.
+
+options:
+  --memory <MiB>     size of the DRAM, 128 by default
+  --harts <count>    number of harts, 1 by default
+  --monitor          start in the monitor, and break into it with Ctrl-C
+  --gdb <address>    wait for a debugger on a TCP address, e.g. 127.0.0.1:1234
+  --log-commits <file>
+                     write a commit log of the retired instructions, like
+                     spike --log-commits
+  --log-range <start>:<end>
+                     only log the instructions at these addresses
+  --log-window <first>:<end>
+                     only log these instructions, counted from 0 on each hart
+  --log-disassembly  also log the disassembly and the exceptions, like spike -l
+  --lockstep <file>  run in lockstep with a reference commit log, and report the
+                     first instruction that differs
+  --signature <file> write the memory between `begin_signature` and
+                     `end_signature` when the program exits, for riscof
+  --signature-granularity <bytes>
+                     bytes per line of the signature, 4 by default
+  --user             run a static Linux executable in user mode, servicing its
+                     system calls on the host, like qemu-user
+  --initrd <file>    load an initramfs for the kernel
+  --append <args>    kernel command line
+  --disk <file>      serve a disk image through a virtio-blk device
+  --disk-read-only   make the disk read-only
+  --sbi              handle the SBI calls of a kernel or other payload, in place
+                     of firmware like OpenSBI
+  --semihosting <directory>
+                     service semihosting calls, with files opened in a
+                     directory, and pass the arguments in the command line
+  --isa-tests <directory>
+                     run every test of riscv-tests or riscv-arch-test in a
+                     directory, and report the results
+";
+
+struct Options {
+    program: String,
+    memory: usize,
+    harts: usize,
+    monitor: bool,
+    gdb: Option<String>,
+    commit_log: Option<String>,
+    commit_log_options: CommitLogOptions,
+    lockstep: Option<String>,
+    signature: Option<String>,
+    signature_granularity: usize,
+    isa_tests: Option<String>,
+    user: bool,
+    initrd: Option<String>,
+    bootargs: Option<String>,
+    disk: Option<String>,
+    disk_read_only: bool,
+    sbi: bool,
+    semihosting: Option<String>,
+    /// Arguments passed to the program in user mode or through semihosting.
+    arguments: Vec<String>,
+}
+
+fn main() {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) if message.is_empty() => {
+            print!("{}", USAGE);
+            process::exit(0)
+        },
+        Err(message) => {
+            eprint!("{}\n\n{}", message, USAGE);
+            process::exit(2)
+        }
+    };
+    match run(options) {
+        Ok(code) => process::exit(code),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(1)
+        }
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        program: String::new(), memory: 128, harts: 1, monitor: false, gdb: None, commit_log: None,
+        commit_log_options: CommitLogOptions::default(), lockstep: None, signature: None,
+        signature_granularity: 4, isa_tests: None, user: false, initrd: None, bootargs: None,
+        disk: None, disk_read_only: false, sbi: false, semihosting: None, arguments: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--memory" => options.memory = value("--memory")?.parse().map_err(|_| "invalid memory size")?,
+            "--harts" => options.harts = value("--harts")?.parse().map_err(|_| "invalid hart count")?,
+            "--monitor" => options.monitor = true,
+            "--gdb" => options.gdb = Some(value("--gdb")?),
+            "--log-commits" => options.commit_log = Some(value("--log-commits")?),
+            "--log-range" => {
+                let range = parse_range(&value("--log-range")?).ok_or("invalid address range")?;
+                options.commit_log_options.address_range = Some(range.start as usize..range.end as usize);
+            },
+            "--log-window" => options.commit_log_options.window =
+                Some(parse_range(&value("--log-window")?).ok_or("invalid instruction window")?),
+            "--log-disassembly" => options.commit_log_options.disassembly = true,
+            "--lockstep" => options.lockstep = Some(value("--lockstep")?),
+            "--signature" => options.signature = Some(value("--signature")?),
+            "--signature-granularity" => options.signature_granularity = match value("--signature-granularity")?.parse() {
+                Ok(bytes @ 1..=8) => bytes,
+                _ => return Err("invalid signature granularity".to_string())
+            },
+            "--isa-tests" => options.isa_tests = Some(value("--isa-tests")?),
+            "--user" => options.user = true,
+            "--initrd" => options.initrd = Some(value("--initrd")?),
+            "--append" => options.bootargs = Some(value("--append")?),
+            "--disk" => options.disk = Some(value("--disk")?),
+            "--disk-read-only" => options.disk_read_only = true,
+            "--sbi" => options.sbi = true,
+            "--semihosting" => options.semihosting = Some(value("--semihosting")?),
+            "--help" | "-h" => return Err("".to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if options.program.is_empty() => {
+                options.program = arg;
+                // The rest belongs to the program.
+                if options.user || options.semihosting.is_some() {
+                    options.arguments = args.by_ref().collect();
+                }
+            },
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+    if options.program.is_empty() && options.isa_tests.is_none() {
+        return Err("no program given".to_string())
+    }
+    Ok(options)
+}
+
+/// Parses `start:end`, in decimal or hexadecimal with `0x`.
+fn parse_range(text: &str) -> Option<Range<u64>> {
+    let parse = |number: &str| match number.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => number.parse().ok()
+    };
+    let (start, end) = text.split_once(':')?;
+    Some(parse(start)?..parse(end)?)
+}
+
+fn run(options: Options) -> Result<i32, Box<dyn Error>> {
+    if let Some(directory) = &options.isa_tests {
+        return run_isa_tests(Path::new(directory))
+    }
+    if options.user {
+        return run_user(&options)
+    }
+    let data = std::fs::read(&options.program)?;
+    let (elf, kernel, image) = if Elf::is_elf(&data) {
+        (Some(Elf::parse(data)?), None, Vec::new())
+    } else if KernelImage::is_image(&data) {
+        (None, Some(KernelImage::parse(data)?), Vec::new())
+    } else {
+        (None, None, data)
+    };
+    let entry = match (&elf, &kernel) {
+        (Some(elf), _) => elf.entry,
+        (_, Some(kernel)) => kernel.get_entry(DRAM_ADDRESS),
+        _ => DRAM_ADDRESS
+    };
+
+    let plic = PLIC::new(options.harts);
+    let mut devices: Vec<(usize, Box<dyn Device>)> = vec![
+        (BOOT_ROM_ADDRESS, Box::new(BootROM::new(entry as u64))),
+        (FINISHER_ADDRESS, Box::new(TestFinisher::new())),
+        (CLINT_ADDRESS, Box::new(CLINT::new(options.harts))),
+        (UART_ADDRESS, Box::new(UART::new())),
+        (DRAM_ADDRESS, Box::new(DRAM::new(options.memory << 20))),
+    ];
+    if let Some(path) = &options.disk {
+        let disk = VirtioBlock::open(path, options.disk_read_only)?;
+        let interrupt = plic.get_source(VIRTIO_INTERRUPT).ok_or("no interrupt for the disk")?;
+        devices.push((VIRTIO_ADDRESS, Box::new(VirtioMmio::new(Box::new(disk), interrupt))));
+    }
+    devices.push((PLIC_ADDRESS, Box::new(plic)));
+    let mut cpu = CPU::with_harts(devices, options.harts)?;
+
+    let symbols = match elf {
+        Some(elf) => {
+            elf.load(&mut *cpu.bus.lock())?;
+            elf.symbols
+        },
+        None => {
+            match kernel {
+                Some(kernel) => {
+                    let initrd = options.initrd.as_ref().map(std::fs::read).transpose()?;
+                    kernel.load(&mut cpu, initrd.as_deref(), options.bootargs.as_deref())?;
+                },
+                None => cpu.bus.lock().write_bytes(DRAM_ADDRESS, &image)?
+            }
+            SymbolTable::default()
+        }
+    };
+    cpu.load_fdt()?;
+    if let Some(path) = &options.lockstep {
+        return match Lockstep::open(path)?.run(&mut cpu)? {
+            Some(divergence) => {
+                eprint!("{}", divergence);
+                Ok(1)
+            },
+            None => {
+                eprintln!("matched the reference");
+                Ok(0)
+            }
+        }
+    }
+    if let Some(path) = &options.commit_log {
+        cpu.start_commit_log(File::create(path)?, options.commit_log_options.clone());
+    }
+
+    let result = if let Some(address) = options.gdb {
+        eprintln!("waiting for a debugger on {}", address);
+        gdb::serve_tcp(&mut cpu, address)?;
+        Ok(0)
+    } else if options.monitor {
+        let monitor = Monitor::new(&mut cpu, symbols.clone());
+        handle_interrupts(monitor.get_interrupt());
+        monitor.run(&mut io::stdin().lock(), &mut io::stdout())?;
+        Ok(0)
+    } else {
+        let host = if let Some(directory) = &options.semihosting {
+            let mut semihosting = Semihosting::new(directory)?;
+            let mut command_line = vec![options.program.clone()];
+            command_line.extend(options.arguments.iter().cloned());
+            semihosting.set_command_line(&command_line.join(" "));
+            HostInterface::Semihosting(semihosting)
+        } else if options.sbi {
+            HostInterface::Sbi(Sbi::new(&mut cpu)?)
+        } else if let Some(htif) = Htif::from_symbols(&symbols) {
+            HostInterface::Htif(htif)
+        } else {
+            HostInterface::None
+        };
+        run_console(&mut cpu, host)
+    };
+    cpu.stop_commit_log()?;
+    if let Some(path) = &options.signature {
+        write_signature(&*cpu.bus.lock(), &symbols, options.signature_granularity, &mut File::create(path)?)?;
+    }
+    result
+}
+
+/// Runs a Linux executable in user mode, and returns its exit code.
+fn run_user(options: &Options) -> Result<i32, Box<dyn Error>> {
+    let elf = Elf::open(&options.program)?;
+    let mut args = vec![options.program.clone()];
+    args.extend(options.arguments.iter().cloned());
+    let env: Vec<String> = std::env::vars().map(|(name, value)| format!("{}={}", name, value)).collect();
+    let (mut cpu, mut process) = Process::new(&elf, &args, &env)?;
+    loop {
+        match process.run(&mut cpu, RUN_CHUNK)? {
+            StopReason::BudgetExhausted => {},
+            StopReason::Exit { code } => return Ok(code as i32),
+            reason => {
+                eprintln!("stopped: {:?}", reason);
+                return Ok(1)
+            }
+        }
+    }
+}
+
+/// Runs the tests in `directory`, and fails if any test fails.
+fn run_isa_tests(directory: &Path) -> Result<i32, Box<dyn Error>> {
+    let results = isa_tests::run_tests(directory, isa_tests::DEFAULT_MAX_INSTRUCTIONS)?;
+    let mut failed = 0;
+    for result in &results {
+        match &result.outcome {
+            TestOutcome::Pass => println!("PASS {}", result.name),
+            outcome => {
+                failed += 1;
+                println!("FAIL {}: {:?}", result.name, outcome)
+            }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+/// How the guest exits and prints besides the UART and test finisher.
+enum HostInterface {
+    None,
+    Htif(Htif),
+    Semihosting(Semihosting),
+    Sbi(Sbi),
+}
+
+/// Runs the guest with stdin connected to the UART, until it exits. With
+/// semihosting, the guest reads stdin itself instead.
+fn run_console(cpu: &mut CPU, mut host: HostInterface) -> Result<i32, Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let reads_stdin = matches!(host, HostInterface::Semihosting(_));
+    thread::spawn(move || {
+        if reads_stdin {
+            return
+        }
+        let mut buffer = [0; 256];
+        while let Ok(count @ 1..) = io::stdin().read(&mut buffer) {
+            if sender.send(buffer[..count].to_vec()).is_err() {
+                break
+            }
+        }
+    });
+
+    loop {
+        while let Ok(input) = receiver.try_recv() {
+            cpu.receive_uart_input(&input);
+        }
+        let (hart, reason) = match &mut host {
+            HostInterface::Htif(htif) => {
+                let reason = htif.run(cpu, RUN_CHUNK)?;
+                io::stdout().write_all(&htif.take_output())?;
+                (0, reason)
+            },
+            HostInterface::Semihosting(semihosting) => (0, semihosting.run(cpu, RUN_CHUNK)?),
+            HostInterface::Sbi(sbi) => (0, sbi.run(cpu, RUN_CHUNK)?),
+            HostInterface::None => cpu.run(RUN_CHUNK)?
+        };
+        io::stdout().flush()?;
+        match reason {
+            StopReason::BudgetExhausted => {},
+            StopReason::WaitForInterrupt => thread::sleep(Duration::from_millis(1)),
+            StopReason::Exit { code } => return Ok(code as i32),
+            reason => {
+                eprintln!("hart {} stopped: {:?}", hart, reason);
+                return Ok(1)
+            }
+        }
+    }
+}
+
+/// Sets `interrupt` when the user presses Ctrl-C.
+#[cfg(unix)]
+fn handle_interrupts(interrupt: Arc<AtomicBool>) {
+    use std::sync::OnceLock;
+    use std::sync::atomic::Ordering;
+
+    static INTERRUPT: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+    extern "C" fn handle_signal(_: libc::c_int) {
+        if let Some(interrupt) = INTERRUPT.get() {
+            interrupt.store(true, Ordering::Relaxed);
+        }
+    }
+
+    if INTERRUPT.set(interrupt).is_ok() {
+        // The handler only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            libc::signal(libc::SIGINT, handle_signal as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_interrupts(_interrupt: Arc<AtomicBool>) {}
@@ -0,0 +1,407 @@
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::csr::{find_csr, get_csr_name, CSR_NAMES};
+use crate::cpu::instruction::Instruction;
+use crate::cpu::register::{FRegister, XRegister};
+use crate::cpu::trap::{StopReason, WatchKind};
+use crate::device::{Device, DeviceError};
+use crate::elf::SymbolTable;
+use crate::endianness::Endianness;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Instructions executed between checks for an interrupt from the user.
+const RESUME_CHUNK: u64 = 100_000;
+
+const DEFAULT_DUMP_SIZE: usize = 64;
+const DEFAULT_DISASSEMBLY_COUNT: usize = 10;
+const DEFAULT_WATCH_SIZE: usize = 8;
+
+const PROMPT: &str = "(yarve) ";
+
+const HELP: &str = "\
+step [count]             execute instructions on the current hart (s)
+continue                 run all harts until a breakpoint or an interrupt (c)
+break <location>         stop before executing the instruction at location (b)
+watch <location> [size]  stop after a write to the bytes at location (w)
+delete <location>        remove the breakpoints and watchpoints at location
+info                     list breakpoints and watchpoints
+regs                     print the integer registers (r)
+fregs                    print the floating point registers
+csr [name]               print a CSR, or all named CSRs
+x <location> [size]      dump memory as hex
+disas [location] [count] disassemble instructions, by default at pc (d)
+hart [n]                 select the hart for step, regs and csr, or print it
+send <text>              send a line of text to the UART
+quit                     leave the monitor (q)
+A location is a number, a register, a symbol, or one of these followed by +/- and a
+number. An empty line repeats the previous command.
+";
+
+
+#[derive(Debug)]
+pub enum MonitorError {
+    IoError(io::Error),
+    CPUError(CPUError),
+    DeviceError(DeviceError),
+    UnknownCommand { command: String },
+    MissingArgument,
+    InvalidArgument { argument: String },
+}
+
+/// Interactive debugger reading commands from a terminal, for when GDB isn't at hand.
+///
+/// Addresses can be given as symbols from the program's ELF symbol table. Breakpoints
+/// and watchpoints are set on all harts. A running `continue` stops when the flag from
+/// `get_interrupt` is set, e.g. from a signal handler.
+pub struct Monitor<'a> {
+    cpu: &'a mut CPU,
+    symbols: SymbolTable,
+    interrupt: Arc<AtomicBool>,
+    /// Hart selected for `step`, `regs` and `csr`.
+    hart: usize,
+    breakpoints: Vec<usize>,
+    watchpoints: Vec<(usize, usize)>,
+    last_command: String,
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(cpu: &'a mut CPU, symbols: SymbolTable) -> Self {
+        Self {
+            cpu,
+            symbols,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            hart: 0,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            last_command: String::new(),
+        }
+    }
+
+    /// Flag that stops a running `continue` when set.
+    pub fn get_interrupt(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Reads and executes commands until `quit` or the end of `input`. Errors in
+    /// commands are printed, and breakpoints and watchpoints are removed before
+    /// returning.
+    pub fn run(mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), MonitorError> {
+        let result = self.run_commands(input, output);
+        for core in &mut self.cpu.harts {
+            for address in &self.breakpoints {
+                core.remove_breakpoint(*address);
+            }
+            for (address, size) in &self.watchpoints {
+                core.remove_watchpoint(*address, *size, WatchKind::Write);
+            }
+        }
+        result
+    }
+
+    fn run_commands(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), MonitorError> {
+        if let Err(error) = self.print_location(output) {
+            writeln!(output, "error: {}", error)?;
+        }
+        loop {
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(())
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string()
+            };
+            self.last_command = line.clone();
+            match self.execute(&line, output) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {},
+                Err(MonitorError::IoError(error)) => return Err(MonitorError::IoError(error)),
+                Err(error) => writeln!(output, "error: {}", error)?
+            }
+        }
+    }
+
+    /// Executes one command. Returns true if the monitor should exit.
+    fn execute(&mut self, line: &str, output: &mut dyn Write) -> Result<bool, MonitorError> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(false)
+        };
+        let arguments: Vec<&str> = words.collect();
+        let argument = |i: usize| arguments.get(i).copied();
+
+        match command {
+            "step" | "s" => {
+                let count = argument(0).map(|x| self.parse_number(x)).transpose()?.unwrap_or(1);
+                let hart = self.hart;
+                let reason = self.cpu.harts[hart].run(count as u64)
+                    .map_err(|error| CPUError::CoreError { hart, error })?;
+                self.report_stop(hart, reason, output)?;
+            },
+            "continue" | "c" => self.resume(output)?,
+            "break" | "b" => {
+                let address = self.parse_location(argument(0))?;
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                    self.cpu.harts.iter_mut().for_each(|core| core.add_breakpoint(address));
+                }
+                writeln!(output, "breakpoint at {}", self.format_address(address))?;
+            },
+            "watch" | "w" => {
+                let address = self.parse_location(argument(0))?;
+                let size = argument(1).map(|x| self.parse_number(x)).transpose()?.unwrap_or(DEFAULT_WATCH_SIZE);
+                if size == 0 {
+                    return Err(MonitorError::InvalidArgument { argument: "0".to_string() })
+                }
+                self.watchpoints.push((address, size));
+                self.cpu.harts.iter_mut().for_each(|core| core.add_watchpoint(address, size, WatchKind::Write));
+                writeln!(output, "watchpoint on {} bytes at {}", size, self.format_address(address))?;
+            },
+            "delete" => {
+                let address = self.parse_location(argument(0))?;
+                let found = self.breakpoints.len() + self.watchpoints.len();
+                self.breakpoints.retain(|x| *x != address);
+                let (removed, kept) = self.watchpoints.iter().partition(|(x, _)| *x == address);
+                self.watchpoints = kept;
+                for core in &mut self.cpu.harts {
+                    core.remove_breakpoint(address);
+                    for (address, size) in &removed {
+                        core.remove_watchpoint(*address, *size, WatchKind::Write);
+                    }
+                }
+                if self.breakpoints.len() + self.watchpoints.len() == found {
+                    return Err(MonitorError::InvalidArgument { argument: arguments[0].to_string() })
+                }
+            },
+            "info" => {
+                for address in &self.breakpoints {
+                    writeln!(output, "breakpoint at {}", self.format_address(*address))?;
+                }
+                for (address, size) in &self.watchpoints {
+                    writeln!(output, "watchpoint on {} bytes at {}", size, self.format_address(*address))?;
+                }
+            },
+            "regs" | "r" => {
+                let core = &self.cpu.harts[self.hart];
+                for row in 0..8 {
+                    let line: Vec<String> = (0..4).map(|column| {
+                        let register = XRegister::from(row * 4 + column);
+                        format!("{:>4} {:#018x}", register, core.x_registers[register])
+                    }).collect();
+                    writeln!(output, "{}", line.join("  "))?;
+                }
+                writeln!(output, "{:>4} {}", "pc", self.format_address(core.pc))?;
+            },
+            "fregs" => {
+                let core = &self.cpu.harts[self.hart];
+                for row in 0..16u32 {
+                    let line: Vec<String> = (0..2).map(|column| {
+                        let register = FRegister::from(row * 2 + column);
+                        let value = core.f_registers[register];
+                        format!("{:>4} {:#018x} {:<14e}", register, value.to_bits(), value)
+                    }).collect();
+                    writeln!(output, "{}", line.join("  ").trim_end())?;
+                }
+            },
+            "csr" => {
+                let csrs: Vec<u16> = match argument(0) {
+                    Some(name) => vec![find_csr(name)
+                        .or_else(|| self.parse_number(name).ok().filter(|x| *x < 0x1000).map(|x| x as u16))
+                        .ok_or_else(|| MonitorError::InvalidArgument { argument: name.to_string() })?],
+                    None => CSR_NAMES.iter().map(|(csr, _)| *csr).collect()
+                };
+                for csr in csrs {
+                    let name = get_csr_name(csr).map_or_else(|| format!("{:#05x}", csr), str::to_string);
+                    writeln!(output, "{:<10} {:#018x}", name, self.cpu.harts[self.hart].csr_registers[csr])?;
+                }
+            },
+            "x" => {
+                let address = self.parse_location(argument(0))?;
+                let size = argument(1).map(|x| self.parse_number(x)).transpose()?.unwrap_or(DEFAULT_DUMP_SIZE);
+                self.dump(address, size, output)?;
+            },
+            "disas" | "d" => {
+                let address = match argument(0) {
+                    Some(_) => self.parse_location(argument(0))?,
+                    None => self.cpu.harts[self.hart].pc
+                };
+                let count = argument(1).map(|x| self.parse_number(x)).transpose()?.unwrap_or(DEFAULT_DISASSEMBLY_COUNT);
+                for i in 0..count {
+                    self.disassemble(address + i * 4, output)?;
+                }
+            },
+            "hart" => {
+                if let Some(hart) = argument(0) {
+                    let hart = self.parse_number(hart)?;
+                    if hart >= self.cpu.harts.len() {
+                        return Err(MonitorError::CPUError(CPUError::InvalidHart { hart }))
+                    }
+                    self.hart = hart;
+                }
+                writeln!(output, "hart {}", self.hart)?;
+            },
+            "send" => {
+                let text = line.trim_start()[command.len()..].trim_start();
+                self.cpu.receive_uart_input(format!("{}\n", text).as_bytes());
+            },
+            "help" | "h" => write!(output, "{}", HELP)?,
+            "quit" | "q" => return Ok(true),
+            _ => return Err(MonitorError::UnknownCommand { command: command.to_string() })
+        }
+        Ok(false)
+    }
+
+    /// Runs all harts until one stops, or the user interrupts.
+    fn resume(&mut self, output: &mut dyn Write) -> Result<(), MonitorError> {
+        self.interrupt.store(false, Ordering::Relaxed);
+        loop {
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                writeln!(output, "interrupted")?;
+                return self.print_location(output)
+            }
+            match self.cpu.run(RESUME_CHUNK)? {
+                (_, StopReason::BudgetExhausted) => {},
+                // Wait for an interrupt from a device, or the user.
+                (_, StopReason::WaitForInterrupt) => thread::sleep(Duration::from_millis(1)),
+                (hart, reason) => {
+                    self.hart = hart;
+                    return self.report_stop(hart, reason, output)
+                }
+            }
+        }
+    }
+
+    fn report_stop(&mut self, hart: usize, reason: StopReason, output: &mut dyn Write) -> Result<(), MonitorError> {
+        match reason {
+            StopReason::BudgetExhausted | StopReason::Condition => {},
+            StopReason::Breakpoint { address } =>
+                writeln!(output, "hart {} stopped at breakpoint {}", hart, self.format_address(address))?,
+            StopReason::Watchpoint { address, .. } =>
+                writeln!(output, "hart {} wrote to {}", hart, self.format_address(address))?,
+            StopReason::Exit { code } => {
+                writeln!(output, "guest exited with code {}", code)?;
+                return Ok(())
+            },
+            StopReason::WaitForInterrupt => writeln!(output, "hart {} is waiting for an interrupt", hart)?,
+            StopReason::Trap { exception, pc, tval } =>
+                writeln!(output, "hart {} raised {:?} at {} with tval {:#x}", hart, exception,
+                         self.format_address(pc), tval)?,
+        }
+        self.print_location(output)
+    }
+
+    fn print_location(&self, output: &mut dyn Write) -> Result<(), MonitorError> {
+        self.disassemble(self.cpu.harts[self.hart].pc, output)
+    }
+
+    /// Prints the instruction at `address`, preceded by a label at the start of a
+    /// symbol. The instruction at pc is marked with an arrow.
+    fn disassemble(&self, address: usize, output: &mut dyn Write) -> Result<(), MonitorError> {
+        if let Some((symbol, 0)) = self.symbols.lookup(address) {
+            writeln!(output, "{}:", symbol.name)?;
+        }
+        let marker = if address == self.cpu.harts[self.hart].pc { "=>" } else { "  " };
+        let word = self.cpu.bus.lock().read_int(address, 4, Endianness::LittleEndian, false)? as u32;
+        let text = match Instruction::decode(word) {
+            Ok(instruction) => match instruction.get_jump_target(address) {
+                Some(target) => format!("{:<24} # {}", instruction.to_string(), self.format_address(target)),
+                None => instruction.to_string()
+            },
+            Err(_) => format!(".word {:#010x}", word)
+        };
+        writeln!(output, "{} {}  {:08x}  {}", marker, self.format_address(address), word, text)?;
+        Ok(())
+    }
+
+    fn dump(&self, address: usize, size: usize, output: &mut dyn Write) -> Result<(), MonitorError> {
+        let bus = self.cpu.bus.lock();
+        for row in (address..address + size).step_by(16) {
+            let bytes = bus.read_bytes(row, (address + size - row).min(16))?;
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = bytes.iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+                .collect();
+            writeln!(output, "{:#010x}  {:<47}  {}", row, hex.join(" "), text)?;
+        }
+        Ok(())
+    }
+
+    /// Formats `address` in hex, followed by the symbol it is in.
+    fn format_address(&self, address: usize) -> String {
+        match self.symbols.lookup(address) {
+            Some((symbol, 0)) => format!("{:#010x} <{}>", address, symbol.name),
+            Some((symbol, offset)) => format!("{:#010x} <{}+{}>", address, symbol.name, offset),
+            None => format!("{:#010x}", address)
+        }
+    }
+
+    fn parse_number(&self, text: &str) -> Result<usize, MonitorError> {
+        let number = match text.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => text.parse()
+        };
+        number.map_err(|_| MonitorError::InvalidArgument { argument: text.to_string() })
+    }
+
+    /// Parses a number, a register, or a symbol, optionally followed by an offset.
+    fn parse_location(&self, text: Option<&str>) -> Result<usize, MonitorError> {
+        let text = text.ok_or(MonitorError::MissingArgument)?;
+        let (base, offset) = match text.find(['+', '-']) {
+            Some(i) if i > 0 => (&text[..i], Some(&text[i..])),
+            _ => (text, None)
+        };
+
+        let core = &self.cpu.harts[self.hart];
+        let base = if base == "pc" {
+            core.pc
+        } else if let Some(register) = XRegister::from_name(base) {
+            core.x_registers[register] as usize
+        } else if let Some(symbol) = self.symbols.find(base) {
+            symbol.address
+        } else {
+            self.parse_number(base)?
+        };
+
+        Ok(match offset {
+            Some(offset) => {
+                let value = self.parse_number(&offset[1..])?;
+                if offset.starts_with('+') { base.wrapping_add(value) } else { base.wrapping_sub(value) }
+            },
+            None => base
+        })
+    }
+}
+
+impl Display for MonitorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for MonitorError {}
+
+impl From<io::Error> for MonitorError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<CPUError> for MonitorError {
+    fn from(error: CPUError) -> Self {
+        Self::CPUError(error)
+    }
+}
+
+impl From<DeviceError> for MonitorError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
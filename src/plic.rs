@@ -0,0 +1,337 @@
+use crate::cpu::cpu::interrupt_controller_label;
+use crate::cpu::csr::{MIP_MEIP, MIP_SEIP};
+use crate::cpu::trap::Interrupt;
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use std::any::Any;
+use std::borrow::Cow;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const PLIC_SIZE: usize = 0x400_0000;
+
+const PLIC_PRIORITY: usize = 0x0;
+const PLIC_PENDING: usize = 0x1000;
+const PLIC_ENABLE: usize = 0x2000;
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_CONTEXT: usize = 0x20_0000;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+const PLIC_THRESHOLD: usize = 0x0;
+const PLIC_CLAIM: usize = 0x4;
+
+/// Number of interrupt sources, including source 0, which doesn't exist.
+const PLIC_SOURCE_COUNT: usize = 64;
+const PLIC_MAX_PRIORITY: u32 = 7;
+
+/// External interrupt bits of the contexts of a hart, in context order.
+const CONTEXT_INTERRUPTS: [u64; 2] = [MIP_MEIP, MIP_SEIP];
+
+/// Label of the PLIC node, for `FdtWriter::get_phandle`.
+pub(crate) const PLIC_LABEL: &str = "plic";
+
+
+/// Platform-level interrupt controller, in the layout of the SiFive PLIC, with two
+/// contexts per hart like QEMU's: context `2 * hart` for M-mode, which drives
+/// `mip.MEIP`, and context `2 * hart + 1` for S-mode, which drives `mip.SEIP`.
+///
+/// Sources are level-triggered: a source is pending while a device holds its
+/// `InterruptSource` high and it isn't being serviced, i.e. claimed and not yet
+/// completed. Changes update the hart interrupt lines right away.
+pub struct PLIC {
+    state: Arc<Mutex<PlicState>>,
+    interrupt_raised: bool,
+}
+
+/// Interrupt line from a device to the PLIC.
+#[derive(Debug, Clone)]
+pub struct InterruptSource {
+    id: u32,
+    state: Arc<Mutex<PlicState>>,
+}
+
+struct PlicState {
+    priority: Vec<u32>,
+    level: Vec<bool>,
+    in_service: Vec<bool>,
+    /// Enabled sources and threshold of each context.
+    enable: Vec<u64>,
+    threshold: Vec<u32>,
+    lines: Vec<Option<Arc<AtomicU64>>>,
+}
+
+impl PLIC {
+    pub fn new(hart_count: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(PlicState {
+                priority: vec![0; PLIC_SOURCE_COUNT],
+                level: vec![false; PLIC_SOURCE_COUNT],
+                in_service: vec![false; PLIC_SOURCE_COUNT],
+                enable: vec![0; hart_count * CONTEXT_INTERRUPTS.len()],
+                threshold: vec![0; hart_count * CONTEXT_INTERRUPTS.len()],
+                lines: vec![None; hart_count],
+            })),
+            interrupt_raised: false,
+        }
+    }
+
+    /// Source `id` for a device, or `None` if the PLIC doesn't have it.
+    pub fn get_source(&self, id: u32) -> Option<InterruptSource> {
+        if id == 0 || id as usize >= PLIC_SOURCE_COUNT {
+            return None
+        }
+        Some(InterruptSource { id, state: self.state.clone() })
+    }
+
+    /// Connects the contexts of `hart` to its interrupt line, from `Core::interrupt_line`.
+    pub fn set_interrupt_line(&mut self, hart: usize, line: Arc<AtomicU64>) {
+        let mut state = self.lock();
+        if let Some(slot) = state.lines.get_mut(hart) {
+            *slot = Some(line);
+        }
+        state.update_lines();
+    }
+
+    /// `mip` bits of the interrupts pending for `hart`.
+    pub fn get_pending(&self, hart: usize) -> u64 {
+        self.lock().get_pending(hart)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PlicState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get_register(&self, address: usize) -> u32 {
+        let mut state = self.lock();
+        let context_count = state.enable.len();
+        if (PLIC_PRIORITY..PLIC_PRIORITY + 4 * PLIC_SOURCE_COUNT).contains(&address) {
+            state.priority[(address - PLIC_PRIORITY) / 4]
+        } else if (PLIC_PENDING..PLIC_PENDING + PLIC_SOURCE_COUNT / 8).contains(&address) {
+            let pending = (1..PLIC_SOURCE_COUNT).filter(|source| state.is_pending(*source))
+                .fold(0u64, |pending, source| pending | 1 << source);
+            (pending >> ((address - PLIC_PENDING) * 8)) as u32
+        } else if (PLIC_ENABLE..PLIC_ENABLE + PLIC_ENABLE_STRIDE * context_count).contains(&address) {
+            let (context, offset) = ((address - PLIC_ENABLE) / PLIC_ENABLE_STRIDE, (address - PLIC_ENABLE) % PLIC_ENABLE_STRIDE);
+            if offset < PLIC_SOURCE_COUNT / 8 { (state.enable[context] >> (offset * 8)) as u32 } else { 0 }
+        } else if (PLIC_CONTEXT..PLIC_CONTEXT + PLIC_CONTEXT_STRIDE * context_count).contains(&address) {
+            let context = (address - PLIC_CONTEXT) / PLIC_CONTEXT_STRIDE;
+            match (address - PLIC_CONTEXT) % PLIC_CONTEXT_STRIDE {
+                PLIC_THRESHOLD => state.threshold[context],
+                PLIC_CLAIM => {
+                    let source = state.get_best(context).unwrap_or(0);
+                    if source != 0 {
+                        state.in_service[source] = true;
+                        state.update_lines();
+                    }
+                    source as u32
+                },
+                _ => 0
+            }
+        } else {
+            0
+        }
+    }
+
+    fn set_register(&mut self, address: usize, value: u32) {
+        let mut state = self.lock();
+        let context_count = state.enable.len();
+        if (PLIC_PRIORITY + 4..PLIC_PRIORITY + 4 * PLIC_SOURCE_COUNT).contains(&address) {
+            state.priority[(address - PLIC_PRIORITY) / 4] = value.min(PLIC_MAX_PRIORITY);
+        } else if (PLIC_ENABLE..PLIC_ENABLE + PLIC_ENABLE_STRIDE * context_count).contains(&address) {
+            let (context, offset) = ((address - PLIC_ENABLE) / PLIC_ENABLE_STRIDE, (address - PLIC_ENABLE) % PLIC_ENABLE_STRIDE);
+            if offset < PLIC_SOURCE_COUNT / 8 {
+                let shift = offset * 8;
+                // Source 0 can't be enabled.
+                let enable = (state.enable[context] & !(0xFFFF_FFFF << shift)) | (value as u64) << shift;
+                state.enable[context] = enable & !1;
+            }
+        } else if (PLIC_CONTEXT..PLIC_CONTEXT + PLIC_CONTEXT_STRIDE * context_count).contains(&address) {
+            let context = (address - PLIC_CONTEXT) / PLIC_CONTEXT_STRIDE;
+            match (address - PLIC_CONTEXT) % PLIC_CONTEXT_STRIDE {
+                PLIC_THRESHOLD => state.threshold[context] = value.min(PLIC_MAX_PRIORITY),
+                // Completing a source the context doesn't have enabled is ignored.
+                PLIC_CLAIM if (value as usize) < PLIC_SOURCE_COUNT && state.enable[context] & 1 << value != 0 =>
+                    state.in_service[value as usize] = false,
+                _ => {}
+            }
+        } else {
+            return
+        }
+        let raised = state.update_lines();
+        drop(state);
+        self.interrupt_raised |= raised;
+    }
+}
+
+impl PlicState {
+    fn is_pending(&self, source: usize) -> bool {
+        self.level[source] && !self.in_service[source]
+    }
+
+    /// Pending source with the highest priority enabled for `context` above its
+    /// threshold, the lowest one on ties.
+    fn get_best(&self, context: usize) -> Option<usize> {
+        let (enable, threshold) = (*self.enable.get(context)?, self.threshold[context]);
+        (1..PLIC_SOURCE_COUNT)
+            .filter(|source| enable & 1 << source != 0 && self.is_pending(*source) && self.priority[*source] > threshold)
+            .min_by_key(|source| PLIC_MAX_PRIORITY - self.priority[*source])
+    }
+
+    /// `mip` bits of the contexts of `hart` with a source to claim.
+    fn get_pending(&self, hart: usize) -> u64 {
+        CONTEXT_INTERRUPTS.iter().enumerate()
+            .filter(|(i, _)| self.get_best(hart * CONTEXT_INTERRUPTS.len() + i).is_some())
+            .fold(0, |pending, (_, bit)| pending | bit)
+    }
+
+    /// Sets or clears `mip.MEIP` and `mip.SEIP` on the lines. Returns true if either
+    /// was raised on any.
+    fn update_lines(&self) -> bool {
+        let mut raised = false;
+        for (hart, line) in self.lines.iter().enumerate() {
+            if let Some(line) = line {
+                let pending = self.get_pending(hart);
+                let previous = line.fetch_or(pending, Ordering::Relaxed);
+                line.fetch_and(!(MIP_MEIP | MIP_SEIP) | pending, Ordering::Relaxed);
+                raised |= pending & !previous != 0;
+            }
+        }
+        raised
+    }
+}
+
+impl InterruptSource {
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    /// Raises or lowers the line. Returns true if this raised an external interrupt
+    /// on a hart.
+    pub fn set_level(&self, level: bool) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.level[self.id as usize] == level {
+            return false
+        }
+        state.level[self.id as usize] = level;
+        state.update_lines()
+    }
+}
+
+impl Debug for PLIC {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PLIC")
+    }
+}
+
+impl Debug for PlicState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PlicState")
+    }
+}
+
+impl Device for PLIC {
+    fn get_address_space_size(&self) -> usize { PLIC_SIZE }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        let value = self.read_int(address, size, Endianness::LittleEndian, false)?;
+        Ok(Cow::Owned(value.to_le_bytes()[..size].to_vec()))
+    }
+
+    fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
+        if binary.len() != 4 {
+            return Err(DeviceError::InvalidSizeWriteFault)
+        }
+        let value = int_from_bytes(binary, Endianness::LittleEndian, false);
+        self.write_int(address, value, 4, Endianness::LittleEndian)
+    }
+
+    fn read_int(&self, address: usize, size: usize, _endianness: Endianness, _sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        if size != 4 {
+            return Err(DeviceError::InvalidSizeReadFault)
+        }
+        if !address.is_multiple_of(4) {
+            return Err(DeviceError::MisalignedAddressReadTrap { address })
+        }
+        Ok(self.get_register(address) as u64)
+    }
+
+    fn write_int(&mut self, address: usize, value: u64, size: usize, _endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        if size != 4 {
+            return Err(DeviceError::InvalidSizeWriteFault)
+        }
+        if !address.is_multiple_of(4) {
+            return Err(DeviceError::MisalignedAddressWriteTrap { address })
+        }
+        self.set_register(address, value as u32);
+        Ok(())
+    }
+
+    fn get_name(&self) -> &str { "plic" }
+
+    fn take_interrupt_raised(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_raised)
+    }
+
+    /// Saves the registers and the sources in service. Source levels belong to the
+    /// devices driving them, which restore them.
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        let state = self.lock();
+        writer.write_u64(state.enable.len() as u64)?;
+        for source in 0..PLIC_SOURCE_COUNT {
+            writer.write_u32(state.priority[source])?;
+            writer.write_bool(state.in_service[source])?;
+        }
+        for context in 0..state.enable.len() {
+            writer.write_u64(state.enable[context])?;
+            writer.write_u32(state.threshold[context])?;
+        }
+        Ok(())
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let mut state = self.lock();
+        if reader.read_u64()? != state.enable.len() as u64 {
+            return Err(SnapshotError::InvalidState { name: "plic".to_string() })
+        }
+        for source in 0..PLIC_SOURCE_COUNT {
+            state.priority[source] = reader.read_u32()?.min(PLIC_MAX_PRIORITY);
+            state.in_service[source] = reader.read_bool()?;
+        }
+        for context in 0..state.enable.len() {
+            state.enable[context] = reader.read_u64()? & !1;
+            state.threshold[context] = reader.read_u32()?.min(PLIC_MAX_PRIORITY);
+        }
+        state.update_lines();
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        let mut interrupts = Vec::new();
+        for hart_id in 0..self.lock().lines.len() as u64 {
+            let phandle = fdt.get_phandle(&interrupt_controller_label(hart_id));
+            interrupts.extend([phandle, Interrupt::MachineExternal as u32]);
+            interrupts.extend([phandle, Interrupt::SupervisorExternal as u32]);
+        }
+        let phandle = fdt.get_phandle(PLIC_LABEL);
+
+        fdt.begin_node(&format!("plic@{:x}", base_address))?;
+        fdt.property_string_list("compatible", &["sifive,plic-1.0.0", "riscv,plic0"])?;
+        fdt.property_reg("reg", &[(base_address as u64, PLIC_SIZE as u64)])?;
+        fdt.property_u32("#address-cells", 0)?;
+        fdt.property_u32("#interrupt-cells", 1)?;
+        fdt.property_null("interrupt-controller")?;
+        fdt.property_cells("interrupts-extended", &interrupts)?;
+        fdt.property_u32("riscv,ndev", PLIC_SOURCE_COUNT as u32 - 1)?;
+        fdt.property_u32("phandle", phandle)?;
+        fdt.end_node()
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
@@ -0,0 +1,362 @@
+use crate::boot_rom::BootROM;
+use crate::cpu::core::{Core, Privilege};
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::csr::{COUNTEREN_TM, MARCHID, MCOUNTEREN, MEDELEG, MIDELEG, MIMPID, MIP, MIP_SSIP, MSTATUS,
+                      MSTATUS_SIE, MVENDORID, SUPERVISOR_INTERRUPTS};
+use crate::cpu::register::XRegister;
+use crate::cpu::trap::{Exception, StopReason};
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::uart::UART;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+// Extension IDs.
+const EXTENSION_BASE: u64 = 0x10;
+const EXTENSION_TIME: u64 = 0x5449_4D45;
+const EXTENSION_IPI: u64 = 0x73_5049;
+const EXTENSION_RFENCE: u64 = 0x5246_4E43;
+const EXTENSION_HSM: u64 = 0x48_534D;
+const EXTENSION_SRST: u64 = 0x5352_5354;
+const LEGACY_SET_TIMER: u64 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const LEGACY_CLEAR_IPI: u64 = 0x03;
+const LEGACY_SEND_IPI: u64 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const LEGACY_SHUTDOWN: u64 = 0x08;
+
+const EXTENSIONS: [u64; 15] = [
+    EXTENSION_BASE, EXTENSION_TIME, EXTENSION_IPI, EXTENSION_RFENCE, EXTENSION_HSM, EXTENSION_SRST,
+    LEGACY_SET_TIMER, LEGACY_CONSOLE_PUTCHAR, LEGACY_CONSOLE_GETCHAR, LEGACY_CLEAR_IPI, LEGACY_SEND_IPI,
+    LEGACY_REMOTE_FENCE_I, LEGACY_REMOTE_SFENCE_VMA, LEGACY_REMOTE_SFENCE_VMA_ASID, LEGACY_SHUTDOWN,
+];
+
+/// Version 2.0 of the SBI specification.
+const SPEC_VERSION: u64 = 2 << 24;
+/// Implementation ID, outside the range of the registered implementations.
+const IMPLEMENTATION_ID: u64 = 0x5941_5256;
+const IMPLEMENTATION_VERSION: u64 = 1;
+
+const SUCCESS: i64 = 0;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_INVALID_ADDRESS: i64 = -5;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const HART_STARTED: u64 = 0;
+const HART_STOPPED: u64 = 1;
+const SUSPEND_RETENTIVE: u64 = 0;
+const RESET_SHUTDOWN: u64 = 0;
+const RESET_COLD_REBOOT: u64 = 1;
+const RESET_WARM_REBOOT: u64 = 2;
+const REASON_SYSTEM_FAILURE: u64 = 1;
+
+/// Exceptions S-mode handles itself, as OpenSBI delegates them: misaligned fetches,
+/// breakpoints, `ecall` from U-mode and page faults.
+const DELEGATED_EXCEPTIONS: u64 = (1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15);
+
+// Registers of the CLINT and UART.
+const CLINT_MTIMECMP: usize = 0x4000;
+const UART_DATA: usize = 0;
+const UART_LSR: usize = 5;
+const UART_LSR_DR: u64 = 1;
+
+#[derive(Debug)]
+pub enum SbiError {
+    CPUError(CPUError),
+    DeviceError(DeviceError),
+    /// The bus doesn't have a device the SBI needs.
+    MissingDevice { name: String },
+}
+
+/// What a call does besides returning to the caller.
+enum Completion {
+    Resume,
+    Exit(u64),
+    Reset,
+}
+
+/// Built-in supervisor binary interface, in place of firmware like OpenSBI. It
+/// implements the base, TIME, IPI, RFENCE, HSM and SRST extensions and the legacy
+/// calls, on top of the CLINT and UART.
+///
+/// The payload runs in S-mode, and as there is no M-mode firmware, `mtvec` stays
+/// clear: its `ecall` stops the hart with an `EnvironmentCallFromSMode` trap. `run`
+/// services the call, with the extension in `a7`, the function in `a6` and the
+/// arguments in `a0` to `a5`, and resumes past it with the error in `a0` and the
+/// value in `a1`. Interrupts and exceptions are delegated to S-mode as OpenSBI does.
+/// Timers raise the supervisor timer interrupt through the CLINT, and IPIs set the
+/// supervisor software interrupt of the target harts.
+pub struct Sbi {
+    clint: usize,
+    uart: usize,
+}
+
+impl Sbi {
+    /// Finds the CLINT and UART on the bus of `cpu`, and boots hart 0 into the payload.
+    pub fn new(cpu: &mut CPU) -> Result<Self, SbiError> {
+        let missing = |name: &str| SbiError::MissingDevice { name: name.to_string() };
+        let clint = cpu.clint_address.ok_or_else(|| missing("CLINT"))?;
+        let uart = cpu.bus.lock().find_devices::<UART>().first().map(|address_range| address_range.start)
+            .ok_or_else(|| missing("UART"))?;
+        Self::boot(cpu)?;
+        Ok(Self { clint, uart })
+    }
+
+    /// Runs `cpu` for up to `max_instructions`, servicing the SBI calls of its harts.
+    /// Returns `StopReason::Exit` when the payload shuts the system down.
+    pub fn run(&mut self, cpu: &mut CPU, max_instructions: u64) -> Result<StopReason, SbiError> {
+        let retired = |cpu: &CPU| cpu.harts.iter().map(|core| core.instructions_retired).sum::<u64>();
+        let start = retired(cpu);
+        loop {
+            let executed = retired(cpu) - start;
+            if executed >= max_instructions {
+                return Ok(StopReason::BudgetExhausted)
+            }
+            match cpu.run(max_instructions - executed)? {
+                (hart, StopReason::Trap { exception: Exception::EnvironmentCallFromSMode, pc, .. }) => {
+                    cpu.harts[hart].pc = pc + 4;
+                    match self.call(cpu, hart)? {
+                        Completion::Resume => {},
+                        Completion::Exit(code) => return Ok(StopReason::Exit { code }),
+                        Completion::Reset => {
+                            cpu.reset();
+                            Self::boot(cpu)?;
+                        }
+                    }
+                },
+                (_, reason) => return Ok(reason)
+            }
+        }
+    }
+
+    /// Boots the harts as firmware does. Hart 0 runs the boot ROM in M-mode if it's
+    /// there, and enters the payload in S-mode. The other harts are stopped until HSM
+    /// starts them.
+    fn boot(cpu: &mut CPU) -> Result<(), SbiError> {
+        let rom = cpu.bus.lock().find_devices::<BootROM>().into_iter()
+            .find(|address_range| address_range.contains(&cpu.harts[0].pc));
+        for core in cpu.harts.iter_mut() {
+            Self::delegate(core);
+        }
+        for core in cpu.harts.iter_mut().skip(1) {
+            core.stopped = true;
+        }
+
+        let core = &mut cpu.harts[0];
+        if let Some(rom) = rom {
+            core.run_until(|core| !rom.contains(&core.pc))
+                .map_err(|error| CPUError::CoreError { hart: 0, error })?;
+        }
+        core.privilege = Privilege::Supervisor;
+        Ok(())
+    }
+
+    /// Delegates interrupts and exceptions to S-mode, lets it read `time`, and
+    /// forwards the timer interrupt to it.
+    fn delegate(core: &mut Core) {
+        core.csr_registers[MEDELEG] = DELEGATED_EXCEPTIONS;
+        core.csr_registers[MIDELEG] = SUPERVISOR_INTERRUPTS;
+        core.csr_registers[MCOUNTEREN] = COUNTEREN_TM;
+        core.supervisor_timer = true;
+    }
+
+    /// Services the call of `hart`, and writes the results to its registers.
+    fn call(&mut self, cpu: &mut CPU, hart: usize) -> Result<Completion, SbiError> {
+        let core = &cpu.harts[hart];
+        let (extension, function) = (core.x_registers[XRegister::x17], core.x_registers[XRegister::x16]);
+        let a = [XRegister::x10, XRegister::x11, XRegister::x12, XRegister::x13, XRegister::x14, XRegister::x15]
+            .map(|register| core.x_registers[register]);
+
+        if extension <= LEGACY_SHUTDOWN {
+            if extension == LEGACY_SHUTDOWN {
+                return Ok(Completion::Exit(0))
+            }
+            let result = self.legacy_call(cpu, hart, extension, a[0])?;
+            cpu.harts[hart].x_registers[XRegister::x10] = result as u64;
+            return Ok(Completion::Resume)
+        }
+
+        let (error, value) = match (extension, function) {
+            (EXTENSION_BASE, 0) => (SUCCESS, SPEC_VERSION),
+            (EXTENSION_BASE, 1) => (SUCCESS, IMPLEMENTATION_ID),
+            (EXTENSION_BASE, 2) => (SUCCESS, IMPLEMENTATION_VERSION),
+            (EXTENSION_BASE, 3) => (SUCCESS, EXTENSIONS.contains(&a[0]) as u64),
+            (EXTENSION_BASE, 4) => (SUCCESS, cpu.harts[hart].csr_registers[MVENDORID]),
+            (EXTENSION_BASE, 5) => (SUCCESS, cpu.harts[hart].csr_registers[MARCHID]),
+            (EXTENSION_BASE, 6) => (SUCCESS, cpu.harts[hart].csr_registers[MIMPID]),
+            (EXTENSION_TIME, 0) => {
+                self.set_timer(cpu, hart, a[0])?;
+                (SUCCESS, 0)
+            },
+            (EXTENSION_IPI, 0) => match Self::select_harts(cpu, a[0], a[1]) {
+                Some(harts) => {
+                    for target in harts {
+                        Self::set_software_interrupt(cpu, target, true);
+                    }
+                    (SUCCESS, 0)
+                },
+                None => (ERR_INVALID_PARAM, 0)
+            },
+            // The address range and ASID are ignored, the whole TLB is flushed.
+            (EXTENSION_RFENCE, 0..=2) => match Self::select_harts(cpu, a[0], a[1]) {
+                Some(harts) => {
+                    if function == 0 {
+                        Self::fence_i(cpu, &harts);
+                    } else {
+                        Self::sfence_vma(cpu, &harts);
+                    }
+                    (SUCCESS, 0)
+                },
+                None => (ERR_INVALID_PARAM, 0)
+            },
+            (EXTENSION_HSM, 0) => Self::hart_start(cpu, a[0], a[1], a[2]),
+            (EXTENSION_HSM, 1) => {
+                cpu.harts[hart].stopped = true;
+                return Ok(Completion::Resume)
+            },
+            (EXTENSION_HSM, 2) => match cpu.harts.get(a[0] as usize) {
+                Some(core) => (SUCCESS, if core.stopped { HART_STOPPED } else { HART_STARTED }),
+                None => (ERR_INVALID_PARAM, 0)
+            },
+            // A retentive suspend returns at once, as if an interrupt woke the hart.
+            (EXTENSION_HSM, 3) if a[0] as u32 as u64 == SUSPEND_RETENTIVE => (SUCCESS, 0),
+            (EXTENSION_HSM, 3) => (ERR_NOT_SUPPORTED, 0),
+            (EXTENSION_SRST, 0) => match (a[0] as u32 as u64, a[1] as u32 as u64) {
+                (RESET_SHUTDOWN, reason) => return Ok(Completion::Exit((reason == REASON_SYSTEM_FAILURE) as u64)),
+                (RESET_COLD_REBOOT | RESET_WARM_REBOOT, _) => return Ok(Completion::Reset),
+                _ => (ERR_INVALID_PARAM, 0)
+            },
+            _ => (ERR_NOT_SUPPORTED, 0)
+        };
+        let core = &mut cpu.harts[hart];
+        core.x_registers[XRegister::x10] = error as u64;
+        core.x_registers[XRegister::x11] = value;
+        Ok(Completion::Resume)
+    }
+
+    /// Services a call of the legacy extensions, which only return a value.
+    fn legacy_call(&mut self, cpu: &mut CPU, hart: usize, extension: u64, argument: u64) -> Result<i64, SbiError> {
+        Ok(match extension {
+            LEGACY_SET_TIMER => {
+                self.set_timer(cpu, hart, argument)?;
+                SUCCESS
+            },
+            LEGACY_CONSOLE_PUTCHAR => {
+                cpu.bus.lock().write_int(self.uart + UART_DATA, argument & 0xFF, 1, Endianness::LittleEndian)?;
+                SUCCESS
+            },
+            LEGACY_CONSOLE_GETCHAR => {
+                let bus = cpu.bus.lock();
+                if bus.read_int(self.uart + UART_LSR, 1, Endianness::LittleEndian, false)? & UART_LSR_DR == 0 {
+                    -1
+                } else {
+                    bus.read_int(self.uart + UART_DATA, 1, Endianness::LittleEndian, false)? as i64
+                }
+            },
+            LEGACY_CLEAR_IPI => {
+                Self::set_software_interrupt(cpu, hart, false);
+                SUCCESS
+            },
+            // The hart mask is passed by address.
+            LEGACY_SEND_IPI | LEGACY_REMOTE_FENCE_I | LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => {
+                let mask = match cpu.bus.lock().read_int(argument as usize, 8, Endianness::LittleEndian, false) {
+                    Ok(mask) => mask,
+                    Err(_) => return Ok(ERR_INVALID_ADDRESS)
+                };
+                let harts = match Self::select_harts(cpu, mask, 0) {
+                    Some(harts) => harts,
+                    None => return Ok(ERR_INVALID_PARAM)
+                };
+                match extension {
+                    LEGACY_SEND_IPI => for target in harts {
+                        Self::set_software_interrupt(cpu, target, true);
+                    },
+                    LEGACY_REMOTE_FENCE_I => Self::fence_i(cpu, &harts),
+                    _ => Self::sfence_vma(cpu, &harts)
+                }
+                SUCCESS
+            },
+            _ => ERR_NOT_SUPPORTED
+        })
+    }
+
+    /// Programs the timer of `hart`, which also clears a pending timer interrupt.
+    fn set_timer(&self, cpu: &CPU, hart: usize, time: u64) -> Result<(), DeviceError> {
+        cpu.bus.lock().write_int(self.clint + CLINT_MTIMECMP + hart * 8, time, 8, Endianness::LittleEndian)
+    }
+
+    /// Sets or clears the supervisor software interrupt of `hart`, which the payload
+    /// clears itself through `sip`.
+    fn set_software_interrupt(cpu: &mut CPU, hart: usize, pending: bool) {
+        let mip = &mut cpu.harts[hart].csr_registers[MIP];
+        *mip = if pending { *mip | MIP_SSIP } else { *mip & !MIP_SSIP };
+    }
+
+    /// The harts of a hart mask, where a base of -1 selects every hart. Returns
+    /// `None` if the mask selects a hart that doesn't exist.
+    fn select_harts(cpu: &CPU, mask: u64, base: u64) -> Option<Vec<usize>> {
+        if base == u64::MAX {
+            return Some((0..cpu.harts.len()).collect())
+        }
+        (0..64).filter(|bit| mask & (1 << bit) != 0)
+            .map(|bit| (base as usize).checked_add(bit).filter(|hart| *hart < cpu.harts.len()))
+            .collect()
+    }
+
+    fn fence_i(cpu: &mut CPU, harts: &[usize]) {
+        for hart in harts {
+            let core = &mut cpu.harts[*hart];
+            core.decode_cache.flush();
+            core.block_cache.flush();
+        }
+    }
+
+    fn sfence_vma(cpu: &mut CPU, harts: &[usize]) {
+        for hart in harts {
+            cpu.harts[*hart].tlb.flush();
+        }
+    }
+
+    /// Starts a stopped hart at `address` in S-mode with interrupts disabled, with its
+    /// hart id in `a0` and `opaque` in `a1`.
+    fn hart_start(cpu: &mut CPU, hart: u64, address: u64, opaque: u64) -> (i64, u64) {
+        let core = match cpu.harts.get_mut(hart as usize) {
+            Some(core) => core,
+            None => return (ERR_INVALID_PARAM, 0)
+        };
+        if !core.stopped {
+            return (ERR_ALREADY_AVAILABLE, 0)
+        }
+        core.stopped = false;
+        core.privilege = Privilege::Supervisor;
+        core.csr_registers[MSTATUS] &= !MSTATUS_SIE;
+        core.pc = address as usize;
+        core.x_registers[XRegister::x10] = hart;
+        core.x_registers[XRegister::x11] = opaque;
+        (SUCCESS, 0)
+    }
+}
+
+impl Display for SbiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SbiError {}
+
+impl From<CPUError> for SbiError {
+    fn from(error: CPUError) -> Self {
+        Self::CPUError(error)
+    }
+}
+
+impl From<DeviceError> for SbiError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
@@ -0,0 +1,450 @@
+use crate::cpu::core::Core;
+use crate::cpu::cpu::{CPU, CPUError};
+use crate::cpu::register::XRegister;
+use crate::cpu::trap::{Exception, StopReason};
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::linux::FileDescriptor;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// The instructions around the `ebreak` of a call, which a debugger wouldn't emit.
+const SLLI_X0_0X1F: u64 = 0x01F0_1013;
+const EBREAK: u64 = 0x0010_0073;
+const SRAI_X0_7: u64 = 0x4070_5013;
+
+// Operation numbers of the Arm semihosting specification, which RISC-V adopts.
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0A;
+const SYS_FLEN: u64 = 0x0C;
+const SYS_TMPNAM: u64 = 0x0D;
+const SYS_REMOVE: u64 = 0x0E;
+const SYS_RENAME: u64 = 0x0F;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_SYSTEM: u64 = 0x12;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+const SYS_ELAPSED: u64 = 0x30;
+const SYS_TICKFREQ: u64 = 0x31;
+
+/// Exit reason of a program that returned from `main` or called `exit`.
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+/// Name that opens the console: standard input in read modes, standard output in
+/// write modes and standard error in append modes.
+const CONSOLE_NAME: &[u8] = b":tt";
+/// Ticks of `SYS_ELAPSED` per second.
+const TICK_FREQUENCY: u64 = 1_000_000;
+/// Longest name or transfer of a single call.
+const MAX_TRANSFER: usize = 1 << 20;
+
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+#[derive(Debug)]
+pub enum SemihostingError {
+    IoError(io::Error),
+    CPUError(CPUError),
+}
+
+/// A file opened by the program.
+enum Handle {
+    /// Standard input, output or error.
+    Console(usize),
+    File(File),
+}
+
+/// RISC-V semihosting, through which bare-metal programs built with newlib or
+/// picolibc print, read files and exit without drivers.
+///
+/// A call is an `ebreak` between `slli x0, x0, 0x1f` and `srai x0, x0, 7`, with the
+/// operation number in `a0` and its argument or parameter block in `a1`. While
+/// `run` runs the harts, such an `ebreak` stops the hart even if the program has
+/// installed a trap handler, and the call is serviced on the host before the hart
+/// resumes past it with the result in `a0`.
+///
+/// Files are opened relative to a sandbox directory, and names that are absolute
+/// or contain `..` are refused.
+pub struct Semihosting {
+    root: PathBuf,
+    /// Standard input, output and error, which the console name opens.
+    console: [FileDescriptor; 3],
+    handles: HashMap<u64, Handle>,
+    /// Error of the last call that failed, as the host reports it.
+    errno: i64,
+    command_line: String,
+    start_time: Instant,
+}
+
+impl Core {
+    /// Whether the instruction at `pc` is the `ebreak` of a semihosting call.
+    pub(crate) fn is_semihosting_call(&self) -> bool {
+        let bus = self.bus.lock();
+        let word = |address: usize| bus.read_int(address, 4, Endianness::LittleEndian, false).ok();
+        self.pc >= 4 && word(self.pc) == Some(EBREAK)
+            && word(self.pc - 4) == Some(SLLI_X0_0X1F) && word(self.pc + 4) == Some(SRAI_X0_7)
+    }
+}
+
+impl Semihosting {
+    /// Serves the files of `root`, which must be a directory, to the program.
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, SemihostingError> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(SemihostingError::IoError(io::Error::new(io::ErrorKind::InvalidInput, "not a directory")))
+        }
+        Ok(Self {
+            root,
+            console: [
+                FileDescriptor::Input(Box::new(io::stdin())),
+                FileDescriptor::Output(Box::new(io::stdout())),
+                FileDescriptor::Output(Box::new(io::stderr())),
+            ],
+            handles: HashMap::new(),
+            errno: 0,
+            command_line: String::new(),
+            start_time: Instant::now(),
+        })
+    }
+
+    /// Replaces standard input (0), output (1) or error (2), e.g. to capture the
+    /// output.
+    pub fn set_console(&mut self, stream: usize, file: FileDescriptor) {
+        self.console[stream] = file;
+    }
+
+    /// Sets the command line `SYS_GET_CMDLINE` returns.
+    pub fn set_command_line(&mut self, command_line: &str) {
+        self.command_line = command_line.to_string();
+    }
+
+    /// Runs `cpu` for up to `max_instructions`, servicing the semihosting calls of
+    /// its harts. Returns `StopReason::Exit` when the program exits. Semihosting
+    /// stays enabled on the harts afterwards, so calls stop later runs with a
+    /// `Breakpoint` trap.
+    pub fn run(&mut self, cpu: &mut CPU, max_instructions: u64) -> Result<StopReason, SemihostingError> {
+        for core in cpu.harts.iter_mut() {
+            core.semihosting = true;
+        }
+        let retired = |cpu: &CPU| cpu.harts.iter().map(|core| core.instructions_retired).sum::<u64>();
+        let start = retired(cpu);
+        loop {
+            let executed = retired(cpu) - start;
+            if executed >= max_instructions {
+                return Ok(StopReason::BudgetExhausted)
+            }
+            match cpu.run(max_instructions - executed)? {
+                (hart, StopReason::Trap { exception: Exception::Breakpoint, pc, .. })
+                        if cpu.harts[hart].is_semihosting_call() => {
+                    if let Some(code) = self.call(cpu, hart) {
+                        return Ok(StopReason::Exit { code })
+                    }
+                    cpu.harts[hart].pc = pc + 4;
+                },
+                (_, reason) => return Ok(reason)
+            }
+        }
+    }
+
+    /// Services the call of `hart`. Returns the exit code if the program exits.
+    fn call(&mut self, cpu: &mut CPU, hart: usize) -> Option<u64> {
+        let core = &cpu.harts[hart];
+        let (operation, parameter) = (core.x_registers[XRegister::x10], core.x_registers[XRegister::x11]);
+        let mut bus = cpu.bus.lock();
+        let bus: &mut dyn Device = &mut *bus;
+        if operation == SYS_EXIT || operation == SYS_EXIT_EXTENDED {
+            // On RV64 the reason and exit code are in a parameter block.
+            let block = read_block::<2>(bus, parameter).unwrap_or([0, 1]);
+            return Some(if block[0] == ADP_STOPPED_APPLICATION_EXIT { block[1] } else { 1 })
+        }
+
+        let result = match operation {
+            SYS_OPEN => self.open(bus, parameter),
+            SYS_CLOSE => read_block::<1>(bus, parameter)
+                .map(|[handle]| if self.handles.remove(&handle).is_some() { 0 } else { self.fail(EBADF) }),
+            SYS_WRITEC => bus.read_bytes(parameter as usize, 1).map(|data| self.write_console(&data)),
+            SYS_WRITE0 => read_string(bus, parameter).map(|data| self.write_console(&data)),
+            SYS_WRITE => self.write(bus, parameter),
+            SYS_READ => self.read(bus, parameter),
+            SYS_READC => {
+                let mut byte = [0];
+                Ok(match read_stream(&mut self.console[0], &mut byte) {
+                    Ok(1) => byte[0] as i64,
+                    Ok(_) => -1,
+                    Err(error) => self.fail_with(&error)
+                })
+            },
+            SYS_ISERROR => read_block::<1>(bus, parameter).map(|[status]| ((status as i64) < 0) as i64),
+            SYS_ISTTY => read_block::<1>(bus, parameter).map(|[handle]| match self.handles.get(&handle) {
+                Some(Handle::Console(_)) => 1,
+                Some(Handle::File(_)) => 0,
+                None => self.fail(EBADF)
+            }),
+            SYS_SEEK => read_block::<2>(bus, parameter).map(|[handle, position]| match self.handles.get_mut(&handle) {
+                Some(Handle::File(file)) => match file.seek(SeekFrom::Start(position)) {
+                    Ok(_) => 0,
+                    Err(error) => self.fail_with(&error)
+                },
+                _ => self.fail(EBADF)
+            }),
+            SYS_FLEN => read_block::<1>(bus, parameter).map(|[handle]| match self.handles.get(&handle) {
+                Some(Handle::File(file)) => match file.metadata() {
+                    Ok(metadata) => metadata.len() as i64,
+                    Err(error) => self.fail_with(&error)
+                },
+                _ => self.fail(EBADF)
+            }),
+            SYS_TMPNAM => self.tmpnam(bus, parameter),
+            SYS_REMOVE => self.remove(bus, parameter),
+            SYS_RENAME => self.rename(bus, parameter),
+            SYS_CLOCK => Ok((self.start_time.elapsed().as_millis() / 10) as i64),
+            SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64),
+            // Running host commands would escape the sandbox.
+            SYS_SYSTEM => Ok(self.fail(EPERM)),
+            SYS_ERRNO => Ok(self.errno),
+            SYS_GET_CMDLINE => self.get_command_line(bus, parameter),
+            // Zeros leave the heap and stack where the C runtime's linker script puts them.
+            SYS_HEAPINFO => bus.read_int(parameter as usize, 8, Endianness::LittleEndian, false)
+                .and_then(|block| bus.write_bytes(block as usize, &[0; 32])).map(|_| 0),
+            SYS_ELAPSED => {
+                let ticks = self.start_time.elapsed().as_micros() as u64;
+                bus.write_int(parameter as usize, ticks, 8, Endianness::LittleEndian).map(|_| 0)
+            },
+            SYS_TICKFREQ => Ok(TICK_FREQUENCY as i64),
+            _ => Ok(self.fail(ENOSYS))
+        };
+        // Bad pointers fail the call rather than the emulator.
+        let result = result.unwrap_or_else(|_| self.fail(EFAULT));
+        cpu.harts[hart].x_registers[XRegister::x10] = result as u64;
+        None
+    }
+
+    fn open(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [name, mode, length] = read_block::<3>(bus, parameter)?;
+        let name = bus.read_bytes(name as usize, (length as usize).min(MAX_TRANSFER))?;
+        // Modes 0 to 11 are those of `fopen`: "r", "rb", "r+", "r+b", "w", ... "a+b".
+        let (kind, update) = (mode / 4, mode & 2 != 0);
+        let handle = if name == CONSOLE_NAME {
+            match kind {
+                0 => Handle::Console(0),
+                1 => Handle::Console(1),
+                _ => Handle::Console(2)
+            }
+        } else {
+            let path = match self.resolve(&name) {
+                Some(path) => path,
+                None => return Ok(self.fail(EACCES))
+            };
+            let mut options = OpenOptions::new();
+            match kind {
+                0 => options.read(true).write(update),
+                1 => options.write(true).create(true).truncate(true).read(update),
+                2 => options.append(true).create(true).read(update),
+                _ => return Ok(self.fail(EINVAL))
+            };
+            match options.open(path) {
+                Ok(file) => Handle::File(file),
+                Err(error) => return Ok(self.fail_with(&error))
+            }
+        };
+        let number = (1..).find(|number| !self.handles.contains_key(number)).unwrap_or_default();
+        self.handles.insert(number, handle);
+        Ok(number as i64)
+    }
+
+    /// Returns the bytes not written, 0 on success.
+    fn write(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [handle, address, length] = read_block::<3>(bus, parameter)?;
+        let data = bus.read_bytes(address as usize, (length as usize).min(MAX_TRANSFER))?;
+        let result = match self.handles.get_mut(&handle) {
+            Some(Handle::Console(stream)) => write_stream(&mut self.console[*stream], &data),
+            Some(Handle::File(file)) => file.write_all(&data),
+            None => return Ok(self.fail(EBADF))
+        };
+        Ok(match result {
+            Ok(_) => length as i64 - data.len() as i64,
+            Err(error) => {
+                self.fail_with(&error);
+                length as i64
+            }
+        })
+    }
+
+    /// Returns the bytes not read, so `length` at the end of the file.
+    fn read(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [handle, address, length] = read_block::<3>(bus, parameter)?;
+        let mut buffer = vec![0; (length as usize).min(MAX_TRANSFER)];
+        let count = match self.handles.get_mut(&handle) {
+            Some(Handle::Console(stream)) => read_stream(&mut self.console[*stream], &mut buffer),
+            Some(Handle::File(file)) => file.read(&mut buffer),
+            None => return Ok(self.fail(EBADF))
+        };
+        match count {
+            Ok(count) => {
+                bus.write_bytes(address as usize, &buffer[..count])?;
+                Ok(length as i64 - count as i64)
+            },
+            Err(error) => {
+                self.fail_with(&error);
+                Ok(length as i64)
+            }
+        }
+    }
+
+    fn write_console(&mut self, data: &[u8]) -> i64 {
+        write_stream(&mut self.console[1], data).map_or_else(|error| self.fail_with(&error), |_| 0)
+    }
+
+    /// Writes a name for a temporary file, numbered by `id`, which is opened in the
+    /// sandbox like any other.
+    fn tmpnam(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [address, id, length] = read_block::<3>(bus, parameter)?;
+        let mut name = format!("tmp{:03}", id & 0xFF).into_bytes();
+        name.push(0);
+        if name.len() > length as usize {
+            return Ok(self.fail(EINVAL))
+        }
+        bus.write_bytes(address as usize, &name)?;
+        Ok(0)
+    }
+
+    /// Returns 0 or the host error.
+    fn remove(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [name, length] = read_block::<2>(bus, parameter)?;
+        let name = bus.read_bytes(name as usize, (length as usize).min(MAX_TRANSFER))?;
+        Ok(match self.resolve(&name) {
+            Some(path) => std::fs::remove_file(path).map_or_else(|error| self.fail_with(&error), |_| 0),
+            None => self.fail(EACCES)
+        }.abs())
+    }
+
+    /// Returns 0 or the host error.
+    fn rename(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [from, from_length, to, to_length] = read_block::<4>(bus, parameter)?;
+        let from = bus.read_bytes(from as usize, (from_length as usize).min(MAX_TRANSFER))?;
+        let to = bus.read_bytes(to as usize, (to_length as usize).min(MAX_TRANSFER))?;
+        Ok(match (self.resolve(&from), self.resolve(&to)) {
+            (Some(from), Some(to)) => std::fs::rename(from, to).map_or_else(|error| self.fail_with(&error), |_| 0),
+            _ => self.fail(EACCES)
+        }.abs())
+    }
+
+    /// Writes the command line with a terminating zero into the buffer of the
+    /// block, and its length into the block.
+    fn get_command_line(&mut self, bus: &mut dyn Device, parameter: u64) -> Result<i64, DeviceError> {
+        let [address, length] = read_block::<2>(bus, parameter)?;
+        let mut command_line = self.command_line.clone().into_bytes();
+        let size = command_line.len() as u64;
+        command_line.push(0);
+        if command_line.len() as u64 > length {
+            return Ok(self.fail(EINVAL))
+        }
+        bus.write_bytes(address as usize, &command_line)?;
+        bus.write_int(parameter as usize + 8, size, 8, Endianness::LittleEndian)?;
+        Ok(0)
+    }
+
+    /// The path of `name` in the sandbox, unless it's absolute or leaves it.
+    fn resolve(&self, name: &[u8]) -> Option<PathBuf> {
+        let name = std::str::from_utf8(name).ok()?;
+        let path = Path::new(name);
+        let contained = path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        (contained && !name.is_empty()).then(|| self.root.join(path))
+    }
+
+    /// Records `errno` as the error of the call, and returns -1.
+    fn fail(&mut self, errno: i64) -> i64 {
+        self.errno = errno;
+        -1
+    }
+
+    fn fail_with(&mut self, error: &io::Error) -> i64 {
+        let errno = match error.raw_os_error() {
+            Some(code) => code as i64,
+            None => match error.kind() {
+                io::ErrorKind::NotFound => ENOENT,
+                io::ErrorKind::PermissionDenied => EACCES,
+                _ => EIO
+            }
+        };
+        self.fail(errno)
+    }
+}
+
+fn read_stream(stream: &mut FileDescriptor, buffer: &mut [u8]) -> io::Result<usize> {
+    match stream {
+        FileDescriptor::Input(input) => input.read(buffer),
+        FileDescriptor::File(file) => file.read(buffer),
+        FileDescriptor::Output(_) => Err(io::Error::from_raw_os_error(EBADF as i32))
+    }
+}
+
+fn write_stream(stream: &mut FileDescriptor, data: &[u8]) -> io::Result<()> {
+    match stream {
+        FileDescriptor::Output(output) => output.write_all(data).and_then(|_| output.flush()),
+        FileDescriptor::File(file) => file.write_all(data),
+        FileDescriptor::Input(_) => Err(io::Error::from_raw_os_error(EBADF as i32))
+    }
+}
+
+/// Reads the first `N` words of the parameter block at `address`.
+fn read_block<const N: usize>(bus: &dyn Device, address: u64) -> Result<[u64; N], DeviceError> {
+    let mut block = [0; N];
+    for (i, word) in block.iter_mut().enumerate() {
+        *word = bus.read_int(address as usize + i * 8, 8, Endianness::LittleEndian, false)?;
+    }
+    Ok(block)
+}
+
+fn read_string(bus: &dyn Device, address: u64) -> Result<Vec<u8>, DeviceError> {
+    let mut bytes = Vec::new();
+    while bytes.len() < MAX_TRANSFER {
+        match bus.read_int(address as usize + bytes.len(), 1, Endianness::LittleEndian, false)? {
+            0 => break,
+            byte => bytes.push(byte as u8)
+        }
+    }
+    Ok(bytes)
+}
+
+impl Display for SemihostingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SemihostingError {}
+
+impl From<io::Error> for SemihostingError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<CPUError> for SemihostingError {
+    fn from(error: CPUError) -> Self {
+        Self::CPUError(error)
+    }
+}
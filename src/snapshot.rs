@@ -0,0 +1,169 @@
+use crate::bus::MemoryMapEntry;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+
+/// Version of the snapshot format written by `CPU::save_snapshot`. Snapshots with
+/// another version are rejected.
+pub const SNAPSHOT_VERSION: u32 = 6;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"YARVESNP";
+
+// Longest string accepted when reading, to catch corrupted lengths early.
+const MAX_STRING_LENGTH: usize = 4096;
+
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    IoError(io::Error),
+    InvalidMagic,
+    UnsupportedVersion { version: u32 },
+    /// The device doesn't implement `Device::save_state` and `Device::restore_state`.
+    UnsupportedDevice { name: String },
+    HartCountMismatch { expected: usize, found: usize },
+    DeviceCountMismatch { expected: usize, found: usize },
+    /// The machine being restored has a different device at this position.
+    DeviceMismatch { expected: MemoryMapEntry, found: MemoryMapEntry },
+    /// The state doesn't fit the device or hart it's restored into.
+    InvalidState { name: String },
+}
+
+/// Writes the fields of a snapshot. All integers are little endian.
+pub struct SnapshotWriter<'a> {
+    writer: &'a mut dyn Write,
+}
+
+/// Reads the fields written by `SnapshotWriter`, in the same order.
+pub struct SnapshotReader<'a> {
+    reader: &'a mut dyn Read,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    pub fn new(writer: &'a mut dyn Write) -> Self {
+        Self { writer }
+    }
+
+    /// Writes the magic number and the format version.
+    pub fn write_header(&mut self) -> Result<(), SnapshotError> {
+        self.write_magic(SNAPSHOT_MAGIC)
+    }
+
+    /// Same as `write_header`, for other kinds of files sharing the format.
+    pub(crate) fn write_magic(&mut self, magic: &[u8; 8]) -> Result<(), SnapshotError> {
+        self.writer.write_all(magic)?;
+        self.write_u32(SNAPSHOT_VERSION)
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> Result<(), SnapshotError> {
+        Ok(self.writer.write_all(&[value])?)
+    }
+
+    pub fn write_bool(&mut self, value: bool) -> Result<(), SnapshotError> {
+        self.write_u8(value as u8)
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> Result<(), SnapshotError> {
+        Ok(self.writer.write_all(&value.to_le_bytes())?)
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> Result<(), SnapshotError> {
+        Ok(self.writer.write_all(&value.to_le_bytes())?)
+    }
+
+    /// Writes the length of `bytes` followed by the bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        self.write_u64(bytes.len() as u64)?;
+        Ok(self.writer.write_all(bytes)?)
+    }
+
+    pub fn write_string(&mut self, value: &str) -> Result<(), SnapshotError> {
+        self.write_bytes(value.as_bytes())
+    }
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(reader: &'a mut dyn Read) -> Self {
+        Self { reader }
+    }
+
+    /// Checks the magic number and the format version.
+    pub fn read_header(&mut self) -> Result<(), SnapshotError> {
+        self.read_magic(SNAPSHOT_MAGIC)
+    }
+
+    /// Same as `read_header`, for other kinds of files sharing the format.
+    pub(crate) fn read_magic(&mut self, expected: &[u8; 8]) -> Result<(), SnapshotError> {
+        let mut magic = [0; 8];
+        self.reader.read_exact(&mut magic)?;
+        if &magic != expected {
+            return Err(SnapshotError::InvalidMagic)
+        }
+        match self.read_u32()? {
+            SNAPSHOT_VERSION => Ok(()),
+            version => Err(SnapshotError::UnsupportedVersion { version })
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SnapshotError> {
+        let mut bytes = [0; 1];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads bytes written by `SnapshotWriter::write_bytes` into `buffer`, which must
+    /// have the same length. `name` identifies the state in the error otherwise.
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8], name: &str) -> Result<(), SnapshotError> {
+        if self.read_u64()? != buffer.len() as u64 {
+            return Err(SnapshotError::InvalidState { name: name.to_string() })
+        }
+        Ok(self.reader.read_exact(buffer)?)
+    }
+
+    /// Reads bytes written by `SnapshotWriter::write_bytes`, of any length up to
+    /// `max_length`. `name` identifies the state in the error otherwise.
+    pub fn read_bytes(&mut self, max_length: usize, name: &str) -> Result<Vec<u8>, SnapshotError> {
+        let length = self.read_u64()?;
+        if length > max_length as u64 {
+            return Err(SnapshotError::InvalidState { name: name.to_string() })
+        }
+        let mut bytes = vec![0; length as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_string(&mut self) -> Result<String, SnapshotError> {
+        let bytes = self.read_bytes(MAX_STRING_LENGTH, "string")?;
+        String::from_utf8(bytes)
+            .map_err(|_| SnapshotError::InvalidState { name: "string".to_string() })
+    }
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
@@ -3,8 +3,36 @@ mod test_instruction_decoding_u;
 mod test_instruction_decoding_r;
 mod test_instruction_decoding_s;
 mod test_instruction_decoding_b;
+mod test_instruction_decoding_j;
 mod test_exec_rv64i;
 mod test_core;
 mod test_dram;
 mod test_bus;
-mod test_utilities;
\ No newline at end of file
+mod test_utilities;mod test_fdt;
+mod test_cpu;
+mod test_boot_rom;
+mod test_exec_zicsr;
+mod test_flash;
+mod test_decode_cache;
+mod test_block;
+mod test_jit;
+mod test_run;
+mod test_exec_rv64a;
+mod test_exec_rv64m;
+mod test_smp;
+mod test_snapshot;
+mod test_clint;
+mod test_replay;
+mod test_gdb;
+mod test_elf;
+mod test_monitor;
+mod test_trace;
+mod test_lockstep;
+mod test_htif;
+mod test_linux;
+mod test_semihosting;
+mod test_sbi;
+mod test_kernel;
+mod test_plic;
+mod test_virtio;
+mod test_mmu;
@@ -0,0 +1,122 @@
+#[cfg(test)]
+mod test_block {
+    use crate::cpu::core::{Core, CoreError};
+    use crate::cpu::register::XRegister;
+    use crate::cpu::execute::InstructionExecuteError;
+    use crate::cpu::trap::Exception;
+    use crate::bus::{Bus, SharedBus};
+    use crate::dram::DRAM;
+    use crate::device::{Device, DeviceError};
+        
+    const PROGRAM: [u32; 19] = [
+        0x0640_0113,  // addi x2, x0, 100
+        0x0000_1537,  // lui  x10, 0x1
+        0x0070_0293,  // addi x5, x0, 7
+        0x0280_00EF,  // jal  x1, 40
+        0x0065_3023,  // sd   x6, 0(x10)
+        0x0045_2383,  // lw   x7, 4(x10)
+        0x4053_D433,  // sra  x8, x7, x5
+        0x4033_5493,  // srai x9, x6, 3
+        0x0085_0513,  // addi x10, x10, 8
+        0xFFF1_0113,  // addi x2, x2, -1
+        0xFE01_12E3,  // bne  x2, x0, -28
+        0x0084_B5B3,  // sltu x11, x9, x8
+        0x0000_006F,  // jal  x0, 0
+        0x0053_4333,  // xor  x6, x6, x5
+        0x0053_1613,  // slli x12, x6, 5
+        0x00C3_0333,  // add  x6, x6, x12
+        0x0053_5633,  // srl  x12, x6, x5
+        0x40C3_0333,  // sub  x6, x6, x12
+        0x0000_8067,  // jalr x0, 0(x1)
+    ];
+
+    fn new_test_core(program: &[u32]) -> Core {
+        let mut dram = DRAM::new(0x2000);
+        for (i, instruction) in program.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        Core::new(SharedBus::new(Bus::new(vec![(0, Box::new(dram))]).unwrap()))
+    }
+
+    fn assert_same_state(a: &Core, b: &Core) {
+        assert_eq!(a.pc, b.pc);
+        for i in 0..32 {
+            let register = XRegister::from(i);
+            assert_eq!(a.x_registers[register], b.x_registers[register], "{:?}", register);
+        }
+        assert_eq!(*a.bus.lock().read_bytes(0, 0x2000).unwrap(),
+                   *b.bus.lock().read_bytes(0, 0x2000).unwrap());
+    }
+
+    #[test]
+    fn test_matches_single_step() {
+        for instructions in [1, 7, 100, 1000, 1500] {
+            let mut stepped = new_test_core(&PROGRAM);
+            for _ in 0..instructions {
+                stepped.execute().unwrap();
+            }
+
+            let mut blocks = new_test_core(&PROGRAM);
+            assert_eq!(blocks.execute_blocks(instructions).unwrap(), instructions);
+            assert_same_state(&stepped, &blocks);
+        }
+    }
+
+    #[test]
+    fn test_chaining() {
+        let mut core = new_test_core(&PROGRAM);
+
+        core.execute_blocks(1500).unwrap();
+        assert_eq!(core.pc, 48);
+        assert_eq!(core.x_registers[XRegister::x2], 0);
+        assert!(core.block_cache.get_translations() <= 6);
+        assert!(core.block_cache.get_executions() > 300);
+    }
+
+    #[test]
+    fn test_self_modifying_block() {
+        let mut core = new_test_core(&[
+            0x0020_2423,  // sw   x2, 8(x0)
+            0x0010_0193,  // addi x3, x0, 1
+            0x0010_0093,  // addi x1, x0, 1
+            0x0000_006F,  // jal  x0, 0
+        ]);
+
+        core.x_registers[XRegister::x2] = 0x0020_0093;  // addi x1, x0, 2
+        assert_eq!(core.execute_blocks(4).unwrap(), 4);
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+        assert_eq!(core.x_registers[XRegister::x3], 1);
+        assert_eq!(core.pc, 12);
+    }
+
+    #[test]
+    fn test_fault_in_block() {
+        let mut core = new_test_core(&[
+            0x0010_0093,  // addi x1, x0, 1
+            0x0001_B103,  // ld   x2, 0(x3)
+            0x0010_0193,  // addi x3, x0, 1
+        ]);
+
+        core.x_registers[XRegister::x3] = 0x10000;
+        match core.execute_blocks(10) {
+            Err(CoreError::InstructionExecuteError(InstructionExecuteError::Exception {
+                exception: Exception::LoadAccessFault, tval: 0x10000 })) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(core.pc, 4);
+        assert_eq!(core.x_registers[XRegister::x1], 1);
+        assert_eq!(core.x_registers[XRegister::x3], 0x10000);
+    }
+
+    #[test]
+    fn test_code_outside_memory() {
+        let mut core = new_test_core(&[]);
+
+        // A jump into unmapped memory still faults when it's executed.
+        core.pc = 0x4000;
+        match core.execute_blocks(1) {
+            Err(CoreError::DeviceError(DeviceError::InvalidAddressReadFault)) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+#[cfg(test)]
+mod test_boot_rom {
+    use crate::boot_rom::BootROM;
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::core::CoreError;
+    use crate::cpu::execute::InstructionExecuteError;
+    use crate::cpu::trap::Exception;
+    use crate::device::{Device, DeviceError};
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+
+    #[test]
+    fn test_write_faults() {
+        let mut boot_rom = BootROM::new(0x8000_0000);
+        let value = boot_rom.read_int(0, 4, Endianness::LittleEndian, false).unwrap();
+
+        match boot_rom.write_bytes(0, &[0x13, 0, 0, 0]) {
+            Err(DeviceError::ReadOnlyWriteFault { address: 0 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match boot_rom.write_int(8, 0, 8, Endianness::LittleEndian) {
+            Err(DeviceError::ReadOnlyWriteFault { address: 8 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        assert_eq!(boot_rom.read_int(0, 4, Endianness::LittleEndian, false).unwrap(), value);
+    }
+
+    #[test]
+    fn test_trampoline() {
+        let mut cpu = CPU::new(vec![
+            (0x1000, Box::new(BootROM::new(0x8000_0000))),
+            (0x8000_0000, Box::new(DRAM::new(0x10000))),
+        ]).unwrap();
+        assert_eq!(cpu.harts[0].pc, 0x1000);
+
+        // addi x6, x0, 7
+        cpu.bus.lock().write_int(
+            0x8000_0000, 0x00700313, 4, Endianness::LittleEndian).unwrap();
+
+        let fdt_address = cpu.load_fdt().unwrap();
+        assert_eq!(cpu.harts[0].pc, 0x1000);
+
+        cpu.harts[0].x_registers[XRegister::x10] = 0x55;
+        cpu.harts[0].x_registers[XRegister::x11] = 0x55;
+        for _ in 0..5 {
+            cpu.harts[0].execute().unwrap();
+        }
+
+        assert_eq!(cpu.harts[0].pc, 0x8000_0000);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x10], 0);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x11], fdt_address as u64);
+
+        cpu.harts[0].execute().unwrap();
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x6], 7);
+    }
+
+    #[test]
+    fn test_stray_store_to_rom() {
+        let mut cpu = CPU::new(vec![
+            (0x1000, Box::new(BootROM::new(0x8000_0000))),
+            (0x8000_0000, Box::new(DRAM::new(0x100))),
+        ]).unwrap();
+
+        // lui x5, 0x1; sw x0, 0(x5)
+        cpu.bus.lock().write_int(
+            0x8000_0000, 0x000012b7, 4, Endianness::LittleEndian).unwrap();
+        cpu.bus.lock().write_int(
+            0x8000_0004, 0x0002a023, 4, Endianness::LittleEndian).unwrap();
+        cpu.harts[0].pc = 0x8000_0000;
+
+        cpu.harts[0].execute().unwrap();
+        match cpu.harts[0].execute() {
+            Err(CoreError::InstructionExecuteError(InstructionExecuteError::Exception {
+                exception: Exception::StoreAccessFault, tval: 0x1000 })) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
@@ -1,9 +1,11 @@
 
 #[cfg(test)]
 mod test_bus {
-    use crate::bus::Bus;
-    use crate::dram::DRAM;
-    use crate::device::Device;
+    use crate::bus::{Bus, BusError, MemoryMapEntry, MisalignedAccessPolicy};
+    use crate::dram::{DRAM, PAGE_SIZE};
+    use crate::boot_rom::BootROM;
+    use crate::uart::UART;
+    use crate::device::{Device, DeviceError};
     use crate::endianness::Endianness;
 
     #[test]
@@ -19,7 +21,7 @@ mod test_bus {
 
         let bus = Bus::new(
             vec![(5 , Box::new(dram))]
-        );
+        ).unwrap();
 
         assert_eq!(bus.get_address_space_size(), 21);
 
@@ -59,16 +61,16 @@ mod test_bus {
 
         let bus = Bus::new(
             vec![(5 , Box::new(dram))]
-        );
+        ).unwrap();
 
         let bytes = bus.read_bytes(8, 5).unwrap();
-        assert_eq!(bytes, [0x13, 0x14, 0x15, 0x16, 0x17]);
+        assert_eq!(*bytes, [0x13, 0x14, 0x15, 0x16, 0x17]);
 
         let bytes = bus.read_bytes(16, 5).unwrap();
-        assert_eq!(bytes, [0x1B, 0x1C, 0x1D, 0x1E, 0x1F]);
+        assert_eq!(*bytes, [0x1B, 0x1C, 0x1D, 0x1E, 0x1F]);
 
         let bytes = bus.read_bytes(5, 5).unwrap();
-        assert_eq!(bytes, [0x10, 0x11, 0x12, 0x13, 0x14]);
+        assert_eq!(*bytes, [0x10, 0x11, 0x12, 0x13, 0x14]);
 
         match bus.read_bytes(17, 5) {
             Ok(_) => {panic!("PANIC")},
@@ -94,11 +96,11 @@ mod test_bus {
 
         let mut bus = Bus::new(
             vec![(5, Box::new(dram))]
-        );
+        ).unwrap();
 
         bus.write_bytes(6, &[0x21, 0x22, 0x23, 0x24, 0x25]).unwrap();
         assert_eq!(
-            bus.read_bytes(5, 16).unwrap(),
+            *bus.read_bytes(5, 16).unwrap(),
             [
                 0x10, 0x21, 0x22, 0x23, 0x24, 0x25, 0x16, 0x17,
                 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F
@@ -107,7 +109,7 @@ mod test_bus {
 
         bus.write_bytes(18, &[0x3D, 0x3E, 0x3F]).unwrap();
         assert_eq!(
-            bus.read_bytes(5, 16).unwrap(),
+            *bus.read_bytes(5, 16).unwrap(),
             [
                 0x10, 0x21, 0x22, 0x23, 0x24, 0x25, 0x16, 0x17,
                 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x3D, 0x3E, 0x3F
@@ -138,7 +140,7 @@ mod test_bus {
 
         let bus = Bus::new(
             vec![(5, Box::new(dram))]
-        );
+        ).unwrap();
 
         assert_eq!(0x11, bus.read_int(6, 1, Endianness::LittleEndian, false).unwrap());
         assert_eq!(0x14_13_12_11, bus.read_int(6, 4, Endianness::LittleEndian, false).unwrap());
@@ -159,12 +161,265 @@ mod test_bus {
 
         let mut bus = Bus::new(
             vec![(5, Box::new(dram))]
-        );
+        ).unwrap();
 
         bus.write_int(8, 0x23, 1, Endianness::LittleEndian).unwrap();
-        assert_eq!(bus.read_bytes(7, 3).unwrap(), [0x12, 0x23, 0x14]);
+        assert_eq!(*bus.read_bytes(7, 3).unwrap(), [0x12, 0x23, 0x14]);
 
         bus.write_int(8, 0x24_23, 2, Endianness::LittleEndian).unwrap();
-        assert_eq!(bus.read_bytes(7, 4).unwrap(), [0x12, 0x23, 0x24, 0x15]);
+        assert_eq!(*bus.read_bytes(7, 4).unwrap(), [0x12, 0x23, 0x24, 0x15]);
+    }
+
+    #[test]
+    fn test_overlapping_devices() {
+        match Bus::new(vec![
+            (0x00, Box::new(DRAM::new(16))),
+            (0x0C, Box::new(UART::new()))
+        ]) {
+            Err(BusError::OverlappingDevices { first, second }) => {
+                assert_eq!(first, MemoryMapEntry {
+                    name: "dram".to_string(), address_range: 0x00..0x10 });
+                assert_eq!(second, MemoryMapEntry {
+                    name: "uart".to_string(), address_range: 0x0C..0x14 });
+            },
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match Bus::new(vec![
+            (0x10, Box::new(DRAM::new(16))),
+            (0x0C, Box::new(UART::new()))
+        ]) {
+            Err(BusError::OverlappingDevices { .. }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_zero_sized_device() {
+        match Bus::new(vec![(0x10, Box::new(DRAM::new(0)))]) {
+            Err(BusError::ZeroSizedDevice { name, base_address }) => {
+                assert_eq!(name, "dram");
+                assert_eq!(base_address, 0x10);
+            },
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_address_space_overflow() {
+        match Bus::new(vec![(usize::MAX - 4, Box::new(DRAM::new(16)))]) {
+            Err(BusError::AddressSpaceOverflow { .. }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let bus = Bus::new(vec![
+            (0x1000_0000, Box::new(UART::new())),
+            (0x8000_0000, Box::new(DRAM::new(16))),
+            (0x0000_0000, Box::new(DRAM::new(16))),
+        ]).unwrap();
+
+        let memory_map = bus.memory_map();
+        assert_eq!(memory_map.0, vec![
+            MemoryMapEntry { name: "dram".to_string(), address_range: 0x0000_0000..0x0000_0010 },
+            MemoryMapEntry { name: "uart".to_string(), address_range: 0x1000_0000..0x1000_0008 },
+            MemoryMapEntry { name: "dram".to_string(), address_range: 0x8000_0000..0x8000_0010 },
+        ]);
+
+        assert_eq!(format!("{}", memory_map),
+                   "0x0000000000000000-0x000000000000000f dram\n\
+                    0x0000000010000000-0x0000000010000007 uart\n\
+                    0x0000000080000000-0x000000008000000f dram\n");
+    }
+
+    /// Two 8 byte DRAM devices, back to back at 0x00 and 0x08.
+    fn new_split_bus(policy: MisalignedAccessPolicy) -> Bus {
+        let mut bus = Bus::new(vec![
+            (0x00, Box::new(DRAM::new(8))),
+            (0x08, Box::new(DRAM::new(8)))
+        ]).unwrap();
+        bus.write_bytes(0x00, &[0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17]).unwrap();
+        bus.write_bytes(0x08, &[0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x9F]).unwrap();
+        bus.set_misaligned_access_policy(policy);
+        bus
+    }
+
+    #[test]
+    fn test_misaligned_emulate_at_device_boundary() {
+        let mut bus = new_split_bus(MisalignedAccessPolicy::Emulate);
+
+        assert_eq_hex!(bus.read_int(0x06, 4, Endianness::LittleEndian, false).unwrap(),
+                       0x19_18_17_16);
+        assert_eq_hex!(bus.read_int(0x06, 4, Endianness::BigEndian, false).unwrap(),
+                       0x16_17_18_19);
+        assert_eq_hex!(bus.read_int(0x07, 2, Endianness::LittleEndian, false).unwrap(),
+                       0x18_17);
+
+        // Misaligned, but within a single device.
+        assert_eq_hex!(bus.read_int(0x0D, 2, Endianness::LittleEndian, false).unwrap(),
+                       0x1E_1D);
+
+        bus.write_int(0x05, 0x24_23_22_21, 4, Endianness::LittleEndian).unwrap();
+        assert_eq!(*bus.read_bytes(0x00, 8).unwrap(),
+                   [0x10, 0x11, 0x12, 0x13, 0x14, 0x21, 0x22, 0x23]);
+        assert_eq!(*bus.read_bytes(0x08, 2).unwrap(), [0x24, 0x19]);
+
+        bus.write_int(0x07, 0x3132, 2, Endianness::BigEndian).unwrap();
+        assert_eq!(*bus.read_bytes(0x07, 1).unwrap(), [0x31]);
+        assert_eq!(*bus.read_bytes(0x08, 1).unwrap(), [0x32]);
+    }
+
+    #[test]
+    fn test_misaligned_emulate_into_unmapped_memory() {
+        let mut bus = new_split_bus(MisalignedAccessPolicy::Emulate);
+
+        match bus.read_int(0x0E, 4, Endianness::LittleEndian, false) {
+            Err(DeviceError::InvalidAddressReadFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        // Nothing may be written if part of the access is unmapped.
+        match bus.write_int(0x0E, 0xFFFF_FFFF, 4, Endianness::LittleEndian) {
+            Err(DeviceError::InvalidAddressWriteFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(*bus.read_bytes(0x0E, 2).unwrap(), [0x1E, 0x9F]);
+    }
+
+    #[test]
+    fn test_misaligned_emulate_sign_extend() {
+        let bus = new_split_bus(MisalignedAccessPolicy::Emulate);
+
+        assert_eq_hex!(bus.read_int(0x0F, 1, Endianness::LittleEndian, true).unwrap(),
+                       0xFFFFFFFF_FFFFFF9F);
+        assert_eq_hex!(bus.read_int(0x07, 8, Endianness::LittleEndian, true).unwrap(),
+                       0x1E_1D_1C_1B_1A_19_18_17);
+        assert_eq_hex!(bus.read_int(0x0D, 3, Endianness::LittleEndian, true).unwrap(),
+                       0xFFFFFFFF_FF9F1E1D);
+    }
+
+    #[test]
+    fn test_misaligned_trap() {
+        let mut bus = new_split_bus(MisalignedAccessPolicy::Trap);
+
+        assert_eq_hex!(bus.read_int(0x04, 4, Endianness::LittleEndian, false).unwrap(),
+                       0x17_16_15_14);
+        assert_eq_hex!(bus.read_int(0x07, 1, Endianness::LittleEndian, false).unwrap(),
+                       0x17);
+
+        match bus.read_int(0x06, 4, Endianness::LittleEndian, false) {
+            Err(e @ DeviceError::MisalignedAddressReadTrap { address: 0x06 }) => {
+                assert_eq!(e.trap_cause(), Some(4));
+            },
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match bus.write_int(0x0A, 0, 4, Endianness::LittleEndian) {
+            Err(e @ DeviceError::MisalignedAddressWriteTrap { address: 0x0A }) => {
+                assert_eq!(e.trap_cause(), Some(6));
+            },
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_misaligned_fault() {
+        let mut bus = new_split_bus(MisalignedAccessPolicy::Fault);
+
+        match bus.read_int(0x07, 2, Endianness::LittleEndian, false) {
+            Err(e @ DeviceError::MisalignedAddressReadFault { address: 0x07 }) => {
+                assert_eq!(e.trap_cause(), None);
+            },
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match bus.write_int(0x01, 0, 8, Endianness::LittleEndian) {
+            Err(DeviceError::MisalignedAddressWriteFault { address: 0x01 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        bus.write_int(0x08, 0x42, 8, Endianness::LittleEndian).unwrap();
+        assert_eq_hex!(bus.read_int(0x08, 8, Endianness::LittleEndian, false).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn test_aligned_straddling_access() {
+        // An aligned access can only straddle two devices if a device is not aligned itself.
+        let mut bus = Bus::new(vec![
+            (0x00, Box::new(DRAM::new(6))),
+            (0x06, Box::new(DRAM::new(10)))
+        ]).unwrap();
+
+        match bus.read_int(0x04, 4, Endianness::LittleEndian, false) {
+            Err(DeviceError::StraddlingAccessFault { address: 0x04, size: 4 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match bus.write_int(0x04, 0, 4, Endianness::LittleEndian) {
+            Err(DeviceError::StraddlingAccessFault { address: 0x04, size: 4 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_straddling_byte_access() {
+        let mut bus = new_split_bus(MisalignedAccessPolicy::Emulate);
+
+        match bus.read_bytes(0x04, 8) {
+            Err(DeviceError::StraddlingAccessFault { address: 0x04, size: 8 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match bus.write_bytes(0x07, &[0, 0]) {
+            Err(DeviceError::StraddlingAccessFault { address: 0x07, size: 2 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(*bus.read_bytes(0x07, 1).unwrap(), [0x17]);
+    }
+
+    #[test]
+    fn test_memory_region_cache() {
+        let mut bus = Bus::new(vec![
+            (0x1000, Box::new(DRAM::new(2 * PAGE_SIZE)))
+        ]).unwrap();
+
+        // The first read caches the shared zero page, which must not be
+        // returned any more once the page has been written.
+        assert_eq!(bus.read_int(0x1008, 8, Endianness::LittleEndian, false).unwrap(), 0);
+        bus.write_int(0x1008, 0x0102_0304_0506_0708, 8, Endianness::LittleEndian).unwrap();
+        assert_eq!(bus.read_int(0x1008, 8, Endianness::LittleEndian, false).unwrap(),
+                   0x0102_0304_0506_0708);
+        assert_eq!(bus.read_int(0x1008, 2, Endianness::BigEndian, false).unwrap(), 0x0807);
+        assert_eq!(*bus.read_bytes(0x1008, 2).unwrap(), [0x08, 0x07]);
+
+        // Writes through the device must be visible as well.
+        assert_eq!(bus.read_int(0x2000, 4, Endianness::LittleEndian, false).unwrap(), 0);
+        let (_, device) = bus.get_device_mut(0x2000).unwrap();
+        device.write_bytes(PAGE_SIZE, &[0xFF, 0xFF, 0xFF, 0xFF]).unwrap();
+        assert_eq!(bus.read_int(0x2000, 4, Endianness::LittleEndian, true).unwrap(),
+                   0xFFFF_FFFF_FFFF_FFFF);
+
+        // Accesses crossing a page boundary take the slow path.
+        bus.write_int(0x1FFC, 0x1122_3344_5566_7788, 8, Endianness::LittleEndian).unwrap();
+        assert_eq!(bus.read_int(0x1FFC, 8, Endianness::LittleEndian, false).unwrap(),
+                   0x1122_3344_5566_7788);
+        assert_eq!(*bus.read_bytes(0x1FFE, 4).unwrap(), [0x66, 0x55, 0x44, 0x33]);
+    }
+
+    #[test]
+    fn test_memory_region_read_only() {
+        let mut bus = Bus::new(vec![
+            (0x0, Box::new(BootROM::new(0x8000_0000)))
+        ]).unwrap();
+
+        assert_eq!(bus.read_int(24, 8, Endianness::LittleEndian, false).unwrap(), 0x8000_0000);
+
+        match bus.write_int(24, 0, 8, Endianness::LittleEndian) {
+            Err(DeviceError::ReadOnlyWriteFault { address: 24 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(bus.read_int(24, 8, Endianness::LittleEndian, false).unwrap(), 0x8000_0000);
     }
 }
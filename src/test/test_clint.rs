@@ -0,0 +1,123 @@
+#[cfg(test)]
+mod test_clint {
+    use crate::clint::CLINT;
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::csr::MEPC;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::{Interrupt, StopReason};
+    use crate::device::{Device, DeviceError};
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::test::test_fdt::test_fdt::{parse, get_property};
+
+    const CLINT_ADDRESS: usize = 0x200_0000;
+
+    // Raises a software interrupt, then a timer interrupt by setting mtimecmp to 0.
+    // The handler at 0x100 saves mcause in x10, clears both and counts them in x11.
+    const PROGRAM: [(usize, u32); 19] = [
+        (0x00, 0x1000_0413),  // addi   x8, x0, 0x100
+        (0x04, 0x3054_1073),  // csrrw  x0, mtvec, x8
+        (0x08, 0x0880_0413),  // addi   x8, x0, 0x88
+        (0x0C, 0x3044_1073),  // csrrw  x0, mie, x8
+        (0x10, 0x0200_02B7),  // lui    x5, 0x2000
+        (0x14, 0x0200_43B7),  // lui    x7, 0x2004
+        (0x18, 0x0010_0313),  // addi   x6, x0, 1
+        (0x1C, 0x0062_A023),  // sw     x6, 0(x5)
+        (0x20, 0x3004_6073),  // csrrsi x0, mstatus, 8
+        (0x24, 0x0005_8063),  // beq    x11, x0, 0
+        (0x28, 0x0005_0613),  // addi   x12, x10, 0
+        (0x2C, 0x0003_B023),  // sd     x0, 0(x7)
+        (0x30, 0x0000_006F),  // jal    x0, 0
+        (0x100, 0x3420_2573), // csrrs  x10, mcause, x0
+        (0x104, 0x0002_A023), // sw     x0, 0(x5)
+        (0x108, 0xFFF0_0313), // addi   x6, x0, -1
+        (0x10C, 0x0063_B023), // sd     x6, 0(x7)
+        (0x110, 0x0015_8593), // addi   x11, x11, 1
+        (0x114, 0x3020_0073), // mret
+    ];
+
+    fn new_test_cpu(hart_count: usize) -> CPU {
+        let mut dram = DRAM::new(0x1000);
+        for (address, instruction) in PROGRAM.iter() {
+            dram.write_bytes(*address, &instruction.to_le_bytes()).unwrap();
+        }
+        CPU::with_harts(vec![
+            (0, Box::new(dram)),
+            (CLINT_ADDRESS, Box::new(CLINT::new(hart_count))),
+        ], hart_count).unwrap()
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut cpu = new_test_cpu(1);
+        cpu.set_quantum(50);
+
+        assert_eq!(cpu.run(1000).unwrap(), (0, StopReason::BudgetExhausted));
+        let core = &cpu.harts[0];
+        assert_eq!(core.x_registers[XRegister::x12], Interrupt::MachineSoftware.get_cause());
+        assert_eq!(core.x_registers[XRegister::x10], Interrupt::MachineTimer.get_cause());
+        assert_eq!(core.x_registers[XRegister::x11], 2);
+        assert_eq!(core.pc, 0x30);
+    }
+
+    #[test]
+    fn test_interrupt_point() {
+        // The software interrupt is taken right after MIE is set, however the run is split.
+        for quantum in [1, 2, 5, 1000] {
+            let mut cpu = new_test_cpu(1);
+            cpu.set_quantum(quantum);
+            while cpu.harts[0].x_registers[XRegister::x11] == 0 {
+                cpu.run(1).unwrap();
+            }
+            // 9 instructions up to csrrsi, then the handler up to the addi.
+            assert_eq!(cpu.harts[0].get_instructions_retired(), 14);
+            assert_eq!(cpu.harts[0].csr_registers[MEPC], 0x24);
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut clint = CLINT::new(2);
+
+        clint.write_int(0x4, 1, 4, Endianness::LittleEndian).unwrap();
+        assert_eq!(clint.read_int(0x0, 4, Endianness::LittleEndian, false).unwrap(), 0);
+        assert_eq!(clint.read_int(0x4, 4, Endianness::LittleEndian, false).unwrap(), 1);
+        assert!(clint.take_interrupt_raised());
+        assert!(!clint.take_interrupt_raised());
+        assert_eq!(clint.get_pending(1), 1 << 3);
+        assert_eq!(clint.get_pending(0), 0);
+
+        assert_eq!(clint.read_int(0x4008, 8, Endianness::LittleEndian, false).unwrap(), u64::MAX);
+        clint.write_int(0x4008, 0x1234, 4, Endianness::LittleEndian).unwrap();
+        clint.write_int(0x400C, 0, 4, Endianness::LittleEndian).unwrap();
+        assert_eq!(clint.read_int(0x4008, 8, Endianness::LittleEndian, false).unwrap(), 0x1234);
+        assert_eq!(clint.read_int(0x400C, 4, Endianness::LittleEndian, false).unwrap(), 0);
+
+        let time = clint.read_int(0xBFF8, 8, Endianness::LittleEndian, false).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(clint.read_int(0xBFF8, 8, Endianness::LittleEndian, false).unwrap() > time);
+        assert_eq!(clint.get_pending(1), (1 << 3) | (1 << 7));
+
+        match clint.read_int(0x4004, 8, Endianness::LittleEndian, false) {
+            Err(DeviceError::MisalignedAddressReadTrap { address: 0x4004 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        match clint.write_int(0x0, 1, 1, Endianness::LittleEndian) {
+            Err(DeviceError::InvalidSizeWriteFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_fdt() {
+        let cpu = new_test_cpu(2);
+        let properties = parse(&cpu.generate_fdt().unwrap());
+
+        let phandle = get_property(&properties, "/cpus/cpu@1/interrupt-controller", "phandle").unwrap();
+        let interrupts = get_property(&properties, "/clint@2000000", "interrupts-extended").unwrap();
+        assert_eq!(interrupts.len(), 32);
+        assert_eq!(&interrupts[16..20], phandle);
+        assert_eq!(&interrupts[20..24], &[0, 0, 0, 3]);
+        assert_eq!(&interrupts[28..32], &[0, 0, 0, 7]);
+    }
+}
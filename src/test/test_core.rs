@@ -3,18 +3,16 @@
 mod test_core {
     use crate::cpu::register::XRegister;
     use crate::cpu::core::Core;
-    use crate::bus::Bus;
+    use crate::bus::{Bus, SharedBus};
     use crate::dram::DRAM;
     use crate::device::Device;
-    use std::rc::Rc;
-    use std::cell::RefCell;
-    use crate::endianness::Endianness;
+            use crate::endianness::Endianness;
 
     fn new_test_core() -> Core {
         let dram = DRAM::new(16);
-        Core::new(Rc::new(RefCell::new(Bus::new(
+        Core::new(SharedBus::new(Bus::new(
             vec![(0 , Box::new(dram))]
-        ))))
+        ).unwrap()))
     }
 
     #[test]
@@ -34,7 +32,7 @@ mod test_core {
     fn test_execute() {
         let mut core = new_test_core();
         {
-            let mut bus = core.bus.borrow_mut();
+            let mut bus = core.bus.lock();
             bus.write_int(
                 0x00,
                 0b_0000000_10111_11010_000_01110_0110011,
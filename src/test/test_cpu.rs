@@ -0,0 +1,67 @@
+#[cfg(test)]
+mod test_cpu {
+    use crate::cpu::cpu::{CPU, CPUError};
+    use crate::cpu::register::XRegister;
+    use crate::dram::DRAM;
+    use crate::uart::UART;
+    use crate::device::Device;
+    use crate::test::test_fdt::test_fdt::{parse, get_property};
+
+    fn new_test_cpu() -> CPU {
+        CPU::new(vec![
+            (0x1000_0000, Box::new(UART::new())),
+            (0x8000_0000, Box::new(DRAM::new(0x10000))),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn test_generate_fdt() {
+        let cpu = new_test_cpu();
+        let properties = parse(&cpu.generate_fdt().unwrap());
+
+        assert_eq!(get_property(&properties, "", "#address-cells").unwrap(), &[0, 0, 0, 2]);
+        assert_eq!(get_property(&properties, "/chosen", "stdout-path").unwrap(),
+                   b"/serial@10000000\0");
+        assert_eq!(get_property(&properties, "/cpus/cpu@0", "riscv,isa").unwrap(), b"rv64ima\0");
+        assert_eq!(get_property(&properties, "/cpus/cpu@0", "mmu-type").unwrap(), b"riscv,sv39\0");
+        assert_eq!(get_property(&properties, "/cpus/cpu@0/interrupt-controller", "compatible")
+                       .unwrap(), b"riscv,cpu-intc\0");
+        assert_eq!(get_property(&properties, "/memory@80000000", "device_type").unwrap(),
+                   b"memory\0");
+        assert_eq!(get_property(&properties, "/memory@80000000", "reg").unwrap(),
+                   &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(get_property(&properties, "/serial@10000000", "compatible").unwrap(),
+                   b"ns16550a\0");
+    }
+
+    #[test]
+    fn test_load_fdt() {
+        let mut cpu = new_test_cpu();
+        let blob = cpu.generate_fdt().unwrap();
+        let address = cpu.load_fdt().unwrap();
+
+        assert_eq!(address % 8, 0);
+        assert!(address + 8 > 0x8001_0000 - blob.len());
+        assert!(address + blob.len() <= 0x8001_0000);
+        assert_eq!(cpu.bus.lock().read_bytes(address, blob.len()).unwrap(), blob.as_slice());
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x10], 0);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x11], address as u64);
+
+        cpu.harts[0].x_registers[XRegister::x11] = 0;
+        cpu.reset();
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x11], address as u64);
+    }
+
+    #[test]
+    fn test_load_fdt_no_memory() {
+        let mut cpu = CPU::new(vec![
+            (0x0, Box::new(DRAM::new(16))),
+            (0x1000_0000, Box::new(UART::new())),
+        ]).unwrap();
+
+        match cpu.load_fdt() {
+            Err(CPUError::NoMemoryForFdt { .. }) => {},
+            x => { panic!("PANIC {:?}", x.map(|_| ())) }
+        }
+    }
+}
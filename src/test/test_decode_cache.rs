@@ -0,0 +1,105 @@
+#[cfg(test)]
+mod test_decode_cache {
+    use crate::cpu::core::Core;
+    use crate::cpu::register::XRegister;
+    use crate::bus::{Bus, SharedBus};
+    use crate::dram::DRAM;
+    use crate::uart::UART;
+    use crate::device::Device;
+    use crate::endianness::Endianness;
+        
+    const ADDI_X1_1: u64 = 0x0010_0093;  // addi x1, x0, 1
+    const ADDI_X1_2: u64 = 0x0020_0093;  // addi x1, x0, 2
+    const SW_X2_4: u64 = 0x0020_2223;    // sw   x2, 4(x0)
+    const FENCE_I: u64 = 0x0000_100F;    // fence.i
+
+    fn new_test_core(program: &[u64]) -> Core {
+        let mut bus = Bus::new(vec![
+            (0x0, Box::new(DRAM::new(0x2000))),
+            (0x2000, Box::new(UART::new()))
+        ]).unwrap();
+        for (i, instruction) in program.iter().enumerate() {
+            bus.write_int(i * 4, *instruction, 4, Endianness::LittleEndian).unwrap();
+        }
+        Core::new(SharedBus::new(bus))
+    }
+
+    fn execute_at(core: &mut Core, pc: usize) {
+        core.pc = pc;
+        core.execute().unwrap();
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let mut core = new_test_core(&[ADDI_X1_1, ADDI_X1_2]);
+
+        execute_at(&mut core, 0);
+        execute_at(&mut core, 4);
+        execute_at(&mut core, 0);
+        execute_at(&mut core, 4);
+        assert_eq!(core.decode_cache.get_misses(), 2);
+        assert_eq!(core.decode_cache.get_hits(), 2);
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+
+        core.decode_cache.reset_counters();
+        assert_eq!(core.decode_cache.get_hits(), 0);
+    }
+
+    #[test]
+    fn test_invalidate_on_bus_write() {
+        let mut core = new_test_core(&[ADDI_X1_1]);
+
+        execute_at(&mut core, 0);
+        core.bus.lock().write_int(0, ADDI_X1_2, 4, Endianness::LittleEndian).unwrap();
+        execute_at(&mut core, 0);
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+        assert_eq!(core.decode_cache.get_misses(), 2);
+    }
+
+    #[test]
+    fn test_invalidate_on_device_write() {
+        let mut core = new_test_core(&[ADDI_X1_1]);
+
+        execute_at(&mut core, 0);
+        core.bus.lock().get_device_mut(0).unwrap().1
+            .write_bytes(0, &(ADDI_X1_2 as u32).to_le_bytes()).unwrap();
+        execute_at(&mut core, 0);
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut core = new_test_core(&[SW_X2_4, ADDI_X1_1]);
+
+        execute_at(&mut core, 4);
+        assert_eq!(core.x_registers[XRegister::x1], 1);
+
+        core.x_registers[XRegister::x2] = ADDI_X1_2;
+        execute_at(&mut core, 0);
+        core.execute().unwrap();
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let mut core = new_test_core(&[ADDI_X1_1, FENCE_I]);
+
+        execute_at(&mut core, 0);
+        execute_at(&mut core, 4);
+        execute_at(&mut core, 0);
+        assert_eq!(core.decode_cache.get_misses(), 3);
+    }
+
+    #[test]
+    fn test_mmio_not_cached() {
+        let mut core = new_test_core(&[]);
+
+        // Executing from the UART: an all-zero word doesn't decode, but must be
+        // read again instead of being looked up in the cache.
+        assert!(!core.bus.lock().mark_code_page(0x2000));
+        core.pc = 0x2000;
+        assert!(core.execute().is_err());
+        assert!(core.execute().is_err());
+        assert_eq!(core.decode_cache.get_misses(), 2);
+    }
+}
@@ -1,8 +1,9 @@
 
 #[cfg(test)]
 mod test_dram {
-    use crate::dram::DRAM;
-    use crate::device::Device;
+    use crate::dram::{DRAM, PAGE_SIZE};
+    use std::borrow::Cow;
+    use crate::device::{Device, DeviceError};
     use crate::endianness::Endianness;
 
     #[test]
@@ -17,7 +18,7 @@ mod test_dram {
         }
 
         match dram.read_bytes(0, 16) {
-            Ok(read_buffer) => { assert_eq!(buffer, read_buffer)},
+            Ok(read_buffer) => { assert_eq!(buffer, *read_buffer)},
             Err(e) => {panic!("PANIC {:?}", e)}
         }
     }
@@ -35,7 +36,7 @@ mod test_dram {
         }
 
         match dram.read_bytes(5, 9) {
-            Ok(read_buffer) => { assert_eq!(expected_read, read_buffer)},
+            Ok(read_buffer) => { assert_eq!(expected_read, *read_buffer)},
             Err(e) => {panic!("PANIC {:?}", e)}
         }
     }
@@ -55,7 +56,7 @@ mod test_dram {
             }
 
             match dram.read_bytes(0, 16) {
-                Ok(read_buffer) => { assert_eq!(expected, read_buffer)},
+                Ok(read_buffer) => { assert_eq!(expected, *read_buffer)},
                 Err(e) => {panic!("PANIC {:?}", e)}
             }
         };
@@ -125,7 +126,7 @@ mod test_dram {
             }
 
             match dram.read_bytes(0, 16) {
-                Ok(read_buffer) => { assert_eq!(expected, read_buffer)},
+                Ok(read_buffer) => { assert_eq!(expected, *read_buffer)},
                 Err(e) => {panic!("PANIC {:?}", e)}
             }
         };
@@ -187,7 +188,7 @@ mod test_dram {
             }
 
             match dram.read_bytes(0, 16) {
-                Ok(read_buffer) => { assert_eq!(expected, read_buffer) },
+                Ok(read_buffer) => { assert_eq!(expected, *read_buffer) },
                 Err(e) => { panic!("PANIC {:?}", e) }
             }
         };
@@ -242,7 +243,7 @@ mod test_dram {
             }
 
             match dram.read_bytes(0, 16) {
-                Ok(read_buffer) => { assert_eq!(expected, read_buffer) },
+                Ok(read_buffer) => { assert_eq!(expected, *read_buffer) },
                 Err(e) => { panic!("PANIC {:?}", e) }
             }
         };
@@ -464,4 +465,88 @@ mod test_dram {
         assert_eq_hex!(sign_extended, 0xFFFFFFFFFFFF8040);
     }
 
+    #[test]
+    fn test_read_int_at_end_of_memory() {
+        let mut dram = DRAM::new(16);
+        dram.write_bytes(12, &[0x8C, 0x8D, 0x8E, 0x8F]).unwrap();
+
+        assert_eq_hex!(dram.read_int(15, 1, Endianness::LittleEndian, false).unwrap(), 0x8F);
+        assert_eq_hex!(dram.read_int(12, 4, Endianness::LittleEndian, false).unwrap(),
+                       0x8F_8E_8D_8C);
+        assert_eq_hex!(dram.read_int(12, 4, Endianness::BigEndian, true).unwrap(),
+                       0xFFFFFFFF_8C8D8E8F);
+        assert_eq_hex!(dram.read_int(8, 8, Endianness::LittleEndian, true).unwrap(),
+                       0x8F_8E_8D_8C_00_00_00_00);
+
+        match dram.read_int(13, 4, Endianness::LittleEndian, false) {
+            Err(DeviceError::InvalidAddressReadFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_sparse_allocation() {
+        let mut dram = DRAM::new(4 << 30);
+        assert_eq!(dram.get_address_space_size(), 4 << 30);
+        assert_eq!(dram.get_resident_pages(), 0);
+
+        assert_eq!(dram.read_int((4 << 30) - 8, 8, Endianness::LittleEndian, false).unwrap(), 0);
+        assert_eq!(dram.get_resident_pages(), 0);
+
+        dram.write_int((4 << 30) - 8, 0x1234, 8, Endianness::LittleEndian).unwrap();
+        dram.write_int((4 << 30) - 16, 0x5678, 8, Endianness::LittleEndian).unwrap();
+        assert_eq!(dram.get_resident_pages(), 1);
+        assert_eq!(dram.get_resident_size(), PAGE_SIZE);
+
+        dram.write_int(0x1000_0000, 0x1, 1, Endianness::LittleEndian).unwrap();
+        assert_eq!(dram.get_resident_pages(), 2);
+
+        assert_eq!(dram.read_int((4 << 30) - 8, 8, Endianness::LittleEndian, false).unwrap(), 0x1234);
+        assert_eq!(dram.read_int(0x1000_0000, 8, Endianness::LittleEndian, false).unwrap(), 0x1);
+        assert_eq!(dram.read_int(0x0FFF_FFF8, 8, Endianness::LittleEndian, false).unwrap(), 0);
+
+        match dram.write_int(4 << 30, 0, 1, Endianness::LittleEndian) {
+            Err(DeviceError::InvalidAddressWriteFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        match dram.read_bytes(usize::MAX, 2) {
+            Err(DeviceError::InvalidAddressReadFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        // The end of memory is the end of a page table.
+        assert!(dram.read_bytes(4 << 30, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_page_boundary() {
+        let mut dram = DRAM::new(3 * PAGE_SIZE);
+
+        dram.write_bytes(PAGE_SIZE - 2, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(dram.get_resident_pages(), 2);
+
+        match dram.read_bytes(PAGE_SIZE - 4, 4).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(*bytes, [0, 0, 1, 2]),
+            Cow::Owned(_) => panic!("PANIC")
+        }
+
+        match dram.read_bytes(PAGE_SIZE - 3, 6).unwrap() {
+            Cow::Owned(bytes) => assert_eq!(bytes, [0, 1, 2, 3, 4, 0]),
+            Cow::Borrowed(_) => panic!("PANIC")
+        }
+
+        // Spans an allocated and a never written page.
+        let bytes = dram.read_bytes(PAGE_SIZE, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(bytes.len(), 2 * PAGE_SIZE);
+        assert_eq!(bytes[..3], [3, 4, 0]);
+        assert!(bytes[2..].iter().all(|b| *b == 0));
+
+        assert_eq_hex!(dram.read_int(PAGE_SIZE - 2, 4, Endianness::LittleEndian, false).unwrap(),
+                       0x04_03_02_01);
+        assert_eq_hex!(dram.read_int(PAGE_SIZE - 1, 2, Endianness::BigEndian, false).unwrap(),
+                       0x02_03);
+
+        dram.write_int(2 * PAGE_SIZE - 4, 0x0102030405060708, 8, Endianness::BigEndian).unwrap();
+        assert_eq!(dram.get_resident_pages(), 3);
+        assert_eq!(*dram.read_bytes(2 * PAGE_SIZE - 4, 8).unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
 }
@@ -0,0 +1,124 @@
+#[cfg(test)]
+pub mod test_elf {
+    use crate::device::{Device, DeviceError};
+    use crate::dram::DRAM;
+    use crate::elf::{Elf, ElfError, Segment};
+    use crate::endianness::Endianness;
+
+    const CODE_OFFSET: usize = 0x100;
+
+    /// Builds an executable with `code` loaded at `address`, followed by `bss_size`
+    /// zero bytes, and `symbols` as (name, address, size, type).
+    pub fn build_elf(address: u64, code: &[u32], bss_size: u64, symbols: &[(&str, u64, u64, u8)]) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut strings = vec![0u8];
+        let mut symbol_table = vec![0u8; 24];
+        for (name, address, size, kind) in symbols {
+            symbol_table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            // Global binding, defined in section 1.
+            symbol_table.extend_from_slice(&[0x10 | kind, 0, 1, 0]);
+            symbol_table.extend_from_slice(&address.to_le_bytes());
+            symbol_table.extend_from_slice(&size.to_le_bytes());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        let symbol_offset = CODE_OFFSET + code.len();
+        let string_offset = symbol_offset + symbol_table.len();
+        let section_offset = string_offset + strings.len();
+
+        let mut elf = vec![0u8; 64];
+        elf[0..8].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+        elf[16..18].copy_from_slice(&2u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&243u16.to_le_bytes());
+        elf[24..32].copy_from_slice(&address.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[40..48].copy_from_slice(&(section_offset as u64).to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+        elf[60..62].copy_from_slice(&3u16.to_le_bytes());
+
+        let mut program_header = vec![0u8; 56];
+        program_header[0..4].copy_from_slice(&1u32.to_le_bytes());
+        program_header[8..16].copy_from_slice(&(CODE_OFFSET as u64).to_le_bytes());
+        program_header[16..24].copy_from_slice(&address.to_le_bytes());
+        program_header[24..32].copy_from_slice(&address.to_le_bytes());
+        program_header[32..40].copy_from_slice(&(code.len() as u64).to_le_bytes());
+        program_header[40..48].copy_from_slice(&(code.len() as u64 + bss_size).to_le_bytes());
+        elf.extend_from_slice(&program_header);
+        elf.resize(CODE_OFFSET, 0);
+        elf.extend_from_slice(&code);
+        elf.extend_from_slice(&symbol_table);
+        elf.extend_from_slice(&strings);
+
+        // A null section, .symtab linked to .strtab, and .strtab.
+        let sections = [(0, 0, 0, 0), (2, symbol_offset, symbol_table.len(), 2), (3, string_offset, strings.len(), 0)];
+        for (kind, offset, size, link) in sections.iter() {
+            let mut header = vec![0u8; 64];
+            header[4..8].copy_from_slice(&(*kind as u32).to_le_bytes());
+            header[24..32].copy_from_slice(&(*offset as u64).to_le_bytes());
+            header[32..40].copy_from_slice(&(*size as u64).to_le_bytes());
+            header[40..44].copy_from_slice(&(*link as u32).to_le_bytes());
+            elf.extend_from_slice(&header);
+        }
+        elf
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = build_elf(0x1000, &[0x0000_0513, 0x0015_0513], 8, &[
+            ("main", 0x1004, 4, 2), ("_start", 0x1000, 0, 0), ("counter", 0x1008, 8, 1),
+            // Sections aren't symbols.
+            ("text", 0x1000, 0, 3),
+        ]);
+        let elf = Elf::parse(data).unwrap();
+        assert_eq!(elf.entry, 0x1000);
+        assert_eq!(elf.segments, vec![Segment { address: 0x1000, memory_size: 16, file_range: 0x100..0x108 }]);
+
+        assert_eq!(elf.symbols.find("counter").unwrap().address, 0x1008);
+        assert!(elf.symbols.find("text").is_none());
+        let (symbol, offset) = elf.symbols.lookup(0x100C).unwrap();
+        assert_eq!((symbol.name.as_str(), offset), ("counter", 4));
+        assert_eq!(elf.symbols.lookup(0x1004).unwrap().0.name, "main");
+        assert_eq!(elf.symbols.lookup(0x1000).unwrap().0.name, "_start");
+        // Symbols without a size only cover their address.
+        assert!(elf.symbols.lookup(0x1002).is_none());
+        assert!(elf.symbols.lookup(0x1010).is_none());
+
+        let mut dram = DRAM::new(0x2000);
+        dram.write_bytes(0x1008, &[0xFF; 16]).unwrap();
+        elf.load(&mut dram).unwrap();
+        assert_eq!(dram.read_int(0x1004, 4, Endianness::LittleEndian, false).unwrap(), 0x0015_0513);
+        assert_eq!(&dram.read_bytes(0x1008, 16).unwrap()[..], &[0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let mut small = DRAM::new(0x1000);
+        match elf.load(&mut small) {
+            Err(ElfError::DeviceError(DeviceError::InvalidAddressWriteFault)) => {},
+            x => { panic!("PANIC {:?}", x.err()) }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = build_elf(0x1000, &[0x0000_0513], 0, &[]);
+        match Elf::parse(b"#!/bin/sh\n".to_vec()) {
+            Err(ElfError::Truncated { offset: 0, size: 64 }) => {},
+            x => { panic!("PANIC {:?}", x.err()) }
+        }
+        let mut invalid = data.clone();
+        invalid[0] = 0;
+        match Elf::parse(invalid) {
+            Err(ElfError::InvalidMagic) => {},
+            x => { panic!("PANIC {:?}", x.err()) }
+        }
+        let mut rv32 = data.clone();
+        rv32[4] = 1;
+        match Elf::parse(rv32) {
+            Err(ElfError::UnsupportedFormat { class: 1, data: 1, machine: 243 }) => {},
+            x => { panic!("PANIC {:?}", x.err()) }
+        }
+        match Elf::parse(data[..0x102].to_vec()) {
+            Err(ElfError::Truncated { offset: 0x100, size: 4 }) => {},
+            x => { panic!("PANIC {:?}", x.err()) }
+        }
+        assert!(Elf::is_elf(&data));
+    }
+}
@@ -0,0 +1,183 @@
+#[cfg(test)]
+mod test_rv64a {
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::core::Core;
+    use crate::cpu::execute::InstructionExecuteError;
+    use crate::cpu::trap::Exception;
+
+    use crate::bus::{Bus, SharedBus};
+    use crate::dram::DRAM;
+    use crate::device::Device;
+    use crate::endianness::Endianness;
+        
+
+    fn new_test_core() -> Core {
+        let dram = DRAM::new(16);
+        Core::new(SharedBus::new(Bus::new(
+            vec![(0 , Box::new(dram))]
+        ).unwrap()))
+    }
+
+    type AmoConstructor = fn(XRegister, XRegister, XRegister) -> Instruction;
+
+    fn read(core: &Core, address: usize, size: usize) -> u64 {
+        core.bus.lock().read_int(address, size, Endianness::LittleEndian, false).unwrap()
+    }
+
+    fn write(core: &Core, address: usize, value: u64, size: usize) {
+        core.bus.lock().write_int(address, value, size, Endianness::LittleEndian).unwrap()
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        let mut core = new_test_core();
+        write(&core, 8, 0x8000_0001, 4);
+        core.x_registers[XRegister::x1] = 8;
+        core.x_registers[XRegister::x2] = 0x1234;
+
+        let lr = Instruction::lr_w { rd: XRegister::x3, rs1: XRegister::x1, rl: false, aq: false };
+        let sc = Instruction::sc_w {
+            rd: XRegister::x4, rs1: XRegister::x1, rs2: XRegister::x2, rl: false, aq: false
+        };
+
+        // No reservation.
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 1);
+        assert_eq_hex!(read(&core, 8, 4), 0x8000_0001);
+
+        lr.execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_8000_0001);
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 0);
+        assert_eq_hex!(read(&core, 8, 4), 0x1234);
+
+        // The reservation is released by the first sc.
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 1);
+
+        // Another store to the reserved location.
+        lr.execute(&mut core).unwrap();
+        write(&core, 8, 0x5678, 4);
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 1);
+        assert_eq_hex!(read(&core, 8, 4), 0x5678);
+
+        // A store restoring the value that was loaded.
+        lr.execute(&mut core).unwrap();
+        write(&core, 8, 0x1234, 4);
+        write(&core, 8, 0x5678, 4);
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 1);
+
+        // A store to the other word of the reserved granule.
+        lr.execute(&mut core).unwrap();
+        write(&core, 12, 0, 4);
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 1);
+
+        // A store outside the granule, and a reservation by another hart.
+        lr.execute(&mut core).unwrap();
+        write(&core, 0, 0, 8);
+        core.bus.lock().reserve(1, 8);
+        sc.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 0);
+        assert_eq_hex!(read(&core, 8, 4), 0x1234);
+
+        // A reservation for another address.
+        Instruction::lr_d { rd: XRegister::x3, rs1: XRegister::x0, rl: false, aq: false }
+            .execute(&mut core).unwrap();
+        Instruction::sc_d {
+            rd: XRegister::x4, rs1: XRegister::x1, rs2: XRegister::x2, rl: false, aq: false
+        }.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x4], 1);
+    }
+
+    #[test]
+    fn test_amo_w() {
+        let mut core = new_test_core();
+        core.x_registers[XRegister::x1] = 4;
+
+        let cases: [(AmoConstructor, u64, u64, u64); 9] = [
+            (|rd, rs1, rs2| Instruction::amoswap_w { rd, rs1, rs2, rl: false, aq: false },
+             5, 7, 7),
+            (|rd, rs1, rs2| Instruction::amoadd_w { rd, rs1, rs2, rl: false, aq: false },
+             0xFFFF_FFFF, 2, 1),
+            (|rd, rs1, rs2| Instruction::amoxor_w { rd, rs1, rs2, rl: false, aq: false },
+             0b1100, 0b1010, 0b0110),
+            (|rd, rs1, rs2| Instruction::amoand_w { rd, rs1, rs2, rl: false, aq: false },
+             0b1100, 0b1010, 0b1000),
+            (|rd, rs1, rs2| Instruction::amoor_w { rd, rs1, rs2, rl: false, aq: false },
+             0b1100, 0b1010, 0b1110),
+            (|rd, rs1, rs2| Instruction::amomin_w { rd, rs1, rs2, rl: false, aq: false },
+             0xFFFF_FFFF, 1, 0xFFFF_FFFF),
+            (|rd, rs1, rs2| Instruction::amomax_w { rd, rs1, rs2, rl: false, aq: false },
+             0xFFFF_FFFF, 0x1_0000_0001, 1),
+            (|rd, rs1, rs2| Instruction::amominu_w { rd, rs1, rs2, rl: false, aq: false },
+             0xFFFF_FFFF, 1, 1),
+            (|rd, rs1, rs2| Instruction::amomaxu_w { rd, rs1, rs2, rl: false, aq: false },
+             0x8000_0000, 0x7FFF_FFFF, 0x8000_0000),
+        ];
+
+        for (instruction, memory, operand, result) in cases {
+            write(&core, 4, memory, 4);
+            write(&core, 8, 0xAA, 4);
+            core.x_registers[XRegister::x2] = operand;
+            instruction(XRegister::x3, XRegister::x1, XRegister::x2).execute(&mut core).unwrap();
+            assert_eq_hex!(core.x_registers[XRegister::x3], memory as u32 as i32 as i64 as u64);
+            assert_eq_hex!(read(&core, 4, 4), result);
+            assert_eq_hex!(read(&core, 8, 4), 0xAA);
+        }
+    }
+
+    #[test]
+    fn test_amo_d() {
+        let mut core = new_test_core();
+        core.x_registers[XRegister::x1] = 8;
+
+        write(&core, 8, u64::MAX, 8);
+        core.x_registers[XRegister::x2] = 3;
+        Instruction::amoadd_d {
+            rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2, rl: false, aq: false
+        }.execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], u64::MAX);
+        assert_eq_hex!(read(&core, 8, 8), 2);
+
+        Instruction::amomin_d {
+            rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x4, rl: false, aq: false
+        }.execute(&mut core).unwrap();
+        assert_eq_hex!(read(&core, 8, 8), 0);
+
+        core.x_registers[XRegister::x4] = 1 << 63;
+        Instruction::amomaxu_d {
+            rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x4, rl: false, aq: false
+        }.execute(&mut core).unwrap();
+        assert_eq_hex!(read(&core, 8, 8), 1 << 63);
+        Instruction::amomax_d {
+            rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x0, rl: false, aq: false
+        }.execute(&mut core).unwrap();
+        assert_eq_hex!(read(&core, 8, 8), 0);
+    }
+
+    #[test]
+    fn test_misaligned() {
+        let mut core = new_test_core();
+        core.x_registers[XRegister::x1] = 4;
+
+        let amoswap = Instruction::amoswap_d {
+            rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2, rl: false, aq: false
+        };
+        match amoswap.execute(&mut core) {
+            Err(InstructionExecuteError::Exception {
+                exception: Exception::StoreAddressMisaligned, tval: 4 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        let lr = Instruction::lr_d { rd: XRegister::x3, rs1: XRegister::x1, rl: false, aq: false };
+        match lr.execute(&mut core) {
+            Err(InstructionExecuteError::Exception {
+                exception: Exception::LoadAddressMisaligned, tval: 4 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
@@ -8,18 +8,16 @@ mod test_rv64i {
     use crate::cpu::register::XRegister;
     use crate::cpu::core::Core;
 
-    use crate::bus::Bus;
+    use crate::bus::{Bus, SharedBus};
     use crate::dram::DRAM;
     use crate::device::Device;
-    use std::rc::Rc;
-    use std::cell::RefCell;
-
+        
 
     fn new_test_core() -> Core {
         let dram = DRAM::new(16);
-        Core::new(Rc::new(RefCell::new(Bus::new(
+        Core::new(SharedBus::new(Bus::new(
             vec![(0 , Box::new(dram))]
-        ))))
+        ).unwrap()))
     }
 
     #[test]
@@ -47,7 +45,7 @@ mod test_rv64i {
     fn test_lb() {
         let mut core = new_test_core();
 
-        core.bus.borrow_mut().write_bytes(0, &[
+        core.bus.lock().write_bytes(0, &[
             0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
             0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F]).unwrap();
 
@@ -161,5 +159,89 @@ mod test_rv64i {
         assert_eq!(core.pc, 44);
     }
 
+    #[test]
+    fn test_shifts() {
+        let mut core = new_test_core();
+
+        core.x_registers[XRegister::x1] = 0x8000_0000_0000_0010;
+        core.x_registers[XRegister::x2] = 68;
+        Instruction::sra { rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xF800_0000_0000_0001);
+
+        Instruction::srl { rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0x0800_0000_0000_0001);
+
+        Instruction::sll { rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0x100);
+
+        Instruction::srai { rd: XRegister::x3, rs1: XRegister::x1, shamt: 4 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xF800_0000_0000_0001);
+    }
+
+    #[test]
+    fn test_word_instructions() {
+        let mut core = new_test_core();
 
-}
\ No newline at end of file
+        core.x_registers[XRegister::x1] = 0x1234_5678_7FFF_FFFF;
+        Instruction::addiw { rd: XRegister::x3, rs1: XRegister::x1, imm: 1 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_8000_0000);
+
+        core.x_registers[XRegister::x2] = 0xFFFF_FFFF_0000_0001;
+        Instruction::addw { rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_8000_0000);
+        Instruction::subw { rd: XRegister::x3, rs1: XRegister::x2, rs2: XRegister::x1 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_8000_0002);
+
+        // Shift amounts only use the low 5 bits.
+        core.x_registers[XRegister::x2] = 33;
+        Instruction::sllw { rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_FFFF_FFFE);
+        Instruction::srlw { rd: XRegister::x3, rs1: XRegister::x3, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0x7FFF_FFFF);
+
+        core.x_registers[XRegister::x1] = 0x8000_0010;
+        Instruction::sraw { rd: XRegister::x3, rs1: XRegister::x1, rs2: XRegister::x2 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_C000_0008);
+        Instruction::sraiw { rd: XRegister::x3, rs1: XRegister::x1, shamt: 4 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0xFFFF_FFFF_F800_0001);
+        Instruction::srliw { rd: XRegister::x3, rs1: XRegister::x1, shamt: 4 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0x0800_0001);
+        Instruction::slliw { rd: XRegister::x3, rs1: XRegister::x1, shamt: 1 }
+            .execute(&mut core).unwrap();
+        assert_eq_hex!(core.x_registers[XRegister::x3], 0x20);
+    }
+
+    #[test]
+    fn test_jalr() {
+        let mut core = new_test_core();
+        core.pc = 0x40;
+        core.x_registers[XRegister::x1] = 0x101;
+
+        Instruction::jalr {
+            rd: XRegister::x1,
+            rs1: XRegister::x1,
+            imm: 0x10,
+        }.execute(&mut core).unwrap();
+        assert_eq!(core.pc, 0x110);
+        assert_eq!(core.x_registers[XRegister::x1], 0x44);
+
+        Instruction::jalr {
+            rd: XRegister::x0,
+            rs1: XRegister::x1,
+            imm: -4,
+        }.execute(&mut core).unwrap();
+        assert_eq!(core.pc, 0x40);
+    }
+}
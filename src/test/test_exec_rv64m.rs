@@ -0,0 +1,105 @@
+#![cfg(test)]
+
+use crate::cpu::instruction::Instruction;
+use crate::cpu::register::XRegister;
+use crate::cpu::core::Core;
+
+use crate::bus::{Bus, SharedBus};
+use crate::dram::DRAM;
+
+type RConstructor = fn(XRegister, XRegister, XRegister) -> Instruction;
+
+fn new_test_core() -> Core {
+    let dram = DRAM::new(16);
+    Core::new(SharedBus::new(Bus::new(
+        vec![(0 , Box::new(dram))]
+    ).unwrap()))
+}
+
+/// Runs `instruction` on each (rs1, rs2, rd) case.
+fn check(instruction: RConstructor, cases: &[(u64, u64, u64)]) {
+    let mut core = new_test_core();
+    let instruction = instruction(XRegister::x3, XRegister::x1, XRegister::x2);
+    for (rs1, rs2, rd) in cases {
+        core.x_registers[XRegister::x1] = *rs1;
+        core.x_registers[XRegister::x2] = *rs2;
+        instruction.execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x3], *rd, "{} with {:#x}, {:#x}", instruction, rs1, rs2);
+    }
+}
+
+const MIN: u64 = 1 << 63;
+const MIN_WORD: u64 = 0xFFFF_FFFF_8000_0000;
+
+#[test]
+fn test_mul() {
+    check(|rd, rs1, rs2| Instruction::mul { rd, rs1, rs2 }, &[
+        (7, 6, 42),
+        (u64::MAX, 3, u64::MAX - 2),
+        (MIN, 2, 0),
+    ]);
+    check(|rd, rs1, rs2| Instruction::mulh { rd, rs1, rs2 }, &[
+        (u64::MAX, u64::MAX, 0),
+        (u64::MAX, 2, u64::MAX),
+        (MIN, MIN, 1 << 62),
+    ]);
+    check(|rd, rs1, rs2| Instruction::mulhsu { rd, rs1, rs2 }, &[
+        (u64::MAX, u64::MAX, u64::MAX),
+        (2, u64::MAX, 1),
+    ]);
+    check(|rd, rs1, rs2| Instruction::mulhu { rd, rs1, rs2 }, &[
+        (u64::MAX, u64::MAX, u64::MAX - 1),
+        (1 << 32, 1 << 32, 1),
+    ]);
+    check(|rd, rs1, rs2| Instruction::mulw { rd, rs1, rs2 }, &[
+        (0x1_0000_0003, 5, 15),
+        (0x4000_0000, 2, MIN_WORD),
+    ]);
+}
+
+#[test]
+fn test_div() {
+    check(|rd, rs1, rs2| Instruction::div { rd, rs1, rs2 }, &[
+        (20, 6, 3),
+        (-20i64 as u64, 6, -3i64 as u64),
+        (20, 0, u64::MAX),
+        (MIN, u64::MAX, MIN),
+    ]);
+    check(|rd, rs1, rs2| Instruction::divu { rd, rs1, rs2 }, &[
+        (u64::MAX, 2, u64::MAX >> 1),
+        (20, 0, u64::MAX),
+    ]);
+    check(|rd, rs1, rs2| Instruction::divw { rd, rs1, rs2 }, &[
+        (0xFFFF_FFEC, 6, -3i64 as u64),
+        (0x1_0000_0014, 0, u64::MAX),
+        (0x8000_0000, 0xFFFF_FFFF, MIN_WORD),
+    ]);
+    check(|rd, rs1, rs2| Instruction::divuw { rd, rs1, rs2 }, &[
+        (0xFFFF_FFFE, 1, 0xFFFF_FFFF_FFFF_FFFE),
+        (0x1_0000_0014, 0x1_0000_0006, 3),
+        (20, 0, u64::MAX),
+    ]);
+}
+
+#[test]
+fn test_rem() {
+    check(|rd, rs1, rs2| Instruction::rem { rd, rs1, rs2 }, &[
+        (20, 6, 2),
+        (-20i64 as u64, 6, -2i64 as u64),
+        (20, 0, 20),
+        (MIN, u64::MAX, 0),
+    ]);
+    check(|rd, rs1, rs2| Instruction::remu { rd, rs1, rs2 }, &[
+        (u64::MAX, 10, 5),
+        (20, 0, 20),
+    ]);
+    check(|rd, rs1, rs2| Instruction::remw { rd, rs1, rs2 }, &[
+        (0xFFFF_FFEC, 6, -2i64 as u64),
+        (0x1_8000_0000, 0, MIN_WORD),
+        (0x8000_0000, 0xFFFF_FFFF, 0),
+    ]);
+    check(|rd, rs1, rs2| Instruction::remuw { rd, rs1, rs2 }, &[
+        (0xFFFF_FFFF, 0x10, 0xF),
+        (0x1_8000_0000, 0, MIN_WORD),
+    ]);
+}
@@ -0,0 +1,151 @@
+#[cfg(test)]
+mod test_zicsr {
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::core::{Core, Privilege};
+    use crate::cpu::csr::{COUNTEREN_TM, MCOUNTEREN, MHARTID, MIDELEG, MIE, MIP_SSIP, MIP_STIP, MIP_MTIP,
+                          MSTATUS, MSTATUS_MIE, MSTATUS_MPP, MSTATUS_SIE, MSTATUS_SUM};
+    use crate::cpu::execute::InstructionExecuteError;
+
+    use crate::bus::{Bus, SharedBus};
+    use crate::dram::DRAM;
+        
+    const MSCRATCH: i64 = 0x340;
+
+    fn new_test_core() -> Core {
+        let dram = DRAM::new(16);
+        Core::new(SharedBus::new(Bus::new(
+            vec![(0 , Box::new(dram))]
+        ).unwrap()))
+    }
+
+    #[test]
+    fn test_csrrw_csrrs_csrrc() {
+        let mut core = new_test_core();
+
+        core.x_registers[XRegister::x1] = 0b1100;
+        Instruction::csrrw { rd: XRegister::x2, rs1: XRegister::x1, imm: MSCRATCH }
+            .execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x2], 0);
+        assert_eq!(core.csr_registers[MSCRATCH as u16], 0b1100);
+
+        core.x_registers[XRegister::x1] = 0b0011;
+        Instruction::csrrs { rd: XRegister::x2, rs1: XRegister::x1, imm: MSCRATCH }
+            .execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x2], 0b1100);
+        assert_eq!(core.csr_registers[MSCRATCH as u16], 0b1111);
+
+        core.x_registers[XRegister::x1] = 0b0101;
+        Instruction::csrrc { rd: XRegister::x2, rs1: XRegister::x1, imm: MSCRATCH }
+            .execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x2], 0b1111);
+        assert_eq!(core.csr_registers[MSCRATCH as u16], 0b1010);
+
+        Instruction::csrrwi { rd: XRegister::x2, uimm: 0b10001, imm: MSCRATCH }
+            .execute(&mut core).unwrap();
+        Instruction::csrrsi { rd: XRegister::x2, uimm: 0b00110, imm: MSCRATCH }
+            .execute(&mut core).unwrap();
+        Instruction::csrrci { rd: XRegister::x2, uimm: 0b00011, imm: MSCRATCH }
+            .execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x2], 0b10111);
+        assert_eq!(core.csr_registers[MSCRATCH as u16], 0b10100);
+        assert_eq!(core.pc, 24);
+    }
+
+    #[test]
+    fn test_read_only_csr() {
+        let mut core = new_test_core();
+        core.csr_registers[MHARTID] = 3;
+
+        // The decoder sign extends the CSR address.
+        let mhartid = MHARTID as i64 - 0x1000;
+
+        Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x0, imm: mhartid }
+            .execute(&mut core).unwrap();
+        assert_eq!(core.x_registers[XRegister::x1], 3);
+
+        match (Instruction::csrrw { rd: XRegister::x1, rs1: XRegister::x0, imm: mhartid })
+                .execute(&mut core) {
+            Err(InstructionExecuteError::IllegalInstruction(_)) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match (Instruction::csrrsi { rd: XRegister::x1, uimm: 1, imm: mhartid })
+                .execute(&mut core) {
+            Err(InstructionExecuteError::IllegalInstruction(_)) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(core.csr_registers[MHARTID], 3);
+    }
+
+    #[test]
+    fn test_hart_id_survives_reset() {
+        let mut core = new_test_core();
+        core.csr_registers[MHARTID] = 2;
+        core.csr_registers[MSCRATCH as u16] = 1;
+        core.reset();
+        assert_eq!(core.get_hart_id(), 2);
+        assert_eq!(core.csr_registers[MSCRATCH as u16], 0);
+    }
+
+    fn csr_instruction(core: &mut Core, instruction: Instruction) -> Result<u64, InstructionExecuteError> {
+        instruction.execute(core)?;
+        Ok(core.x_registers[XRegister::x1])
+    }
+
+    #[test]
+    fn test_csr_privilege() {
+        let mut core = new_test_core();
+        let read = |imm: i64| Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x0, imm };
+        let sscratch = 0x140;
+        let time = 0xC01 - 0x1000;
+
+        core.privilege = Privilege::Supervisor;
+        assert!(csr_instruction(&mut core, read(sscratch)).is_ok());
+        match csr_instruction(&mut core, read(MSCRATCH)) {
+            Err(InstructionExecuteError::IllegalInstruction(_)) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        // Counters need to be enabled for the lower privilege levels.
+        match csr_instruction(&mut core, read(time)) {
+            Err(InstructionExecuteError::IllegalInstruction(_)) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        core.csr_registers[MCOUNTEREN] = COUNTEREN_TM;
+        assert!(csr_instruction(&mut core, read(time)).is_ok());
+        core.privilege = Privilege::User;
+        assert!(csr_instruction(&mut core, read(time)).is_err());
+        assert!(csr_instruction(&mut core, read(sscratch)).is_err());
+    }
+
+    #[test]
+    fn test_supervisor_views() {
+        let mut core = new_test_core();
+        let sstatus = 0x100;
+        let sie = 0x104;
+        let sip = 0x144;
+        core.csr_registers[MSTATUS] = MSTATUS_MIE | MSTATUS_MPP;
+        core.csr_registers[MIDELEG] = MIP_SSIP;
+
+        // `sstatus` only shows and changes the supervisor fields of `mstatus`.
+        core.x_registers[XRegister::x2] = u64::MAX;
+        assert_eq!(csr_instruction(&mut core,
+            Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x2, imm: sstatus }).unwrap(), 0);
+        assert_eq!(core.csr_registers[MSTATUS] & (MSTATUS_MIE | MSTATUS_MPP | MSTATUS_SIE | MSTATUS_SUM),
+                   MSTATUS_MIE | MSTATUS_MPP | MSTATUS_SIE | MSTATUS_SUM);
+        assert_eq!(csr_instruction(&mut core,
+            Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x0, imm: sstatus }).unwrap() & MSTATUS_MIE, 0);
+
+        // `sie` and `sip` only show the interrupts delegated to S-mode.
+        csr_instruction(&mut core, Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x2, imm: sie }).unwrap();
+        assert_eq!(core.csr_registers[MIE], MIP_SSIP);
+        core.csr_registers[MIE] |= MIP_MTIP | MIP_STIP;
+        assert_eq!(csr_instruction(&mut core,
+            Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x0, imm: sie }).unwrap(), MIP_SSIP);
+        csr_instruction(&mut core, Instruction::csrrs { rd: XRegister::x1, rs1: XRegister::x2, imm: sip }).unwrap();
+        assert_eq!(csr_instruction(&mut core,
+            Instruction::csrrc { rd: XRegister::x1, rs1: XRegister::x2, imm: sip }).unwrap(), MIP_SSIP);
+        assert_eq!(core.csr_registers[0x344], 0);
+    }
+}
@@ -0,0 +1,132 @@
+#[cfg(test)]
+pub(crate) mod test_fdt {
+    use crate::fdt::{FdtWriter, FdtError};
+    use std::convert::TryInto;
+
+    fn be_u32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Walks the structure block and returns every property as (node path, name, value).
+    pub fn parse(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        assert_eq!(be_u32(blob, 0), 0xd00dfeed);
+        assert_eq!(be_u32(blob, 4) as usize, blob.len());
+        let off_dt_struct = be_u32(blob, 8) as usize;
+        let off_dt_strings = be_u32(blob, 12) as usize;
+
+        let mut path: Vec<String> = Vec::new();
+        let mut properties = Vec::new();
+        let mut offset = off_dt_struct;
+        loop {
+            let token = be_u32(blob, offset);
+            offset += 4;
+            match token {
+                0x1 => {
+                    let end = offset + blob[offset..].iter().position(|b| *b == 0).unwrap();
+                    path.push(String::from_utf8(blob[offset..end].to_vec()).unwrap());
+                    offset = (end + 4) & !0x3;
+                },
+                0x2 => { path.pop(); },
+                0x3 => {
+                    let length = be_u32(blob, offset) as usize;
+                    let name_offset = off_dt_strings + be_u32(blob, offset + 4) as usize;
+                    let name_end = name_offset
+                        + blob[name_offset..].iter().position(|b| *b == 0).unwrap();
+                    let name = String::from_utf8(blob[name_offset..name_end].to_vec()).unwrap();
+                    offset += 8;
+                    properties.push((path.join("/"), name, blob[offset..offset + length].to_vec()));
+                    offset = (offset + length + 3) & !0x3;
+                },
+                0x9 => break,
+                token => panic!("PANIC unknown token {}", token)
+            }
+        }
+        assert!(path.is_empty());
+        properties
+    }
+
+    pub fn get_property<'a>(properties: &'a [(String, String, Vec<u8>)], path: &str, name: &str)
+            -> Option<&'a [u8]> {
+        properties.iter()
+            .find(|(p, n, _)| p == path && n == name)
+            .map(|(_, _, value)| value.as_slice())
+    }
+
+    #[test]
+    fn test_minimal_blob() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("").unwrap();
+        fdt.property_u32("a", 0x01020304).unwrap();
+        fdt.end_node().unwrap();
+        let blob = fdt.finish().unwrap();
+
+        assert_eq!(blob, vec![
+            // Header
+            0xd0, 0x0d, 0xfe, 0xed,  0x00, 0x00, 0x00, 0x5a,
+            0x00, 0x00, 0x00, 0x38,  0x00, 0x00, 0x00, 0x58,
+            0x00, 0x00, 0x00, 0x28,  0x00, 0x00, 0x00, 0x11,
+            0x00, 0x00, 0x00, 0x10,  0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x02,  0x00, 0x00, 0x00, 0x20,
+            // Memory reservation block
+            0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00,
+            // Structure block
+            0x00, 0x00, 0x00, 0x01,  0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x03,  0x00, 0x00, 0x00, 0x04,
+            0x00, 0x00, 0x00, 0x00,  0x01, 0x02, 0x03, 0x04,
+            0x00, 0x00, 0x00, 0x02,  0x00, 0x00, 0x00, 0x09,
+            // Strings block
+            0x61, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn test_properties() {
+        let mut fdt = FdtWriter::new();
+        fdt.add_memory_reservation(0x8000_0000, 0x1000);
+        fdt.begin_node("").unwrap();
+        fdt.property_string("compatible", "yarve").unwrap();
+        fdt.begin_node("node@10").unwrap();
+        fdt.property_string_list("compatible", &["a", "bc"]).unwrap();
+        fdt.property_reg("reg", &[(0x10, 0x20)]).unwrap();
+        fdt.property_cells("cells", &[1, 2]).unwrap();
+        fdt.property_u64("u64", 0x0102030405060708).unwrap();
+        fdt.property_null("empty").unwrap();
+        fdt.end_node().unwrap();
+        fdt.end_node().unwrap();
+        let blob = fdt.finish().unwrap();
+
+        assert_eq!(be_u32(&blob, 16), 40);
+        assert_eq!(&blob[40..56], &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0]);
+        assert_eq!(&blob[56..72], &[0; 16]);
+
+        let properties = parse(&blob);
+        assert_eq!(get_property(&properties, "", "compatible").unwrap(), b"yarve\0");
+        assert_eq!(get_property(&properties, "/node@10", "compatible").unwrap(), b"a\0bc\0");
+        assert_eq!(get_property(&properties, "/node@10", "reg").unwrap(),
+                   &[0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0x20]);
+        assert_eq!(get_property(&properties, "/node@10", "cells").unwrap(),
+                   &[0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(get_property(&properties, "/node@10", "u64").unwrap(),
+                   &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(get_property(&properties, "/node@10", "empty").unwrap(), &[]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut fdt = FdtWriter::new();
+        assert_eq!(fdt.property_u32("a", 0),
+                   Err(FdtError::PropertyOutsideNode { name: "a".to_string() }));
+        assert_eq!(fdt.end_node(), Err(FdtError::UnexpectedEndNode));
+
+        fdt.begin_node("").unwrap();
+        fdt.begin_node("child").unwrap();
+        fdt.end_node().unwrap();
+        assert_eq!(FdtWriter::finish(fdt), Err(FdtError::UnclosedNodes { depth: 1 }));
+
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("").unwrap();
+        fdt.end_node().unwrap();
+        assert_eq!(fdt.begin_node(""), Err(FdtError::MultipleRootNodes));
+    }
+}
@@ -0,0 +1,98 @@
+#[cfg(test)]
+mod test_flash {
+    use crate::flash::{Flash, FlashMode};
+    use crate::bus::Bus;
+    use crate::device::{Device, DeviceError};
+    use crate::endianness::Endianness;
+    use std::io::Write;
+    use std::fs;
+    use tempfile::NamedTempFile;
+
+    fn new_image() -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&[
+            0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+            0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F]).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_read_only() {
+        let image = new_image();
+        let mut flash = Flash::open(image.path(), FlashMode::ReadOnly).unwrap();
+
+        assert_eq!(flash.get_address_space_size(), 16);
+        assert_eq!(*flash.read_bytes(14, 2).unwrap(), [0x1E, 0x1F]);
+        assert_eq_hex!(flash.read_int(4, 4, Endianness::LittleEndian, false).unwrap(),
+                       0x17_16_15_14);
+
+        match flash.write_bytes(3, &[0]) {
+            Err(DeviceError::ReadOnlyWriteFault { address: 3 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match flash.write_int(8, 0, 8, Endianness::LittleEndian) {
+            Err(DeviceError::ReadOnlyWriteFault { address: 8 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match flash.read_bytes(15, 2) {
+            Err(DeviceError::InvalidAddressReadFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match flash.read_bytes(usize::MAX, 2) {
+            Err(DeviceError::InvalidAddressReadFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let image = new_image();
+        let mut flash = Flash::open(image.path(), FlashMode::CopyOnWrite).unwrap();
+
+        flash.write_int(2, 0x2423, 2, Endianness::LittleEndian).unwrap();
+        assert_eq!(*flash.read_bytes(1, 4).unwrap(), [0x11, 0x23, 0x24, 0x14]);
+        flash.flush().unwrap();
+        drop(flash);
+
+        assert_eq!(fs::read(image.path()).unwrap()[1..5], [0x11, 0x12, 0x13, 0x14]);
+
+        let mut flash = Flash::open(image.path(), FlashMode::CopyOnWrite).unwrap();
+        match flash.write_bytes(usize::MAX, &[0, 0]) {
+            Err(DeviceError::InvalidAddressWriteFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_write_through() {
+        let image = new_image();
+        let mut flash = Flash::open(image.path(), FlashMode::WriteThrough).unwrap();
+
+        flash.write_int(2, 0x2324, 2, Endianness::BigEndian).unwrap();
+        assert_eq!(*flash.read_bytes(1, 4).unwrap(), [0x11, 0x23, 0x24, 0x14]);
+        flash.flush().unwrap();
+        drop(flash);
+
+        assert_eq!(fs::read(image.path()).unwrap()[1..5], [0x11, 0x23, 0x24, 0x14]);
+
+        match flash_on_bus(&image).write_bytes(0x1000_000F, &[0, 0]) {
+            Err(DeviceError::StraddlingAccessFault { .. }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    fn flash_on_bus(image: &NamedTempFile) -> Bus {
+        Bus::new(vec![
+            (0x1000_0000, Box::new(Flash::open(image.path(), FlashMode::WriteThrough).unwrap()))
+        ]).unwrap()
+    }
+
+    #[test]
+    fn test_missing_file() {
+        assert!(Flash::open("/nonexistent/flash.img", FlashMode::ReadOnly).is_err());
+    }
+}
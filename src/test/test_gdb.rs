@@ -0,0 +1,194 @@
+#[cfg(all(test, unix))]
+mod test_gdb {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::register::{FRegister, XRegister};
+    use crate::cpu::trap::StopReason;
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::finisher::TestFinisher;
+    use crate::gdb::GdbServer;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const PROGRAM: [u32; 5] = [
+        0x0000_0093,  // addi x1, x0, 0
+        0x0010_8093,  // addi x1, x1, 1
+        0x1010_3023,  // sd   x1, 0x100(x0)
+        0x1040_3103,  // ld   x2, 0x104(x0)
+        0xFF5F_F06F,  // jal  x0, -12
+    ];
+
+    struct Client {
+        stream: UnixStream,
+        no_ack: bool,
+    }
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, data: &str) {
+            let checksum = data.bytes().fold(0u8, |sum, x| sum.wrapping_add(x));
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+        }
+
+        fn read_packet(&mut self) -> String {
+            while self.read_byte() != b'$' {}
+            let mut packet = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => packet.push(byte)
+                }
+            }
+            let checksum = [self.read_byte(), self.read_byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+            assert_eq!(checksum, packet.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)));
+            if !self.no_ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(packet).unwrap()
+        }
+
+        /// Sends `data` and returns the reply.
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            if !self.no_ack {
+                assert_eq!(self.read_byte(), b'+');
+            }
+            self.read_packet()
+        }
+    }
+
+    fn new_test_cpu() -> CPU {
+        let mut dram = DRAM::new(0x1000);
+        for (i, instruction) in PROGRAM.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        let mut cpu = CPU::with_harts(vec![
+            (0, Box::new(dram)),
+            (0x10_0000, Box::new(TestFinisher::new())),
+        ], 2).unwrap();
+        cpu.set_quantum(3);
+        cpu
+    }
+
+    /// Runs a session with `client` against a server for `cpu`.
+    fn with_server(cpu: &mut CPU, client: impl FnOnce(&mut Client) + Send) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        thread::scope(|scope| {
+            let server = scope.spawn(|| GdbServer::new(cpu, server_stream).serve());
+            client(&mut Client { stream: client_stream, no_ack: false });
+            server.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut cpu = new_test_cpu();
+        with_server(&mut cpu, |client| {
+            assert!(client.request("qSupported:swbreak+;hwbreak+").contains("qXfer:features:read+"));
+            let description = client.request("qXfer:features:read:target.xml:0,3fff");
+            assert!(description.starts_with('l'));
+            assert!(description.contains("org.gnu.gdb.riscv.cpu"));
+            assert!(description.contains("<reg name=\"mstatus\" bitsize=\"64\" type=\"int\" regnum=\"833\"/>"));
+            assert!(client.request("qXfer:features:read:target.xml:0,10").starts_with('m'));
+
+            assert_eq!(client.request("qfThreadInfo"), "m1,2");
+            assert_eq!(client.request("qsThreadInfo"), "l");
+            assert_eq!(client.request("qC"), "QC1");
+            assert_eq!(client.request("?"), "T05thread:1;");
+            assert_eq!(client.request("T2"), "OK");
+            assert_eq!(client.request("T3"), "E01");
+            assert_eq!(client.request("qUnknown"), "");
+
+            // A packet with a bad checksum is rejected.
+            client.stream.write_all(b"$qC#00").unwrap();
+            assert_eq!(client.read_byte(), b'-');
+
+            assert_eq!(client.request("QStartNoAckMode"), "OK");
+            client.no_ack = true;
+            assert_eq!(client.request("qC"), "QC1");
+            assert_eq!(client.request("D"), "OK");
+        });
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = new_test_cpu();
+        cpu.harts[1].pc = 0x10;
+        with_server(&mut cpu, |client| {
+            assert_eq!(client.request("g").len(), 33 * 16);
+            assert_eq!(client.request("Hg2"), "OK");
+            assert_eq!(client.request("P5=2a00000000000000"), "OK");
+            assert_eq!(client.request("p5"), "2a00000000000000");
+            assert_eq!(client.request("p20"), "1000000000000000");
+            assert_eq!(client.request("P0=0100000000000000"), "OK");
+            assert_eq!(client.request("p0"), "0000000000000000");
+            // f1, mstatus and fflags.
+            assert_eq!(client.request("P22=000000000000f03f"), "OK");
+            assert_eq!(client.request("p341"), "0000000000000000");
+            assert_eq!(client.request("p42"), "00000000");
+            assert_eq!(client.request("p1066"), "E01");
+
+            assert_eq!(client.request("M200,4:78563412"), "OK");
+            assert_eq!(client.request("m200,4"), "78563412");
+            assert_eq!(client.request("m0,4"), "93000000");
+            // Reads stop at the end of memory.
+            assert_eq!(client.request("mffe,4"), "0000");
+            assert_eq!(client.request("m50000000,4"), "E01");
+            assert_eq!(client.request("M50000000,1:00"), "E01");
+            client.send("k");
+            assert_eq!(client.read_byte(), b'+');
+        });
+        assert_eq!(cpu.harts[1].x_registers[XRegister::x5], 42);
+        assert_eq!(cpu.harts[1].f_registers[FRegister::f1], 1.0);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x5], 0);
+    }
+
+    #[test]
+    fn test_execution() {
+        let mut cpu = new_test_cpu();
+        with_server(&mut cpu, |client| {
+            assert_eq!(client.request("Z0,8,4"), "OK");
+            assert_eq!(client.request("c"), "T05thread:1;swbreak:;");
+            assert_eq!(client.request("p20"), "0800000000000000");
+            // Hart 0 uses up its quantum with the store, then hart 1 stops.
+            assert_eq!(client.request("c"), "T05thread:2;swbreak:;");
+            assert_eq!(client.request("z0,8,4"), "OK");
+
+            assert_eq!(client.request("Z1,c,4"), "OK");
+            assert!(client.request("c").ends_with(";hwbreak:;"));
+            assert_eq!(client.request("z1,c,4"), "OK");
+
+            assert_eq!(client.request("Z2,104,4"), "OK");
+            assert!(client.request("c").ends_with(";watch:104;"));
+            assert_eq!(client.request("z2,104,4"), "OK");
+
+            assert_eq!(client.request("vCont?"), "vCont;c;C;s;S");
+            assert_eq!(client.request("vCont;s:1"), "T05thread:1;");
+            let pc = client.request("p20");
+            assert_eq!(client.request("Hc1"), "OK");
+            assert_eq!(client.request("s"), "T05thread:1;");
+            assert_ne!(client.request("p20"), pc);
+
+            // Interrupt a continue.
+            client.send("c");
+            assert_eq!(client.read_byte(), b'+');
+            client.stream.write_all(&[0x03]).unwrap();
+            assert!(client.read_packet().starts_with("T02thread:"));
+
+            assert_eq!(client.request("M100000,4:55550000"), "OK");
+            assert_eq!(client.request("c"), "W00");
+            assert_eq!(client.request("?"), "W00");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        // Breakpoints are gone after the session.
+        assert_eq!(cpu.run(100).unwrap().1, StopReason::BudgetExhausted);
+    }
+}
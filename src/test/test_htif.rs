@@ -0,0 +1,139 @@
+#[cfg(test)]
+mod test_htif {
+    use crate::elf::Elf;
+    use crate::htif::{write_signature, Htif, HtifError};
+    use crate::isa_tests::{run_test, run_tests, TestOutcome, TestResult, DEFAULT_MAX_INSTRUCTIONS};
+    use crate::test::test_elf::test_elf::build_elf;
+    use crate::cpu::trap::StopReason;
+    use crate::cpu::cpu::CPU;
+    use crate::dram::DRAM;
+    use std::path::Path;
+
+    const ADDRESS: u64 = 0x8000_0000;
+
+    // Writes 1 to tohost, passing.
+    const PASS: [u32; 4] = [
+        0x0000_0297,  // auipc x5, 0
+        0x0010_0513,  // addi  x10, x0, 1
+        0x40A2_B023,  // sd    x10, 0x400(x5)
+        0x0000_006F,  // jal   x0, 0
+    ];
+
+    // Writes 3 << 1 | 1 to tohost, failing case 3.
+    const FAIL: [u32; 4] = [0x0000_0297, 0x0070_0513, 0x40A2_B023, 0x0000_006F];
+
+    // Calls write(1, "hi\n", 3) through the syscall proxy and prints '!' on the
+    // console, storing the result and the reply in the signature, then passes.
+    const SYSCALL: [u32; 26] = [
+        0x0000_0297,  // auipc x5, 0
+        0x0400_0513,  // addi  x10, x0, 64
+        0x48A2_B023,  // sd    x10, 0x480(x5)
+        0x0010_0513,  // addi  x10, x0, 1
+        0x48A2_B423,  // sd    x10, 0x488(x5)
+        0x5002_8513,  // addi  x10, x5, 0x500
+        0x48A2_B823,  // sd    x10, 0x490(x5)
+        0x0030_0513,  // addi  x10, x0, 3
+        0x48A2_BC23,  // sd    x10, 0x498(x5)
+        0x4802_8513,  // addi  x10, x5, 0x480
+        0x40A2_B023,  // sd    x10, 0x400(x5)
+        0x4402_B583,  // ld    x11, 0x440(x5)
+        0xFE05_8EE3,  // beq   x11, x0, -4
+        0x4402_B023,  // sd    x0, 0x440(x5)
+        0x4802_B603,  // ld    x12, 0x480(x5)
+        0x60C2_B023,  // sd    x12, 0x600(x5)
+        0x1010_0513,  // addi  x10, x0, 0x101
+        0x0305_1513,  // slli  x10, x10, 48
+        0x0215_0513,  // addi  x10, x10, 0x21
+        0x40A2_B023,  // sd    x10, 0x400(x5)
+        0x4402_B583,  // ld    x11, 0x440(x5)
+        0xFE05_8EE3,  // beq   x11, x0, -4
+        0x60B2_B423,  // sd    x11, 0x608(x5)
+        0x0010_0513,  // addi  x10, x0, 1
+        0x40A2_B023,  // sd    x10, 0x400(x5)
+        0x0000_006F,  // jal   x0, 0
+    ];
+
+    /// Builds a test with `code` at the start of DRAM, "hi\n" at offset 0x500, and
+    /// the symbols of the test environments.
+    fn build_test(code: &[u32]) -> Vec<u8> {
+        let mut words = vec![0; 0x610 / 4];
+        words[..code.len()].copy_from_slice(code);
+        words[0x500 / 4] = 0x000A_6968;
+        build_elf(ADDRESS, &words, 0, &[
+            ("tohost", ADDRESS + 0x400, 8, 1), ("fromhost", ADDRESS + 0x440, 8, 1),
+            ("begin_signature", ADDRESS + 0x600, 0, 0), ("end_signature", ADDRESS + 0x610, 0, 0),
+        ])
+    }
+
+    #[test]
+    fn test_exit() {
+        let elf = Elf::parse(build_test(&PASS)).unwrap();
+        assert_eq!(run_test(&elf, 100_000).unwrap(), TestOutcome::Pass);
+        let elf = Elf::parse(build_test(&FAIL)).unwrap();
+        assert_eq!(run_test(&elf, 100_000).unwrap(), TestOutcome::Fail { test: 3 });
+        // Never writes to tohost.
+        let elf = Elf::parse(build_test(&[0x0000_006F])).unwrap();
+        assert_eq!(run_test(&elf, 100_000).unwrap(), TestOutcome::Timeout);
+
+        let elf = Elf::parse(build_elf(ADDRESS, &PASS, 0, &[])).unwrap();
+        match run_test(&elf, 100_000) {
+            Err(HtifError::MissingSymbol { name }) if name == "tohost" => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_syscalls() {
+        let elf = Elf::parse(build_test(&SYSCALL)).unwrap();
+        let mut cpu = CPU::new(vec![(ADDRESS as usize, Box::new(DRAM::new(0x1000)))]).unwrap();
+        elf.load(&mut *cpu.bus.lock()).unwrap();
+        cpu.harts[0].pc = elf.entry;
+        let mut htif = Htif::from_symbols(&elf.symbols).unwrap();
+        assert_eq!(htif.run(&mut cpu, 100_000).unwrap(), StopReason::Exit { code: 0 });
+        assert_eq!(htif.take_output(), b"hi\n!");
+        assert!(htif.take_output().is_empty());
+
+        let mut signature = Vec::new();
+        write_signature(&*cpu.bus.lock(), &elf.symbols, 4, &mut signature).unwrap();
+        assert_eq!(String::from_utf8(signature).unwrap(), "00000003\n00000000\n00000001\n01010000\n");
+        let mut signature = Vec::new();
+        write_signature(&*cpu.bus.lock(), &elf.symbols, 8, &mut signature).unwrap();
+        assert_eq!(String::from_utf8(signature).unwrap(), "0000000000000003\n0101000000000001\n");
+    }
+
+    #[test]
+    fn test_runner() {
+        let directory = std::env::temp_dir().join(format!("yarve-isa-tests-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("rv64ui-p-pass"), build_test(&PASS)).unwrap();
+        std::fs::write(directory.join("rv64ui-p-fail"), build_test(&FAIL)).unwrap();
+        std::fs::write(directory.join("rv64ui-p-pass.dump"), "not a test").unwrap();
+        std::fs::write(directory.join("rv64um-p-truncated"), &build_test(&PASS)[..0x80]).unwrap();
+        let results = run_tests(&directory, 100_000);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let results = results.unwrap();
+        assert_eq!(results[0], TestResult { name: "rv64ui-p-fail".to_string(), outcome: TestOutcome::Fail { test: 3 } });
+        assert_eq!(results[1], TestResult { name: "rv64ui-p-pass".to_string(), outcome: TestOutcome::Pass });
+        assert_eq!(results[2].name, "rv64um-p-truncated");
+        match &results[2].outcome {
+            TestOutcome::Error(message) if message.starts_with("Truncated") => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(results.len(), 3);
+    }
+
+    /// Runs the suites in `resources/isa-tests`, built from the generator in its `src`
+    /// directory in the format of riscv-tests, like `rv64ui-p-add`.
+    #[test]
+    fn test_isa_suites() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/isa-tests");
+        let results = run_tests(&directory, DEFAULT_MAX_INSTRUCTIONS).unwrap();
+        assert!(results.iter().any(|result| result.name == "rv64ui-p-add"));
+        assert!(results.iter().any(|result| result.name == "rv64um-p-div"));
+        let failed: Vec<TestResult> = results.into_iter()
+            .filter(|result| result.outcome != TestOutcome::Pass)
+            .collect();
+        assert!(failed.is_empty(), "{:#?}", failed);
+    }
+}
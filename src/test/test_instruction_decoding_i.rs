@@ -306,6 +306,17 @@ mod test_instruction_decoding_i {
         assert_eq!(instruction, Instruction::ebreak);
     }
 
+    #[test]
+    fn sfence_vma() {
+        // funct7 0001001, rs2 x2, rs1 x1
+        let raw_instruction: u32 = 0x1220_8073;
+        let instruction = Instruction::decode(raw_instruction).unwrap();
+        assert_eq!(instruction, Instruction::sfence_vma {
+            rs1: XRegister::x1,
+            rs2: XRegister::x2,
+        });
+    }
+
     #[test]
     fn slli() {
         let raw_instruction: u32 = 0b_000000_110111_11010_001_01110_0010011;
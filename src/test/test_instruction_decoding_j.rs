@@ -0,0 +1,34 @@
+#![cfg(test)]
+
+use crate::cpu::instruction::Instruction;
+use crate::cpu::register::XRegister;
+
+#[test]
+fn jal() {
+    let raw_instruction: u32 = 0b_1_1111110110_1_11111111_00001_1101111;
+    let instruction = Instruction::decode(raw_instruction).unwrap();
+    assert_eq!(instruction, Instruction::jal {
+        rd: XRegister::x1,
+        imm: -20,
+    });
+}
+
+#[test]
+fn jal_imm_11() {
+    let raw_instruction: u32 = 0b_0_0000000000_1_00000000_00000_1101111;
+    let instruction = Instruction::decode(raw_instruction).unwrap();
+    assert_eq!(instruction, Instruction::jal {
+        rd: XRegister::x0,
+        imm: 0x800,
+    });
+}
+
+#[test]
+fn jal_imm_19_12() {
+    let raw_instruction: u32 = 0b_0_1000000001_0_10000001_01110_1101111;
+    let instruction = Instruction::decode(raw_instruction).unwrap();
+    assert_eq!(instruction, Instruction::jal {
+        rd: XRegister::x14,
+        imm: 0b1000_0001_0_1000000001_0,
+    });
+}
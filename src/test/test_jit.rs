@@ -0,0 +1,364 @@
+#[cfg(all(test, feature = "jit"))]
+mod test_jit {
+    use crate::cpu::core::{Core, CoreError};
+    use crate::cpu::execute::InstructionExecuteError;
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::jit::{compile, Context, JitMode, MemoryAccess, TracedAccess};
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::Exception;
+    use crate::bus::{Bus, SharedBus};
+    use crate::dram::DRAM;
+    use crate::device::Device;
+        
+    const VALUES: [(u64, u64); 9] = [
+        (0, 0),
+        (1, 2),
+        (0x8000_0000_0000_0010, 68),
+        (0xFFFF_FFFF_FFFF_FFFF, 0x7FFF_FFFF_FFFF_FFFF),
+        (0x1234_5678_9ABC_DEF0, 0xFFFF_FFFF_FFFF_FFC1),
+        (0x8000_0000_0000_0000, 0xFFFF_FFFF_FFFF_FFFF),
+        (0xFFFF_FFFF_8000_0000, 0xFFFF_FFFF_FFFF_FFFF),
+        (0x0000_0001_7FFF_FFFF, 0x0000_0000_8000_0001),
+        (0xDEAD_BEEF, 0),
+    ];
+
+    // Loops 200 times over a block with loads, stores, word and M instructions that
+    // walks a pointer from 0x1000 by 8 bytes.
+    const PROGRAM: [u32; 20] = [
+        0x0C80_0113,  // addi x2, x0, 200
+        0x0000_1537,  // lui  x10, 1
+        0x0070_0293,  // addi x5, x0, 7
+        0x0065_3023,  // sd   x6, 0(x10)
+        0x0045_2383,  // lw   x7, 4(x10)
+        0x0253_843B,  // mulw x8, x7, x5
+        0x0223_54B3,  // divu x9, x6, x2
+        0x0254_65BB,  // remw x11, x8, x5
+        0x0283_1633,  // mulh x12, x6, x8
+        0x00B5_01A3,  // sb   x11, 3(x10)
+        0x0035_4683,  // lbu  x13, 3(x10)
+        0x0093_033B,  // addw x6, x6, x9
+        0x0053_4333,  // xor  x6, x6, x5
+        0x0053_1613,  // slli x12, x6, 5
+        0x00C3_0333,  // add  x6, x6, x12
+        0x40D3_0333,  // sub  x6, x6, x13
+        0x0085_0513,  // addi x10, x10, 8
+        0xFFF1_0113,  // addi x2, x2, -1
+        0xFC01_12E3,  // bne  x2, x0, -60
+        0x0000_006F,  // jal  x0, 0
+    ];
+
+    fn new_test_core(program: &[u32]) -> Core {
+        let mut dram = DRAM::new(0x2000);
+        for (i, instruction) in program.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        Core::new(SharedBus::new(Bus::new(vec![(0, Box::new(dram))]).unwrap()))
+    }
+
+    fn test_instructions() -> Vec<Instruction> {
+        use XRegister::*;
+        vec![
+            Instruction::add { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sub { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::xor { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::or { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::and { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sll { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::srl { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sra { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::slt { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sltu { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sub { rd: x3, rs1: x0, rs2: x2 },
+            Instruction::add { rd: x0, rs1: x1, rs2: x2 },
+            Instruction::addi { rd: x3, rs1: x1, imm: -2048 },
+            Instruction::xori { rd: x3, rs1: x1, imm: 0xFFFF_FFFF_FFFF_F800 },
+            Instruction::ori { rd: x3, rs1: x1, imm: 0x7FF },
+            Instruction::andi { rd: x3, rs1: x1, imm: 0xFFFF_FFFF_FFFF_FFF0 },
+            Instruction::slli { rd: x3, rs1: x1, shamt: 63 },
+            Instruction::srli { rd: x3, rs1: x1, shamt: 4 },
+            Instruction::srai { rd: x3, rs1: x1, shamt: 4 },
+            Instruction::slti { rd: x3, rs1: x1, imm: -1 },
+            Instruction::sltiu { rd: x3, rs1: x1, imm: 0xFFFF_FFFF_FFFF_FFFF },
+            Instruction::lui { rd: x3, uimm: 0xFFFF_FFFF_8000_0000 },
+            Instruction::auipc { rd: x3, imm: -0x1000 },
+            Instruction::addw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::subw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sllw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::srlw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::sraw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::addiw { rd: x3, rs1: x1, imm: -2048 },
+            Instruction::slliw { rd: x3, rs1: x1, shamt: 31 },
+            Instruction::srliw { rd: x3, rs1: x1, shamt: 0 },
+            Instruction::sraiw { rd: x3, rs1: x1, shamt: 7 },
+            Instruction::mul { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::mulh { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::mulhsu { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::mulhu { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::mulw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::div { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::divu { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::rem { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::remu { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::divw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::divuw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::remw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::remuw { rd: x3, rs1: x1, rs2: x2 },
+            Instruction::mul { rd: x0, rs1: x1, rs2: x2 },
+            Instruction::div { rd: x2, rs1: x2, rs2: x1 },
+        ]
+    }
+
+    /// Loads and stores around 0x1000, where memory holds `memory_pattern`. The signed
+    /// loads read negative values.
+    fn memory_instructions() -> Vec<Instruction> {
+        use XRegister::*;
+        vec![
+            Instruction::lb { rd: x3, rs1: x1, imm: 5 },
+            Instruction::lh { rd: x3, rs1: x1, imm: -4 },
+            Instruction::lw { rd: x3, rs1: x1, imm: -12 },
+            Instruction::ld { rd: x3, rs1: x1, imm: -8 },
+            Instruction::lbu { rd: x3, rs1: x1, imm: 5 },
+            Instruction::lhu { rd: x3, rs1: x1, imm: -4 },
+            Instruction::lwu { rd: x3, rs1: x1, imm: -12 },
+            Instruction::ld { rd: x1, rs1: x1, imm: 0 },
+            Instruction::ld { rd: x0, rs1: x1, imm: 0 },
+            Instruction::sb { rs1: x1, rs2: x2, imm: 3 },
+            Instruction::sh { rs1: x1, rs2: x2, imm: -6 },
+            Instruction::sw { rs1: x1, rs2: x2, imm: 12 },
+            Instruction::sd { rs1: x1, rs2: x2, imm: -16 },
+            Instruction::sd { rs1: x1, rs2: x0, imm: 0 },
+        ]
+    }
+
+    fn memory_pattern() -> Vec<u8> {
+        (0..64).map(|i| (i * 37 + 0x85) as u8).collect()
+    }
+
+    fn assert_same_registers(a: &Core, b: &Core) {
+        for i in 1..32 {
+            let register = XRegister::from(i);
+            assert_eq!(a.x_registers[register], b.x_registers[register], "{:?}", register);
+        }
+    }
+
+    fn assert_same_state(a: &Core, b: &Core) {
+        assert_eq!(a.pc, b.pc);
+        assert_same_registers(a, b);
+        assert_eq!(*a.bus.lock().read_bytes(0, 0x2000).unwrap(),
+                   *b.bus.lock().read_bytes(0, 0x2000).unwrap());
+    }
+
+    #[test]
+    fn test_instructions_match_interpreter() {
+        for instruction in test_instructions() {
+            let native = compile(0x40, &[instruction]).unwrap();
+
+            for (rs1, rs2) in VALUES {
+                let mut core = new_test_core(&[]);
+                core.pc = 0x40;
+                core.x_registers[XRegister::x1] = rs1;
+                core.x_registers[XRegister::x2] = rs2;
+
+                let mut registers = core.x_registers.clone();
+                assert_eq!(native.execute(&mut registers, &mut Context::new(&core.bus)), None);
+                instruction.execute(&mut core).unwrap();
+
+                for i in 1..32 {
+                    let register = XRegister::from(i);
+                    assert_eq!(registers[register], core.x_registers[register],
+                               "{:?} {:?} {:#x} {:#x}", instruction, register, rs1, rs2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_instructions_match_interpreter() {
+        for instruction in memory_instructions() {
+            let native = compile(0x40, &[instruction]).unwrap();
+
+            for (_, value) in VALUES {
+                let mut cores = [new_test_core(&[]), new_test_core(&[])];
+                for core in &mut cores {
+                    core.bus.lock().write_bytes(0xFE0, &memory_pattern()).unwrap();
+                    core.pc = 0x40;
+                    core.x_registers[XRegister::x1] = 0x1000;
+                    core.x_registers[XRegister::x2] = value;
+                }
+                let [compiled, interpreted] = &mut cores;
+
+                let mut context = Context::new(&compiled.bus);
+                assert_eq!(native.execute(&mut compiled.x_registers, &mut context), None);
+                assert!(context.take_error().is_none());
+                instruction.execute(interpreted).unwrap();
+                interpreted.pc = 0x40;
+                assert_same_state(compiled, interpreted);
+            }
+        }
+    }
+
+    #[test]
+    fn test_access_errors() {
+        use XRegister::*;
+        let mut core = new_test_core(&[]);
+        core.x_registers[x1] = 0x10000;
+        let native = compile(0, &[
+            Instruction::addi { rd: x5, rs1: x0, imm: 1 },
+            Instruction::ld { rd: x3, rs1: x1, imm: 0 },
+            Instruction::addi { rd: x6, rs1: x0, imm: 1 },
+        ]).unwrap();
+        let mut context = Context::new(&core.bus);
+        assert_eq!(native.execute(&mut core.x_registers, &mut context), Some(1));
+        match context.take_error() {
+            Some(InstructionExecuteError::Exception { exception: Exception::LoadAccessFault, tval: 0x10000 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(core.x_registers[x5], 1);
+        assert_eq!(core.x_registers[x6], 0);
+
+        let native = compile(0, &[
+            Instruction::sw { rs1: x0, rs2: x5, imm: 0x100 },
+            Instruction::sd { rs1: x1, rs2: x5, imm: 8 },
+            Instruction::addi { rd: x6, rs1: x0, imm: 1 },
+        ]).unwrap();
+        let mut context = Context::new(&core.bus);
+        assert_eq!(native.execute(&mut core.x_registers, &mut context), Some(1));
+        match context.take_error() {
+            Some(InstructionExecuteError::Exception { exception: Exception::StoreAccessFault, tval: 0x10008 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(core.bus.lock().read_bytes(0x100, 4).unwrap()[..], [1, 0, 0, 0]);
+        assert_eq!(core.x_registers[x6], 0);
+    }
+
+    #[test]
+    fn test_replay() {
+        use XRegister::*;
+        let native = compile(0, &[
+            Instruction::ld { rd: x3, rs1: x1, imm: 8 },
+            Instruction::sd { rs1: x1, rs2: x3, imm: 16 },
+        ]).unwrap();
+        let load = MemoryAccess::Load { address: 0x108, size: 8, sign_extend: false };
+        let store = MemoryAccess::Store { address: 0x110, size: 8, value: 42 };
+
+        let mut registers = new_test_core(&[]).x_registers;
+        registers[x1] = 0x100;
+        let trace = [TracedAccess { access: load, value: 42, stop: false },
+                     TracedAccess { access: store, value: 0, stop: false }];
+        let mut context = Context::replay(&trace);
+        assert_eq!(native.execute(&mut registers, &mut context), None);
+        assert_eq!(context.take_mismatch(), None);
+        assert_eq!(registers[x3], 42);
+
+        // The interpreter left the block at the load.
+        let mut context = Context::replay(&trace[..1]);
+        let stopped = [TracedAccess { access: load, value: 0, stop: true }];
+        assert_eq!(native.execute(&mut registers, &mut Context::replay(&stopped)), Some(0));
+        assert_eq!(native.execute(&mut registers, &mut context), Some(1));
+        assert_eq!(context.take_mismatch(), Some((None, Some(store))));
+
+        // A different value is stored, or the store is missing.
+        registers[x1] = 0x100;
+        let mut context = Context::replay(&trace);
+        let wrong_store = [TracedAccess { access: load, value: 41, stop: false }, trace[1]];
+        assert_eq!(native.execute(&mut registers, &mut Context::replay(&wrong_store)), Some(1));
+        let load_only = compile(0, &[Instruction::ld { rd: x3, rs1: x1, imm: 8 }]).unwrap();
+        assert_eq!(load_only.execute(&mut registers, &mut context), None);
+        assert_eq!(context.take_mismatch(), Some((Some(store), None)));
+    }
+
+    #[test]
+    fn test_unsupported_instruction() {
+        let instructions = [
+            Instruction::addi { rd: XRegister::x1, rs1: XRegister::x0, imm: 1 },
+            Instruction::lr_d { rd: XRegister::x2, rs1: XRegister::x1, rl: false, aq: false },
+        ];
+        assert!(compile(0, &instructions).is_none());
+        assert!(compile(0, &[]).is_none());
+    }
+
+    #[test]
+    fn test_differential() {
+        let instructions = 3 + 200 * 16 + 10;
+
+        let mut stepped = new_test_core(&PROGRAM);
+        for _ in 0..instructions {
+            stepped.execute().unwrap();
+        }
+
+        for mode in [JitMode::Enabled, JitMode::Differential] {
+            let mut core = new_test_core(&PROGRAM);
+            core.block_cache.set_jit_mode(mode);
+            assert_eq!(core.execute_blocks(instructions).unwrap(), instructions);
+            assert_eq!(core.block_cache.get_compilations(), 1);
+            assert_same_state(&core, &stepped);
+        }
+    }
+
+    #[test]
+    fn test_fault_in_compiled_block() {
+        // Starts the pointer at 0x1F60, so the store faults at the end of DRAM in the
+        // 21st iteration, once the block is compiled.
+        let mut program = PROGRAM.to_vec();
+        program.splice(1..2, [
+            0x0000_2537,  // lui  x10, 2
+            0xF605_0513,  // addi x10, x10, -160
+        ]);
+
+        let mut stepped = new_test_core(&program);
+        let mut instructions = 0;
+        while stepped.execute().is_ok() {
+            instructions += 1;
+        }
+        assert_eq!(stepped.pc, 0x10);
+
+        for mode in [JitMode::Enabled, JitMode::Differential] {
+            let mut core = new_test_core(&program);
+            core.block_cache.set_jit_mode(mode);
+            match core.execute_blocks(10_000) {
+                Err(CoreError::InstructionExecuteError(InstructionExecuteError::Exception {
+                    exception: Exception::StoreAccessFault, .. })) => {},
+                x => { panic!("PANIC {:?}", x) }
+            }
+            assert_eq!(core.block_cache.get_compilations(), 1);
+            assert_eq!(core.instructions_retired, instructions);
+            assert_same_state(&core, &stepped);
+        }
+    }
+
+    #[test]
+    fn test_self_modifying_compiled_block() {
+        // Stores to data until the last iteration, which replaces the addi after the
+        // store with addi x1, x0, 2 once the block is compiled.
+        let program = [
+            0x0140_0113,  // addi x2, x0, 20
+            0xFFF1_0113,  // addi x2, x2, -1
+            0x0020_3433,  // sltu x8, x0, x2
+            0x00C4_1413,  // slli x8, x8, 12
+            0x0184_0393,  // addi x7, x8, 24
+            0x0053_A023,  // sw   x5, 0(x7)
+            0x0010_0093,  // addi x1, x0, 1
+            0xFE01_14E3,  // bne  x2, x0, -24
+            0x0000_006F,  // jal  x0, 0
+        ];
+        let new_test_core = |program: &[u32]| {
+            let mut core = new_test_core(program);
+            core.x_registers[XRegister::x5] = 0x0020_0093;
+            core
+        };
+
+        let mut stepped = new_test_core(&program);
+        for _ in 0..200 {
+            stepped.execute().unwrap();
+        }
+        assert_eq!(stepped.x_registers[XRegister::x1], 2);
+
+        for mode in [JitMode::Enabled, JitMode::Differential] {
+            let mut core = new_test_core(&program);
+            core.block_cache.set_jit_mode(mode);
+            assert_eq!(core.execute_blocks(200).unwrap(), 200);
+            assert_eq!(core.block_cache.get_compilations(), 1);
+            assert_same_state(&core, &stepped);
+        }
+    }
+}
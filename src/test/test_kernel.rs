@@ -0,0 +1,127 @@
+#[cfg(test)]
+mod test_kernel {
+    use crate::boot_rom::BootROM;
+    use crate::clint::CLINT;
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::trap::StopReason;
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::kernel::{KernelError, KernelImage};
+    use crate::sbi::Sbi;
+    use crate::test::test_fdt::test_fdt::{parse, get_property};
+    use crate::uart::UART;
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    const BOOT_ROM_ADDRESS: usize = 0x1000;
+    const CLINT_ADDRESS: usize = 0x200_0000;
+    const UART_ADDRESS: usize = 0x1000_0000;
+    const DRAM_ADDRESS: usize = 0x8000_0000;
+
+    /// Instructions the boot may take before the shell prompt appears.
+    const MAX_BOOT_INSTRUCTIONS: u64 = 5_000_000_000;
+
+    /// Output that stays readable after it's given to the UART.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An `Image` header with `code` in place of the jump to the kernel.
+    fn build_image(text_offset: u64, image_size: u64, code: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; 64];
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[48..56].copy_from_slice(b"RISCV\0\0\0");
+        image[56..60].copy_from_slice(b"RSC\x05");
+        image.extend_from_slice(code);
+        image
+    }
+
+    #[test]
+    fn test_parse() {
+        let kernel = KernelImage::parse(build_image(0, 0x1000, &[])).unwrap();
+        assert_eq!((kernel.text_offset, kernel.image_size), (0x20_0000, 0x1000));
+        assert_eq!(kernel.get_entry(DRAM_ADDRESS), 0x8020_0000);
+        let kernel = KernelImage::parse(build_image(0x40_0000, 0, &[1; 100])).unwrap();
+        assert_eq!((kernel.text_offset, kernel.image_size), (0x40_0000, 164));
+
+        assert!(!KernelImage::is_image(b"\x7fELF"));
+        match KernelImage::parse(vec![0; 64]) {
+            Err(KernelError::InvalidHeader) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let mut cpu = CPU::new(vec![(DRAM_ADDRESS, Box::new(DRAM::new(16 << 20)))]).unwrap();
+        let kernel = KernelImage::parse(build_image(0x20_0000, 0x10_0000, &[0x13, 0, 0, 0])).unwrap();
+        let entry = kernel.load(&mut cpu, Some(b"070701"), Some("console=ttyS0 rdinit=/bin/sh")).unwrap();
+        assert_eq!(entry, 0x8020_0000);
+        assert_eq!(cpu.bus.lock().read_int(entry + 64, 4, Endianness::LittleEndian, false).unwrap(), 0x13);
+        // Halfway through the memory.
+        assert_eq!(&cpu.bus.lock().read_bytes(0x8080_0000, 6).unwrap()[..], b"070701");
+
+        let properties = parse(&cpu.generate_fdt().unwrap());
+        assert_eq!(get_property(&properties, "/chosen", "bootargs").unwrap(), b"console=ttyS0 rdinit=/bin/sh\0");
+        assert_eq!(get_property(&properties, "/chosen", "linux,initrd-start").unwrap(),
+                   &[0, 0, 0, 0, 0x80, 0x80, 0, 0]);
+        assert_eq!(get_property(&properties, "/chosen", "linux,initrd-end").unwrap(),
+                   &[0, 0, 0, 0, 0x80, 0x80, 0, 6]);
+
+        let kernel = KernelImage::parse(build_image(0x20_0000, 0x100_0000, &[])).unwrap();
+        match kernel.load(&mut cpu, None, None) {
+            Err(KernelError::TooLarge { size: 0x100_0000 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    /// Boots the kernel and initramfs in `resources/linux` through the built-in SBI,
+    /// and waits for the shell prompt of `/init` on the UART.
+    #[test]
+    fn test_boot_linux() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/linux");
+        let (image, initrd) = (directory.join("Image"), directory.join("rootfs.cpio"));
+        let kernel = KernelImage::open(&image).unwrap();
+        let output = SharedOutput::default();
+        let mut uart = UART::new();
+        uart.set_output(Box::new(output.clone()));
+        let mut cpu = CPU::new(vec![
+            (BOOT_ROM_ADDRESS, Box::new(BootROM::new(kernel.get_entry(DRAM_ADDRESS) as u64))),
+            (CLINT_ADDRESS, Box::new(CLINT::new(1))),
+            (UART_ADDRESS, Box::new(uart)),
+            (DRAM_ADDRESS, Box::new(DRAM::new(256 << 20))),
+        ]).unwrap();
+        kernel.load(&mut cpu, Some(&std::fs::read(&initrd).unwrap()), Some("console=ttyS0 earlycon=sbi")).unwrap();
+        cpu.load_fdt().unwrap();
+        let mut sbi = Sbi::new(&mut cpu).unwrap();
+
+        let mut executed = 0;
+        while executed < MAX_BOOT_INSTRUCTIONS {
+            match sbi.run(&mut cpu, 10_000_000).unwrap() {
+                StopReason::BudgetExhausted => {},
+                // The timer follows the host clock, as it does for `main`.
+                StopReason::WaitForInterrupt => thread::sleep(Duration::from_millis(1)),
+                x => { panic!("PANIC {:?}\n{}", x, String::from_utf8_lossy(&output.0.lock().unwrap())) }
+            }
+            if output.0.lock().unwrap().windows(4).any(|window| window == b"/ # ") {
+                return
+            }
+            executed += 10_000_000;
+        }
+        panic!("PANIC no shell prompt\n{}", String::from_utf8_lossy(&output.0.lock().unwrap()));
+    }
+}
@@ -0,0 +1,209 @@
+#[cfg(test)]
+mod test_linux {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::{Exception, StopReason};
+    use crate::device::Device;
+    use crate::elf::Elf;
+    use crate::endianness::Endianness;
+    use crate::linux::{FileDescriptor, Process};
+    use crate::test::test_elf::test_elf::build_elf;
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    const ADDRESS: u64 = 0x10000;
+    const GADGET: usize = 0x10030;
+    const SCRATCH: usize = 0x20000;
+
+    // Writes the first 5 bytes of argv[1] and exits with argc, followed by an
+    // ecall and ebreak that the tests use to make single system calls.
+    const PROGRAM: [u32; 14] = [
+        0x0001_3503,  // ld   x10, 0(x2)
+        0x0005_0413,  // addi x8, x10, 0
+        0x0101_3583,  // ld   x11, 16(x2)
+        0x0010_0513,  // addi x10, x0, 1
+        0x0050_0613,  // addi x12, x0, 5
+        0x0400_0893,  // addi x17, x0, 64
+        0x0000_0073,  // ecall
+        0x0004_0513,  // addi x10, x8, 0
+        0x05E0_0893,  // addi x17, x0, 94
+        0x0000_0073,  // ecall
+        0x0000_0000,
+        0x0000_0000,
+        0x0000_0073,  // ecall
+        0x0010_0073,  // ebreak
+    ];
+
+    /// Output that stays readable after it's given to the process.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_process(args: &[&str]) -> (CPU, Process, SharedOutput) {
+        let elf = Elf::parse(build_elf(ADDRESS, &PROGRAM, 0x100, &[])).unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let (cpu, mut process) = Process::new(&elf, &args, &["HOME=/root".to_string()]).unwrap();
+        let output = SharedOutput::default();
+        process.set_file(1, FileDescriptor::Output(Box::new(output.clone())));
+        (cpu, process, output)
+    }
+
+    /// Makes a system call through the gadget at the end of the program.
+    fn syscall(cpu: &mut CPU, process: &mut Process, number: u64, args: &[u64]) -> i64 {
+        let registers = [XRegister::x10, XRegister::x11, XRegister::x12, XRegister::x13, XRegister::x14, XRegister::x15];
+        for (register, value) in registers.iter().zip(args) {
+            cpu.harts[0].x_registers[*register] = *value;
+        }
+        cpu.harts[0].x_registers[XRegister::x17] = number;
+        cpu.harts[0].pc = GADGET;
+        match process.run(cpu, 100).unwrap() {
+            StopReason::Trap { exception: Exception::Breakpoint, .. } => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        cpu.harts[0].x_registers[XRegister::x10] as i64
+    }
+
+    fn read_u64(cpu: &CPU, address: usize) -> u64 {
+        cpu.bus.lock().read_int(address, 8, Endianness::LittleEndian, false).unwrap()
+    }
+
+    fn read_string(cpu: &CPU, address: usize) -> String {
+        let bytes = cpu.bus.lock().read_bytes(address, 64).unwrap().into_owned();
+        String::from_utf8(bytes.split(|byte| *byte == 0).next().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_program() {
+        let (mut cpu, mut process, output) = new_process(&["echo", "hello, world"]);
+        let sp = cpu.harts[0].x_registers[XRegister::x2] as usize;
+        assert_eq!(sp % 16, 0);
+        assert_eq!(read_u64(&cpu, sp), 2);
+        assert_eq!(read_string(&cpu, read_u64(&cpu, sp + 8) as usize), "echo");
+        assert_eq!(read_string(&cpu, read_u64(&cpu, sp + 16) as usize), "hello, world");
+        assert_eq!(read_u64(&cpu, sp + 24), 0);
+        assert_eq!(read_string(&cpu, read_u64(&cpu, sp + 32) as usize), "HOME=/root");
+        assert_eq!(read_u64(&cpu, sp + 40), 0);
+
+        let mut auxv = Vec::new();
+        let mut address = sp + 48;
+        while read_u64(&cpu, address) != 0 {
+            auxv.push((read_u64(&cpu, address), read_u64(&cpu, address + 8)));
+            address += 16;
+        }
+        assert!(auxv.contains(&(6, 4096)));
+        assert!(auxv.contains(&(9, ADDRESS)));
+        // The program headers follow the ELF header, outside the loaded segment.
+        assert!(auxv.contains(&(5, 0)));
+        let execfn = auxv.iter().find(|(key, _)| *key == 31).unwrap().1;
+        assert_eq!(read_string(&cpu, execfn as usize), "echo");
+
+        assert_eq!(process.run(&mut cpu, 1000).unwrap(), StopReason::Exit { code: 2 });
+        assert_eq!(&output.0.lock().unwrap()[..], b"hello");
+    }
+
+    /// Runs the static binary built from `resources/linux-user/src/stats.rs`, whose
+    /// 32-bit arithmetic and divisions compile to the W and M instructions.
+    #[test]
+    fn test_static_binary() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/linux-user/stats");
+        let elf = Elf::open(&path).unwrap();
+        let args: Vec<String> = ["stats", "10", "97", "1000", "3000000000"].iter().map(|arg| arg.to_string()).collect();
+        let (mut cpu, mut process) = Process::new(&elf, &args, &[]).unwrap();
+        let output = SharedOutput::default();
+        process.set_file(1, FileDescriptor::Output(Box::new(output.clone())));
+
+        assert_eq!(process.run(&mut cpu, 100_000_000).unwrap(), StopReason::Exit { code: 4 });
+        assert_eq!(String::from_utf8(output.0.lock().unwrap().clone()).unwrap(), concat!(
+            "10: primes=4 sum=17 collatz=6 quotients=7 remainders=9913 gcd=10 div=-1 rem=3\n",
+            "97: primes=25 sum=1060 collatz=118 quotients=65 remainders=89245 gcd=1 div=-13 rem=6\n",
+            "1000: primes=168 sum=76127 collatz=111 quotients=689 remainders=176919 gcd=40 div=-142 rem=6\n",
+            "3000000000: primes=9592 sum=454396537 collatz=223 quotients=-896955769 remainders=238412 ",
+            "gcd=120 div=-428571428 rem=4\n",
+        ));
+    }
+
+    #[test]
+    fn test_memory() {
+        let (mut cpu, mut process, _) = new_process(&["test"]);
+        let start = syscall(&mut cpu, &mut process, 214, &[0]);
+        assert_eq!(start, 0x11000);
+        assert_eq!(syscall(&mut cpu, &mut process, 214, &[0x13000]), 0x13000);
+        cpu.bus.lock().write_int(0x12000, 42, 8, Endianness::LittleEndian).unwrap();
+        assert_eq!(syscall(&mut cpu, &mut process, 214, &[0x11000]), 0x11000);
+        assert_eq!(syscall(&mut cpu, &mut process, 214, &[0x13000]), 0x13000);
+        assert_eq!(read_u64(&cpu, 0x12000), 0);
+        // Below the start of the heap.
+        assert_eq!(syscall(&mut cpu, &mut process, 214, &[0x1000]), 0x13000);
+
+        let first = syscall(&mut cpu, &mut process, 222, &[0, 0x2000, 3, 0x22, u64::MAX, 0]);
+        assert_eq!(first, 0xEF7F_E000);
+        let second = syscall(&mut cpu, &mut process, 222, &[0, 100, 3, 0x22, u64::MAX, 0]);
+        assert_eq!(second, first - 0x1000);
+        cpu.bus.lock().write_int(second as usize, 42, 8, Endianness::LittleEndian).unwrap();
+        assert_eq!(syscall(&mut cpu, &mut process, 215, &[second as u64, 100]), 0);
+        assert_eq!(syscall(&mut cpu, &mut process, 222, &[0, 0x1000, 3, 0x22, u64::MAX, 0]), second);
+        assert_eq!(read_u64(&cpu, second as usize), 0);
+        assert_eq!(syscall(&mut cpu, &mut process, 222, &[0, 0, 3, 0x22, u64::MAX, 0]), -22);
+    }
+
+    #[test]
+    fn test_files() {
+        let (mut cpu, mut process, output) = new_process(&["test"]);
+        let path = std::env::temp_dir().join(format!("yarve-linux-{}", std::process::id()));
+        let mut bytes = path.to_str().unwrap().as_bytes().to_vec();
+        bytes.push(0);
+        cpu.bus.lock().write_bytes(SCRATCH, &bytes).unwrap();
+        cpu.bus.lock().write_bytes(SCRATCH + 0x100, b"test vector").unwrap();
+
+        // openat(AT_FDCWD, path, O_RDWR | O_CREAT | O_TRUNC)
+        let fd = syscall(&mut cpu, &mut process, 56, &[-100i64 as u64, SCRATCH as u64, 0x242, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(syscall(&mut cpu, &mut process, 64, &[3, SCRATCH as u64 + 0x100, 11]), 11);
+        assert_eq!(syscall(&mut cpu, &mut process, 62, &[3, 5, 0]), 5);
+        assert_eq!(syscall(&mut cpu, &mut process, 63, &[3, SCRATCH as u64 + 0x200, 100]), 6);
+        assert_eq!(read_string(&cpu, SCRATCH + 0x200), "vector");
+        assert_eq!(syscall(&mut cpu, &mut process, 80, &[3, SCRATCH as u64 + 0x300]), 0);
+        assert_eq!(read_u64(&cpu, SCRATCH + 0x300 + 48), 11);
+        assert_eq!(syscall(&mut cpu, &mut process, 57, &[3]), 0);
+        assert_eq!(syscall(&mut cpu, &mut process, 57, &[3]), -9);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(syscall(&mut cpu, &mut process, 56, &[-100i64 as u64, SCRATCH as u64, 0, 0]), -2);
+
+        // writev(1, [("test", 4), (" vector", 7)], 2)
+        for (i, value) in [SCRATCH as u64 + 0x100, 4, SCRATCH as u64 + 0x104, 7].iter().enumerate() {
+            cpu.bus.lock().write_int(SCRATCH + 0x400 + i * 8, *value, 8, Endianness::LittleEndian).unwrap();
+        }
+        assert_eq!(syscall(&mut cpu, &mut process, 66, &[1, SCRATCH as u64 + 0x400, 2]), 11);
+        assert_eq!(&output.0.lock().unwrap()[..], b"test vector");
+        // The standard output is a character device, but not a terminal.
+        assert_eq!(syscall(&mut cpu, &mut process, 80, &[1, SCRATCH as u64 + 0x300]), 0);
+        assert_eq!(read_u64(&cpu, SCRATCH + 0x300 + 16) as u32 & 0o170000, 0o020000);
+        assert_eq!(syscall(&mut cpu, &mut process, 29, &[1, 0x5413, 0]), -25);
+        // Bad pointers fail the call.
+        assert_eq!(syscall(&mut cpu, &mut process, 64, &[1, 1 << 40, 4]), -14);
+    }
+
+    #[test]
+    fn test_system() {
+        let (mut cpu, mut process, _) = new_process(&["test"]);
+        assert_eq!(syscall(&mut cpu, &mut process, 160, &[SCRATCH as u64]), 0);
+        assert_eq!(read_string(&cpu, SCRATCH), "Linux");
+        assert_eq!(read_string(&cpu, SCRATCH + 4 * 65), "riscv64");
+        assert_eq!(syscall(&mut cpu, &mut process, 113, &[0, SCRATCH as u64]), 0);
+        assert!(read_u64(&cpu, SCRATCH) > 1_600_000_000);
+        assert!(read_u64(&cpu, SCRATCH + 8) < 1_000_000_000);
+        assert_eq!(syscall(&mut cpu, &mut process, 278, &[SCRATCH as u64, 16, 0]), 16);
+        assert!(read_u64(&cpu, SCRATCH) != 0 || read_u64(&cpu, SCRATCH + 8) != 0);
+        assert_eq!(syscall(&mut cpu, &mut process, 1234, &[]), -38);
+    }
+}
@@ -0,0 +1,112 @@
+#[cfg(test)]
+mod test_lockstep {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::lockstep::{CommitRecord, Effect, Lockstep, LockstepError, Mismatch};
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::StopReason;
+    use crate::device::Device;
+    use crate::dram::DRAM;
+
+    /// Commit log of `PROGRAM`, with the disassembly lines of `spike -l`.
+    const REFERENCE: &str = include_str!("../../resources/traces/sum.log");
+
+    // Sums 4 + 3 + 2 + 1, storing the partial sums, then moves the sum to mepc and
+    // calls into a missing trap handler.
+    const PROGRAM: [u32; 10] = [
+        0x0000_0297,  // auipc x5, 0
+        0x0000_0513,  // addi  x10, x0, 0
+        0x0040_0593,  // addi  x11, x0, 4
+        0x00B5_0533,  // add   x10, x10, x11
+        0x10A2_B023,  // sd    x10, 0x100(x5)
+        0xFFF5_8593,  // addi  x11, x11, -1
+        0xFE05_9AE3,  // bne   x11, x0, -12
+        0x1002_B603,  // ld    x12, 0x100(x5)
+        0x3416_16F3,  // csrrw x13, mepc, x12
+        0x0000_0073,  // ecall
+    ];
+
+    fn new_test_cpu() -> CPU {
+        let mut cpu = CPU::new(vec![(0x8000_0000, Box::new(DRAM::new(0x1000)))]).unwrap();
+        let code: Vec<u8> = PROGRAM.iter().flat_map(|x| x.to_le_bytes()).collect();
+        cpu.bus.lock().write_bytes(0x8000_0000, &code).unwrap();
+        cpu.harts[0].pc = 0x8000_0000;
+        cpu
+    }
+
+    #[test]
+    fn test_parse() {
+        let record = CommitRecord::parse(
+            "core   1: 3 0x0000000080000020 (0x341616f3) x13 0x0000000000000000 c833_mepc 0x000000000000000a \
+             mem 0x0000000080000100 mem 0x0000000080000108 0x00000005").unwrap();
+        assert_eq!(record, CommitRecord {
+            hart: 1,
+            privilege: 3,
+            pc: 0x8000_0020,
+            word: 0x3416_16F3,
+            effects: vec![
+                Effect::Write { register: "x13".to_string(), value: 0 },
+                Effect::Write { register: "c833".to_string(), value: 10 },
+                Effect::Load { address: 0x8000_0100 },
+                Effect::Store { address: 0x8000_0108, size: 4, value: 5 },
+            ],
+        });
+        assert!(CommitRecord::parse("core   0: 0x0000000080000000 (0x00000297) auipc   t0, 0x0").is_none());
+        assert!(CommitRecord::parse("core   0: exception trap_machine_ecall, epc 0x0000000080000024").is_none());
+        match Lockstep::parse("hello\n") {
+            Err(LockstepError::NoRecords) => {},
+            x => { panic!("PANIC {:?}", x.err()) }
+        }
+    }
+
+    #[test]
+    fn test_match() {
+        let mut cpu = new_test_cpu();
+        assert!(Lockstep::parse(REFERENCE).unwrap().run(&mut cpu).unwrap().is_none());
+        assert_eq!(cpu.harts[0].pc, 0x8000_0024);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x12], 10);
+        assert!(cpu.harts[0].commit_log.is_none());
+    }
+
+    #[test]
+    fn test_divergence() {
+        // The third partial sum is 9.
+        let reference = REFERENCE.replace("x10 0x0000000000000009", "x10 0x0000000000000008");
+        let mut cpu = new_test_cpu();
+        let divergence = Lockstep::parse(&reference).unwrap().run(&mut cpu).unwrap().unwrap();
+        assert_eq!((divergence.mismatch, divergence.index, divergence.line), (Mismatch::Writeback, 11, 24));
+        assert_eq!(divergence.actual.as_deref(),
+                   Some("core   0: 3 0x000000008000000c (0x00b50533) x10 0x0000000000000009"));
+        assert_eq!(divergence.before.len(), 3);
+        assert!(divergence.to_string().contains("\
+  core   0: 3 0x0000000080000018 (0xfe059ae3)
+  core   0: 0x000000008000000c (0x00b50533) add     a0, a0, a1
+- core   0: 3 0x000000008000000c (0x00b50533) x10 0x0000000000000008
++ core   0: 3 0x000000008000000c (0x00b50533) x10 0x0000000000000009
+  core   0: 0x0000000080000010 (0x10a2b023) sd      a0, 256(t0)
+"), "{}", divergence);
+        // The hart stops at the divergence.
+        assert_eq!(cpu.harts[0].pc, 0x8000_0010);
+
+        let reference = REFERENCE.replace("0x0000000080000100 0x0000000000000004", "0x0000000080000108 0x0000000000000004");
+        let divergence = Lockstep::parse(&reference).unwrap().run(&mut new_test_cpu()).unwrap().unwrap();
+        assert_eq!((divergence.mismatch, divergence.index), (Mismatch::MemoryAccess, 4));
+
+        let reference = REFERENCE.replace("(0x00400593) x11", "(0x00500593) x11");
+        let divergence = Lockstep::parse(&reference).unwrap().run(&mut new_test_cpu()).unwrap().unwrap();
+        assert_eq!((divergence.mismatch, divergence.index), (Mismatch::Instruction, 2));
+    }
+
+    #[test]
+    fn test_not_retired() {
+        // A reference where ecall returns, while the hart has no trap handler.
+        let reference = format!("{}core   0: 3 0x0000000080000028 (0x00000013)\n", REFERENCE);
+        let divergence = Lockstep::parse(&reference).unwrap().run(&mut new_test_cpu()).unwrap().unwrap();
+        assert_eq!((divergence.mismatch, divergence.index), (Mismatch::NotRetired, 21));
+        match divergence.stop {
+            Some(StopReason::Trap { pc: 0x8000_0024, .. }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert!(divergence.to_string().contains("+ (stopped: Trap"), "{}", divergence);
+        assert!(divergence.after.is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+#[cfg(test)]
+mod test_mmu {
+    use crate::bus::{Bus, SharedBus};
+    use crate::cpu::core::{Core, CoreError, Privilege};
+    use crate::cpu::csr::{MSTATUS, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM, MSTATUS_TVM, SATP, SATP_MODE_SV39};
+    use crate::cpu::execute::InstructionExecuteError;
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::mmu::{AccessType, PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::{Exception, StopReason};
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+
+    const ROOT: usize = 0x10000;
+    const LEVEL_1: usize = 0x11000;
+    const LEVEL_0: usize = 0x12000;
+    /// Start of the 2 MiB the level 0 table maps.
+    const BASE: usize = 0x4000_0000;
+    const RWX: u64 = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+
+    /// A core in S-mode with `program` at physical address 0, and Sv39 enabled with
+    /// page tables that don't map anything at `BASE` yet.
+    fn new_test_core(program: &[u32]) -> Core {
+        let mut dram = DRAM::new(0x20000);
+        for (i, instruction) in program.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        let mut core = Core::new(SharedBus::new(Bus::new(vec![(0, Box::new(dram))]).unwrap()));
+        write_pte(&core, ROOT, BASE >> 30, LEVEL_1, PTE_V);
+        write_pte(&core, LEVEL_1, 0, LEVEL_0, PTE_V);
+        core.write_csr(SATP, SATP_MODE_SV39 | (ROOT >> 12) as u64);
+        core.privilege = Privilege::Supervisor;
+        core
+    }
+
+    fn write_pte(core: &Core, table: usize, index: usize, physical: usize, flags: u64) {
+        let pte = ((physical >> 12) << 10) as u64 | flags;
+        core.bus.lock().write_int(table + index * 8, pte, 8, Endianness::LittleEndian).unwrap();
+    }
+
+    /// Maps the page `page` pages above `BASE` to `physical`.
+    fn map(core: &Core, page: usize, physical: usize, flags: u64) {
+        write_pte(core, LEVEL_0, page, physical, flags);
+    }
+
+    fn assert_page_fault(result: Result<usize, InstructionExecuteError>, expected: Exception, address: usize) {
+        match result {
+            Err(InstructionExecuteError::Exception { exception, tval })
+                if exception == expected && tval == address as u64 => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, RWX);
+
+        assert_eq!(core.translate(BASE + 0x123, AccessType::Load).unwrap(), 0x3123);
+        assert_eq!(core.translate(BASE + 0xFFF, AccessType::Store).unwrap(), 0x3FFF);
+        assert_eq!(core.translate(BASE, AccessType::Fetch).unwrap(), 0x3000);
+        assert_page_fault(core.translate(BASE + 0x1000, AccessType::Load), Exception::LoadPageFault, BASE + 0x1000);
+        assert_page_fault(core.translate(BASE + 0x1000, AccessType::Fetch), Exception::InstructionPageFault,
+                          BASE + 0x1000);
+        // Addresses whose upper bits don't equal bit 38.
+        assert_page_fault(core.translate(0x80_4000_0000, AccessType::Store), Exception::StorePageFault,
+                          0x80_4000_0000);
+
+        // M-mode and Bare don't translate.
+        core.privilege = Privilege::Machine;
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), BASE);
+        core.privilege = Privilege::User;
+        core.write_csr(SATP, 0);
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), BASE);
+    }
+
+    #[test]
+    fn test_superpages() {
+        let mut core = new_test_core(&[]);
+        // A gigapage at 0x8000_0000 and a megapage at BASE + 2 MiB.
+        write_pte(&core, ROOT, 2, 0, RWX);
+        write_pte(&core, LEVEL_1, 1, 0x20_0000, RWX);
+        assert_eq!(core.translate(0x8123_4567, AccessType::Load).unwrap(), 0x0123_4567);
+        assert_eq!(core.translate(BASE + 0x20_5678, AccessType::Load).unwrap(), 0x20_5678);
+
+        // Superpages must be aligned.
+        write_pte(&core, ROOT, 3, 0x20_0000, RWX);
+        write_pte(&core, LEVEL_1, 2, 0x1000, RWX);
+        assert_page_fault(core.translate(0xC000_0000, AccessType::Load), Exception::LoadPageFault, 0xC000_0000);
+        assert_page_fault(core.translate(BASE + 0x40_0000, AccessType::Load), Exception::LoadPageFault,
+                          BASE + 0x40_0000);
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, RWX & !PTE_V);
+        map(&core, 1, 0x3000, PTE_V | PTE_W | PTE_A | PTE_D);
+        map(&core, 2, 0x3000, RWX | (1 << 61));
+        // A pointer to a table outside of memory.
+        write_pte(&core, LEVEL_1, 3, 0x100_0000, PTE_V);
+
+        for page in 0..3 {
+            let address = BASE + page * 0x1000;
+            assert_page_fault(core.translate(address, AccessType::Load), Exception::LoadPageFault, address);
+        }
+        assert_page_fault(core.translate(BASE + 0x60_0000, AccessType::Store), Exception::StoreAccessFault,
+                          BASE + 0x60_0000);
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, PTE_V | PTE_R | PTE_A | PTE_D);
+        map(&core, 1, 0x3000, PTE_V | PTE_X | PTE_A);
+        map(&core, 2, 0x3000, RWX | PTE_U);
+
+        assert_page_fault(core.translate(BASE, AccessType::Store), Exception::StorePageFault, BASE);
+        assert_page_fault(core.translate(BASE, AccessType::Fetch), Exception::InstructionPageFault, BASE);
+
+        // Execute-only pages are readable with MXR.
+        assert_page_fault(core.translate(BASE + 0x1000, AccessType::Load), Exception::LoadPageFault, BASE + 0x1000);
+        core.csr_registers[MSTATUS] |= MSTATUS_MXR;
+        assert_eq!(core.translate(BASE + 0x1000, AccessType::Load).unwrap(), 0x3000);
+
+        // S-mode accesses the data of user pages with SUM, and never executes them.
+        assert_page_fault(core.translate(BASE + 0x2000, AccessType::Load), Exception::LoadPageFault, BASE + 0x2000);
+        core.csr_registers[MSTATUS] |= MSTATUS_SUM;
+        assert_eq!(core.translate(BASE + 0x2000, AccessType::Store).unwrap(), 0x3000);
+        assert_page_fault(core.translate(BASE + 0x2000, AccessType::Fetch), Exception::InstructionPageFault,
+                          BASE + 0x2000);
+
+        // U-mode only accesses user pages.
+        core.privilege = Privilege::User;
+        assert_eq!(core.translate(BASE + 0x2000, AccessType::Fetch).unwrap(), 0x3000);
+        assert_page_fault(core.translate(BASE, AccessType::Load), Exception::LoadPageFault, BASE);
+    }
+
+    #[test]
+    fn test_accessed_and_dirty() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, RWX & !PTE_A);
+        map(&core, 1, 0x3000, RWX & !PTE_D);
+
+        assert_page_fault(core.translate(BASE, AccessType::Load), Exception::LoadPageFault, BASE);
+        assert_eq!(core.translate(BASE + 0x1000, AccessType::Load).unwrap(), 0x3000);
+        assert_page_fault(core.translate(BASE + 0x1000, AccessType::Store), Exception::StorePageFault,
+                          BASE + 0x1000);
+
+        // Entries cached without D are walked again once software sets it.
+        map(&core, 1, 0x3000, RWX);
+        assert_eq!(core.translate(BASE + 0x1000, AccessType::Store).unwrap(), 0x3000);
+    }
+
+    #[test]
+    fn test_sfence_vma() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, RWX);
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), 0x3000);
+
+        // The cached translation is used until `sfence.vma` or a write to `satp`.
+        map(&core, 0, 0x4000, RWX);
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), 0x3000);
+        let sfence = Instruction::sfence_vma { rs1: XRegister::x0, rs2: XRegister::x0 };
+        sfence.execute(&mut core).unwrap();
+        assert_eq!(core.pc, 4);
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), 0x4000);
+        map(&core, 0, 0x5000, RWX);
+        core.write_csr(SATP, core.csr_registers[SATP]);
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), 0x5000);
+
+        // `sfence.vma` is illegal in U-mode, and in S-mode with TVM.
+        core.csr_registers[MSTATUS] |= MSTATUS_TVM;
+        for privilege in [Privilege::User, Privilege::Supervisor] {
+            core.privilege = privilege;
+            match sfence.execute(&mut core) {
+                Err(InstructionExecuteError::IllegalInstruction(_)) => {},
+                x => { panic!("PANIC {:?}", x) }
+            }
+        }
+    }
+
+    #[test]
+    fn test_satp() {
+        let mut core = new_test_core(&[]);
+        // The ASID isn't implemented, and modes other than Bare and Sv39 are ignored.
+        core.write_csr(SATP, SATP_MODE_SV39 | (0xFFFF << 44) | 0x20);
+        assert_eq!(core.read_csr(SATP).unwrap(), SATP_MODE_SV39 | 0x20);
+        core.write_csr(SATP, (9 << 60) | 0x30);
+        assert_eq!(core.read_csr(SATP).unwrap(), SATP_MODE_SV39 | 0x20);
+
+        assert!(core.can_access_csr(SATP, true));
+        core.csr_registers[MSTATUS] |= MSTATUS_TVM;
+        assert!(!core.can_access_csr(SATP, false));
+        core.privilege = Privilege::Machine;
+        assert!(core.can_access_csr(SATP, true));
+    }
+
+    #[test]
+    fn test_mprv() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, RWX);
+        core.privilege = Privilege::Machine;
+        core.csr_registers[MSTATUS] |= MSTATUS_MPRV | (Privilege::Supervisor as u64) << 11;
+
+        // Loads and stores are translated at the privilege in MPP, fetches aren't.
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), 0x3000);
+        assert_eq!(core.translate(BASE, AccessType::Store).unwrap(), 0x3000);
+        assert_eq!(core.translate(BASE, AccessType::Fetch).unwrap(), BASE);
+        core.csr_registers[MSTATUS] |= (Privilege::Machine as u64) << 11;
+        assert_eq!(core.translate(BASE, AccessType::Load).unwrap(), BASE);
+    }
+
+    #[test]
+    fn test_page_crossing_access() {
+        let mut core = new_test_core(&[]);
+        map(&core, 0, 0x3000, RWX);
+        map(&core, 1, 0x5000, RWX);
+
+        core.store(BASE + 0xFFC, 0x1122_3344_5566_7788, 8).unwrap();
+        let mut bus = core.bus.lock();
+        assert_eq!(bus.read_int(0x3FFC, 4, Endianness::LittleEndian, false).unwrap(), 0x5566_7788);
+        assert_eq!(bus.read_int(0x5000, 4, Endianness::LittleEndian, false).unwrap(), 0x1122_3344);
+        bus.write_int(0x5000, 0x80, 1, Endianness::LittleEndian).unwrap();
+        drop(bus);
+        assert_eq!(core.load(BASE + 0xFFC, 8, false).unwrap(), 0x1122_3380_5566_7788);
+        assert_eq!(core.load(BASE + 0xFFF, 2, true).unwrap(), 0xFFFF_FFFF_FFFF_8055);
+
+        // Nothing is written if the second page faults.
+        match core.store(BASE + 0x1FFE, u64::MAX, 4) {
+            Err(InstructionExecuteError::Exception { exception: Exception::StorePageFault, tval })
+                if tval == (BASE + 0x2000) as u64 => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        assert_eq!(core.bus.lock().read_int(0x5FFE, 2, Endianness::LittleEndian, false).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_run_translated() {
+        let mut core = new_test_core(&[
+            0x0050_0093,  // addi x1, x0, 5
+            0x0011_3023,  // sd   x1, 0(x2)
+            0x0001_3183,  // ld   x3, 0(x2)
+            0x0002_B203,  // ld   x4, 0(x5)
+        ]);
+        map(&core, 0, 0, RWX);
+        map(&core, 1, 0x3000, RWX);
+        core.pc = BASE;
+        core.x_registers[XRegister::x2] = (BASE + 0x1008) as u64;
+        core.x_registers[XRegister::x5] = (BASE + 0x2000) as u64;
+
+        assert_eq!(core.run(100).unwrap(), StopReason::Trap {
+            exception: Exception::LoadPageFault, pc: BASE + 12, tval: (BASE + 0x2000) as u64
+        });
+        assert_eq!(core.x_registers[XRegister::x3], 5);
+        assert_eq!(core.bus.lock().read_int(0x3008, 8, Endianness::LittleEndian, false).unwrap(), 5);
+
+        core.pc = BASE + 0x2000;
+        assert_eq!(core.run(100).unwrap(), StopReason::Trap {
+            exception: Exception::InstructionPageFault, pc: BASE + 0x2000, tval: (BASE + 0x2000) as u64
+        });
+        match core.execute() {
+            Err(CoreError::InstructionExecuteError(InstructionExecuteError::Exception {
+                exception: Exception::InstructionPageFault, ..
+            })) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
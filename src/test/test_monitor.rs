@@ -0,0 +1,156 @@
+#[cfg(test)]
+mod test_monitor {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::instruction::Instruction;
+    use crate::cpu::register::{FRegister, XRegister};
+    use crate::cpu::trap::StopReason;
+    use crate::dram::DRAM;
+    use crate::elf::{Elf, SymbolTable};
+    use crate::monitor::Monitor;
+    use crate::test::test_elf::test_elf::build_elf;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    // Counts in a0 and stores the count to `counter`, calling `bump` on every
+    // iteration.
+    const PROGRAM: [u32; 7] = [
+        0x0000_0513,  // addi x10, x0, 0
+        0x0015_0513,  // addi x10, x10, 1
+        0x20A0_3023,  // sd   x10, 0x200(x0)
+        0x0080_00EF,  // jal  x1, 8
+        0xFF5F_F06F,  // jal  x0, -12
+        0x0025_8593,  // addi x11, x11, 2
+        0x0000_8067,  // jalr x0, 0(x1)
+    ];
+
+    fn new_test_cpu() -> (CPU, SymbolTable) {
+        let elf = Elf::parse(build_elf(0x100, &PROGRAM, 0, &[
+            ("_start", 0x100, 0x14, 2), ("bump", 0x114, 8, 2), ("counter", 0x200, 8, 1),
+        ])).unwrap();
+        let mut cpu = CPU::new(vec![(0, Box::new(DRAM::new(0x1000)))]).unwrap();
+        elf.load(&mut *cpu.bus.lock()).unwrap();
+        cpu.harts[0].pc = elf.entry;
+        (cpu, elf.symbols)
+    }
+
+    /// Runs the monitor on the commands in `input`, and returns its output.
+    fn run_monitor(cpu: &mut CPU, symbols: SymbolTable, input: &str) -> String {
+        let mut output = Vec::new();
+        Monitor::new(cpu, symbols).run(&mut input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_disassembly() {
+        let cases = [
+            (0x0000_0513, "addi a0, zero, 0"),
+            (0xFFF5_C513, "xori a0, a1, -1"),
+            (0x20A0_3023, "sd a0, 512(zero)"),
+            (0x0008_0663, "beq a6, zero, 12"),
+            (0xFF5F_F06F, "jal zero, -12"),
+            (0x0000_8067, "jalr zero, 0(ra)"),
+            (0x1000_02B7, "lui t0, 0x10000"),
+            (0x3054_1073, "csrrw zero, mtvec, s0"),
+            (0x3004_6073, "csrrsi zero, mstatus, 8"),
+            (0x7C00_2573, "csrrs a0, 0x7c0, zero"),
+            (0x0FF0_000F, "fence iorw, iorw"),
+            (0x0000_0073, "ecall"),
+            (0x1220_8073, "sfence.vma ra, sp"),
+            (0x06C5_A52F, "amoadd.w.aqrl a0, a2, (a1)"),
+            (0x0085_A507, "flw fa0, 8(a1)"),
+        ];
+        for (word, text) in cases.iter() {
+            assert_eq!(Instruction::decode(*word).unwrap().to_string(), *text);
+        }
+        let jal = Instruction::decode(0xFF5F_F06F).unwrap();
+        assert_eq!(jal.get_jump_target(0x110), Some(0x104));
+        assert_eq!(Instruction::decode(0x0000_8067).unwrap().get_jump_target(0x110), None);
+    }
+
+    #[test]
+    fn test_register_names() {
+        assert_eq!(XRegister::from_name("fp"), Some(XRegister::x8));
+        assert_eq!(XRegister::from_name("s0"), Some(XRegister::x8));
+        assert_eq!(XRegister::from_name("a0"), Some(XRegister::x10));
+        assert_eq!(XRegister::from_name("x31"), Some(XRegister::x31));
+        assert_eq!(XRegister::from_name("x32"), None);
+        assert_eq!(XRegister::from_name("pc"), None);
+        assert_eq!(FRegister::from_name("fa0"), Some(FRegister::f10));
+        assert_eq!(FRegister::from_name("f31"), Some(FRegister::f31));
+        assert_eq!(XRegister::x1.get_abi_name(), "ra");
+        assert_eq!(FRegister::f8.get_abi_name(), "fs0");
+    }
+
+    #[test]
+    fn test_session() {
+        let (mut cpu, symbols) = new_test_cpu();
+        let output = run_monitor(&mut cpu, symbols, "\
+            break bump\n\
+            continue\n\
+            regs\n\
+            delete bump\n\
+            watch counter\n\
+            c\n\
+            x counter 8\n\
+            disas _start 2\n\
+            step 2\n\
+            \n\
+            hart 1\n\
+            csr mepc\n\
+            delete counter\n\
+            delete counter\n\
+            b pc+0x100\n\
+            info\n\
+            frobnicate\n\
+            quit\n\
+            step\n");
+        let expected = [
+            "=> 0x00000100 <_start>  00000513  addi a0, zero, 0\n",
+            "(yarve) breakpoint at 0x00000114 <bump>\n",
+            "(yarve) hart 0 stopped at breakpoint 0x00000114 <bump>\n",
+            "   a0 0x0000000000000001",
+            "  pc 0x00000114 <bump>\n",
+            "(yarve) watchpoint on 8 bytes at 0x00000200 <counter>\n",
+            "hart 0 wrote to 0x00000200 <counter>\n",
+            "=> 0x0000010c <_start+12>  008000ef  jal ra, 8                # 0x00000114 <bump>\n",
+            "0x00000200  02 00 00 00 00 00 00 00",
+            "_start:\n   0x00000100 <_start>  00000513  addi a0, zero, 0\n   0x00000104 <_start+4>",
+            "=> 0x00000118 <bump+4>  00008067  jalr zero, 0(ra)\n",
+            "(yarve) => 0x00000104 <_start+4>  00150513  addi a0, a0, 1\n",
+            "error: CPUError(InvalidHart { hart: 1 })\n",
+            "mepc       0x0000000000000000\n",
+            "error: InvalidArgument { argument: \"counter\" }\n",
+            "(yarve) breakpoint at 0x00000204 <counter+4>\n",
+            "(yarve) breakpoint at 0x00000204 <counter+4>\n(yarve) error: UnknownCommand",
+        ];
+        for text in expected.iter() {
+            assert!(output.contains(text), "{:?} not in {}", text, output);
+        }
+        // Commands after quit aren't executed, and breakpoints are removed.
+        assert!(output.ends_with("(yarve) "));
+        assert_eq!(cpu.harts[0].pc, 0x104);
+        assert_eq!(cpu.run(100).unwrap().1, StopReason::BudgetExhausted);
+        assert!(cpu.harts[0].x_registers[XRegister::x10] > 2);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let (mut cpu, symbols) = new_test_cpu();
+        let monitor = Monitor::new(&mut cpu, symbols);
+        let interrupt = monitor.get_interrupt();
+        // Set before the command, which must not stop it right away.
+        interrupt.store(true, Ordering::Relaxed);
+        let mut output = Vec::new();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                interrupt.store(true, Ordering::Relaxed);
+            });
+            monitor.run(&mut "c\n".as_bytes(), &mut output).unwrap();
+        });
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("(yarve) interrupted\n"), "{}", output);
+        assert!(cpu.harts[0].x_registers[XRegister::x10] > 1000);
+    }
+}
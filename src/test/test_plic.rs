@@ -0,0 +1,147 @@
+#[cfg(test)]
+mod test_plic {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::csr::{MCAUSE, MIE, MIP_MEIP, MIP_SEIP, MSTATUS, MSTATUS_MIE, MTVEC};
+    use crate::cpu::trap::Interrupt;
+    use crate::device::{Device, DeviceError};
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::plic::{InterruptSource, PLIC};
+    use crate::test::test_fdt::test_fdt::{parse, get_property};
+    use std::sync::atomic::Ordering;
+
+    const PLIC_ADDRESS: usize = 0xC00_0000;
+    const ADDRESS: usize = 0x8000_0000;
+    const HANDLER: usize = ADDRESS + 0x100;
+
+    fn new_machine(hart_count: usize) -> (CPU, InterruptSource, InterruptSource) {
+        let plic = PLIC::new(hart_count);
+        let (first, second) = (plic.get_source(1).unwrap(), plic.get_source(2).unwrap());
+        let cpu = CPU::with_harts(vec![
+            (PLIC_ADDRESS, Box::new(plic)),
+            (ADDRESS, Box::new(DRAM::new(0x1000))),
+        ], hart_count).unwrap();
+        (cpu, first, second)
+    }
+
+    fn read(cpu: &CPU, offset: usize) -> u64 {
+        cpu.bus.lock().read_int(PLIC_ADDRESS + offset, 4, Endianness::LittleEndian, false).unwrap()
+    }
+
+    fn write(cpu: &CPU, offset: usize, value: u64) {
+        cpu.bus.lock().write_int(PLIC_ADDRESS + offset, value, 4, Endianness::LittleEndian).unwrap();
+    }
+
+    fn external_pending(cpu: &CPU, hart: usize) -> bool {
+        line(cpu, hart) & MIP_MEIP != 0
+    }
+
+    fn line(cpu: &CPU, hart: usize) -> u64 {
+        cpu.harts[hart].interrupt_line.as_ref().unwrap().load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_claim_and_complete() {
+        let (cpu, first, second) = new_machine(1);
+        write(&cpu, 0x4, 1);
+        write(&cpu, 0x8, 3);
+        assert!(!first.set_level(true));
+        assert!(!second.set_level(true));
+        assert_eq!(read(&cpu, 0x1000), 0b110);
+        assert!(!external_pending(&cpu, 0));
+
+        write(&cpu, 0x2000, 0b111);
+        assert_eq!(read(&cpu, 0x2000), 0b110);
+        assert!(external_pending(&cpu, 0));
+        // The highest priority first, then the other one.
+        assert_eq!(read(&cpu, 0x20_0004), 2);
+        assert_eq!(read(&cpu, 0x1000), 0b010);
+        assert_eq!(read(&cpu, 0x20_0004), 1);
+        assert!(!external_pending(&cpu, 0));
+        assert_eq!(read(&cpu, 0x20_0004), 0);
+
+        // A source still high is pending again once completed.
+        write(&cpu, 0x20_0004, 2);
+        assert!(external_pending(&cpu, 0));
+        second.set_level(false);
+        assert!(!external_pending(&cpu, 0));
+        write(&cpu, 0x20_0004, 1);
+        first.set_level(false);
+        assert_eq!(read(&cpu, 0x1000), 0);
+
+        // Sources at or below the threshold are masked.
+        write(&cpu, 0x20_0000, 3);
+        second.set_level(true);
+        assert!(!external_pending(&cpu, 0));
+        write(&cpu, 0x20_0000, 2);
+        assert!(external_pending(&cpu, 0));
+        assert_eq!(read(&cpu, 0x20_0000), 2);
+    }
+
+    #[test]
+    fn test_contexts() {
+        let (cpu, first, _) = new_machine(2);
+        write(&cpu, 0x4, 1);
+        // The M-mode context of hart 1.
+        write(&cpu, 0x2100, 0b10);
+        assert!(first.set_level(true));
+        assert!(!external_pending(&cpu, 0));
+        assert!(external_pending(&cpu, 1));
+        assert_eq!(read(&cpu, 0x20_0004), 0);
+        assert_eq!(read(&cpu, 0x20_2004), 1);
+        assert!(!external_pending(&cpu, 1));
+        write(&cpu, 0x20_2004, 1);
+
+        // The S-mode context of hart 0 raises `mip.SEIP` instead.
+        write(&cpu, 0x2100, 0);
+        write(&cpu, 0x2080, 0b10);
+        assert_eq!(line(&cpu, 0), MIP_SEIP);
+        assert_eq!(line(&cpu, 1), 0);
+        write(&cpu, 0x2000, 0b10);
+        assert_eq!(line(&cpu, 0), MIP_MEIP | MIP_SEIP);
+        assert_eq!(read(&cpu, 0x20_1004), 1);
+        assert_eq!(line(&cpu, 0), 0);
+
+        let result = cpu.bus.lock().read_int(PLIC_ADDRESS + 0x4, 8, Endianness::LittleEndian, false);
+        match result {
+            Err(DeviceError::InvalidSizeReadFault) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let (mut cpu, first, _) = new_machine(1);
+        // Both the program and the handler loop.
+        cpu.bus.lock().write_int(ADDRESS, 0x0000_006F, 4, Endianness::LittleEndian).unwrap();
+        cpu.bus.lock().write_int(HANDLER, 0x0000_006F, 4, Endianness::LittleEndian).unwrap();
+        let core = &mut cpu.harts[0];
+        core.pc = ADDRESS;
+        core.csr_registers[MTVEC] = HANDLER as u64;
+        core.csr_registers[MIE] = MIP_MEIP;
+        core.csr_registers[MSTATUS] |= MSTATUS_MIE;
+        write(&cpu, 0x4, 1);
+        write(&cpu, 0x2000, 0b10);
+
+        cpu.run(10).unwrap();
+        assert_eq!(cpu.harts[0].pc, ADDRESS);
+        first.set_level(true);
+        cpu.run(1).unwrap();
+        assert_eq!(cpu.harts[0].csr_registers[MCAUSE], Interrupt::MachineExternal.get_cause());
+        assert_eq!(cpu.harts[0].pc, HANDLER);
+    }
+
+    #[test]
+    fn test_fdt() {
+        let (cpu, _, _) = new_machine(2);
+        let properties = parse(&cpu.generate_fdt().unwrap());
+        let node = "/plic@c000000";
+        assert_eq!(get_property(&properties, node, "compatible").unwrap(), b"sifive,plic-1.0.0\0riscv,plic0\0");
+        assert_eq!(get_property(&properties, node, "riscv,ndev").unwrap(), &[0, 0, 0, 63]);
+        let interrupts = get_property(&properties, node, "interrupts-extended").unwrap();
+        assert_eq!(interrupts.len(), 32);
+        assert_eq!(&interrupts[4..8], &[0, 0, 0, 11]);
+        assert_eq!(&interrupts[12..16], &[0, 0, 0, 9]);
+        assert!(get_property(&properties, node, "phandle").is_some());
+    }
+}
@@ -0,0 +1,192 @@
+#[cfg(test)]
+mod test_replay {
+    use crate::clint::CLINT;
+    use crate::cpu::cpu::{CPU, CPUError};
+    use crate::cpu::register::XRegister;
+    use crate::cpu::replay::{InputMode, Recording, Watch};
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::uart::UART;
+
+    // Arms the timer 500 ticks ahead, then loops reading mtime into x15 and summing
+    // UART input in x19, with a counter stored at 0x200. The timer handler at 0x100
+    // counts interrupts in x17, stores the count at 0x208 and rearms the timer.
+    const PROGRAM: [(usize, u32); 27] = [
+        (0x00, 0x1000_0413),  // addi   x8, x0, 0x100
+        (0x04, 0x3054_1073),  // csrrw  x0, mtvec, x8
+        (0x08, 0x0800_0413),  // addi   x8, x0, 0x80
+        (0x0C, 0x3044_1073),  // csrrw  x0, mie, x8
+        (0x10, 0x0200_43B7),  // lui    x7, 0x2004
+        (0x14, 0x1000_04B7),  // lui    x9, 0x10000
+        (0x18, 0x0200_C6B7),  // lui    x13, 0x200C
+        (0x1C, 0xFF86_B703),  // ld     x14, -8(x13)
+        (0x20, 0x1F47_0713),  // addi   x14, x14, 500
+        (0x24, 0x00E3_B023),  // sd     x14, 0(x7)
+        (0x28, 0x3004_6073),  // csrrsi x0, mstatus, 8
+        (0x2C, 0xFF86_B783),  // ld     x15, -8(x13)
+        (0x30, 0x0054_C803),  // lbu    x16, 5(x9)
+        (0x34, 0x0018_7813),  // andi   x16, x16, 1
+        (0x38, 0x0008_0663),  // beq    x16, x0, 12
+        (0x3C, 0x0004_C903),  // lbu    x18, 0(x9)
+        (0x40, 0x0129_89B3),  // add    x19, x19, x18
+        (0x44, 0x001A_0A13),  // addi   x20, x20, 1
+        (0x48, 0x2140_3023),  // sd     x20, 0x200(x0)
+        (0x4C, 0x00FA_8AB3),  // add    x21, x21, x15
+        (0x50, 0xFDDF_F06F),  // jal    x0, -36
+        (0x100, 0x0018_8893), // addi   x17, x17, 1
+        (0x104, 0x2110_3423), // sd     x17, 0x208(x0)
+        (0x108, 0xFF86_BB03), // ld     x22, -8(x13)
+        (0x10C, 0x1F4B_0B13), // addi   x22, x22, 500
+        (0x110, 0x0163_B023), // sd     x22, 0(x7)
+        (0x114, 0x3020_0073), // mret
+    ];
+
+    fn new_test_cpu() -> CPU {
+        let mut dram = DRAM::new(0x1000);
+        for (address, instruction) in PROGRAM.iter() {
+            dram.write_bytes(*address, &instruction.to_le_bytes()).unwrap();
+        }
+        let mut cpu = CPU::new(vec![
+            (0, Box::new(dram)),
+            (0x200_0000, Box::new(CLINT::new(1))),
+            (0x1000_0000, Box::new(UART::new())),
+        ]).unwrap();
+        cpu.set_quantum(7);
+        cpu
+    }
+
+    fn read(cpu: &CPU, address: usize) -> u64 {
+        cpu.bus.lock().read_int(address, 8, Endianness::LittleEndian, false).unwrap()
+    }
+
+    fn get_state(cpu: &CPU) -> (usize, Vec<u64>, Vec<u8>) {
+        let core = &cpu.harts[0];
+        let registers = (0..32u32).map(|i| core.x_registers[XRegister::from(i)]).collect();
+        let memory = cpu.bus.lock().read_bytes(0, 0x1000).unwrap().into_owned();
+        (core.pc, registers, memory)
+    }
+
+    /// Records until the handler ran at least `interrupts` times, feeding the UART on
+    /// the way. More bytes are sent than the FIFO holds.
+    fn record(cpu: &mut CPU, interrupts: u64) {
+        cpu.start_recording().unwrap();
+        cpu.receive_uart_input(b"yarve");
+        cpu.run(1000).unwrap();
+        cpu.receive_uart_input(&[0xFF; 20]);
+        cpu.run(1000).unwrap();
+        while cpu.harts[0].x_registers[XRegister::x17] < interrupts {
+            cpu.run(1000).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut cpu = new_test_cpu();
+        record(&mut cpu, 3);
+        let state = get_state(&cpu);
+        let position = cpu.get_position();
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x19], 551 + 20 * 0xFF);
+
+        let recording = cpu.stop_recording().unwrap();
+        assert_eq!(recording.end, position);
+        let mut saved = Vec::new();
+        recording.save(&mut saved).unwrap();
+        let recording = Recording::load(&mut saved.as_slice()).unwrap();
+
+        // Inputs from the host are held back until the end of the recording.
+        let mut replay = new_test_cpu();
+        replay.start_replay(recording).unwrap();
+        replay.receive_uart_input(b"!");
+        for budget in [1, 13, 1000, 6].iter().cycle() {
+            let remaining = position - replay.get_position();
+            if remaining == 0 {
+                break
+            }
+            replay.run(remaining.min(*budget)).unwrap();
+        }
+        assert_eq!(get_state(&replay), state);
+
+        // Recording resumes at the end.
+        replay.run(1000).unwrap();
+        assert_eq!(replay.get_input_mode(), InputMode::Recording);
+        assert_eq!(replay.harts[0].x_registers[XRegister::x19], 551 + 20 * 0xFF + 33);
+    }
+
+    #[test]
+    fn test_reverse_step() {
+        let mut cpu = new_test_cpu();
+        cpu.set_checkpoint_interval(100);
+        record(&mut cpu, 2);
+
+        let mut states = Vec::new();
+        for _ in 0..250 {
+            states.push(get_state(&cpu));
+            cpu.run(1).unwrap();
+        }
+        let position = cpu.get_position();
+        while let Some(state) = states.pop() {
+            assert!(cpu.reverse_step().unwrap());
+            assert_eq!(get_state(&cpu), state);
+        }
+        assert_eq!(cpu.get_position(), position - 250);
+
+        let mut cpu = new_test_cpu();
+        cpu.start_recording().unwrap();
+        assert!(!cpu.reverse_step().unwrap());
+        cpu.run(3).unwrap();
+        assert!(cpu.reverse_step().unwrap());
+        assert_eq!(cpu.get_position(), 2);
+        assert_eq!(cpu.harts[0].pc, 0x08);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut cpu = new_test_cpu();
+        cpu.set_checkpoint_interval(500);
+        record(&mut cpu, 3);
+        cpu.run(100).unwrap();
+        let interrupts = cpu.harts[0].x_registers[XRegister::x17];
+
+        assert!(cpu.reverse_continue(Watch::Memory { address: 0x208, size: 8 }).unwrap());
+        assert_eq!(cpu.harts[0].pc, 0x104);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x17], interrupts);
+        assert_eq!(read(&cpu, 0x208), interrupts - 1);
+
+        assert!(cpu.reverse_continue(Watch::Register { hart: 0, register: XRegister::x17 }).unwrap());
+        let position = cpu.get_position();
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x17], interrupts - 1);
+        cpu.run(1).unwrap();
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x17], interrupts);
+        assert_eq!(cpu.harts[0].pc, 0x104);
+        assert_eq!(cpu.get_position(), position + 1);
+
+        // Nothing writes 0x300, so execution goes back to the start.
+        assert!(!cpu.reverse_continue(Watch::Memory { address: 0x300, size: 8 }).unwrap());
+        assert_eq!(cpu.get_position(), 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut cpu = new_test_cpu();
+        match cpu.reverse_step() {
+            Err(CPUError::NotRecording) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        match cpu.stop_recording() {
+            Err(CPUError::NotRecording) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        cpu.start_recording().unwrap();
+        cpu.run(10).unwrap();
+        match cpu.run_threaded(100) {
+            Err(CPUError::NotDeterministic) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        match cpu.reverse_continue(Watch::Register { hart: 1, register: XRegister::x1 }) {
+            Err(CPUError::InvalidHart { hart: 1 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
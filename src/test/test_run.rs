@@ -0,0 +1,264 @@
+#[cfg(test)]
+mod test_run {
+    use crate::cpu::core::{Core, CoreError, Privilege};
+    use crate::cpu::csr::{MCAUSE, MEDELEG, MEPC, MSTATUS, MSTATUS_MPP, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP,
+                          MTVAL, SCAUSE, STVEC};
+    use crate::cpu::execute::InstructionExecuteError;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::{Exception, StopReason, WatchKind};
+    use crate::bus::{Bus, SharedBus};
+    use crate::dram::DRAM;
+    use crate::finisher::TestFinisher;
+    use crate::device::Device;
+        
+    const FINISHER_ADDRESS: usize = 0x10_0000;
+
+    const LOOP: [u32; 3] = [
+        0x0000_0093,  // addi x1, x0, 0
+        0x0010_8093,  // addi x1, x1, 1
+        0xFFDF_F06F,  // jal  x0, -4
+    ];
+
+    fn new_test_core(program: &[u32]) -> Core {
+        let mut dram = DRAM::new(0x2000);
+        for (i, instruction) in program.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        Core::new(SharedBus::new(Bus::new(vec![
+            (0, Box::new(dram)),
+            (FINISHER_ADDRESS, Box::new(TestFinisher::new()))
+        ]).unwrap()))
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut core = new_test_core(&LOOP);
+
+        assert_eq!(core.run(100).unwrap(), StopReason::BudgetExhausted);
+        assert_eq!(core.get_instructions_retired(), 100);
+        assert_eq!(core.x_registers[XRegister::x1], 50);
+
+        assert_eq!(core.run(0).unwrap(), StopReason::BudgetExhausted);
+        assert_eq!(core.get_instructions_retired(), 100);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut core = new_test_core(&LOOP);
+        core.add_breakpoint(4);
+
+        // The breakpoint on the first instruction of a call is skipped.
+        for i in 1..=3 {
+            assert_eq!(core.run(100).unwrap(), StopReason::Breakpoint { address: 4 });
+            assert_eq!(core.pc, 4);
+            assert_eq!(core.x_registers[XRegister::x1], i - 1);
+        }
+
+        assert!(core.remove_breakpoint(4));
+        assert!(!core.remove_breakpoint(4));
+        assert_eq!(core.run(10).unwrap(), StopReason::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut core = new_test_core(&[
+            0x0000_0093,  // addi x1, x0, 0
+            0x0010_8093,  // addi x1, x1, 1
+            0x1010_3023,  // sd   x1, 0x100(x0)
+            0x1040_3103,  // ld   x2, 0x104(x0)
+            0xFF5F_F06F,  // jal  x0, -12
+        ]);
+
+        // The store overlaps the watched bytes and has been executed.
+        core.add_watchpoint(0x104, 4, WatchKind::Write);
+        let reason = core.run(100).unwrap();
+        assert_eq!(reason, StopReason::Watchpoint { address: 0x104, kind: WatchKind::Write });
+        assert_eq!(core.pc, 0x0C);
+        assert_eq!(core.x_registers[XRegister::x1], 1);
+
+        assert!(core.remove_watchpoint(0x104, 4, WatchKind::Write));
+        core.add_watchpoint(0x108, 8, WatchKind::Read);
+        let reason = core.run(100).unwrap();
+        assert_eq!(reason, StopReason::Watchpoint { address: 0x108, kind: WatchKind::Read });
+        assert_eq!(core.pc, 0x10);
+
+        core.add_watchpoint(0x100, 1, WatchKind::Access);
+        let reason = core.run(100).unwrap();
+        assert_eq!(reason, StopReason::Watchpoint { address: 0x100, kind: WatchKind::Access });
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+        assert!(!core.remove_watchpoint(0x100, 1, WatchKind::Read));
+    }
+
+    #[test]
+    fn test_exit() {
+        let program = [
+            0x0010_02B7,  // lui  x5, 0x100
+            0x0000_5337,  // lui  x6, 0x5
+            0x5553_0313,  // addi x6, x6, 0x555
+            0x0062_A023,  // sw   x6, 0(x5)
+            0x0003_3337,  // lui  x6, 0x33
+            0x3333_0313,  // addi x6, x6, 0x333
+            0x0062_A023,  // sw   x6, 0(x5)
+            0x0000_006F,  // jal  x0, 0
+        ];
+
+        let mut core = new_test_core(&program);
+        assert_eq!(core.run(100).unwrap(), StopReason::Exit { code: 0 });
+        assert_eq!(core.pc, 16);
+        assert_eq!(core.run(100).unwrap(), StopReason::Exit { code: 3 });
+        assert_eq!(core.pc, 28);
+        assert_eq!(core.run(100).unwrap(), StopReason::BudgetExhausted);
+
+        // Same with single-stepping.
+        let mut core = new_test_core(&program);
+        core.add_breakpoint(0x1000);
+        assert_eq!(core.run(100).unwrap(), StopReason::Exit { code: 0 });
+        assert_eq!(core.step().unwrap(), None);
+        assert_eq!(core.step().unwrap(), None);
+        assert_eq!(core.step().unwrap(), Some(StopReason::Exit { code: 3 }));
+    }
+
+    #[test]
+    fn test_wait_for_interrupt() {
+        let mut core = new_test_core(&[
+            0x0010_0093,  // addi x1, x0, 1
+            0x1050_0073,  // wfi
+            0x0010_8093,  // addi x1, x1, 1
+            0x0000_006F,  // jal  x0, 0
+        ]);
+
+        assert_eq!(core.run(100).unwrap(), StopReason::WaitForInterrupt);
+        assert_eq!(core.pc, 8);
+        assert_eq!(core.get_instructions_retired(), 2);
+
+        assert_eq!(core.run(100).unwrap(), StopReason::BudgetExhausted);
+        assert_eq!(core.x_registers[XRegister::x1], 2);
+    }
+
+    #[test]
+    fn test_trap_without_handler() {
+        let mut core = new_test_core(&[
+            0x0010_0093,  // addi x1, x0, 1
+            0x0000_0073,  // ecall
+            0x0010_0073,  // ebreak
+            0x0000_0000,  // illegal
+        ]);
+
+        let expected = StopReason::Trap {
+            exception: Exception::EnvironmentCallFromMMode, pc: 4, tval: 0
+        };
+        assert_eq!(core.run(100).unwrap(), expected);
+        assert_eq!(core.pc, 4);
+        assert_eq!(core.csr_registers[MEPC], 0);
+        assert_eq!(core.csr_registers[MCAUSE], 0);
+        // The trap is reported again until something changes.
+        assert_eq!(core.step().unwrap(), Some(expected));
+
+        core.pc = 8;
+        assert_eq!(core.step().unwrap(), Some(StopReason::Trap {
+            exception: Exception::Breakpoint, pc: 8, tval: 8
+        }));
+
+        core.pc = 12;
+        assert_eq!(core.run(1).unwrap(), StopReason::Trap {
+            exception: Exception::IllegalInstruction, pc: 12, tval: 0
+        });
+    }
+
+    #[test]
+    fn test_trap_handler() {
+        let mut program = vec![0; 0x44];
+        program[..6].copy_from_slice(&[
+            0x1000_0393,  // addi  x7, x0, 0x100
+            0x3053_9073,  // csrrw x0, mtvec, x7
+            0x0001_04B7,  // lui   x9, 0x10
+            0x0004_B403,  // ld    x8, 0(x9)
+            0x0050_0513,  // addi  x10, x0, 5
+            0x0000_006F,  // jal   x0, 0
+        ]);
+        program[0x40..].copy_from_slice(&[
+            0x3410_25F3,  // csrrs x11, mepc, x0
+            0x0045_8593,  // addi  x11, x11, 4
+            0x3415_9073,  // csrrw x0, mepc, x11
+            0x3020_0073,  // mret
+        ]);
+        let mut core = new_test_core(&program);
+
+        assert_eq!(core.run(100).unwrap(), StopReason::BudgetExhausted);
+        assert_eq!(core.pc, 20);
+        assert_eq!(core.x_registers[XRegister::x10], 5);
+        assert_eq!(core.csr_registers[MEPC], 16);
+        assert_eq!(core.csr_registers[MCAUSE], Exception::LoadAccessFault.get_cause());
+        assert_eq!(core.csr_registers[MTVAL], 0x10000);
+        // `mret` returned to M-mode, and left the least privileged mode in MPP.
+        assert_eq!(core.privilege, Privilege::Machine);
+        assert_eq!(core.csr_registers[MSTATUS] & MSTATUS_MPP, 0);
+    }
+
+    #[test]
+    fn test_delegated_trap() {
+        let mut program = vec![0; 0x14];
+        program[..3].copy_from_slice(&[
+            0x0000_0073,  // ecall
+            0x0070_0293,  // addi  x5, x0, 7
+            0x0000_006F,  // jal   x0, 0
+        ]);
+        program[0x10..].copy_from_slice(&[
+            0x1410_25F3,  // csrrs x11, sepc, x0
+            0x0045_8593,  // addi  x11, x11, 4
+            0x1415_9073,  // csrrw x0, sepc, x11
+            0x1020_0073,  // sret
+        ]);
+        let mut core = new_test_core(&program);
+        core.csr_registers[STVEC] = 0x40;
+        core.csr_registers[MEDELEG] = 1 << Exception::EnvironmentCallFromUMode.get_cause();
+        core.csr_registers[MSTATUS] |= MSTATUS_SIE;
+        core.privilege = Privilege::User;
+
+        // The `ecall` from U-mode goes to the S-mode handler, which returns past it.
+        assert_eq!(core.run(100).unwrap(), StopReason::BudgetExhausted);
+        assert_eq!(core.pc, 8);
+        assert_eq!(core.privilege, Privilege::User);
+        assert_eq!(core.x_registers[XRegister::x5], 7);
+        assert_eq!(core.csr_registers[SCAUSE], Exception::EnvironmentCallFromUMode.get_cause());
+        assert_eq!(core.csr_registers[MSTATUS] & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+                   MSTATUS_SIE | MSTATUS_SPIE);
+        assert_eq!(core.csr_registers[MEPC], 0);
+
+        // Exceptions that aren't delegated go to M-mode, and `sret` is illegal in U-mode.
+        core.pc = 0x4C;
+        assert_eq!(core.run(1).unwrap(), StopReason::Trap {
+            exception: Exception::IllegalInstruction, pc: 0x4C, tval: 0
+        });
+        core.csr_registers[MEDELEG] = 0;
+        core.pc = 0;
+        assert_eq!(core.run(1).unwrap(), StopReason::Trap {
+            exception: Exception::EnvironmentCallFromUMode, pc: 0, tval: 0
+        });
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut core = new_test_core(&LOOP);
+
+        let reason = core.run_until(|core| core.x_registers[XRegister::x1] == 10);
+        assert_eq!(reason.unwrap(), StopReason::Condition);
+        assert_eq!(core.pc, 8);
+
+        core.add_breakpoint(4);
+        assert_eq!(core.run_until(|_| false).unwrap(), StopReason::Breakpoint { address: 4 });
+        assert_eq!(core.x_registers[XRegister::x1], 10);
+    }
+
+    #[test]
+    fn test_unhandled_error() {
+        let mut core = new_test_core(&[
+            0x0231_70D3,  // fadd.d f1, f2, f3
+        ]);
+
+        match core.run(10) {
+            Err(CoreError::InstructionExecuteError(InstructionExecuteError::NotImplemented(_))) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
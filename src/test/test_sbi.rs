@@ -0,0 +1,240 @@
+#[cfg(test)]
+mod test_sbi {
+    use crate::boot_rom::BootROM;
+    use crate::clint::CLINT;
+    use crate::cpu::core::Privilege;
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::csr::{MIE, MIP, MIP_SSIP, MIP_STIP, MSTATUS, MSTATUS_SIE, SCAUSE, SEPC, STVEC};
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::{Exception, Interrupt, StopReason};
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::sbi::{Sbi, SbiError};
+    use crate::uart::UART;
+
+    const CLINT_ADDRESS: usize = 0x200_0000;
+    const UART_ADDRESS: usize = 0x1000_0000;
+    const ADDRESS: usize = 0x8000_0000;
+    const MASK: usize = ADDRESS + 0x100;
+
+    const EXTENSION_BASE: u64 = 0x10;
+    const EXTENSION_TIME: u64 = 0x5449_4D45;
+    const EXTENSION_IPI: u64 = 0x73_5049;
+    const EXTENSION_RFENCE: u64 = 0x5246_4E43;
+    const EXTENSION_HSM: u64 = 0x48_534D;
+    const EXTENSION_SRST: u64 = 0x5352_5354;
+
+    // An SBI call, then a breakpoint that stops the tests.
+    const PROGRAM: [u32; 2] = [
+        0x0000_0073,  // ecall
+        0x0010_0073,  // ebreak
+    ];
+
+    fn new_machine() -> (CPU, Sbi) {
+        let mut cpu = CPU::with_harts(vec![
+            (CLINT_ADDRESS, Box::new(CLINT::new(2))),
+            (UART_ADDRESS, Box::new(UART::new())),
+            (ADDRESS, Box::new(DRAM::new(0x1000))),
+        ], 2).unwrap();
+        for (i, word) in PROGRAM.iter().enumerate() {
+            cpu.bus.lock().write_int(ADDRESS + i * 4, *word as u64, 4, Endianness::LittleEndian).unwrap();
+        }
+        let sbi = Sbi::new(&mut cpu).unwrap();
+        (cpu, sbi)
+    }
+
+    fn run_call(cpu: &mut CPU, sbi: &mut Sbi, extension: u64, function: u64, args: &[u64]) -> StopReason {
+        let registers = [XRegister::x10, XRegister::x11, XRegister::x12, XRegister::x13, XRegister::x14, XRegister::x15];
+        for (register, value) in registers.iter().zip(args) {
+            cpu.harts[0].x_registers[*register] = *value;
+        }
+        cpu.harts[0].x_registers[XRegister::x17] = extension;
+        cpu.harts[0].x_registers[XRegister::x16] = function;
+        cpu.harts[0].pc = ADDRESS;
+        sbi.run(cpu, 100).unwrap()
+    }
+
+    /// Makes a call on hart 0, and returns the error and value.
+    fn call(cpu: &mut CPU, sbi: &mut Sbi, extension: u64, function: u64, args: &[u64]) -> (i64, u64) {
+        match run_call(cpu, sbi, extension, function, args) {
+            StopReason::Trap { exception: Exception::Breakpoint, pc, .. } if pc == ADDRESS + 4 => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        let core = &cpu.harts[0];
+        (core.x_registers[XRegister::x10] as i64, core.x_registers[XRegister::x11])
+    }
+
+    fn read_clint(cpu: &CPU, offset: usize, size: usize) -> u64 {
+        cpu.bus.lock().read_int(CLINT_ADDRESS + offset, size, Endianness::LittleEndian, false).unwrap()
+    }
+
+    #[test]
+    fn test_base() {
+        let (mut cpu, mut sbi) = new_machine();
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_BASE, 0, &[]), (0, 0x0200_0000));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_BASE, 3, &[EXTENSION_HSM]), (0, 1));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_BASE, 3, &[0x1]), (0, 1));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_BASE, 3, &[0x4442_434E]), (0, 0));
+        assert_eq!(call(&mut cpu, &mut sbi, 0x4442_434E, 0, &[]).0, -2);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_BASE, 7, &[]).0, -2);
+    }
+
+    #[test]
+    fn test_supervisor_mode() {
+        let (mut cpu, mut sbi) = new_machine();
+        assert_eq!(cpu.harts[0].privilege, Privilege::Supervisor);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_BASE, 0, &[]), (0, 0x0200_0000));
+        assert_eq!(cpu.harts[0].privilege, Privilege::Supervisor);
+
+        // Only S-mode calls are serviced.
+        cpu.harts[0].privilege = Privilege::Machine;
+        match run_call(&mut cpu, &mut sbi, EXTENSION_BASE, 0, &[]) {
+            StopReason::Trap { exception: Exception::EnvironmentCallFromMMode, pc, .. } if pc == ADDRESS => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_boot() {
+        let mut cpu = CPU::with_harts(vec![
+            (0x1000, Box::new(BootROM::new(ADDRESS as u64))),
+            (CLINT_ADDRESS, Box::new(CLINT::new(2))),
+            (UART_ADDRESS, Box::new(UART::new())),
+            (ADDRESS, Box::new(DRAM::new(0x1000))),
+        ], 2).unwrap();
+        Sbi::new(&mut cpu).unwrap();
+
+        // Hart 0 ran the boot ROM, and is about to enter the payload in S-mode.
+        let core = &cpu.harts[0];
+        assert_eq!((core.pc, core.privilege, core.x_registers[XRegister::x10]),
+                   (ADDRESS, Privilege::Supervisor, 0));
+        assert!(!core.stopped);
+        assert!(cpu.harts[1].stopped);
+        assert_eq!(cpu.harts[1].privilege, Privilege::Machine);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let (mut cpu, mut sbi) = new_machine();
+        // Sets the timer, then spins until the interrupt enters the handler at 0x80,
+        // where `mret` stops the run as an illegal instruction, which isn't delegated.
+        let mut bus = cpu.bus.lock();
+        bus.write_int(ADDRESS + 0x40, 0x0000_0073, 4, Endianness::LittleEndian).unwrap();  // ecall
+        bus.write_int(ADDRESS + 0x44, 0x0000_006F, 4, Endianness::LittleEndian).unwrap();  // jal x0, 0
+        bus.write_int(ADDRESS + 0x80, 0x3020_0073, 4, Endianness::LittleEndian).unwrap();  // mret
+        drop(bus);
+        let core = &mut cpu.harts[0];
+        core.csr_registers[STVEC] = ADDRESS as u64 + 0x80;
+        core.csr_registers[MIE] = MIP_STIP;
+        core.csr_registers[MSTATUS] |= MSTATUS_SIE;
+        core.x_registers[XRegister::x17] = EXTENSION_TIME;
+        core.x_registers[XRegister::x16] = 0;
+        core.x_registers[XRegister::x10] = 0;
+        core.pc = ADDRESS + 0x40;
+
+        match sbi.run(&mut cpu, 1_000_000).unwrap() {
+            StopReason::Trap { exception: Exception::IllegalInstruction, pc, .. } if pc == ADDRESS + 0x80 => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        let core = &cpu.harts[0];
+        assert_eq!(core.privilege, Privilege::Supervisor);
+        assert_eq!(core.csr_registers[SCAUSE], Interrupt::SupervisorTimer.get_cause());
+        assert_eq!(core.csr_registers[SEPC], ADDRESS as u64 + 0x44);
+
+        // Setting the timer again clears the interrupt.
+        cpu.harts[0].csr_registers[STVEC] = 0;
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_TIME, 0, &[u64::MAX]), (0, 0));
+        cpu.harts[0].synchronize_interrupts();
+        assert_eq!(cpu.harts[0].csr_registers[MIP] & MIP_STIP, 0);
+    }
+
+    #[test]
+    fn test_timer_and_ipi() {
+        let (mut cpu, mut sbi) = new_machine();
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_TIME, 0, &[12345]), (0, 0));
+        assert_eq!(read_clint(&cpu, 0x4000, 8), 12345);
+
+        // IPIs set the supervisor software interrupt, and leave the CLINT alone.
+        let ipi = |cpu: &CPU, hart: usize| cpu.harts[hart].csr_registers[MIP] & MIP_SSIP != 0;
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_IPI, 0, &[0b1, 1]).0, 0);
+        assert_eq!((ipi(&cpu, 0), ipi(&cpu, 1)), (false, true));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_IPI, 0, &[0b100, 0]).0, -3);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_IPI, 0, &[0b10, u64::MAX - 1]).0, -3);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_IPI, 0, &[0, u64::MAX]).0, 0);
+        assert!(ipi(&cpu, 0));
+        assert_eq!(read_clint(&cpu, 0, 4), 0);
+
+        // Legacy calls, with the hart mask in memory.
+        call(&mut cpu, &mut sbi, 0x03, 0, &[]);
+        assert!(!ipi(&cpu, 0));
+        cpu.bus.lock().write_int(MASK, 0b01, 8, Endianness::LittleEndian).unwrap();
+        assert_eq!(call(&mut cpu, &mut sbi, 0x04, 0, &[MASK as u64]).0, 0);
+        assert!(ipi(&cpu, 0));
+        assert_eq!(call(&mut cpu, &mut sbi, 0x04, 0, &[0x10]).0, -5);
+        assert_eq!(call(&mut cpu, &mut sbi, 0x00, 0, &[777]).0, 0);
+        assert_eq!(read_clint(&cpu, 0x4000, 8), 777);
+
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_RFENCE, 0, &[0b11, 0]).0, 0);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_RFENCE, 1, &[0b11, 0, 0, u64::MAX]).0, 0);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_RFENCE, 3, &[0b11, 0]).0, -2);
+    }
+
+    #[test]
+    fn test_hart_state() {
+        let (mut cpu, mut sbi) = new_machine();
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 2, &[1]), (0, 1));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 2, &[0]), (0, 0));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 2, &[2]).0, -3);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 0, &[1, ADDRESS as u64 + 0x200, 0x55]).0, 0);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 0, &[1, ADDRESS as u64, 0]).0, -6);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 2, &[1]), (0, 0));
+        let core = &cpu.harts[1];
+        assert_eq!((core.pc, core.x_registers[XRegister::x10], core.x_registers[XRegister::x11]),
+                   (ADDRESS + 0x200, 1, 0x55));
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 3, &[0, 0, 0]).0, 0);
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_HSM, 3, &[0x8000_0000, 0, 0]).0, -2);
+
+        // A stopped hart doesn't run, and when every hart is stopped, they all wait.
+        cpu.harts[1].stopped = true;
+        assert_eq!(run_call(&mut cpu, &mut sbi, EXTENSION_HSM, 1, &[]), StopReason::WaitForInterrupt);
+        assert_eq!(cpu.harts[0].pc, ADDRESS + 4);
+    }
+
+    #[test]
+    fn test_reset() {
+        let (mut cpu, mut sbi) = new_machine();
+        assert_eq!(run_call(&mut cpu, &mut sbi, EXTENSION_SRST, 0, &[0, 0]), StopReason::Exit { code: 0 });
+        assert_eq!(run_call(&mut cpu, &mut sbi, EXTENSION_SRST, 0, &[0, 1]), StopReason::Exit { code: 1 });
+        assert_eq!(run_call(&mut cpu, &mut sbi, 0x08, 0, &[]), StopReason::Exit { code: 0 });
+        assert_eq!(call(&mut cpu, &mut sbi, EXTENSION_SRST, 0, &[3, 0]).0, -3);
+
+        // Rebooting resets the harts, and stops the secondary ones again.
+        call(&mut cpu, &mut sbi, EXTENSION_HSM, 0, &[1, ADDRESS as u64, 0]);
+        cpu.harts[0].reset_vector = ADDRESS + 4;
+        call(&mut cpu, &mut sbi, EXTENSION_SRST, 0, &[1, 0]);
+        assert_eq!(cpu.harts[0].x_registers[XRegister::x17], 0);
+        assert!(cpu.harts[1].stopped);
+    }
+
+    #[test]
+    fn test_console() {
+        let (mut cpu, mut sbi) = new_machine();
+        assert_eq!(call(&mut cpu, &mut sbi, 0x02, 0, &[]).0, -1);
+        cpu.receive_uart_input(b"y");
+        assert_eq!(call(&mut cpu, &mut sbi, 0x02, 0, &[]).0, b'y' as i64);
+        assert_eq!(call(&mut cpu, &mut sbi, 0x01, 0, &[b'\n' as u64]).0, 0);
+    }
+
+    #[test]
+    fn test_missing_device() {
+        let mut cpu = CPU::new(vec![
+            (CLINT_ADDRESS, Box::new(CLINT::new(1))),
+            (ADDRESS, Box::new(DRAM::new(0x1000))),
+        ]).unwrap();
+        match Sbi::new(&mut cpu) {
+            Err(SbiError::MissingDevice { name }) if name == "UART" => {},
+            x => { panic!("PANIC {:?}", x.map(|_| ())) }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(test)]
+mod test_semihosting {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::csr::MTVEC;
+    use crate::cpu::register::XRegister;
+    use crate::cpu::trap::{Exception, StopReason};
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::linux::FileDescriptor;
+    use crate::semihosting::{Semihosting, SemihostingError};
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    const ADDRESS: usize = 0x8000_0000;
+    const HANDLER: usize = ADDRESS + 0x10;
+    const BLOCK: usize = ADDRESS + 0x1000;
+    const NAME: usize = ADDRESS + 0x1100;
+    const BUFFER: usize = ADDRESS + 0x1200;
+
+    // A semihosting call followed by a plain ebreak, which stops the tests, and a
+    // trap handler that loops.
+    const PROGRAM: [u32; 5] = [
+        0x01F0_1013,  // slli x0, x0, 0x1f
+        0x0010_0073,  // ebreak
+        0x4070_5013,  // srai x0, x0, 7
+        0x0010_0073,  // ebreak
+        0x0000_006F,  // jal  x0, 0
+    ];
+
+    const SYS_OPEN: u64 = 0x01;
+    const SYS_CLOSE: u64 = 0x02;
+    const SYS_WRITEC: u64 = 0x03;
+    const SYS_WRITE0: u64 = 0x04;
+    const SYS_WRITE: u64 = 0x05;
+    const SYS_READ: u64 = 0x06;
+    const SYS_ISTTY: u64 = 0x09;
+    const SYS_SEEK: u64 = 0x0A;
+    const SYS_FLEN: u64 = 0x0C;
+    const SYS_REMOVE: u64 = 0x0E;
+    const SYS_CLOCK: u64 = 0x10;
+    const SYS_SYSTEM: u64 = 0x12;
+    const SYS_ERRNO: u64 = 0x13;
+    const SYS_GET_CMDLINE: u64 = 0x15;
+    const SYS_EXIT: u64 = 0x18;
+
+    /// Output that stays readable after it's given to the semihosting host.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_host(name: &str) -> (CPU, Semihosting, SharedOutput, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("yarve-semihosting-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let cpu = CPU::new(vec![(ADDRESS, Box::new(DRAM::new(0x2000)))]).unwrap();
+        for (i, word) in PROGRAM.iter().enumerate() {
+            cpu.bus.lock().write_int(ADDRESS + i * 4, *word as u64, 4, Endianness::LittleEndian).unwrap();
+        }
+        let mut semihosting = Semihosting::new(&directory).unwrap();
+        let output = SharedOutput::default();
+        semihosting.set_console(1, FileDescriptor::Output(Box::new(output.clone())));
+        (cpu, semihosting, output, directory)
+    }
+
+    /// Makes a call with a parameter block of `words`, and returns `a0`.
+    fn call(cpu: &mut CPU, semihosting: &mut Semihosting, operation: u64, words: &[u64]) -> i64 {
+        for (i, word) in words.iter().enumerate() {
+            cpu.bus.lock().write_int(BLOCK + i * 8, *word, 8, Endianness::LittleEndian).unwrap();
+        }
+        cpu.harts[0].x_registers[XRegister::x10] = operation;
+        cpu.harts[0].x_registers[XRegister::x11] = BLOCK as u64;
+        cpu.harts[0].pc = ADDRESS;
+        match semihosting.run(cpu, 100).unwrap() {
+            StopReason::Trap { exception: Exception::Breakpoint, pc, .. } if pc == ADDRESS + 0xC => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+        cpu.harts[0].x_registers[XRegister::x10] as i64
+    }
+
+    fn write_name(cpu: &mut CPU, name: &str) -> u64 {
+        cpu.bus.lock().write_bytes(NAME, name.as_bytes()).unwrap();
+        name.len() as u64
+    }
+
+    #[test]
+    fn test_console() {
+        let (mut cpu, mut semihosting, output, directory) = new_host("console");
+        cpu.bus.lock().write_bytes(BUFFER, b"hello\n\0").unwrap();
+        cpu.harts[0].x_registers[XRegister::x11] = BUFFER as u64;
+        cpu.harts[0].x_registers[XRegister::x10] = SYS_WRITE0;
+        cpu.harts[0].pc = ADDRESS;
+        semihosting.run(&mut cpu, 100).unwrap();
+        cpu.harts[0].x_registers[XRegister::x10] = SYS_WRITEC;
+        cpu.harts[0].pc = ADDRESS;
+        semihosting.run(&mut cpu, 100).unwrap();
+
+        let length = write_name(&mut cpu, ":tt");
+        let handle = call(&mut cpu, &mut semihosting, SYS_OPEN, &[NAME as u64, 4, length]);
+        assert!(handle > 0);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_ISTTY, &[handle as u64]), 1);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[handle as u64, BUFFER as u64, 3]), 0);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(*output.0.lock().unwrap(), b"hello\nhhel");
+    }
+
+    #[test]
+    fn test_files() {
+        let (mut cpu, mut semihosting, _, directory) = new_host("files");
+        cpu.bus.lock().write_bytes(BUFFER, b"test vector").unwrap();
+        let length = write_name(&mut cpu, "vector.bin");
+        // "wb", then "rb".
+        let handle = call(&mut cpu, &mut semihosting, SYS_OPEN, &[NAME as u64, 5, length]);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_ISTTY, &[handle as u64]), 0);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_WRITE, &[handle as u64, BUFFER as u64, 11]), 0);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_CLOSE, &[handle as u64]), 0);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_CLOSE, &[handle as u64]), -1);
+        let contents = std::fs::read(directory.join("vector.bin"));
+
+        let handle = call(&mut cpu, &mut semihosting, SYS_OPEN, &[NAME as u64, 1, length]);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_FLEN, &[handle as u64]), 11);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_SEEK, &[handle as u64, 5]), 0);
+        // Returns the bytes not read.
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[handle as u64, (BUFFER + 0x100) as u64, 16]), 10);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_READ, &[handle as u64, BUFFER as u64, 16]), 16);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_REMOVE, &[NAME as u64, length]), 0);
+        let removed = !directory.join("vector.bin").exists();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(contents.unwrap(), b"test vector");
+        assert_eq!(&cpu.bus.lock().read_bytes(BUFFER + 0x100, 6).unwrap()[..], b"vector");
+        assert!(removed);
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let (mut cpu, mut semihosting, _, directory) = new_host("sandbox");
+        for name in ["../outside", "/etc/passwd", "inner/../../outside"] {
+            let length = write_name(&mut cpu, name);
+            assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[NAME as u64, 4, length]), -1);
+            assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 13);
+        }
+        let length = write_name(&mut cpu, "missing");
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_OPEN, &[NAME as u64, 0, length]), -1);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 2);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_SYSTEM, &[NAME as u64, length]), -1);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(!directory.parent().unwrap().join("outside").exists());
+
+        match Semihosting::new(directory.join("missing")) {
+            Err(SemihostingError::IoError(_)) => {},
+            x => { panic!("PANIC {:?}", x.map(|_| ())) }
+        }
+    }
+
+    #[test]
+    fn test_command_line_and_clock() {
+        let (mut cpu, mut semihosting, _, directory) = new_host("cmdline");
+        std::fs::remove_dir_all(&directory).unwrap();
+        semihosting.set_command_line("test.elf --seed 1");
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_GET_CMDLINE, &[BUFFER as u64, 64]), 0);
+        assert_eq!(&cpu.bus.lock().read_bytes(BUFFER, 18).unwrap()[..], b"test.elf --seed 1\0");
+        assert_eq!(cpu.bus.lock().read_int(BLOCK + 8, 8, Endianness::LittleEndian, false).unwrap(), 17);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_GET_CMDLINE, &[BUFFER as u64, 4]), -1);
+        assert!(call(&mut cpu, &mut semihosting, SYS_CLOCK, &[]) >= 0);
+        assert_eq!(call(&mut cpu, &mut semihosting, 0x99, &[]), -1);
+        assert_eq!(call(&mut cpu, &mut semihosting, SYS_ERRNO, &[]), 38);
+    }
+
+    #[test]
+    fn test_exit() {
+        let (mut cpu, mut semihosting, _, directory) = new_host("exit");
+        std::fs::remove_dir_all(&directory).unwrap();
+        for (reason, code) in [(0x20026, 3), (0x20023, 1)] {
+            cpu.bus.lock().write_int(BLOCK, reason, 8, Endianness::LittleEndian).unwrap();
+            cpu.bus.lock().write_int(BLOCK + 8, 3, 8, Endianness::LittleEndian).unwrap();
+            cpu.harts[0].x_registers[XRegister::x10] = SYS_EXIT;
+            cpu.harts[0].x_registers[XRegister::x11] = BLOCK as u64;
+            cpu.harts[0].pc = ADDRESS;
+            assert_eq!(semihosting.run(&mut cpu, 100).unwrap(), StopReason::Exit { code });
+        }
+    }
+
+    /// Calls bypass the trap handler, but other breakpoints still enter it.
+    #[test]
+    fn test_trap_handler() {
+        let (mut cpu, mut semihosting, output, directory) = new_host("handler");
+        std::fs::remove_dir_all(&directory).unwrap();
+        cpu.harts[0].csr_registers[MTVEC] = HANDLER as u64;
+        cpu.bus.lock().write_bytes(BUFFER, b"ok\0").unwrap();
+        cpu.harts[0].x_registers[XRegister::x10] = SYS_WRITE0;
+        cpu.harts[0].x_registers[XRegister::x11] = BUFFER as u64;
+        cpu.harts[0].pc = ADDRESS;
+        assert_eq!(semihosting.run(&mut cpu, 100).unwrap(), StopReason::BudgetExhausted);
+        assert_eq!(cpu.harts[0].pc, HANDLER);
+        assert_eq!(*output.0.lock().unwrap(), b"ok");
+    }
+}
@@ -0,0 +1,202 @@
+#[cfg(test)]
+mod test_smp {
+    use crate::cpu::cpu::{CPU, CPUError};
+    use crate::cpu::trap::StopReason;
+    use crate::bus::SharedBus;
+    use crate::dram::DRAM;
+    use crate::device::Device;
+    use crate::endianness::Endianness;
+    use crate::test::test_fdt::test_fdt::{parse, get_property};
+    use std::thread;
+
+    // Every hart adds 1 to a word with amoadd.w and to a doubleword with lr.d/sc.d,
+    // 100 times, then stores its hart id + 1 and waits.
+    const PROGRAM: [u32; 19] = [
+        0xF140_2573,  // csrrs    x10, mhartid, x0
+        0x0000_12B7,  // lui      x5, 0x1
+        0x0082_8413,  // addi     x8, x5, 8
+        0x0640_0313,  // addi     x6, x0, 100
+        0x0010_0393,  // addi     x7, x0, 1
+        0x0072_A02F,  // amoadd.w x0, x7, (x5)
+        0x1004_36AF,  // lr.d     x13, (x8)
+        0x0016_8693,  // addi     x13, x13, 1
+        0x18D4_372F,  // sc.d     x14, x13, (x8)
+        0xFE07_1AE3,  // bne      x14, x0, -12
+        0xFFF3_0313,  // addi     x6, x6, -1
+        0xFE03_14E3,  // bne      x6, x0, -24
+        0x0035_1593,  // slli     x11, x10, 3
+        0x0055_85B3,  // add      x11, x11, x5
+        0x0015_0613,  // addi     x12, x10, 1
+        0x00C5_B823,  // sd       x12, 16(x11)
+        0x1050_0073,  // wfi
+        0xFFDF_F06F,  // jal      x0, -4
+        0x0000_0000,
+    ];
+
+    fn new_test_cpu(hart_count: usize, quantum: u64) -> CPU {
+        let mut dram = DRAM::new(0x2000);
+        for (i, instruction) in PROGRAM.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        let mut cpu = CPU::with_harts(vec![(0, Box::new(dram))], hart_count).unwrap();
+        cpu.set_quantum(quantum);
+        cpu
+    }
+
+    fn read(cpu: &CPU, address: usize, size: usize) -> u64 {
+        cpu.bus.lock().read_int(address, size, Endianness::LittleEndian, false).unwrap()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        for quantum in [1, 3, 7, 1000] {
+            let mut cpu = new_test_cpu(4, quantum);
+
+            let (_, reason) = cpu.run(100_000).unwrap();
+            assert_eq!(reason, StopReason::WaitForInterrupt);
+            assert_eq!(read(&cpu, 0x1000, 4), 400);
+            assert_eq!(read(&cpu, 0x1008, 8), 400);
+            for hart in 0..4 {
+                assert_eq!(cpu.harts[hart].get_hart_id(), hart as u64);
+                assert_eq!(read(&cpu, 0x1010 + hart * 8, 8), hart as u64 + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let run = || {
+            let mut cpu = new_test_cpu(3, 5);
+            let (hart, reason) = cpu.run(1000).unwrap();
+            assert_eq!(reason, StopReason::BudgetExhausted);
+            (hart, cpu.harts.iter().map(|core| core.get_instructions_retired()).collect::<Vec<_>>(),
+             read(&cpu, 0x1000, 4), read(&cpu, 0x1008, 8))
+        };
+
+        let first = run();
+        assert_eq!(first.1.iter().sum::<u64>(), 1000);
+        assert_eq!(run(), first);
+    }
+
+    #[test]
+    fn test_budget_split() {
+        let mut cpu = new_test_cpu(2, 10);
+
+        assert_eq!(cpu.run(15).unwrap(), (1, StopReason::BudgetExhausted));
+        assert_eq!(cpu.harts[0].get_instructions_retired(), 10);
+        assert_eq!(cpu.harts[1].get_instructions_retired(), 5);
+
+        // The next call finishes the quantum of hart 1 first.
+        assert_eq!(cpu.run(10).unwrap(), (0, StopReason::BudgetExhausted));
+        assert_eq!(cpu.harts[0].get_instructions_retired(), 15);
+        assert_eq!(cpu.harts[1].get_instructions_retired(), 10);
+
+        // Splitting a run doesn't change the interleaving.
+        let mut whole = new_test_cpu(3, 7);
+        whole.run(500).unwrap();
+        let mut split = new_test_cpu(3, 7);
+        for budget in [1, 6, 13, 80, 400] {
+            split.run(budget).unwrap();
+        }
+        for (whole, split) in whole.harts.iter().zip(&split.harts) {
+            assert_eq!(whole.get_instructions_retired(), split.get_instructions_retired());
+            assert_eq!(whole.pc, split.pc);
+        }
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = new_test_cpu(2, 10);
+        cpu.harts[1].add_breakpoint(0x14);
+
+        assert_eq!(cpu.run(100).unwrap(), (1, StopReason::Breakpoint { address: 0x14 }));
+        assert_eq!(cpu.harts[0].pc, 0x28);
+        // Resumes with the hart that hit the breakpoint.
+        assert_eq!(cpu.run(1).unwrap(), (1, StopReason::BudgetExhausted));
+        assert_eq!(cpu.harts[1].pc, 0x18);
+
+        // A breakpoint where a quantum starts isn't skipped.
+        let mut cpu = new_test_cpu(2, 5);
+        cpu.harts[0].add_breakpoint(0x14);
+        assert_eq!(cpu.run(100).unwrap(), (0, StopReason::Breakpoint { address: 0x14 }));
+        assert_eq!(cpu.harts[1].get_instructions_retired(), 5);
+    }
+
+    #[test]
+    fn test_fdt() {
+        let cpu = new_test_cpu(2, 10);
+        let properties = parse(&cpu.generate_fdt().unwrap());
+
+        assert_eq!(get_property(&properties, "/cpus/cpu@0", "reg").unwrap(), &[0, 0, 0, 0]);
+        assert_eq!(get_property(&properties, "/cpus/cpu@1", "reg").unwrap(), &[0, 0, 0, 1]);
+        assert!(get_property(&properties, "/cpus/cpu@1/interrupt-controller", "phandle").is_some());
+    }
+
+    #[test]
+    fn test_threaded() {
+        let mut cpu = new_test_cpu(4, 50);
+
+        let reasons = cpu.run_threaded(1_000_000).unwrap();
+        assert_eq!(reasons, vec![StopReason::WaitForInterrupt; 4]);
+        assert_eq!(read(&cpu, 0x1000, 4), 400);
+        assert_eq!(read(&cpu, 0x1008, 8), 400);
+        for hart in 0..4 {
+            assert_eq!(read(&cpu, 0x1010 + hart * 8, 8), hart as u64 + 1);
+        }
+    }
+
+    #[test]
+    fn test_threaded_budget() {
+        let mut cpu = new_test_cpu(2, 10);
+
+        let reasons = cpu.run_threaded(25).unwrap();
+        assert_eq!(reasons, vec![StopReason::BudgetExhausted; 2]);
+        for core in &cpu.harts {
+            assert_eq!(core.get_instructions_retired(), 25);
+        }
+    }
+
+    #[test]
+    fn test_threaded_stopped_hart() {
+        let mut cpu = new_test_cpu(2, 10);
+        // The first hart loops while the second one never runs.
+        cpu.bus.lock().write_int(0, 0x0000_006F, 4, Endianness::LittleEndian).unwrap();
+        cpu.harts[1].stopped = true;
+
+        let reasons = cpu.run_threaded(1000).unwrap();
+        assert_eq!(reasons, vec![StopReason::BudgetExhausted, StopReason::WaitForInterrupt]);
+        assert_eq!(cpu.harts[0].get_instructions_retired(), 1000);
+        assert_eq!(cpu.harts[1].get_instructions_retired(), 0);
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<CPU>();
+        assert_send::<SharedBus>();
+        assert_sync::<SharedBus>();
+
+        // Run on a worker thread while this one inspects memory.
+        let mut cpu = new_test_cpu(2, 10);
+        let bus = cpu.bus.clone();
+        let worker = thread::spawn(move || {
+            let reason = cpu.run(100_000).unwrap();
+            (cpu, reason)
+        });
+        let counter = bus.lock().read_int(0x1000, 4, Endianness::LittleEndian, false).unwrap();
+        assert!(counter <= 200);
+
+        let (cpu, (_, reason)) = worker.join().unwrap();
+        assert_eq!(reason, StopReason::WaitForInterrupt);
+        assert_eq!(read(&cpu, 0x1000, 4), 200);
+    }
+
+    #[test]
+    fn test_no_harts() {
+        match CPU::with_harts(vec![(0, Box::new(DRAM::new(16)))], 0) {
+            Err(CPUError::NoHarts) => {},
+            x => { panic!("PANIC {:?}", x.map(|_| ())) }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(test)]
+mod test_snapshot {
+    use crate::cpu::core::Privilege;
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::register::XRegister;
+    use crate::boot_rom::BootROM;
+    use crate::device::{Device, DeviceError};
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::finisher::TestFinisher;
+    use crate::snapshot::{SnapshotError, SNAPSHOT_VERSION};
+    use crate::uart::UART;
+    use std::any::Any;
+    use std::borrow::Cow;
+
+    const PROGRAM: [u32; 19] = [
+        0x0640_0113,  // addi x2, x0, 100
+        0x0000_1537,  // lui  x10, 0x1
+        0x0070_0293,  // addi x5, x0, 7
+        0x0280_00EF,  // jal  x1, 40
+        0x0065_3023,  // sd   x6, 0(x10)
+        0x0045_2383,  // lw   x7, 4(x10)
+        0x4053_D433,  // sra  x8, x7, x5
+        0x4033_5493,  // srai x9, x6, 3
+        0x0085_0513,  // addi x10, x10, 8
+        0xFFF1_0113,  // addi x2, x2, -1
+        0xFE01_12E3,  // bne  x2, x0, -28
+        0x0084_B5B3,  // sltu x11, x9, x8
+        0x0000_006F,  // jal  x0, 0
+        0x0053_4333,  // xor  x6, x6, x5
+        0x0053_1613,  // slli x12, x6, 5
+        0x00C3_0333,  // add  x6, x6, x12
+        0x0053_5633,  // srl  x12, x6, x5
+        0x40C3_0333,  // sub  x6, x6, x12
+        0x0000_8067,  // jalr x0, 0(x1)
+    ];
+
+    fn new_test_cpu(dram_address: usize) -> CPU {
+        let mut dram = DRAM::new(0x10_0000);
+        for (i, instruction) in PROGRAM.iter().enumerate() {
+            dram.write_bytes(i * 4, &instruction.to_le_bytes()).unwrap();
+        }
+        let mut cpu = CPU::with_harts(vec![
+            (dram_address, Box::new(dram)),
+            (0x1000_0000, Box::new(UART::new())),
+            (0x2000_0000, Box::new(BootROM::new(0))),
+            (0x3000_0000, Box::new(TestFinisher::new())),
+        ], 2).unwrap();
+        cpu.set_quantum(7);
+        for core in &mut cpu.harts {
+            core.pc = dram_address;
+        }
+        cpu
+    }
+
+    fn assert_same_state(a: &CPU, b: &CPU) {
+        for (a, b) in a.harts.iter().zip(&b.harts) {
+            assert_eq!(a.pc, b.pc);
+            assert_eq!((a.privilege, a.stopped, a.supervisor_timer), (b.privilege, b.stopped, b.supervisor_timer));
+            assert_eq!(a.get_instructions_retired(), b.get_instructions_retired());
+            for i in 0..32u32 {
+                let register = XRegister::from(i);
+                assert_eq!(a.x_registers[register], b.x_registers[register], "{:?}", register);
+            }
+            for csr in 0..4096 {
+                assert_eq!(a.csr_registers[csr], b.csr_registers[csr], "{:#x}", csr);
+            }
+        }
+        assert_eq!(*a.bus.lock().read_bytes(0, 0x2000).unwrap(),
+                   *b.bus.lock().read_bytes(0, 0x2000).unwrap());
+    }
+
+    #[test]
+    fn test_continuation() {
+        let mut cpu = new_test_cpu(0);
+        cpu.run(500).unwrap();
+        let mut snapshot = Vec::new();
+        cpu.save_snapshot(&mut snapshot).unwrap();
+        cpu.run(1000).unwrap();
+
+        let mut restored = new_test_cpu(0);
+        restored.restore_snapshot(&mut snapshot.as_slice()).unwrap();
+        restored.run(1000).unwrap();
+        assert_same_state(&cpu, &restored);
+
+        // Rewinding the original machine, with stale caches.
+        cpu.restore_snapshot(&mut snapshot.as_slice()).unwrap();
+        cpu.run(1000).unwrap();
+        assert_same_state(&cpu, &restored);
+    }
+
+    #[test]
+    fn test_privilege_and_stopped_harts() {
+        let mut cpu = new_test_cpu(0);
+        cpu.harts[0].privilege = Privilege::Supervisor;
+        cpu.harts[1].stopped = true;
+        cpu.harts[0].supervisor_timer = true;
+        cpu.run(500).unwrap();
+        let mut snapshot = Vec::new();
+        cpu.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = new_test_cpu(0);
+        restored.restore_snapshot(&mut snapshot.as_slice()).unwrap();
+        assert_eq!(restored.harts[0].privilege, Privilege::Supervisor);
+        assert!(restored.harts[1].stopped);
+        assert!(restored.harts[0].supervisor_timer);
+        cpu.run(1000).unwrap();
+        restored.run(1000).unwrap();
+        assert_same_state(&cpu, &restored);
+        assert_eq!(restored.harts[1].pc, 0);
+    }
+
+    #[test]
+    fn test_sparse_memory() {
+        let cpu = new_test_cpu(0);
+        cpu.bus.lock().write_int(0x8_0000, 0x1234, 8, Endianness::LittleEndian).unwrap();
+        let mut snapshot = Vec::new();
+        cpu.save_snapshot(&mut snapshot).unwrap();
+        // Only the resident pages are saved, not the whole megabyte.
+        assert!(snapshot.len() < 0x4_0000);
+
+        let mut restored = new_test_cpu(0);
+        restored.bus.lock().write_int(0x4_0000, 0x5678, 8, Endianness::LittleEndian).unwrap();
+        restored.restore_snapshot(&mut snapshot.as_slice()).unwrap();
+
+        let bus = restored.bus.lock();
+        assert_eq!(bus.read_int(0x8_0000, 8, Endianness::LittleEndian, false).unwrap(), 0x1234);
+        assert_eq!(bus.read_int(0x4_0000, 8, Endianness::LittleEndian, false).unwrap(), 0);
+        let (_, dram) = bus.get_device(0).unwrap();
+        assert_eq!(dram.as_any().downcast_ref::<DRAM>().unwrap().get_resident_pages(), 2);
+    }
+
+    #[derive(Debug)]
+    struct Scratch;
+
+    impl Device for Scratch {
+        fn get_address_space_size(&self) -> usize { 8 }
+        fn read_bytes(&self, _address: usize, _size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+            Err(DeviceError::InvalidAddressReadFault)
+        }
+        fn write_bytes(&mut self, _address: usize, _binary: &[u8]) -> Result<(), DeviceError> {
+            Err(DeviceError::InvalidAddressWriteFault)
+        }
+        fn read_int(&self, _address: usize, _size: usize, _endianness: Endianness,
+                    _sign_extend: bool) -> Result<u64, DeviceError> {
+            Err(DeviceError::InvalidAddressReadFault)
+        }
+        fn write_int(&mut self, _address: usize, _value: u64, _size: usize,
+                     _endianness: Endianness) -> Result<(), DeviceError> {
+            Err(DeviceError::InvalidAddressWriteFault)
+        }
+        fn get_name(&self) -> &str { "scratch" }
+        fn as_any(&self) -> &dyn Any { self }
+        fn as_any_mut(&mut self) -> &mut dyn Any { self }
+    }
+
+    #[test]
+    fn test_unsupported_device() {
+        let cpu = CPU::new(vec![(0, Box::new(Scratch))]).unwrap();
+
+        match cpu.save_snapshot(&mut Vec::new()) {
+            Err(SnapshotError::UnsupportedDevice { name }) => assert_eq!(name, "scratch"),
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        let cpu = new_test_cpu(0);
+        let mut snapshot = Vec::new();
+        cpu.save_snapshot(&mut snapshot).unwrap();
+
+        let mut invalid = snapshot.clone();
+        invalid[0] = b'X';
+        match new_test_cpu(0).restore_snapshot(&mut invalid.as_slice()) {
+            Err(SnapshotError::InvalidMagic) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        let mut invalid = snapshot.clone();
+        invalid[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        match new_test_cpu(0).restore_snapshot(&mut invalid.as_slice()) {
+            Err(SnapshotError::UnsupportedVersion { version }) =>
+                assert_eq!(version, SNAPSHOT_VERSION + 1),
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match new_test_cpu(0).restore_snapshot(&mut &snapshot[..snapshot.len() - 1]) {
+            Err(SnapshotError::IoError(_)) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        match new_test_cpu(0x4000_0000).restore_snapshot(&mut snapshot.as_slice()) {
+            Err(SnapshotError::DeviceMismatch { expected, found }) => {
+                assert_eq!(expected.address_range.start, 0x1000_0000);
+                assert_eq!(found.address_range.start, 0);
+            },
+            x => { panic!("PANIC {:?}", x) }
+        }
+
+        let mut single = CPU::new(vec![(0, Box::new(DRAM::new(0x1000)))]).unwrap();
+        match single.restore_snapshot(&mut snapshot.as_slice()) {
+            Err(SnapshotError::HartCountMismatch { expected: 1, found: 2 }) => {},
+            x => { panic!("PANIC {:?}", x) }
+        }
+    }
+}
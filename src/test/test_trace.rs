@@ -0,0 +1,91 @@
+#![cfg(test)]
+
+use crate::cpu::cpu::CPU;
+use crate::cpu::register::XRegister;
+use crate::cpu::trace::CommitLogOptions;
+use crate::device::Device;
+use crate::dram::DRAM;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+const PROGRAM: [u32; 8] = [
+    0x0050_0513,  // addi x10, x0, 5
+    0x10A0_3023,  // sd   x10, 0x100(x0)
+    0x1000_3583,  // ld   x11, 0x100(x0)
+    0x0400_0613,  // addi x12, x0, 0x40
+    0x3056_1073,  // csrrw x0, mtvec, x12
+    0x1000_0713,  // addi x14, x0, 0x100
+    0x00A7_36AF,  // amoadd.d x13, x10, (x14)
+    0x0000_0000,  // illegal
+];
+const HANDLER: u32 = 0x0010_0793;  // addi x15, x0, 1
+
+/// Log that stays readable after it's given to the CPU.
+#[derive(Clone, Default)]
+struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the program for `count` instructions while logging with `options`.
+fn run_logged(options: CommitLogOptions, count: u64) -> (CPU, String) {
+    let mut cpu = CPU::new(vec![(0, Box::new(DRAM::new(0x1000)))]).unwrap();
+    let code: Vec<u8> = PROGRAM.iter().flat_map(|x| x.to_le_bytes()).collect();
+    cpu.bus.lock().write_bytes(0, &code).unwrap();
+    cpu.bus.lock().write_bytes(0x40, &HANDLER.to_le_bytes()).unwrap();
+    let log = SharedLog::default();
+    cpu.start_commit_log(log.clone(), options);
+    cpu.run(count).unwrap();
+    cpu.stop_commit_log().unwrap();
+    let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    (cpu, text)
+}
+
+#[test]
+fn test_commit_log() {
+    let (cpu, log) = run_logged(CommitLogOptions::default(), 9);
+    assert_eq!(log, "\
+core   0: 3 0x0000000000000000 (0x00500513) x10 0x0000000000000005
+core   0: 3 0x0000000000000004 (0x10a03023) mem 0x0000000000000100 0x0000000000000005
+core   0: 3 0x0000000000000008 (0x10003583) x11 0x0000000000000005 mem 0x0000000000000100
+core   0: 3 0x000000000000000c (0x04000613) x12 0x0000000000000040
+core   0: 3 0x0000000000000010 (0x30561073) c773_mtvec 0x0000000000000040
+core   0: 3 0x0000000000000014 (0x10000713) x14 0x0000000000000100
+core   0: 3 0x0000000000000018 (0x00a736af) x13 0x0000000000000005 mem 0x0000000000000100 mem 0x0000000000000100 0x000000000000000a
+core   0: 3 0x0000000000000040 (0x00100793) x15 0x0000000000000001
+");
+    assert_eq!(cpu.harts[0].x_registers[XRegister::x15], 1);
+}
+
+#[test]
+fn test_disassembly() {
+    let options = CommitLogOptions { address_range: Some(0x18..0x100), disassembly: true, ..Default::default() };
+    let (_, log) = run_logged(options, 9);
+    assert_eq!(log, "\
+core   0: 0x0000000000000018 (0x00a736af) amoadd.d a3, a0, (a4)
+core   0: 3 0x0000000000000018 (0x00a736af) x13 0x0000000000000005 mem 0x0000000000000100 mem 0x0000000000000100 0x000000000000000a
+core   0: 0x000000000000001c (0x00000000) .word   0x00000000
+core   0: exception trap_illegal_instruction, epc 0x000000000000001c
+core   0:           tval 0x0000000000000000
+core   0: 0x0000000000000040 (0x00100793) addi    a5, zero, 1
+core   0: 3 0x0000000000000040 (0x00100793) x15 0x0000000000000001
+");
+}
+
+#[test]
+fn test_window() {
+    let options = CommitLogOptions { window: Some(1..3), ..Default::default() };
+    let (cpu, log) = run_logged(options, 9);
+    assert_eq!(log.lines().count(), 2);
+    assert!(log.starts_with("core   0: 3 0x0000000000000004 (0x10a03023)"), "{}", log);
+    assert!(log.lines().nth(1).unwrap().starts_with("core   0: 3 0x0000000000000008"), "{}", log);
+    // The harts stop logging, and run blocks again.
+    assert!(cpu.harts.iter().all(|core| core.commit_log.is_none()));
+}
@@ -0,0 +1,253 @@
+#[cfg(test)]
+mod test_virtio {
+    use crate::cpu::cpu::CPU;
+    use crate::cpu::csr::{MCAUSE, MIE, MIP_MEIP, MSTATUS, MSTATUS_MIE, MTVEC};
+    use crate::cpu::trap::Interrupt;
+    use crate::device::Device;
+    use crate::dram::DRAM;
+    use crate::endianness::Endianness;
+    use crate::plic::PLIC;
+    use crate::test::test_fdt::test_fdt::{parse, get_property};
+    use crate::virtio::VirtioMmio;
+    use crate::virtio_blk::VirtioBlock;
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+
+    const PLIC_ADDRESS: usize = 0xC00_0000;
+    const VIRTIO_ADDRESS: usize = 0x1000_1000;
+    const ADDRESS: usize = 0x8000_0000;
+    const HANDLER: usize = ADDRESS + 0x100;
+    const DESCRIPTORS: usize = ADDRESS + 0x1000;
+    const AVAILABLE: usize = ADDRESS + 0x1100;
+    const USED: usize = ADDRESS + 0x1200;
+    const HEADER: usize = ADDRESS + 0x2000;
+    const STATUS: usize = ADDRESS + 0x2100;
+    const DATA: usize = ADDRESS + 0x3000;
+
+    const QUEUE_SIZE: u64 = 8;
+    const SECTORS: usize = 8;
+
+    const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+    const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+    const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+    const DESC_F_NEXT: u64 = 1;
+    const DESC_F_WRITE: u64 = 2;
+
+    // Notifies queue 0 from the guest, then loops, as does the interrupt handler.
+    const PROGRAM: [u32; 3] = [
+        0x1000_12B7,  // lui x5, 0x10001
+        0x0402_A823,  // sw  x0, 0x50(x5)
+        0x0000_006F,  // jal x0, 0
+    ];
+
+    /// A machine with a disk whose sector `i` is filled with `i`.
+    fn new_machine(name: &str, read_only: bool) -> (CPU, PathBuf) {
+        let path = std::env::temp_dir().join(format!("yarve-virtio-{}-{}.img", name, std::process::id()));
+        let image: Vec<u8> = (0..SECTORS * 512).map(|i| (i / 512) as u8).collect();
+        std::fs::write(&path, image).unwrap();
+
+        let plic = PLIC::new(1);
+        let disk = VirtioBlock::open(&path, read_only).unwrap();
+        let virtio = VirtioMmio::new(Box::new(disk), plic.get_source(1).unwrap());
+        let cpu = CPU::new(vec![
+            (PLIC_ADDRESS, Box::new(plic)),
+            (VIRTIO_ADDRESS, Box::new(virtio)),
+            (ADDRESS, Box::new(DRAM::new(0x4000))),
+        ]).unwrap();
+        (cpu, path)
+    }
+
+    fn read(cpu: &CPU, address: usize, size: usize) -> u64 {
+        cpu.bus.lock().read_int(address, size, Endianness::LittleEndian, false).unwrap()
+    }
+
+    fn write(cpu: &CPU, address: usize, value: u64, size: usize) {
+        cpu.bus.lock().write_int(address, value, size, Endianness::LittleEndian).unwrap();
+    }
+
+    /// Initializes the device and queue 0 as a driver does, with the PLIC routing its
+    /// interrupt to the hart. Returns the final status.
+    fn initialize(cpu: &CPU, features: u64) -> u64 {
+        write(cpu, VIRTIO_ADDRESS + 0x70, 0x3, 4);
+        write(cpu, VIRTIO_ADDRESS + 0x24, 0, 4);
+        write(cpu, VIRTIO_ADDRESS + 0x20, features & 0xFFFF_FFFF, 4);
+        write(cpu, VIRTIO_ADDRESS + 0x24, 1, 4);
+        write(cpu, VIRTIO_ADDRESS + 0x20, features >> 32, 4);
+        write(cpu, VIRTIO_ADDRESS + 0x70, 0xB, 4);
+        if read(cpu, VIRTIO_ADDRESS + 0x70, 4) & 0x8 == 0 {
+            return read(cpu, VIRTIO_ADDRESS + 0x70, 4)
+        }
+
+        write(cpu, VIRTIO_ADDRESS + 0x30, 0, 4);
+        assert_eq!(read(cpu, VIRTIO_ADDRESS + 0x34, 4), 256);
+        write(cpu, VIRTIO_ADDRESS + 0x38, QUEUE_SIZE, 4);
+        for (register, address) in [(0x80, DESCRIPTORS), (0x90, AVAILABLE), (0xA0, USED)] {
+            write(cpu, VIRTIO_ADDRESS + register, address as u64 & 0xFFFF_FFFF, 4);
+            write(cpu, VIRTIO_ADDRESS + register + 4, address as u64 >> 32, 4);
+        }
+        write(cpu, VIRTIO_ADDRESS + 0x44, 1, 4);
+        write(cpu, VIRTIO_ADDRESS + 0x70, 0xF, 4);
+
+        write(cpu, PLIC_ADDRESS + 0x4, 1, 4);
+        write(cpu, PLIC_ADDRESS + 0x2000, 0b10, 4);
+        read(cpu, VIRTIO_ADDRESS + 0x70, 4)
+    }
+
+    /// Makes `descriptors` available as a chain, without notifying the device.
+    fn make_available(cpu: &CPU, descriptors: &[(usize, u64, u64)]) {
+        for (i, (address, length, flags)) in descriptors.iter().enumerate() {
+            let descriptor = DESCRIPTORS + 16 * i;
+            let next = if i + 1 < descriptors.len() { DESC_F_NEXT } else { 0 };
+            write(cpu, descriptor, *address as u64, 8);
+            write(cpu, descriptor + 8, *length, 4);
+            write(cpu, descriptor + 12, flags | next, 2);
+            write(cpu, descriptor + 14, i as u64 + 1, 2);
+        }
+        let index = read(cpu, AVAILABLE + 2, 2);
+        write(cpu, AVAILABLE + 4 + 2 * (index % QUEUE_SIZE) as usize, 0, 2);
+        write(cpu, AVAILABLE + 2, index + 1, 2);
+    }
+
+    /// Makes a request on queue 0 and notifies the device. Returns the status.
+    fn request(cpu: &CPU, request_type: u64, sector: u64, data: Option<(u64, u64)>) -> u8 {
+        write(cpu, HEADER, request_type, 4);
+        write(cpu, HEADER + 8, sector, 8);
+        write(cpu, STATUS, 0xFF, 1);
+        let mut descriptors = vec![(HEADER, 16, 0)];
+        if let Some((length, flags)) = data {
+            descriptors.push((DATA, length, flags));
+        }
+        descriptors.push((STATUS, 1, DESC_F_WRITE));
+        make_available(cpu, &descriptors);
+        write(cpu, VIRTIO_ADDRESS + 0x50, 0, 4);
+        read(cpu, STATUS, 1) as u8
+    }
+
+    fn external_pending(cpu: &CPU) -> bool {
+        cpu.harts[0].interrupt_line.as_ref().unwrap().load(Ordering::Relaxed) & MIP_MEIP != 0
+    }
+
+    #[test]
+    fn test_registers() {
+        let (cpu, path) = new_machine("registers", true);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS, 4), 0x7472_6976);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x4, 4), 2);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x8, 4), 2);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x100, 8), SECTORS as u64);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x104, 4), 0);
+
+        write(&cpu, VIRTIO_ADDRESS + 0x14, 0, 4);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x10, 4), VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH);
+        write(&cpu, VIRTIO_ADDRESS + 0x14, 1, 4);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x10, 4), 1);
+
+        // Features the device doesn't offer, or a driver without version 1, are refused.
+        assert_eq!(initialize(&cpu, VIRTIO_F_VERSION_1 | 1 << 28), 0x3);
+        write(&cpu, VIRTIO_ADDRESS + 0x70, 0, 4);
+        assert_eq!(initialize(&cpu, VIRTIO_BLK_F_FLUSH), 0x3);
+        write(&cpu, VIRTIO_ADDRESS + 0x70, 0, 4);
+        assert_eq!(initialize(&cpu, VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO), 0xF);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x44, 4), 1);
+        write(&cpu, VIRTIO_ADDRESS + 0x70, 0, 4);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x44, 4), 0);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x70, 4), 0);
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        let (cpu, path) = new_machine("read-write", false);
+        assert_eq!(initialize(&cpu, VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH), 0xF);
+
+        // Two sectors into the data buffer.
+        assert_eq!(request(&cpu, 0, 3, Some((1024, DESC_F_WRITE))), 0);
+        assert_eq!(read(&cpu, USED + 2, 2), 1);
+        assert_eq!((read(&cpu, USED + 4, 4), read(&cpu, USED + 8, 4)), (0, 1025));
+        assert_eq!((read(&cpu, DATA, 1), read(&cpu, DATA + 511, 1), read(&cpu, DATA + 512, 1)), (3, 3, 4));
+
+        cpu.bus.lock().write_bytes(DATA, &[0xAB; 512]).unwrap();
+        assert_eq!(request(&cpu, 1, 7, Some((512, 0))), 0);
+        assert_eq!(request(&cpu, 4, 0, None), 0);
+        assert_eq!(request(&cpu, 8, 0, Some((20, DESC_F_WRITE))), 0);
+        assert_eq!(&cpu.bus.lock().read_bytes(DATA, 6).unwrap()[..], b"yarve\0");
+        assert_eq!((read(&cpu, USED + 2, 2), read(&cpu, USED + 4 + 3 * 8 + 4, 4)), (4, 21));
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(image[7 * 512..].iter().all(|byte| *byte == 0xAB));
+        assert_eq!(image[7 * 512 - 1], 6);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (cpu, path) = new_machine("errors", true);
+        assert_eq!(initialize(&cpu, VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH), 0xF);
+        cpu.bus.lock().write_bytes(DATA, &[0xAB; 512]).unwrap();
+        // Writes to a read-only disk, past the end, and unknown requests.
+        assert_eq!(request(&cpu, 1, 0, Some((512, 0))), 1);
+        assert_eq!(request(&cpu, 0, 7, Some((1024, DESC_F_WRITE))), 1);
+        assert_eq!(request(&cpu, 0, 0, Some((100, DESC_F_WRITE))), 1);
+        assert_eq!(request(&cpu, 4, 0, None), 0);
+        assert_eq!(request(&cpu, 11, 0, None), 2);
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image[0], 0);
+
+        // A chain that loops needs a reset.
+        make_available(&cpu, &[(HEADER, 16, 0), (STATUS, 1, DESC_F_WRITE)]);
+        write(&cpu, DESCRIPTORS + 16 + 12, DESC_F_WRITE | DESC_F_NEXT, 2);
+        write(&cpu, DESCRIPTORS + 16 + 14, 1, 2);
+        write(&cpu, VIRTIO_ADDRESS + 0x50, 0, 4);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x70, 4), 0x4F);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x60, 4) & 0x2, 0x2);
+        assert_eq!(read(&cpu, USED + 2, 2), 5);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let (mut cpu, path) = new_machine("interrupt", true);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(initialize(&cpu, VIRTIO_F_VERSION_1), 0xF);
+        for (i, word) in PROGRAM.iter().enumerate() {
+            write(&cpu, ADDRESS + i * 4, *word as u64, 4);
+        }
+        write(&cpu, HANDLER, PROGRAM[2] as u64, 4);
+        let core = &mut cpu.harts[0];
+        core.pc = ADDRESS;
+        core.csr_registers[MTVEC] = HANDLER as u64;
+        core.csr_registers[MIE] = MIP_MEIP;
+        core.csr_registers[MSTATUS] |= MSTATUS_MIE;
+
+        // The request completes on the store of the guest, which then takes the interrupt.
+        write(&cpu, HEADER, 0, 4);
+        write(&cpu, HEADER + 8, 5, 8);
+        make_available(&cpu, &[(HEADER, 16, 0), (DATA, 512, DESC_F_WRITE), (STATUS, 1, DESC_F_WRITE)]);
+        cpu.run(3).unwrap();
+        assert_eq!(cpu.harts[0].csr_registers[MCAUSE], Interrupt::MachineExternal.get_cause());
+        assert_eq!(cpu.harts[0].pc, HANDLER);
+        assert_eq!(read(&cpu, DATA, 1), 5);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x60, 4), 1);
+
+        // Acknowledging the interrupt lowers the line, and a driver can suppress it.
+        assert_eq!(read(&cpu, PLIC_ADDRESS + 0x20_0004, 4), 1);
+        write(&cpu, VIRTIO_ADDRESS + 0x64, 1, 4);
+        write(&cpu, PLIC_ADDRESS + 0x20_0004, 1, 4);
+        assert!(!external_pending(&cpu));
+        write(&cpu, AVAILABLE, 1, 2);
+        assert_eq!(request(&cpu, 0, 1, Some((512, DESC_F_WRITE))), 0);
+        assert_eq!(read(&cpu, VIRTIO_ADDRESS + 0x60, 4), 0);
+        assert!(!external_pending(&cpu));
+    }
+
+    #[test]
+    fn test_fdt() {
+        let (cpu, path) = new_machine("fdt", true);
+        std::fs::remove_file(&path).unwrap();
+        let properties = parse(&cpu.generate_fdt().unwrap());
+        let node = "/virtio_mmio@10001000";
+        assert_eq!(get_property(&properties, node, "compatible").unwrap(), b"virtio,mmio\0");
+        assert_eq!(get_property(&properties, node, "interrupts").unwrap(), &[0, 0, 0, 1]);
+        assert_eq!(get_property(&properties, node, "interrupt-parent").unwrap(),
+                   get_property(&properties, "/plic@c000000", "phandle").unwrap());
+    }
+}
@@ -1,9 +1,15 @@
 use crate::device::{Device, DeviceError};
 use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 use std::any::Any;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fmt::{Formatter, Debug};
+use std::io::Write;
 
-const _UART_RBR: u8 = 0;
+const UART_RBR: u8 = 0;
 const UART_THR: u8 = 0;
 const _UART_DLL: u8 = 0;
 const _UART_IER: u8 = 1;
@@ -12,17 +18,54 @@ const _UART_IIR: u8 = 2;
 const _UART_FCR: u8 = 2;
 const _UART_LCR: u8 = 3;
 const _UART_MCR: u8 = 4;
-const _UART_LSR: u8 = 5;
+const UART_LSR: u8 = 5;
 const _UART_MSR: u8 = 6;
 const _UART_SCR: u8 = 7;
 
-pub struct UART {
+// Line status register fields
+const UART_LSR_DR: u8 = 1 << 0;
+const UART_LSR_THRE: u8 = 1 << 5;
+const UART_LSR_TEMT: u8 = 1 << 6;
+
+const UART_CLOCK_FREQUENCY: u32 = 3686400;
+
+const UART_FIFO_SIZE: usize = 16;
 
+
+/// 16550 UART. Transmitted bytes are printed to stdout unless another output is set,
+/// received bytes are passed in by the host with `receive`.
+pub struct UART {
+    // Reading RBR removes a byte, but reads only get a shared reference.
+    receive_buffer: RefCell<VecDeque<u8>>,
+    output: Option<Box<dyn Write + Send>>,
 }
 
 impl UART {
-    fn _new() -> Self {
-        Self { }
+    pub fn new() -> Self {
+        Self { receive_buffer: RefCell::new(VecDeque::with_capacity(UART_FIFO_SIZE)), output: None }
+    }
+
+    /// Sends transmitted bytes to `output` instead of stdout, e.g. to capture them.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = Some(output);
+    }
+
+    /// Adds `byte` to the receive FIFO. Returns false if the FIFO is full.
+    ///
+    /// To keep it in recordings, pass input through `CPU::receive_uart_input` instead.
+    pub fn receive(&mut self, byte: u8) -> bool {
+        let receive_buffer = self.receive_buffer.get_mut();
+        if receive_buffer.len() == UART_FIFO_SIZE {
+            return false
+        }
+        receive_buffer.push_back(byte);
+        true
+    }
+}
+
+impl Default for UART {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -35,9 +78,18 @@ impl Debug for UART {
 impl Device for UART {
     fn get_address_space_size(&self) -> usize { 8 }
 
-    fn read_bytes(&self, _address: usize, size: usize) -> Result<&[u8], DeviceError> {
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
         if size == 1 {
-            Ok(&[0])
+            // The divisor latch isn't implemented, so DLAB is ignored.
+            let value = match address as u8 {
+                UART_RBR => self.receive_buffer.borrow_mut().pop_front().unwrap_or(0),
+                UART_LSR => {
+                    let ready = !self.receive_buffer.borrow().is_empty();
+                    UART_LSR_THRE | UART_LSR_TEMT | if ready { UART_LSR_DR } else { 0 }
+                },
+                _ => 0
+            };
+            Ok(Cow::Owned(vec![value]))
         }
         else {
             Err(DeviceError::InvalidSizeReadFault)
@@ -46,9 +98,12 @@ impl Device for UART {
 
     fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
         if binary.len() == 1 {
-            match address as u8 {
-                UART_THR => print!("{}", binary[0] as char),
-                _ => {}
+            if address as u8 == UART_THR {
+                match &mut self.output {
+                    // Output errors aren't visible to the guest.
+                    Some(output) => { let _ = output.write_all(binary); },
+                    None => print!("{}", binary[0] as char)
+                }
             }
             Ok(())
         }
@@ -70,7 +125,32 @@ impl Device for UART {
         }
     }
 
+    fn get_name(&self) -> &str { "uart" }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        let receive_buffer: Vec<u8> = self.receive_buffer.borrow().iter().copied().collect();
+        writer.write_bytes(&receive_buffer)
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let receive_buffer = reader.read_bytes(UART_FIFO_SIZE, "uart")?;
+        *self.receive_buffer.get_mut() = receive_buffer.into();
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        fdt.begin_node(&format!("serial@{:x}", base_address))?;
+        fdt.property_string("compatible", "ns16550a")?;
+        fdt.property_reg("reg", &[(base_address as u64, self.get_address_space_size() as u64)])?;
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY)?;
+        fdt.end_node()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
@@ -1,4 +1,5 @@
 use num::{PrimInt};
+use crate::endianness::Endianness;
 
 
 pub fn extend_sign<T: PrimInt>(value: T, bits: usize) -> T {
@@ -6,3 +7,26 @@ pub fn extend_sign<T: PrimInt>(value: T, bits: usize) -> T {
     let mask = T::max_value() << bits;
     value | (sign_bit * mask)
 }
+
+/// Converts up to 8 bytes to an integer.
+pub fn int_from_bytes(bytes: &[u8], endianness: Endianness, sign_extend: bool) -> u64 {
+    let size = bytes.len();
+    let mut buffer = [0u8; 8];
+
+    let value = match endianness {
+        Endianness::LittleEndian => {
+            buffer[..size].copy_from_slice(bytes);
+            u64::from_le_bytes(buffer)
+        },
+        Endianness::BigEndian => {
+            buffer[(8 - size)..].copy_from_slice(bytes);
+            u64::from_be_bytes(buffer)
+        }
+    };
+
+    if sign_extend && size < 8 {
+        extend_sign(value, size * 8)
+    } else {
+        value
+    }
+}
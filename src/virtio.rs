@@ -0,0 +1,522 @@
+use crate::bus::Bus;
+use crate::device::{Device, DeviceError};
+use crate::endianness::Endianness;
+use crate::fdt::{FdtWriter, FdtError};
+use crate::plic::{InterruptSource, PLIC_LABEL};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::utilities::int_from_bytes;
+use std::any::Any;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+const VIRTIO_MMIO_SIZE: usize = 0x1000;
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00C;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0A0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0A4;
+const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0FC;
+const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+const VIRTIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_VERSION: u32 = 2;
+/// "YARV", as in the SBI implementation id.
+const VIRTIO_VENDOR_ID: u32 = 0x5941_5256;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_FEATURES_OK: u32 = 0x08;
+const STATUS_DRIVER_OK: u32 = 0x04;
+const STATUS_NEEDS_RESET: u32 = 0x40;
+
+const INTERRUPT_USED_BUFFER: u32 = 0x1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 0x2;
+
+const QUEUE_SIZE_MAX: u32 = 256;
+
+const VIRTQ_DESC_F_NEXT: u64 = 0x1;
+const VIRTQ_DESC_F_WRITE: u64 = 0x2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u64 = 0x1;
+
+
+#[derive(Debug)]
+pub enum VirtioError {
+    DeviceError(DeviceError),
+    /// The driver made more requests available than the queue holds.
+    InvalidAvailableIndex { queue: usize, index: u16 },
+    /// A descriptor chain loops, or has a readable buffer after a writable one.
+    InvalidChain { queue: usize, head: u16 },
+}
+
+/// A device behind the virtio-mmio transport, which handles the requests the driver
+/// makes on its queues.
+pub trait VirtioBackend: Send {
+    /// Virtio device id, e.g. 2 for a block device.
+    fn get_device_id(&self) -> u32;
+
+    /// Device-specific features offered to the driver. `VIRTIO_F_VERSION_1` is
+    /// always offered by the transport.
+    fn get_features(&self) -> u64;
+
+    fn get_queue_count(&self) -> usize;
+
+    /// Contents of the device configuration space.
+    fn get_config(&self) -> Vec<u8>;
+
+    /// Handles the request in `chain`, made on `queue`, accessing its buffers through
+    /// `bus`. Returns the number of bytes written to the writable buffers.
+    fn process_request(&mut self, queue: usize, chain: &DescriptorChain, bus: &mut Bus) -> Result<u32, VirtioError>;
+}
+
+/// Guest memory a descriptor points to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Buffer {
+    pub address: usize,
+    pub length: usize,
+}
+
+/// Buffers of a request, in the order of the descriptor chain.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DescriptorChain {
+    /// Buffers the device reads.
+    pub readable: Vec<Buffer>,
+    /// Buffers the device writes, which follow the readable ones.
+    pub writable: Vec<Buffer>,
+}
+
+impl DescriptorChain {
+    /// Contents of the readable buffers, concatenated.
+    pub fn read(&self, bus: &Bus) -> Result<Vec<u8>, DeviceError> {
+        let mut data = Vec::with_capacity(self.readable.iter().map(|buffer| buffer.length).sum());
+        for buffer in &self.readable {
+            data.extend_from_slice(&bus.read_bytes(buffer.address, buffer.length)?);
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` to the writable buffers, concatenated, starting `offset` bytes
+    /// into them. Returns the number of bytes written, which is less than the length
+    /// of `data` if the buffers end first.
+    pub fn write(&self, bus: &mut Bus, offset: usize, data: &[u8]) -> Result<usize, DeviceError> {
+        let (mut skipped, mut written) = (0, 0);
+        for buffer in &self.writable {
+            if written == data.len() {
+                break
+            }
+            if skipped + buffer.length <= offset {
+                skipped += buffer.length;
+                continue
+            }
+            let start = offset.saturating_sub(skipped).min(buffer.length);
+            let length = (buffer.length - start).min(data.len() - written);
+            bus.write_bytes(buffer.address + start, &data[written..written + length])?;
+            written += length;
+            skipped += buffer.length;
+        }
+        Ok(written)
+    }
+
+    /// Total length of the writable buffers.
+    pub fn writable_length(&self) -> usize {
+        self.writable.iter().map(|buffer| buffer.length).sum()
+    }
+}
+
+/// A split virtqueue, with the addresses of its descriptor table and rings.
+#[derive(Debug, Clone, Default)]
+struct Virtqueue {
+    size: u32,
+    ready: bool,
+    descriptors: u64,
+    driver: u64,
+    device: u64,
+    /// Next entries of the available and used rings.
+    next_available: u16,
+    next_used: u16,
+}
+
+/// virtio-mmio transport, with the version 2 register layout, for a `VirtioBackend`.
+///
+/// Notifications are handled right after the write to `QueueNotify`: the bus lets the
+/// device process the queue through `Device::access_bus`, and the requests complete
+/// before the write returns. The device then raises its interrupt through the PLIC,
+/// unless the driver suppressed it. A malformed queue sets `DEVICE_NEEDS_RESET`.
+pub struct VirtioMmio {
+    backend: Box<dyn VirtioBackend>,
+    interrupt: InterruptSource,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    interrupt_status: u32,
+    status: u32,
+    /// Queues notified since the bus last called `access_bus`.
+    notified: u64,
+    interrupt_raised: bool,
+}
+
+impl VirtioMmio {
+    pub fn new(backend: Box<dyn VirtioBackend>, interrupt: InterruptSource) -> Self {
+        let queues = vec![Virtqueue::default(); backend.get_queue_count()];
+        Self {
+            backend,
+            interrupt,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+            notified: 0,
+            interrupt_raised: false,
+        }
+    }
+
+    fn get_device_features(&self) -> u64 {
+        self.backend.get_features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.iter_mut().for_each(|queue| *queue = Virtqueue::default());
+        self.status = 0;
+        self.notified = 0;
+        self.set_interrupt_status(0);
+    }
+
+    fn set_interrupt_status(&mut self, interrupt_status: u32) {
+        self.interrupt_status = interrupt_status;
+        self.interrupt_raised |= self.interrupt.set_level(interrupt_status != 0);
+    }
+
+    fn get_register(&self, address: usize) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match address {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MAGIC,
+            VIRTIO_MMIO_VERSION => VIRTIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.backend.get_device_id(),
+            VIRTIO_MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.get_device_features() as u32,
+                1 => (self.get_device_features() >> 32) as u32,
+                _ => 0
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX),
+            VIRTIO_MMIO_QUEUE_NUM => queue.map_or(0, |queue| queue.size),
+            VIRTIO_MMIO_QUEUE_READY => queue.is_some_and(|queue| queue.ready) as u32,
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_MMIO_STATUS => self.status,
+            VIRTIO_MMIO_QUEUE_DESC_LOW => queue.map_or(0, |queue| queue.descriptors as u32),
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => queue.map_or(0, |queue| (queue.descriptors >> 32) as u32),
+            VIRTIO_MMIO_QUEUE_DRIVER_LOW => queue.map_or(0, |queue| queue.driver as u32),
+            VIRTIO_MMIO_QUEUE_DRIVER_HIGH => queue.map_or(0, |queue| (queue.driver >> 32) as u32),
+            VIRTIO_MMIO_QUEUE_DEVICE_LOW => queue.map_or(0, |queue| queue.device as u32),
+            VIRTIO_MMIO_QUEUE_DEVICE_HIGH => queue.map_or(0, |queue| (queue.device >> 32) as u32),
+            // The configuration never changes.
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            _ => 0
+        }
+    }
+
+    fn set_register(&mut self, address: usize, value: u32) {
+        let features_ok = self.status & STATUS_FEATURES_OK != 0;
+        match address {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES if !features_ok => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | value as u64,
+                1 => self.driver_features = (self.driver_features & 0xFFFF_FFFF) | (value as u64) << 32,
+                _ => {}
+            },
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let ready = self.queues.get(value as usize).is_some_and(|queue| queue.ready);
+                if ready && self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_NEEDS_RESET == 0 {
+                    self.notified |= 1 << value;
+                }
+            },
+            VIRTIO_MMIO_INTERRUPT_ACK => self.set_interrupt_status(self.interrupt_status & !value),
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                    return
+                }
+                let mut status = value & 0xFF;
+                // Features the device doesn't offer can't be accepted.
+                let features = self.driver_features;
+                if status & STATUS_FEATURES_OK != 0 && !features_ok &&
+                        (features & !self.get_device_features() != 0 || features & VIRTIO_F_VERSION_1 == 0) {
+                    status &= !STATUS_FEATURES_OK;
+                }
+                self.status = status | (self.status & STATUS_NEEDS_RESET);
+            },
+            _ => {
+                let Some(queue) = self.queues.get_mut(self.queue_sel as usize) else { return };
+                if queue.ready && address != VIRTIO_MMIO_QUEUE_READY {
+                    return
+                }
+                match address {
+                    VIRTIO_MMIO_QUEUE_NUM if value <= QUEUE_SIZE_MAX => queue.size = value,
+                    VIRTIO_MMIO_QUEUE_READY => queue.ready = value & 1 != 0 && queue.size != 0,
+                    VIRTIO_MMIO_QUEUE_DESC_LOW => queue.descriptors = (queue.descriptors & !0xFFFF_FFFF) | value as u64,
+                    VIRTIO_MMIO_QUEUE_DESC_HIGH => queue.descriptors = (queue.descriptors & 0xFFFF_FFFF) | (value as u64) << 32,
+                    VIRTIO_MMIO_QUEUE_DRIVER_LOW => queue.driver = (queue.driver & !0xFFFF_FFFF) | value as u64,
+                    VIRTIO_MMIO_QUEUE_DRIVER_HIGH => queue.driver = (queue.driver & 0xFFFF_FFFF) | (value as u64) << 32,
+                    VIRTIO_MMIO_QUEUE_DEVICE_LOW => queue.device = (queue.device & !0xFFFF_FFFF) | value as u64,
+                    VIRTIO_MMIO_QUEUE_DEVICE_HIGH => queue.device = (queue.device & 0xFFFF_FFFF) | (value as u64) << 32,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Handles the requests in the available ring of `index`. Returns true if the
+    /// driver should be interrupted.
+    fn process_queue(&mut self, index: usize, bus: &mut Bus) -> Result<bool, VirtioError> {
+        let queue = self.queues[index].clone();
+        let size = queue.size as usize;
+        let read_u16 = |bus: &Bus, address: u64| bus.read_int(address as usize, 2, Endianness::LittleEndian, false);
+        let available = read_u16(bus, queue.driver + 2)? as u16;
+        let (mut next_available, mut next_used) = (queue.next_available, queue.next_used);
+        if available.wrapping_sub(next_available) as u32 > queue.size {
+            return Err(VirtioError::InvalidAvailableIndex { queue: index, index: available })
+        }
+
+        while next_available != available {
+            let slot = next_available as usize % size;
+            let head = read_u16(bus, queue.driver + 4 + 2 * slot as u64)? as u16;
+            let chain = Self::read_chain(bus, &queue, index, head)?;
+            let written = self.backend.process_request(index, &chain, bus)?;
+
+            let element = queue.device as usize + 4 + 8 * (next_used as usize % size);
+            bus.write_int(element, head as u64, 4, Endianness::LittleEndian)?;
+            bus.write_int(element + 4, written as u64, 4, Endianness::LittleEndian)?;
+            next_used = next_used.wrapping_add(1);
+            bus.write_int(queue.device as usize + 2, next_used as u64, 2, Endianness::LittleEndian)?;
+            next_available = next_available.wrapping_add(1);
+            self.queues[index].next_available = next_available;
+            self.queues[index].next_used = next_used;
+        }
+
+        let flags = read_u16(bus, queue.driver)?;
+        Ok(next_used != queue.next_used && flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+    }
+
+    /// Follows the descriptors from `head`. A chain can't be longer than the queue.
+    fn read_chain(bus: &Bus, queue: &Virtqueue, index: usize, head: u16) -> Result<DescriptorChain, VirtioError> {
+        let mut chain = DescriptorChain::default();
+        let mut descriptor = head;
+        for _ in 0..queue.size {
+            if descriptor as u32 >= queue.size {
+                break
+            }
+            let address = queue.descriptors as usize + 16 * descriptor as usize;
+            let buffer_address = bus.read_int(address, 8, Endianness::LittleEndian, false)?;
+            let length = bus.read_int(address + 8, 4, Endianness::LittleEndian, false)?;
+            let flags = bus.read_int(address + 12, 2, Endianness::LittleEndian, false)?;
+            let buffer = Buffer { address: buffer_address as usize, length: length as usize };
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else if chain.writable.is_empty() {
+                chain.readable.push(buffer);
+            } else {
+                break
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain)
+            }
+            descriptor = bus.read_int(address + 14, 2, Endianness::LittleEndian, false)? as u16;
+        }
+        Err(VirtioError::InvalidChain { queue: index, head })
+    }
+}
+
+impl Debug for VirtioMmio {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VirtioMmio")
+    }
+}
+
+impl Device for VirtioMmio {
+    fn get_address_space_size(&self) -> usize { VIRTIO_MMIO_SIZE }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, DeviceError> {
+        if address >= VIRTIO_MMIO_CONFIG {
+            let config = self.backend.get_config();
+            let offset = address - VIRTIO_MMIO_CONFIG;
+            return Ok(Cow::Owned((offset..offset + size).map(|i| config.get(i).copied().unwrap_or(0)).collect()))
+        }
+        let value = self.read_int(address, size, Endianness::LittleEndian, false)?;
+        Ok(Cow::Owned(value.to_le_bytes()[..size].to_vec()))
+    }
+
+    fn write_bytes(&mut self, address: usize, binary: &[u8]) -> Result<(), DeviceError> {
+        // The configuration of the backends is read-only.
+        if address >= VIRTIO_MMIO_CONFIG {
+            return Ok(())
+        }
+        if binary.len() != 4 {
+            return Err(DeviceError::InvalidSizeWriteFault)
+        }
+        let value = int_from_bytes(binary, Endianness::LittleEndian, false);
+        self.write_int(address, value, 4, Endianness::LittleEndian)
+    }
+
+    fn read_int(&self, address: usize, size: usize, endianness: Endianness, sign_extend: bool)
+            -> Result<u64, DeviceError> {
+        if address >= VIRTIO_MMIO_CONFIG && size <= 8 {
+            return Ok(int_from_bytes(&self.read_bytes(address, size)?, endianness, sign_extend))
+        }
+        if size != 4 {
+            return Err(DeviceError::InvalidSizeReadFault)
+        }
+        if !address.is_multiple_of(4) {
+            return Err(DeviceError::MisalignedAddressReadTrap { address })
+        }
+        Ok(self.get_register(address) as u64)
+    }
+
+    fn write_int(&mut self, address: usize, value: u64, size: usize, _endianness: Endianness)
+                 -> Result<(), DeviceError> {
+        if address >= VIRTIO_MMIO_CONFIG {
+            return Ok(())
+        }
+        if size != 4 {
+            return Err(DeviceError::InvalidSizeWriteFault)
+        }
+        if !address.is_multiple_of(4) {
+            return Err(DeviceError::MisalignedAddressWriteTrap { address })
+        }
+        self.set_register(address, value as u32);
+        Ok(())
+    }
+
+    fn get_name(&self) -> &str { "virtio_mmio" }
+
+    fn take_interrupt_raised(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_raised)
+    }
+
+    fn take_bus_request(&mut self) -> bool {
+        self.notified != 0
+    }
+
+    fn access_bus(&mut self, bus: &mut Bus) -> Result<(), DeviceError> {
+        let mut interrupt_status = self.interrupt_status;
+        for index in 0..self.queues.len() {
+            if self.notified & 1 << index == 0 {
+                continue
+            }
+            match self.process_queue(index, bus) {
+                Ok(true) => interrupt_status |= INTERRUPT_USED_BUFFER,
+                Ok(false) => {},
+                Err(_) => {
+                    self.status |= STATUS_NEEDS_RESET;
+                    interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+                    break
+                }
+            }
+        }
+        self.notified = 0;
+        self.set_interrupt_status(interrupt_status);
+        Ok(())
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        writer.write_u32(self.device_features_sel)?;
+        writer.write_u64(self.driver_features)?;
+        writer.write_u32(self.driver_features_sel)?;
+        writer.write_u32(self.queue_sel)?;
+        writer.write_u32(self.interrupt_status)?;
+        writer.write_u32(self.status)?;
+        writer.write_u64(self.queues.len() as u64)?;
+        for queue in &self.queues {
+            writer.write_u32(queue.size)?;
+            writer.write_bool(queue.ready)?;
+            writer.write_u64(queue.descriptors)?;
+            writer.write_u64(queue.driver)?;
+            writer.write_u64(queue.device)?;
+            writer.write_u32(queue.next_available as u32)?;
+            writer.write_u32(queue.next_used as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Restores the transport. The contents of the backend, such as a disk image,
+    /// aren't part of the snapshot.
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.device_features_sel = reader.read_u32()?;
+        self.driver_features = reader.read_u64()?;
+        self.driver_features_sel = reader.read_u32()?;
+        self.queue_sel = reader.read_u32()?;
+        let interrupt_status = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        if reader.read_u64()? != self.queues.len() as u64 {
+            return Err(SnapshotError::InvalidState { name: "virtio_mmio".to_string() })
+        }
+        for queue in &mut self.queues {
+            queue.size = reader.read_u32()?.min(QUEUE_SIZE_MAX);
+            queue.ready = reader.read_bool()?;
+            queue.descriptors = reader.read_u64()?;
+            queue.driver = reader.read_u64()?;
+            queue.device = reader.read_u64()?;
+            queue.next_available = reader.read_u32()? as u16;
+            queue.next_used = reader.read_u32()? as u16;
+        }
+        self.notified = 0;
+        self.set_interrupt_status(interrupt_status);
+        Ok(())
+    }
+
+    fn fdt_node(&self, base_address: usize, fdt: &mut FdtWriter) -> Result<(), FdtError> {
+        let plic = fdt.get_phandle(PLIC_LABEL);
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base_address))?;
+        fdt.property_string("compatible", "virtio,mmio")?;
+        fdt.property_reg("reg", &[(base_address as u64, VIRTIO_MMIO_SIZE as u64)])?;
+        fdt.property_u32("interrupt-parent", plic)?;
+        fdt.property_u32("interrupts", self.interrupt.get_id())?;
+        fdt.end_node()
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+impl Display for VirtioError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for VirtioError {}
+
+impl From<DeviceError> for VirtioError {
+    fn from(error: DeviceError) -> Self {
+        Self::DeviceError(error)
+    }
+}
@@ -0,0 +1,128 @@
+use crate::bus::Bus;
+use crate::endianness::Endianness;
+use crate::utilities::int_from_bytes;
+use crate::virtio::{DescriptorChain, VirtioBackend, VirtioError};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: u64 = 512;
+const REQUEST_HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+
+/// virtio-blk backend serving a host disk image. The capacity is the size of the
+/// file, rounded down to whole sectors. A read-only disk fails writes with an I/O
+/// error, and flushes sync the file.
+#[derive(Debug)]
+pub struct VirtioBlock {
+    file: File,
+    read_only: bool,
+    capacity: u64,
+}
+
+impl VirtioBlock {
+    pub fn open(path: impl AsRef<Path>, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let capacity = file.metadata()?.len() / SECTOR_SIZE;
+        Ok(Self { file, read_only, capacity })
+    }
+
+    /// Size of the disk in sectors.
+    pub fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Performs a request on the disk, with the data that follows its header or the
+    /// length of the data it reads. Returns the data read and the status.
+    fn execute(&mut self, request_type: u32, sector: u64, data: &[u8], length: usize) -> (Vec<u8>, u8) {
+        let result = match request_type {
+            VIRTIO_BLK_T_IN => self.access(sector, length)
+                .and_then(|offset| {
+                    let mut buffer = vec![0; length];
+                    self.file.seek(SeekFrom::Start(offset))?;
+                    self.file.read_exact(&mut buffer)?;
+                    Ok(buffer)
+                }),
+            VIRTIO_BLK_T_OUT if self.read_only => Err(io::ErrorKind::PermissionDenied.into()),
+            VIRTIO_BLK_T_OUT => self.access(sector, data.len())
+                .and_then(|offset| {
+                    self.file.seek(SeekFrom::Start(offset))?;
+                    self.file.write_all(data)?;
+                    Ok(Vec::new())
+                }),
+            VIRTIO_BLK_T_FLUSH if self.read_only => Ok(Vec::new()),
+            VIRTIO_BLK_T_FLUSH => self.file.sync_data().map(|_| Vec::new()),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = b"yarve".to_vec();
+                id.resize(ID_SIZE, 0);
+                Ok(id)
+            },
+            _ => return (Vec::new(), VIRTIO_BLK_S_UNSUPP)
+        };
+        match result {
+            Ok(data) => (data, VIRTIO_BLK_S_OK),
+            Err(_) => (Vec::new(), VIRTIO_BLK_S_IOERR)
+        }
+    }
+
+    /// Offset in the file of `length` bytes at `sector`, which must be whole sectors
+    /// within the disk.
+    fn access(&self, sector: u64, length: usize) -> io::Result<u64> {
+        let length = length as u64;
+        if !length.is_multiple_of(SECTOR_SIZE) || sector.saturating_add(length / SECTOR_SIZE) > self.capacity {
+            return Err(io::ErrorKind::InvalidInput.into())
+        }
+        Ok(sector * SECTOR_SIZE)
+    }
+}
+
+impl VirtioBackend for VirtioBlock {
+    fn get_device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn get_features(&self) -> u64 {
+        VIRTIO_BLK_F_FLUSH | if self.read_only { VIRTIO_BLK_F_RO } else { 0 }
+    }
+
+    fn get_queue_count(&self) -> usize {
+        1
+    }
+
+    fn get_config(&self) -> Vec<u8> {
+        self.capacity.to_le_bytes().to_vec()
+    }
+
+    /// Handles a request made of a header, the data for writes, the buffers for the
+    /// data read, and a status byte at the end.
+    fn process_request(&mut self, _queue: usize, chain: &DescriptorChain, bus: &mut Bus) -> Result<u32, VirtioError> {
+        let writable_length = chain.writable_length();
+        let readable = chain.read(bus)?;
+        if readable.len() < REQUEST_HEADER_SIZE || writable_length == 0 {
+            return Ok(0)
+        }
+        let request_type = int_from_bytes(&readable[0..4], Endianness::LittleEndian, false) as u32;
+        let sector = int_from_bytes(&readable[8..16], Endianness::LittleEndian, false);
+
+        let (mut data, status) = self.execute(request_type, sector, &readable[REQUEST_HEADER_SIZE..], writable_length - 1);
+        data.truncate(writable_length - 1);
+        let written = chain.write(bus, 0, &data)?;
+        chain.write(bus, writable_length - 1, &[status])?;
+        Ok(written as u32 + 1)
+    }
+}